@@ -1,7 +1,9 @@
 use clap::{Args, Subcommand};
 use fclib::client::balloon::Balloon;
+use fclib::client::types::MiB;
 use fclib::client::ApiClient;
 
+use crate::output::OutputFormat;
 use crate::Result;
 
 #[derive(Debug, Clone, Args)]
@@ -12,7 +14,7 @@ pub(crate) struct BalloonArgs {
 
 #[derive(Debug, Clone, Args)]
 pub(crate) struct BalloonUpdateArgs {
-    size_mib: i32,
+    size_mib: MiB,
 }
 
 #[derive(Debug, Clone, Args)]
@@ -29,10 +31,18 @@ pub(crate) enum BalloonCmd {
     Update(BalloonUpdateArgs),
     /// Update configuration of balloon stats
     StatsUpdate(BalloonStatsUpdateArgs),
+    /// Print the configuration of the balloon device
+    Get,
+    /// Print the latest statistics reported by the balloon device
+    Stats,
 }
 
 impl BalloonCmd {
-    pub(crate) async fn parse(self, api_client: &mut ApiClient) -> Result<()> {
+    pub(crate) async fn parse(
+        self,
+        api_client: &mut ApiClient,
+        output: OutputFormat,
+    ) -> Result<()> {
         match self {
             BalloonCmd::Config(balloon) => {
                 api_client.configure_balloon(&balloon.balloon).await?;
@@ -45,6 +55,8 @@ impl BalloonCmd {
                     .update_balloon_stats_interval(balloon.stats_interval_sec)
                     .await?;
             }
+            BalloonCmd::Get => output.print(&api_client.balloon_config().await?)?,
+            BalloonCmd::Stats => output.print(&api_client.balloon_stats().await?)?,
         }
 
         Ok(())
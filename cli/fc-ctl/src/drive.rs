@@ -1,8 +1,14 @@
+use std::path::PathBuf;
+
 use clap::{Args, Subcommand};
 use fclib::client::drive::{Drive, PartialDrive};
+use fclib::client::partition::PartitionTable;
 use fclib::client::rate_limiter::RateLimiter;
+use fclib::client::resize::resize_image;
+use fclib::client::types::MiB;
 use fclib::client::ApiClient;
 
+use crate::output::OutputFormat;
 use crate::rate_limiter::{RateLimiterConf, WithRateLimiterConf};
 use crate::Result;
 
@@ -13,6 +19,10 @@ pub(crate) struct DriveHelper {
 
     #[clap(flatten)]
     rate_limiter: RateLimiterConf,
+
+    /// Do not fill in the PARTUUID of a root drive backed by a partitioned image.
+    #[arg(long)]
+    no_detect_partuuid: bool,
 }
 
 #[derive(Debug, Args)]
@@ -29,6 +39,64 @@ pub(crate) struct PartialDriveHelper {
 pub(crate) enum DriveCmd {
     Add(DriveHelper),
     Update(PartialDriveHelper),
+    Clone(CloneArgs),
+    Swap(SwapArgs),
+    /// List the partitions of a disk image, along with their PARTUUIDs
+    Partitions {
+        /// Disk image to inspect.
+        path: PathBuf,
+    },
+    /// Resize a disk image and the ext4 filesystem inside, growing its last partition first if it
+    /// is partitioned
+    ///
+    /// The image is extended sparsely. It cannot shrink below the space used by its filesystem.
+    /// The image must not be in use by a microVM.
+    Resize {
+        /// Disk image to resize.
+        path: PathBuf,
+        /// New size of the image, in MiB, or with a unit, e.g. `2G`.
+        size: MiB,
+    },
+}
+
+/// Clone a drive image, with a reflink where the filesystem supports it, and print the drive
+/// backed by the clone
+#[derive(Debug, Args)]
+pub(crate) struct CloneArgs {
+    /// Image to clone.
+    source: String,
+
+    /// Path of the clone, which must not exist.
+    path: PathBuf,
+
+    /// ID of the drive.
+    #[arg(long, default_value = "rootfs")]
+    drive_id: String,
+
+    /// The drive contains the root file system of the microVM.
+    #[arg(long)]
+    is_root_device: bool,
+
+    /// Add the drive to the microVM.
+    #[arg(long)]
+    add: bool,
+}
+
+/// Replace the backing file of a drive of a booted microVM
+///
+/// The new file must not be smaller than the current one. The drive is switched back to its
+/// current file if Firecracker does not apply the change.
+#[derive(Debug, Args)]
+pub(crate) struct SwapArgs {
+    /// ID of the drive.
+    drive_id: String,
+
+    /// New backing file of the drive.
+    path_on_host: String,
+
+    /// Pause the microVM during the swap.
+    #[arg(long)]
+    pause: bool,
 }
 
 impl WithRateLimiterConf for Drive {
@@ -44,11 +112,27 @@ impl WithRateLimiterConf for PartialDrive {
 }
 
 impl DriveCmd {
-    pub(crate) async fn parse(self, api_client: &mut ApiClient) -> Result<()> {
+    pub(crate) async fn parse(
+        self,
+        api_client: &mut ApiClient,
+        output: OutputFormat,
+        dry_run: bool,
+    ) -> Result<()> {
         match self {
             DriveCmd::Add(mut drive) => {
                 let d = &mut drive.drive;
                 drive.rate_limiter.parse_rate_limiter(d);
+                // Boot from the root partition of a partitioned image
+                if !drive.no_detect_partuuid {
+                    if let Err(err) = d.detect_partuuid() {
+                        if !dry_run {
+                            eprintln!(
+                                "Cannot read the partition table of {}: {err}",
+                                d.path_on_host
+                            );
+                        }
+                    }
+                }
                 api_client.add_drive(&d.drive_id, d).await?;
             }
             DriveCmd::Update(mut drive) => {
@@ -56,6 +140,32 @@ impl DriveCmd {
                 drive.rate_limiter.parse_rate_limiter(d);
                 api_client.update_drive(&d.drive_id, d).await?;
             }
+            DriveCmd::Clone(args) => {
+                let source = Drive::new(args.drive_id, args.source, args.is_root_device, false);
+                // The clone outlives fc-ctl
+                let drive = source.clone_to(&args.path)?.keep();
+                if args.add {
+                    api_client.add_drive(&drive.drive_id, &drive).await?;
+                }
+                output.print(&drive)?;
+            }
+            DriveCmd::Partitions { path } => match PartitionTable::read(&path)? {
+                Some(table) => {
+                    let mut value = serde_json::to_value(&table)?;
+                    value["root_partuuid"] = table
+                        .root_partition()
+                        .map(|partition| partition.partuuid.clone())
+                        .into();
+                    output.print(&value)?;
+                }
+                None => println!("{} is not partitioned", path.display()),
+            },
+            DriveCmd::Resize { path, size } => resize_image(&path, size)?,
+            DriveCmd::Swap(args) => {
+                api_client
+                    .swap_drive(&args.drive_id, &args.path_on_host, args.pause)
+                    .await?;
+            }
         }
 
         Ok(())
@@ -0,0 +1,34 @@
+use std::path::Path;
+
+use fclib::client::transport::ApiRequest;
+use fclib::client::vm::FullVmConfiguration;
+
+use crate::Result;
+
+/// Print the requests that would have been sent to Firecracker, one per line.
+pub(crate) fn print_requests(requests: &[ApiRequest]) {
+    for request in requests {
+        println!("{request}");
+    }
+}
+
+/// Add the pre-boot requests to the Firecracker configuration file at `path`.
+///
+/// If the file already exists, the requests are applied on top of the configuration it holds, so
+/// that a configuration file can be built with a sequence of `fc-ctl` invocations.
+pub(crate) fn emit_config(path: &Path, requests: &[ApiRequest]) -> Result<()> {
+    let mut config: FullVmConfiguration = if path.exists() {
+        serde_json::from_str(&std::fs::read_to_string(path)?)?
+    } else {
+        FullVmConfiguration::default()
+    };
+
+    for request in requests.iter().take_while(|r| !r.is_instance_start()) {
+        if !config.apply_request(request)? {
+            eprintln!("Not a pre-boot configuration request, skipping: {request}");
+        }
+    }
+
+    std::fs::write(path, serde_json::to_string_pretty(&config)?)?;
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use clap::{Args, Subcommand};
+use fclib::client::hotplug::MemoryHotplugConfig;
+use fclib::client::types::MiB;
+use fclib::client::ApiClient;
+
+use crate::output::OutputFormat;
+use crate::Result;
+
+#[derive(Debug, Args)]
+pub(crate) struct MemoryHotplugUpdateArgs {
+    /// Amount of memory, in MiB, that the guest should plug.
+    requested_size_mib: MiB,
+}
+
+/// Manage hotpluggable memory
+#[derive(Debug, Subcommand)]
+pub(crate) enum MemoryHotplugCmd {
+    /// Configure the memory hotplug device
+    Config(MemoryHotplugConfig),
+    /// Ask the guest to plug or unplug memory
+    Update(MemoryHotplugUpdateArgs),
+    /// Print the status of the memory hotplug device
+    Get,
+}
+
+/// Hotplug resources into the microVM
+#[derive(Debug, Subcommand)]
+pub(crate) enum HotplugCmd {
+    #[command(subcommand)]
+    Memory(MemoryHotplugCmd),
+}
+
+impl HotplugCmd {
+    pub(crate) async fn parse(&self, api_client: &ApiClient, output: OutputFormat) -> Result<()> {
+        match self {
+            HotplugCmd::Memory(MemoryHotplugCmd::Config(config)) => {
+                api_client.configure_memory_hotplug(config).await?
+            }
+            HotplugCmd::Memory(MemoryHotplugCmd::Update(args)) => {
+                api_client
+                    .update_memory_hotplug_size(args.requested_size_mib)
+                    .await?
+            }
+            HotplugCmd::Memory(MemoryHotplugCmd::Get) => {
+                output.print(&api_client.memory_hotplug_status().await?)?
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+use std::path::PathBuf;
+
+use clap::{ArgGroup, Args, Subcommand};
+use fclib::client::drive::Drive;
+use fclib::client::types::MiB;
+use fclib::client::ApiClient;
+use fclib::image::{DiskImage, Init, RootfsBuilder, Source};
+
+use crate::output::OutputFormat;
+use crate::Result;
+
+/// Build root filesystem images
+#[derive(Debug, Subcommand)]
+pub(crate) enum ImageCmd {
+    Build(BuildArgs),
+    Convert(ConvertArgs),
+}
+
+/// Build an ext4 root filesystem image, and print the drive backed by it
+///
+/// The image is built with `mkfs.ext4` and `debugfs`, from e2fsprogs, and does not require root.
+#[derive(Debug, Args)]
+#[command(group(ArgGroup::new("source").required(true).args(["dir", "oci"])))]
+pub(crate) struct BuildArgs {
+    /// Path of the image to write.
+    path: PathBuf,
+
+    /// Host directory to copy into the image.
+    #[arg(long)]
+    dir: Option<PathBuf>,
+
+    /// OCI image layout, or `docker save` output, as a tarball, to unpack into the image.
+    #[arg(long, value_name = "TARBALL")]
+    oci: Option<PathBuf>,
+
+    /// Size of the image, e.g. `1G`. By default, the image is sized for its content.
+    #[arg(long)]
+    size: Option<MiB>,
+
+    /// Free space left in an image sized for its content, e.g. `256M`.
+    #[arg(long, default_value = "64", conflicts_with = "size")]
+    free_space: MiB,
+
+    /// Label of the filesystem.
+    #[arg(long, default_value = "rootfs")]
+    label: String,
+
+    /// Host executable to inject as /sbin/init.
+    #[arg(long, value_name = "FILE")]
+    init: Option<PathBuf>,
+
+    /// Inject a /sbin/init that runs the entrypoint of the OCI image.
+    #[arg(long, requires = "oci", conflicts_with = "init")]
+    init_entrypoint: bool,
+
+    /// ID of the drive.
+    #[arg(long, default_value = "rootfs")]
+    drive_id: String,
+
+    /// Add the drive to the microVM, as its root device.
+    #[arg(long)]
+    add: bool,
+}
+
+/// Convert a qcow2 or VMDK image to a sparse raw image, and print the drive backed by it
+///
+/// Backing files of qcow2 images, and parents of VMDK images, are merged into the raw image.
+#[derive(Debug, Args)]
+pub(crate) struct ConvertArgs {
+    /// Image to convert.
+    source: PathBuf,
+
+    /// Path of the raw image to write.
+    path: String,
+
+    /// ID of the drive.
+    #[arg(long, default_value = "rootfs")]
+    drive_id: String,
+
+    /// The drive contains the root file system of the microVM.
+    #[arg(long)]
+    is_root_device: bool,
+
+    /// Add the drive to the microVM.
+    #[arg(long)]
+    add: bool,
+}
+
+impl ImageCmd {
+    pub(crate) async fn parse(
+        self,
+        api_client: &mut ApiClient,
+        output: OutputFormat,
+    ) -> Result<()> {
+        match self {
+            ImageCmd::Build(args) => {
+                let source = match (args.dir, args.oci) {
+                    (Some(dir), _) => Source::Dir(dir),
+                    (None, Some(tarball)) => Source::OciTarball(tarball),
+                    // clap requires one of them
+                    (None, None) => unreachable!(),
+                };
+                let mut builder = RootfsBuilder::new(source)
+                    .with_free_space(args.free_space)
+                    .with_label(args.label)
+                    .with_drive_id(args.drive_id);
+                if let Some(size) = args.size {
+                    builder = builder.with_size(size);
+                }
+                if let Some(init) = args.init {
+                    builder = builder.with_init(Init::Binary(init));
+                } else if args.init_entrypoint {
+                    builder = builder.with_init(Init::Entrypoint);
+                }
+
+                let drive = builder.build(&args.path)?;
+                if args.add {
+                    api_client.add_drive(&drive.drive_id, &drive).await?;
+                }
+                output.print(&drive)?;
+            }
+            ImageCmd::Convert(args) => {
+                DiskImage::open(&args.source)?.convert(&args.path)?;
+                let drive = Drive::new(args.drive_id, args.path, args.is_root_device, false);
+                if args.add {
+                    api_client.add_drive(&drive.drive_id, &drive).await?;
+                }
+                output.print(&drive)?;
+            }
+        }
+
+        Ok(())
+    }
+}
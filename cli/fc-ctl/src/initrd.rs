@@ -0,0 +1,52 @@
+use std::path::PathBuf;
+
+use clap::{Args, Subcommand};
+use fclib::initrd::{Compression, Initramfs};
+
+use crate::Result;
+
+/// Build initramfs archives
+#[derive(Debug, Subcommand)]
+pub(crate) enum InitrdCmd {
+    Build(BuildArgs),
+}
+
+/// Build a `newc` cpio archive, to boot a microVM with `kernel --initrd-path`
+///
+/// The content of the directories is added first, then the entries of the file lists.
+#[derive(Debug, Args)]
+pub(crate) struct BuildArgs {
+    /// Path of the archive to write.
+    path: PathBuf,
+
+    /// Host directory to add to the root of the archive. Can be repeated.
+    #[arg(long, value_name = "DIR")]
+    dir: Vec<PathBuf>,
+
+    /// File list in the format of the kernel's `gen_init_cpio`. Can be repeated.
+    #[arg(long, value_name = "FILE")]
+    file_list: Vec<PathBuf>,
+
+    /// Compression of the archive.
+    #[arg(long, value_enum, default_value = "gzip")]
+    compression: Compression,
+}
+
+impl InitrdCmd {
+    pub(crate) fn parse(self) -> Result<()> {
+        match self {
+            InitrdCmd::Build(args) => {
+                let mut initramfs = Initramfs::new().with_compression(args.compression);
+                for dir in &args.dir {
+                    initramfs.add_host_dir("/", dir)?;
+                }
+                for file_list in &args.file_list {
+                    initramfs.add_file_list(file_list)?;
+                }
+                initramfs.build(&args.path)?;
+            }
+        }
+
+        Ok(())
+    }
+}
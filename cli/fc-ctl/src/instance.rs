@@ -0,0 +1,32 @@
+use std::collections::BTreeMap;
+
+use fclib::client::version::Feature;
+use fclib::client::ApiClient;
+
+use crate::output::OutputFormat;
+use crate::Result;
+
+/// Print information about the microVM instance
+pub(crate) async fn info(api_client: &ApiClient, output: OutputFormat) -> Result<()> {
+    output.print(&api_client.instance_info().await?)
+}
+
+/// Print the version of the Firecracker process
+pub(crate) async fn version(api_client: &ApiClient, output: OutputFormat) -> Result<()> {
+    output.print(&api_client.firecracker_version().await?)
+}
+
+/// Print the full configuration of the microVM
+pub(crate) async fn config(api_client: &ApiClient, output: OutputFormat) -> Result<()> {
+    output.print(&api_client.vm_config().await?)
+}
+
+/// Print which of the features of newer Firecracker versions the Firecracker process has
+pub(crate) async fn features(api_client: &ApiClient, output: OutputFormat) -> Result<()> {
+    let version = api_client.fc_version().await?;
+    let features: BTreeMap<String, bool> = Feature::ALL
+        .iter()
+        .map(|feature| (feature.to_string(), feature.is_supported_by(&version)))
+        .collect();
+    output.print(&features)
+}
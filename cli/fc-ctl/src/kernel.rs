@@ -1,17 +1,73 @@
-use clap::Args;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use clap::{Args, Subcommand};
 use fclib::client::kernel::BootSource;
+use fclib::client::kernel_image::{
+    KernelImage, CONSOLE_OPTIONS, NET_OPTIONS, REQUIRED_OPTIONS, VSOCK_OPTIONS,
+};
 use fclib::client::ApiClient;
 
+use crate::output::OutputFormat;
 use crate::Result;
 
 /// Configure microVM guest kernel
 #[derive(Debug, Args)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub(crate) struct BootSourceArgs {
+    #[command(subcommand)]
+    command: Option<KernelCmd>,
+
     #[clap(flatten)]
-    kernel: BootSource,
+    kernel: Option<BootSource>,
+}
+
+#[derive(Debug, Subcommand)]
+enum KernelCmd {
+    Inspect(InspectArgs),
 }
 
-pub(crate) async fn parse(api_client: &mut ApiClient, args: &BootSourceArgs) -> Result<()> {
-    api_client.set_boot_source(&args.kernel).await?;
+/// Check that Firecracker can boot a kernel image on this host
+///
+/// Prints the format, architecture, entry point and version of the kernel, and, if its
+/// configuration is embedded, whether the options Firecracker needs to boot it, and the options of
+/// its devices, are built in, built as modules or missing.
+#[derive(Debug, Args)]
+struct InspectArgs {
+    /// Path to the kernel image.
+    path: PathBuf,
+}
+
+pub(crate) async fn parse(
+    api_client: &mut ApiClient,
+    args: &BootSourceArgs,
+    output: OutputFormat,
+) -> Result<()> {
+    match (&args.command, &args.kernel) {
+        (Some(KernelCmd::Inspect(args)), _) => inspect(args, output),
+        (None, Some(kernel)) => Ok(api_client.set_boot_source(kernel).await?),
+        // clap requires either a subcommand or a kernel
+        (None, None) => unreachable!(),
+    }
+}
+
+fn inspect(args: &InspectArgs, output: OutputFormat) -> Result<()> {
+    let kernel = KernelImage::inspect(&args.path)?;
+    // Whether the options are built in, as modules, or missing. Empty without embedded
+    // configuration.
+    let statuses = |options: &[&'static str]| -> BTreeMap<_, _> {
+        options
+            .iter()
+            .filter_map(|option| Some((*option, kernel.option_status(option)?)))
+            .collect()
+    };
+    let device_options = [NET_OPTIONS, VSOCK_OPTIONS, CONSOLE_OPTIONS].concat();
+
+    let mut inspection = serde_json::to_value(&kernel)?;
+    inspection["required_options"] = serde_json::to_value(statuses(REQUIRED_OPTIONS))?;
+    inspection["device_options"] = serde_json::to_value(statuses(&device_options))?;
+    output.print(&inspection)?;
+
+    kernel.check()?;
     Ok(())
 }
@@ -2,6 +2,7 @@ use clap::Subcommand;
 use fclib::client::vm::MachineConfiguration;
 use fclib::client::ApiClient;
 
+use crate::output::OutputFormat;
 use crate::Result;
 
 /// Configure architectural characteristics of the microVM
@@ -13,13 +14,16 @@ pub(crate) enum MachineConfigCmd {
 }
 
 impl MachineConfigCmd {
-    pub(crate) async fn parse(&self, api_client: &mut ApiClient) -> Result<()> {
+    pub(crate) async fn parse(
+        &self,
+        api_client: &mut ApiClient,
+        output: OutputFormat,
+    ) -> Result<()> {
         match self {
             MachineConfigCmd::Config(config) => api_client.configure_machine(config).await?,
             MachineConfigCmd::Get => {
                 let config = api_client.get_machine_configuration().await?;
-                let json = serde_json::to_string_pretty(&config)?;
-                println!("{json}");
+                output.print(&config)?;
             }
             MachineConfigCmd::Update(config) => {
                 api_client.update_machine_configuration(config).await?
@@ -1,23 +1,59 @@
 mod balloon;
 mod drive;
+mod dry_run;
 mod entropy;
+mod hotplug;
+mod image;
+mod initrd;
+mod instance;
 mod kernel;
 mod machine_config;
+mod mmds;
 mod network;
+mod output;
 mod rate_limiter;
+mod replay;
+mod serial;
 mod snapshot;
+mod validate;
 mod vm_state;
 mod vsock;
 
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
 use balloon::BalloonCmd;
 use clap::{Parser, Subcommand};
 use drive::DriveCmd;
 use entropy::EntropyArgs;
-use fclib::client::{ApiClient, FcClientError};
+use fclib::client::drive::DriveSwapError;
+use fclib::client::kernel_image::KernelImageError;
+use fclib::client::partition::PartitionError;
+use fclib::client::policy::RequestPolicy;
+use fclib::client::resize::ResizeError;
+use fclib::client::transport::{DryRun, Recorder};
+use fclib::client::validate::ValidationErrors;
+use fclib::client::version::parse_version;
+use fclib::client::{ApiClient, FcClientError, FcErrorKind};
+use fclib::image::ImageError;
+use fclib::initrd::InitrdError;
+use fclib::net::ipam::IpamError;
+use fclib::net::stats::StatsError;
+use fclib::net::NetError;
+use hotplug::HotplugCmd;
+use image::ImageCmd;
+use initrd::InitrdCmd;
 use kernel::BootSourceArgs;
 use machine_config::MachineConfigCmd;
+use mmds::MmdsCmd;
 use network::NetCommand;
+use output::OutputFormat;
+use replay::ReplayArgs;
+use semver::Version;
+use serial::SerialArgs;
 use snapshot::SnapshotCmd;
+use validate::ValidateArgs;
 use vm_state::VmStateCmd;
 use vsock::VsockArgs;
 
@@ -25,8 +61,135 @@ use vsock::VsockArgs;
 enum Error {
     #[error("Argument error: {0}")]
     Json(#[from] serde_json::Error),
+    #[error("Output error: {0}")]
+    Yaml(#[from] serde_yaml::Error),
     #[error("API Client error: {0}")]
     ApiClient(#[from] FcClientError),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("{0}")]
+    Invalid(#[from] ValidationErrors),
+    #[error("Invalid kernel image: {0}")]
+    Kernel(#[from] KernelImageError),
+    #[error("Initramfs error: {0}")]
+    Initrd(#[from] InitrdError),
+    #[error("Image error: {0}")]
+    Image(#[from] ImageError),
+    #[error("Partition table error: {0}")]
+    Partition(#[from] PartitionError),
+    #[error("Resize error: {0}")]
+    Resize(#[from] ResizeError),
+    #[error("Network error: {0}")]
+    Net(#[from] NetError),
+    #[error("Address allocation error: {0}")]
+    Ipam(#[from] IpamError),
+    #[error("Metrics error: {0}")]
+    Stats(#[from] StatsError),
+}
+
+/// Firecracker rejected the request, for any reason other than the ones below.
+const EXIT_FIRECRACKER: u8 = 1;
+/// Firecracker rejected the request because the microVM has already been started.
+const EXIT_NOT_ALLOWED_AFTER_BOOT: u8 = 10;
+/// Firecracker rejected the request because the microVM has not been started yet.
+const EXIT_NOT_ALLOWED_BEFORE_BOOT: u8 = 11;
+/// Firecracker rejected a field of the request body.
+const EXIT_INVALID_FIELD: u8 = 12;
+/// Firecracker could not use a host resource because it is busy.
+const EXIT_RESOURCE_BUSY: u8 = 13;
+/// The request refers to a device that does not exist.
+const EXIT_DEVICE_NOT_FOUND: u8 = 14;
+/// The request is not supported on the architecture of the host.
+const EXIT_UNSUPPORTED_ARCH: u8 = 15;
+/// The request does not match any API endpoint.
+const EXIT_INVALID_ENDPOINT: u8 = 16;
+/// Could not communicate with the Firecracker API server.
+const EXIT_CONNECTION: u8 = 3;
+/// Request or response body could not be (de)serialized.
+const EXIT_DATA: u8 = 4;
+/// A local file, or a network device of the host, could not be used.
+const EXIT_IO: u8 = 5;
+/// Firecracker did not respond in time.
+const EXIT_TIMEOUT: u8 = 6;
+/// The microVM configuration has problems.
+const EXIT_INVALID_CONFIG: u8 = 7;
+/// The request uses a feature that the running Firecracker does not have.
+const EXIT_UNSUPPORTED_VERSION: u8 = 17;
+
+impl Error {
+    /// Process exit code matching the class of the error.
+    fn exit_code(&self) -> u8 {
+        match self {
+            Error::ApiClient(err) if err.is_timeout() => EXIT_TIMEOUT,
+            Error::Json(_) | Error::Yaml(_) => EXIT_DATA,
+            Error::ApiClient(FcClientError::Serde(_) | FcClientError::InvalidVersion(_)) => {
+                EXIT_DATA
+            }
+            Error::ApiClient(FcClientError::Client(_) | FcClientError::Transport(_)) => {
+                EXIT_CONNECTION
+            }
+            Error::ApiClient(FcClientError::Firecracker(err)) => match err.kind {
+                FcErrorKind::NotAllowedAfterBoot => EXIT_NOT_ALLOWED_AFTER_BOOT,
+                FcErrorKind::NotAllowedBeforeBoot => EXIT_NOT_ALLOWED_BEFORE_BOOT,
+                FcErrorKind::InvalidField => EXIT_INVALID_FIELD,
+                FcErrorKind::ResourceBusy => EXIT_RESOURCE_BUSY,
+                FcErrorKind::DeviceNotFound => EXIT_DEVICE_NOT_FOUND,
+                FcErrorKind::UnsupportedArchitecture => EXIT_UNSUPPORTED_ARCH,
+                FcErrorKind::InvalidEndpoint => EXIT_INVALID_ENDPOINT,
+                FcErrorKind::Other => EXIT_FIRECRACKER,
+            },
+            Error::ApiClient(FcClientError::DryRun) => EXIT_CONNECTION,
+            Error::ApiClient(FcClientError::ConnectTimeout(_) | FcClientError::Timeout(_)) => {
+                EXIT_TIMEOUT
+            }
+            Error::ApiClient(FcClientError::Unsupported(_)) => EXIT_UNSUPPORTED_VERSION,
+            Error::ApiClient(FcClientError::DriveSwap(err)) => match err {
+                DriveSwapError::NewFile(..) => EXIT_IO,
+                DriveSwapError::UnknownDrive(_) => EXIT_DEVICE_NOT_FOUND,
+                DriveSwapError::TooSmall { .. } | DriveSwapError::VhostUser(_) => {
+                    EXIT_INVALID_CONFIG
+                }
+                DriveSwapError::NotApplied(_) | DriveSwapError::RollbackFailed { .. } => {
+                    EXIT_FIRECRACKER
+                }
+            },
+            Error::ApiClient(FcClientError::InvalidKernel(KernelImageError::Io(_)))
+            | Error::Kernel(KernelImageError::Io(_))
+            | Error::Initrd(InitrdError::Io(_))
+            | Error::Image(ImageError::Io(_) | ImageError::Command(..))
+            | Error::Partition(PartitionError::Io(_))
+            | Error::Resize(
+                ResizeError::Io(_)
+                | ResizeError::Command(..)
+                | ResizeError::Partition(PartitionError::Io(_)),
+            )
+            | Error::Net(
+                NetError::Failed(..)
+                | NetError::PermissionDenied(_)
+                | NetError::Command(..)
+                | NetError::Teardown(_),
+            )
+            | Error::Ipam(IpamError::Io(_))
+            | Error::Stats(StatsError::Io(_))
+            | Error::Io(_) => EXIT_IO,
+            Error::Net(NetError::Exists(_)) | Error::Ipam(IpamError::Exhausted(_)) => {
+                EXIT_RESOURCE_BUSY
+            }
+            Error::Net(NetError::NotFound(_) | NetError::NotTap(_)) => EXIT_DEVICE_NOT_FOUND,
+            Error::Initrd(_)
+            | Error::Image(_)
+            | Error::Partition(_)
+            | Error::Resize(ResizeError::Partition(_))
+            | Error::Ipam(IpamError::Json(_))
+            | Error::Stats(_) => EXIT_DATA,
+            Error::ApiClient(FcClientError::InvalidKernel(_))
+            | Error::Kernel(_)
+            | Error::Resize(ResizeError::TooSmall { .. } | ResizeError::Unsupported(_))
+            | Error::Net(_)
+            | Error::Ipam(_)
+            | Error::Invalid(_) => EXIT_INVALID_CONFIG,
+        }
+    }
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -38,11 +201,62 @@ struct Cli {
     #[arg(short, long, default_value = "/tmp/firecracker.socket")]
     api_sock: String,
 
+    /// Output format for commands that print data.
+    #[arg(short, long, global = true, value_enum, default_value_t = OutputFormat::Json)]
+    output: OutputFormat,
+
+    /// Print the HTTP requests that would be sent to Firecracker, instead of sending them.
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Instead of sending the pre-boot configuration requests to Firecracker, add them to the
+    /// Firecracker configuration file at this path. Implies `--dry-run`.
+    #[arg(long, global = true, value_name = "PATH")]
+    emit_config: Option<PathBuf>,
+
+    /// Maximum time for connecting to the API socket, e.g. `500ms`.
+    #[arg(long, global = true, value_parser = humantime::parse_duration)]
+    connect_timeout: Option<Duration>,
+
+    /// Maximum time for each request to complete, e.g. `5s`.
+    #[arg(long, global = true, value_parser = humantime::parse_duration)]
+    timeout: Option<Duration>,
+
+    /// Number of times a request is retried, if it is safe to do so.
+    #[arg(long, global = true, default_value_t = 0)]
+    retries: u32,
+
+    /// Append every request sent to Firecracker, along with its response, to this session file.
+    #[arg(long, global = true, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// Version of Firecracker to shape requests for, instead of querying it. Defaults to the
+    /// supported version in dry-run mode.
+    #[arg(long, global = true, value_name = "VERSION", value_parser = parse_fc_version)]
+    fc_version: Option<Version>,
+
+    /// Check the configuration on the host before sending it, e.g. inspect the kernel image.
+    #[arg(long, global = true)]
+    validate: bool,
+
     /// Command to execute.
     #[command(subcommand)]
     command: Commands,
 }
 
+impl Cli {
+    fn policy(&self) -> RequestPolicy {
+        let mut policy = RequestPolicy::default().with_retries(self.retries);
+        policy.connect_timeout = self.connect_timeout;
+        policy.request_timeout = self.timeout;
+        policy
+    }
+}
+
+fn parse_fc_version(version: &str) -> std::result::Result<Version, String> {
+    parse_version(version).ok_or_else(|| format!("invalid version `{version}`"))
+}
+
 #[derive(Debug, Subcommand)]
 enum Commands {
     #[command(subcommand)]
@@ -53,6 +267,10 @@ enum Commands {
     MachineConfig(MachineConfigCmd),
     Kernel(BootSourceArgs),
     #[command(subcommand)]
+    Initrd(InitrdCmd),
+    #[command(subcommand)]
+    Image(ImageCmd),
+    #[command(subcommand)]
     Microvm(VmStateCmd),
     #[command(subcommand)]
     Snapshot(SnapshotCmd),
@@ -60,24 +278,114 @@ enum Commands {
     #[command(subcommand)]
     Balloon(BalloonCmd),
     Vsock(VsockArgs),
+    #[command(subcommand)]
+    Mmds(MmdsCmd),
+    Serial(SerialArgs),
+    #[command(subcommand)]
+    Hotplug(HotplugCmd),
+    /// Print information about the microVM instance
+    Info,
+    /// Print the version of Firecracker
+    Version,
+    /// Print the full configuration of the microVM
+    Config,
+    /// Print which features of newer Firecracker versions are supported
+    Features,
+    Validate(ValidateArgs),
+    Replay(ReplayArgs),
+}
+
+async fn execute(
+    command: Commands,
+    api_sock: &str,
+    api_client: &mut ApiClient,
+    output: OutputFormat,
+    dry_run: bool,
+) -> Result<()> {
+    match command {
+        Commands::Drive(cmd) => cmd.parse(api_client, output, dry_run).await?,
+        Commands::MachineConfig(cmd) => cmd.parse(api_client, output).await?,
+        Commands::Net(cmd) => cmd.parse(api_client, output).await?,
+        Commands::Kernel(args) => kernel::parse(api_client, &args, output).await?,
+        Commands::Initrd(cmd) => cmd.parse()?,
+        Commands::Image(cmd) => cmd.parse(api_client, output).await?,
+        Commands::Microvm(cmd) => cmd.parse(api_client).await?,
+        Commands::Snapshot(cmd) => cmd.parse(api_client).await?,
+        Commands::Entropy(args) => entropy::parse(api_client, &args).await?,
+        Commands::Balloon(cmd) => cmd.parse(api_client, output).await?,
+        Commands::Vsock(args) => vsock::parse(api_client, &args).await?,
+        Commands::Mmds(cmd) => cmd.parse(api_client, output).await?,
+        Commands::Serial(args) => serial::parse(api_client, &args).await?,
+        Commands::Hotplug(cmd) => cmd.parse(api_client, output).await?,
+        Commands::Info => instance::info(api_client, output).await?,
+        Commands::Version => instance::version(api_client, output).await?,
+        Commands::Config => instance::config(api_client, output).await?,
+        Commands::Features => instance::features(api_client, output).await?,
+        Commands::Validate(args) => validate::validate(api_client, &args).await?,
+        Commands::Replay(args) => replay::serve(api_sock, &args).await?,
+    }
+
+    Ok(())
+}
+
+async fn run(args: Cli) -> Result<()> {
+    if !args.dry_run && args.emit_config.is_none() {
+        let mut api_client = ApiClient::new(&args.api_sock).with_validation(args.validate);
+        api_client.set_policy(args.policy());
+        if let Some(path) = &args.record {
+            api_client = api_client.record_to(path)?;
+        }
+        if let Some(version) = args.fc_version {
+            api_client = api_client.with_fc_version(version);
+        }
+        return execute(
+            args.command,
+            &args.api_sock,
+            &mut api_client,
+            args.output,
+            false,
+        )
+        .await;
+    }
+
+    let recorder = Recorder::new(DryRun);
+    let version = args.fc_version.unwrap_or_else(fclib::supported_fc_version);
+    let mut api_client = ApiClient::with_transport(recorder.clone())
+        .with_fc_version(version)
+        .with_validation(args.validate);
+    match execute(
+        args.command,
+        &args.api_sock,
+        &mut api_client,
+        args.output,
+        true,
+    )
+    .await
+    {
+        // GET requests have nothing to print in dry-run mode
+        Ok(()) | Err(Error::ApiClient(FcClientError::DryRun)) => (),
+        Err(err) => return Err(err),
+    }
+
+    let requests = recorder.requests();
+    match args.emit_config {
+        Some(path) => dry_run::emit_config(&path, &requests),
+        None => {
+            dry_run::print_requests(&requests);
+            Ok(())
+        }
+    }
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> ExitCode {
     let args = Cli::parse();
 
-    let mut api_client = ApiClient::new(args.api_sock);
-    match args.command {
-        Commands::Drive(cmd) => cmd.parse(&mut api_client).await?,
-        Commands::MachineConfig(cmd) => cmd.parse(&mut api_client).await?,
-        Commands::Net(cmd) => cmd.parse(&mut api_client).await?,
-        Commands::Kernel(args) => kernel::parse(&mut api_client, &args).await?,
-        Commands::Microvm(cmd) => cmd.parse(&api_client).await?,
-        Commands::Snapshot(cmd) => cmd.parse(&api_client).await?,
-        Commands::Entropy(args) => entropy::parse(&mut api_client, &args).await?,
-        Commands::Balloon(cmd) => cmd.parse(&mut api_client).await?,
-        Commands::Vsock(args) => vsock::parse(&mut api_client, &args).await?,
+    match run(args).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            ExitCode::from(err.exit_code())
+        }
     }
-
-    Ok(())
 }
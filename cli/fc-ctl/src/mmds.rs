@@ -0,0 +1,22 @@
+use clap::Subcommand;
+use fclib::client::ApiClient;
+
+use crate::output::OutputFormat;
+use crate::Result;
+
+/// Manage the microVM metadata service (MMDS)
+#[derive(Debug, Subcommand)]
+pub(crate) enum MmdsCmd {
+    /// Print the contents of the MMDS data store
+    Get,
+}
+
+impl MmdsCmd {
+    pub(crate) async fn parse(&self, api_client: &ApiClient, output: OutputFormat) -> Result<()> {
+        match self {
+            MmdsCmd::Get => output.print(&api_client.mmds_contents().await?)?,
+        }
+
+        Ok(())
+    }
+}
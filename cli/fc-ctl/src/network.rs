@@ -1,22 +1,36 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
 use clap::{Args, Subcommand};
 use fclib::client::network::{NetworkInterface, PartialNetworkInterface};
 use fclib::client::rate_limiter::RateLimiter;
 use fclib::client::ApiClient;
+use fclib::net::host::{self, HostNetworkBuilder, PortForward, DEFAULT_BRIDGE};
+use fclib::net::ipam::{Ipam, Subnet};
+use fclib::net::netns::NetNs;
+use fclib::net::stats::{InterfaceStats, MetricsReader};
+use fclib::net::tap::Tap;
+use fclib::net::NetError;
+use serde_json::{json, Value};
 
+use crate::output::OutputFormat;
 use crate::rate_limiter::RateLimiterConf;
 use crate::Result;
 
+const DEFAULT_LEASE_FILE: &str = "/var/lib/fc-ctl/leases.json";
+
 #[derive(Debug, Args)]
 pub(crate) struct TxRateLimiterConf {
     /// Rate limiter configuration for operations per second on the TX queue.
     /// Values [token_bucket_size, refill_time_in_msec, (initial_burst_size)]
     #[arg(long, num_args(2..4))]
-    tx_ops: Option<Vec<i64>>,
+    tx_ops: Option<Vec<u64>>,
 
     /// Rate limiter configuration for bandwidth on the TX queue.
     /// Values [token_bucket_size, refill_time_in_msec, (initial_burst_size)]
     #[arg(long, num_args(2..4))]
-    tx_bw: Option<Vec<i64>>,
+    tx_bw: Option<Vec<u64>>,
 }
 
 #[derive(Debug, Args)]
@@ -24,12 +38,12 @@ pub(crate) struct RxRateLimiterConf {
     /// Rate limiter configuration for operations per second on the RX queue.
     /// Values [token_bucket_size, refill_time_in_msec, (initial_burst_size)]
     #[arg(long, num_args(2..4))]
-    rx_ops: Option<Vec<i64>>,
+    rx_ops: Option<Vec<u64>>,
 
     /// Rate limiter configuration for bandwidth on the RX queue.
     /// Values [token_bucket_size, refill_time_in_msec, (initial_burst_size)]
     #[arg(long, num_args(2..4))]
-    rx_bw: Option<Vec<i64>>,
+    rx_bw: Option<Vec<u64>>,
 }
 
 #[derive(Debug, Args)]
@@ -47,21 +61,21 @@ trait WithNetRateLimiterConf {
 
 impl WithNetRateLimiterConf for NetworkInterface {
     fn set_tx_rate_limiter(&mut self, rate_limiter: RateLimiter) {
-        self.set_rx_rate_limiter(rate_limiter);
+        self.tx_rate_limiter = Some(rate_limiter);
     }
 
     fn set_rx_rate_limiter(&mut self, rate_limiter: RateLimiter) {
-        self.set_tx_rate_limiter(rate_limiter);
+        self.rx_rate_limiter = Some(rate_limiter);
     }
 }
 
 impl WithNetRateLimiterConf for PartialNetworkInterface {
     fn set_tx_rate_limiter(&mut self, rate_limiter: RateLimiter) {
-        self.set_rx_rate_limiter(rate_limiter);
+        self.tx_rate_limiter = Some(rate_limiter);
     }
 
     fn set_rx_rate_limiter(&mut self, rate_limiter: RateLimiter) {
-        self.set_tx_rate_limiter(rate_limiter);
+        self.rx_rate_limiter = Some(rate_limiter);
     }
 }
 
@@ -74,8 +88,9 @@ impl NetRateLimiterConf {
         if let Some(bw) = &self.tx_rate_limiter.tx_bw {
             rate_limiter.bandwidth = Some(RateLimiterConf::parse_token_bucket(bw));
         }
-        net.set_tx_rate_limiter(rate_limiter.clone());
+        net.set_tx_rate_limiter(rate_limiter);
 
+        let mut rate_limiter = RateLimiter::default();
         if let Some(ops) = &self.rx_rate_limiter.rx_ops {
             rate_limiter.ops = Some(RateLimiterConf::parse_token_bucket(ops));
         }
@@ -111,10 +126,121 @@ pub(crate) enum NetCommand {
     Add(NetworkInterfaceHelper),
     /// Update a network interface
     Update(PartialNetworkInterfaceHelper),
+    Up(UpArgs),
+    Down(DownArgs),
+    Stats(StatsArgs),
+}
+
+/// Connect a network interface of a microVM to the outside world through a bridge and NAT, and
+/// print it along with its addresses
+///
+/// The addresses are allocated in the lease file, then a TAP device is created on the bridge,
+/// which is created if needed, and firewall rules tagged with the ID of the microVM are added.
+/// Everything outlives fc-ctl until `net down`.
+#[derive(Debug, Args)]
+pub(crate) struct UpArgs {
+    /// ID of the microVM.
+    vm_id: String,
+
+    /// Name of the TAP device to create.
+    #[arg(long)]
+    tap: String,
+
+    /// ID of the network interface.
+    #[arg(long, default_value = "eth0")]
+    iface_id: String,
+
+    /// Subnet to allocate the address of the guest from. Can be repeated.
+    #[arg(long, default_value = "172.16.0.0/24")]
+    subnet: Vec<Subnet>,
+
+    /// Lease file of the allocated addresses.
+    #[arg(long, default_value = DEFAULT_LEASE_FILE)]
+    lease_file: PathBuf,
+
+    /// Bridge to attach the TAP device to.
+    #[arg(long, default_value = DEFAULT_BRIDGE)]
+    bridge: String,
+
+    /// User allowed to open the TAP device, e.g. the user Firecracker runs as.
+    #[arg(long)]
+    owner: Option<u32>,
+
+    /// MTU of the TAP device.
+    #[arg(long)]
+    mtu: Option<u32>,
+
+    /// Forward a port of the host to the guest, e.g. `8080:80/tcp`. Can be repeated.
+    #[arg(long)]
+    forward: Vec<PortForward>,
+
+    /// Enable IPv6 forwarding on the host, if an IPv6 guest needs it. The host then ignores router
+    /// advertisements, and forwarding stays enabled after `net down`.
+    #[arg(long)]
+    ipv6_forwarding: bool,
+
+    /// Add the network interface to the microVM.
+    #[arg(long)]
+    add: bool,
+}
+
+/// Remove the networking of a microVM set up by `net up`
+///
+/// All the firewall rules of the microVM are removed, along with its TAP devices, and its addresses
+/// are released.
+#[derive(Debug, Args)]
+pub(crate) struct DownArgs {
+    /// ID of the microVM.
+    vm_id: String,
+
+    /// TAP device to delete. Can be repeated.
+    #[arg(long)]
+    tap: Vec<String>,
+
+    /// Lease file of the allocated addresses.
+    #[arg(long, default_value = DEFAULT_LEASE_FILE)]
+    lease_file: PathBuf,
+
+    /// Also delete this bridge, if no device is attached to it anymore.
+    #[arg(long)]
+    bridge: Option<String>,
+}
+
+/// Print the traffic of the network interfaces of the microVM, and its rates
+///
+/// The counters of the TAP devices of the host are joined with the net metrics of Firecracker, if
+/// they are configured, by interface ID. The rates are measured over an interval, after which the
+/// metrics are flushed again.
+#[derive(Debug, Args)]
+pub(crate) struct StatsArgs {
+    /// Metrics file of Firecracker. Defaults to the one the microVM is configured with.
+    #[arg(long)]
+    metrics: Option<PathBuf>,
+
+    /// Interval to measure the rates over, e.g. `500ms`.
+    #[arg(long, default_value = "1s", value_parser = humantime::parse_duration)]
+    interval: Duration,
+
+    /// Keep printing the traffic after each interval.
+    #[arg(long)]
+    watch: bool,
+
+    /// Do not flush the metrics of Firecracker, e.g. before the microVM is started. The rates of
+    /// the guest are then only known when Firecracker flushed them in the interval.
+    #[arg(long)]
+    no_flush: bool,
+
+    /// Named network namespace Firecracker runs in, where its TAP devices are.
+    #[arg(long)]
+    netns: Option<String>,
 }
 
 impl NetCommand {
-    pub(crate) async fn parse(self, api_client: &mut ApiClient) -> Result<()> {
+    pub(crate) async fn parse(
+        self,
+        api_client: &mut ApiClient,
+        output: OutputFormat,
+    ) -> Result<()> {
         match self {
             Self::Add(mut net) => {
                 let n = &mut net.net;
@@ -126,8 +252,157 @@ impl NetCommand {
                 net.rate_limiter.parse_rate_limiters(n)?;
                 api_client.update_network_interface(&n.iface_id, n).await?;
             }
+            Self::Up(args) => up(api_client, output, args).await?,
+            Self::Down(args) => down(args)?,
+            Self::Stats(args) => stats(api_client, output, args).await?,
         }
 
         Ok(())
     }
 }
+
+async fn up(api_client: &mut ApiClient, output: OutputFormat, args: UpArgs) -> Result<()> {
+    if let Some(dir) = args.lease_file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let ipam = Ipam::new(&args.lease_file, args.subnet);
+    let leased = ipam
+        .vm_leases(&args.vm_id)?
+        .iter()
+        .any(|lease| lease.iface_id == args.iface_id);
+    let lease = ipam.allocate(&args.vm_id, &args.iface_id)?;
+    let mut builder = HostNetworkBuilder::new(&args.vm_id, &args.tap, lease.clone())
+        .with_bridge(&args.bridge)
+        .with_ipv6_forwarding(args.ipv6_forwarding);
+    if let Some(uid) = args.owner {
+        builder = builder.with_owner(uid);
+    }
+    if let Some(mtu) = args.mtu {
+        builder = builder.with_mtu(mtu);
+    }
+    for forward in args.forward {
+        builder = builder.with_port_forward(forward);
+    }
+    let network = match builder.setup() {
+        Ok(network) => network,
+        Err(err) => {
+            // Keep the lease of an interface that is already up
+            if !leased {
+                ipam.release(&args.vm_id, &args.iface_id)?;
+            }
+            return Err(err.into());
+        }
+    };
+
+    if network.enabled_forwarding() {
+        let version = if lease.address.is_ipv6() { 6 } else { 4 };
+        eprintln!("Enabled IPv{version} forwarding on the host, it stays enabled after `net down`");
+    }
+    let iface = network.network_interface();
+    if args.add {
+        // The TAP device and the rules are removed, and a new lease released, if the microVM
+        // rejects the interface
+        if let Err(err) = api_client
+            .add_network_interface(&iface.iface_id, &iface)
+            .await
+        {
+            drop(network);
+            if !leased {
+                ipam.release(&args.vm_id, &args.iface_id)?;
+            }
+            return Err(err.into());
+        }
+    }
+    let forwards: Vec<String> = network
+        .port_forwards()
+        .iter()
+        .map(PortForward::to_string)
+        .collect();
+    network.keep();
+    output.print(&json!({
+        "network_interface": iface,
+        "lease": lease,
+        "boot_args": lease.ip_config().map(|ip| format!("ip={ip}")),
+        "port_forwards": forwards,
+    }))
+}
+
+fn down(args: DownArgs) -> Result<()> {
+    let removed = host::remove_vm_rules(&args.vm_id)?;
+    for tap in &args.tap {
+        match Tap::open(tap) {
+            Ok(tap) => tap.delete()?,
+            Err(NetError::NotFound(_)) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    let released = if args.lease_file.exists() {
+        Ipam::new(&args.lease_file, Vec::new()).release_vm(&args.vm_id)?
+    } else {
+        Vec::new()
+    };
+    if let Some(bridge) = &args.bridge {
+        if !host::delete_bridge(bridge, true)? {
+            eprintln!("Bridge {bridge} is still in use, not deleting it");
+        }
+    }
+    eprintln!(
+        "Removed {removed} firewall rules and released {} leases of {}",
+        released.len(),
+        args.vm_id
+    );
+    Ok(())
+}
+
+async fn stats(api_client: &ApiClient, output: OutputFormat, args: StatsArgs) -> Result<()> {
+    let config = api_client.vm_config().await?;
+    let ifaces = config.network_interfaces.unwrap_or_default();
+    let mut metrics = args
+        .metrics
+        .or_else(|| config.metrics.map(|metrics| metrics.metrics_path.into()))
+        .map(MetricsReader::new);
+    let flush = !args.no_flush;
+    let netns = args.netns.as_deref().map(NetNs::open).transpose()?;
+    let netns = netns.as_ref();
+
+    let mut earlier = sample(api_client, &ifaces, metrics.as_mut(), flush, netns).await?;
+    loop {
+        tokio::time::sleep(args.interval).await;
+        let stats = sample(api_client, &ifaces, metrics.as_mut(), flush, netns).await?;
+        let report = stats
+            .iter()
+            .zip(&earlier)
+            .map(|(now, before)| {
+                let mut value = serde_json::to_value(now)?;
+                value["rates"] = serde_json::to_value(now.rates(before))?;
+                Ok(value)
+            })
+            .collect::<Result<Vec<Value>>>()?;
+        output.print(&report)?;
+        if !args.watch {
+            return Ok(());
+        }
+        earlier = stats;
+    }
+}
+
+// Sample the traffic of `ifaces`, in `netns` if any, after flushing the metrics of Firecracker if
+// `flush` is set.
+async fn sample(
+    api_client: &ApiClient,
+    ifaces: &[NetworkInterface],
+    metrics: Option<&mut MetricsReader>,
+    flush: bool,
+    netns: Option<&NetNs>,
+) -> Result<Vec<InterfaceStats>> {
+    let metrics = match metrics {
+        Some(reader) => {
+            if flush {
+                api_client.flush_metrics().await?;
+            }
+            Some(reader.read()?)
+        }
+        None => None,
+    };
+    Ok(InterfaceStats::sample_all(ifaces, metrics, netns)?)
+}
@@ -0,0 +1,76 @@
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::Result;
+
+/// Format used for printing the responses of read commands
+#[derive(Debug, Default, Clone, Copy, ValueEnum)]
+pub(crate) enum OutputFormat {
+    /// Pretty-printed JSON
+    #[default]
+    Json,
+    /// YAML document
+    Yaml,
+    /// Two-column table with one row per (flattened) field
+    Table,
+}
+
+impl OutputFormat {
+    /// Print `value` on stdout using this format.
+    pub(crate) fn print<T: Serialize>(&self, value: &T) -> Result<()> {
+        match self {
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(value)?),
+            OutputFormat::Yaml => print!("{}", serde_yaml::to_string(value)?),
+            OutputFormat::Table => {
+                let mut rows = Vec::new();
+                flatten("", &serde_json::to_value(value)?, &mut rows);
+                print_table(&rows);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Flatten a JSON value into (key, value) rows. Nested objects and arrays are
+// expanded using dotted keys, e.g. `drives.0.drive_id`.
+fn flatten(prefix: &str, value: &Value, rows: &mut Vec<(String, String)>) {
+    let key = |k: &str| {
+        if prefix.is_empty() {
+            k.to_owned()
+        } else {
+            format!("{prefix}.{k}")
+        }
+    };
+
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (k, v) in map {
+                flatten(&key(k), v, rows);
+            }
+        }
+        Value::Array(array) if !array.is_empty() => {
+            for (i, v) in array.iter().enumerate() {
+                flatten(&key(&i.to_string()), v, rows);
+            }
+        }
+        Value::String(s) => rows.push((prefix.to_owned(), s.clone())),
+        Value::Null => rows.push((prefix.to_owned(), "-".to_owned())),
+        other => rows.push((prefix.to_owned(), other.to_string())),
+    }
+}
+
+fn print_table(rows: &[(String, String)]) {
+    let width = rows
+        .iter()
+        .map(|(k, _)| k.len())
+        .chain(std::iter::once("FIELD".len()))
+        .max()
+        .unwrap_or_default();
+
+    println!("{:width$}  VALUE", "FIELD");
+    for (key, value) in rows {
+        println!("{key:width$}  {value}");
+    }
+}
@@ -10,16 +10,16 @@ pub(crate) struct RateLimiterConf {
     /// Rate limiter configuration for operations per second.
     /// Values [token_bucket_size, refill_time_in_msec, (initial_burst_size)]
     #[arg(long, num_args(2..4))]
-    ops: Option<Vec<i64>>,
+    ops: Option<Vec<u64>>,
 
     /// Rate limiter configuration for bandwidth.
     /// Values [token_bucket_size, refill_time_in_msec, (initial_burst_size)]
     #[arg(long, num_args(2..4))]
-    bw: Option<Vec<i64>>,
+    bw: Option<Vec<u64>>,
 }
 
 impl RateLimiterConf {
-    pub(crate) fn parse_token_bucket(args: &[i64]) -> TokenBucket {
+    pub(crate) fn parse_token_bucket(args: &[u64]) -> TokenBucket {
         let mut bucket = TokenBucket::new(args[0], args[1]);
         if args.len() == 3 {
             bucket.one_time_burst = Some(args[2]);
@@ -0,0 +1,31 @@
+use std::path::PathBuf;
+
+use clap::Args;
+use fclib::client::session::{read_session, ReplayServer};
+
+use crate::Result;
+
+/// Replay a recorded API session
+///
+/// Serves the responses recorded with `--record` on the API socket, until all the recorded
+/// requests have been replayed.
+#[derive(Debug, Args)]
+pub(crate) struct ReplayArgs {
+    /// Session file written with `--record`
+    session: PathBuf,
+
+    /// Delay every response by the latency recorded for it
+    #[arg(long)]
+    preserve_latency: bool,
+}
+
+pub(crate) async fn serve(api_sock: &str, args: &ReplayArgs) -> Result<()> {
+    let exchanges = read_session(&args.session)?;
+    let mut server = ReplayServer::bind(api_sock, exchanges)?;
+    if args.preserve_latency {
+        server = server.preserve_latency();
+    }
+
+    server.serve().await?;
+    Ok(())
+}
@@ -0,0 +1,17 @@
+use clap::Args;
+use fclib::client::serial::SerialConfig;
+use fclib::client::ApiClient;
+
+use crate::Result;
+
+/// Configure the serial console of the microVM
+#[derive(Debug, Args)]
+pub(crate) struct SerialArgs {
+    #[clap(flatten)]
+    serial: SerialConfig,
+}
+
+pub(crate) async fn parse(api_client: &ApiClient, args: &SerialArgs) -> Result<()> {
+    api_client.configure_serial(&args.serial).await?;
+    Ok(())
+}
@@ -0,0 +1,27 @@
+use std::path::PathBuf;
+
+use clap::Args;
+use fclib::client::vm::FullVmConfiguration;
+use fclib::client::ApiClient;
+
+use crate::Result;
+
+/// Check a microVM configuration for problems before booting it
+///
+/// Without a configuration file, the configuration of the running Firecracker is checked.
+#[derive(Debug, Args)]
+pub(crate) struct ValidateArgs {
+    /// Firecracker configuration file to check.
+    config: Option<PathBuf>,
+}
+
+pub(crate) async fn validate(api_client: &ApiClient, args: &ValidateArgs) -> Result<()> {
+    let config: FullVmConfiguration = match &args.config {
+        Some(path) => serde_json::from_str(&std::fs::read_to_string(path)?)?,
+        None => api_client.vm_config().await?,
+    };
+
+    config.validate()?;
+    println!("The microVM configuration is valid");
+    Ok(())
+}
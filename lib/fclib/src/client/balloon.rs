@@ -12,6 +12,7 @@
 use clap::Args;
 use serde_derive::{Deserialize, Serialize};
 
+use super::types::MiB;
 use super::{ApiClient, Result};
 
 /// Configuration of a Firecracker Balloon device
@@ -19,12 +20,23 @@ use super::{ApiClient, Result};
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Balloon {
     /// Target balloon size in MiB.
-    pub amount_mib: i32,
+    pub amount_mib: MiB,
     /// Whether the balloon should deflate when the guest has memory pressure.
+    #[cfg_attr(feature = "clap", arg(long))]
     pub deflate_on_oom: bool,
     /// Interval in seconds between refreshing statistics. A non-zero value will enable the
     /// statistics. Defaults to 0.
     pub stats_polling_interval_s: Option<i32>,
+    /// Let the guest hint the pages it does not use to the host. Requires Firecracker 1.14 or
+    /// newer.
+    #[cfg_attr(feature = "clap", arg(long))]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub free_page_hinting: bool,
+    /// Let the guest report the pages it frees to the host, which reclaims them. Requires
+    /// Firecracker 1.14 or newer.
+    #[cfg_attr(feature = "clap", arg(long))]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub free_page_reporting: bool,
 }
 
 /// Statistics that the balloon device can report to host
@@ -73,7 +85,7 @@ pub struct BalloonStatsUpdate {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BalloonUpdate {
     /// Target balloon size in MiB.
-    pub amount_mib: i32,
+    pub amount_mib: MiB,
 }
 
 impl ApiClient {
@@ -88,7 +100,7 @@ impl ApiClient {
     }
 
     /// Update the maximum size of the balloon device
-    pub async fn update_balloon_size(&self, size: i32) -> Result<()> {
+    pub async fn update_balloon_size(&self, size: MiB) -> Result<()> {
         let balloon_update = BalloonUpdate { amount_mib: size };
         self.patch("/balloon", balloon_update).await
     }
@@ -104,6 +116,6 @@ impl ApiClient {
 
     /// Get the balloon device statistics
     pub async fn balloon_stats(&self) -> Result<BalloonStats> {
-        self.get("/ballon/statistics").await
+        self.get("/balloon/statistics").await
     }
 }
@@ -0,0 +1,591 @@
+//! Kernel command line builder
+//!
+//! [`KernelCmdline`] builds the `boot_args` of a [`BootSource`] from typed options, instead of a
+//! free-form string. Setting a parameter replaces all its values, at the position of the first one,
+//! while parsing keeps parameters that are repeated, e.g. `console=tty0 console=ttyS0`. Values that
+//! contain whitespace are quoted, and the command line is checked against the length limit of the
+//! guest kernel.
+//!
+//! The `root=` and `ip=` parameters can be derived from the [`Drive`] and [`NetworkInterface`]
+//! entries of the microVM:
+//!
+//! - Firecracker attaches the root device first, so it is `/dev/vda` in the guest, unless it has a
+//!   partuuid;
+//! - network interfaces are `eth0`, `eth1`... in the guest, in the order they are attached.
+//!
+//! [`BootSource`]: super::kernel::BootSource
+
+use std::fmt;
+use std::net::Ipv4Addr;
+
+use super::drive::Drive;
+use super::network::NetworkInterface;
+
+/// Size of the command line buffer of the guest kernel, `COMMAND_LINE_SIZE`, on x86_64 and aarch64
+///
+/// It includes the terminating NUL byte. Firecracker appends its own parameters to the command
+/// line, e.g. to describe virtio-mmio devices, which also count towards this limit.
+pub const MAX_LEN: usize = 2048;
+
+/// An invalid kernel command line
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error, displaydoc::Display)]
+pub enum CmdlineError {
+    /// invalid parameter name `{0}`
+    InvalidName(String),
+    /// invalid value `{1}` for parameter `{0}`
+    InvalidValue(String, String),
+    /// invalid init argument `{0}`
+    InvalidInitArg(String),
+    /// invalid hostname `{0}`
+    InvalidHostname(String),
+    /// network interface `{0}` is not configured
+    UnknownInterface(String),
+    /// the command line is {0} bytes long, which exceeds the limit of {1} bytes
+    TooLong(usize, usize),
+}
+
+/// Console of the guest kernel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Console {
+    /// The serial port emulated by Firecracker, `ttyS0`
+    Serial,
+    /// Any other device, e.g. `hvc0`
+    Device(String),
+}
+
+impl fmt::Display for Console {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Console::Serial => f.write_str("ttyS0"),
+            Console::Device(device) => f.write_str(device),
+        }
+    }
+}
+
+/// How the guest kernel reboots, i.e. `reboot=`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebootMode {
+    /// Through the keyboard controller, which Firecracker handles by stopping the microVM
+    Kbd,
+    /// With a triple fault
+    Triple,
+    /// Through the BIOS
+    Bios,
+    /// Through ACPI
+    Acpi,
+    /// Through EFI
+    Efi,
+    /// Through the PCI configuration port
+    Pci,
+}
+
+impl fmt::Display for RebootMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RebootMode::Kbd => "k",
+            RebootMode::Triple => "t",
+            RebootMode::Bios => "b",
+            RebootMode::Acpi => "a",
+            RebootMode::Efi => "e",
+            RebootMode::Pci => "p",
+        })
+    }
+}
+
+/// Root device of the guest, i.e. `root=`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RootDevice {
+    /// A device node, e.g. `/dev/vda`
+    Device(String),
+    /// A GPT partition, by its UUID
+    PartUuid(String),
+}
+
+impl fmt::Display for RootDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RootDevice::Device(device) => f.write_str(device),
+            RootDevice::PartUuid(uuid) => write!(f, "PARTUUID={uuid}"),
+        }
+    }
+}
+
+/// Protocol used by the guest kernel to configure its network
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Autoconf {
+    /// Static configuration, from the other fields of [`IpConfig`]
+    #[default]
+    Off,
+    /// DHCP
+    Dhcp,
+    /// BOOTP
+    Bootp,
+    /// RARP
+    Rarp,
+    /// Any protocol the kernel supports
+    Any,
+}
+
+impl fmt::Display for Autoconf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Autoconf::Off => "off",
+            Autoconf::Dhcp => "dhcp",
+            Autoconf::Bootp => "bootp",
+            Autoconf::Rarp => "rarp",
+            Autoconf::Any => "any",
+        })
+    }
+}
+
+/// Network configuration of the guest kernel, i.e. `ip=`
+///
+/// It is formatted as
+/// `<client-ip>:<server-ip>:<gw-ip>:<netmask>:<hostname>:<device>:<autoconf>:<dns0-ip>:<dns1-ip>`,
+/// see the kernel's `Documentation/admin-guide/nfs/nfsroot.rst`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpConfig {
+    /// Address of the guest
+    pub client: Option<Ipv4Addr>,
+    /// Address of the NFS server
+    pub server: Option<Ipv4Addr>,
+    /// Address of the gateway
+    pub gateway: Option<Ipv4Addr>,
+    /// Netmask of the network
+    pub netmask: Option<Ipv4Addr>,
+    /// Hostname of the guest
+    hostname: Option<String>,
+    /// Network device to configure, e.g. `eth0`
+    pub device: Option<String>,
+    /// Protocol used to configure the network
+    pub autoconf: Autoconf,
+    /// Up to two DNS servers
+    pub dns: [Option<Ipv4Addr>; 2],
+}
+
+impl IpConfig {
+    /// A static configuration
+    pub fn new(client: Ipv4Addr, gateway: Ipv4Addr, netmask: Ipv4Addr) -> IpConfig {
+        IpConfig {
+            client: Some(client),
+            gateway: Some(gateway),
+            netmask: Some(netmask),
+            ..IpConfig::default()
+        }
+    }
+
+    /// A configuration obtained with DHCP
+    pub fn dhcp() -> IpConfig {
+        IpConfig {
+            autoconf: Autoconf::Dhcp,
+            ..IpConfig::default()
+        }
+    }
+
+    /// Set the hostname of the guest
+    ///
+    /// Fails if `hostname` contains a colon or whitespace.
+    pub fn with_hostname<S: Into<String>>(mut self, hostname: S) -> Result<Self, CmdlineError> {
+        let hostname = hostname.into();
+        if hostname.is_empty() || hostname.contains(|c: char| c == ':' || !c.is_ascii_graphic()) {
+            return Err(CmdlineError::InvalidHostname(hostname));
+        }
+        self.hostname = Some(hostname);
+        Ok(self)
+    }
+
+    /// Hostname of the guest
+    pub fn hostname(&self) -> Option<&str> {
+        self.hostname.as_deref()
+    }
+}
+
+impl fmt::Display for IpConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let addr = |addr: Option<Ipv4Addr>| addr.map(|addr| addr.to_string()).unwrap_or_default();
+        let fields = [
+            addr(self.client),
+            addr(self.server),
+            addr(self.gateway),
+            addr(self.netmask),
+            self.hostname.clone().unwrap_or_default(),
+            self.device.clone().unwrap_or_default(),
+            self.autoconf.to_string(),
+            addr(self.dns[0]),
+            addr(self.dns[1]),
+        ];
+
+        // `ip=dhcp` is short for a configuration with only a protocol
+        if fields[..6].iter().all(String::is_empty) && fields[7..].iter().all(String::is_empty) {
+            return f.write_str(&fields[6]);
+        }
+
+        let line = fields.join(":");
+        f.write_str(line.trim_end_matches(':'))
+    }
+}
+
+/// A kernel command line
+///
+/// Options are set with chained calls, e.g.
+/// `KernelCmdline::firecracker().root_from_drives(&drives).init("/sbin/init")`, and the command
+/// line is checked by [`KernelCmdline::build`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelCmdline {
+    /// Parameters, as names and optional values
+    params: Vec<(String, Option<String>)>,
+    /// Arguments passed to init, after `--`
+    init_args: Vec<String>,
+    /// Maximum length, including the terminating NUL byte
+    max_len: usize,
+}
+
+impl Default for KernelCmdline {
+    fn default() -> Self {
+        KernelCmdline::new()
+    }
+}
+
+impl KernelCmdline {
+    /// An empty command line
+    pub fn new() -> KernelCmdline {
+        KernelCmdline {
+            params: Vec::new(),
+            init_args: Vec::new(),
+            max_len: MAX_LEN,
+        }
+    }
+
+    /// The command line recommended for Firecracker: `console=ttyS0 reboot=k panic=1 pci=off`
+    pub fn firecracker() -> KernelCmdline {
+        KernelCmdline::new()
+            .console(Console::Serial)
+            .reboot(RebootMode::Kbd)
+            .panic(1)
+            .pci_off()
+    }
+
+    /// Parse an existing command line, e.g. the `boot_args` of a boot source
+    ///
+    /// Parameters that are set more than once, e.g. `console=`, keep all their values.
+    pub fn parse(cmdline: &str) -> KernelCmdline {
+        let mut parsed = KernelCmdline::new();
+        let mut words = split_words(cmdline).into_iter();
+        for word in words.by_ref() {
+            if word == "--" {
+                break;
+            }
+            let param = match word.split_once('=') {
+                Some((name, value)) => (name.to_owned(), Some(value.to_owned())),
+                None => (word, None),
+            };
+            parsed.params.push(param);
+        }
+        parsed.init_args.extend(words);
+        parsed
+    }
+
+    /// Change the maximum length of the command line, including the terminating NUL byte
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    /// Set the console, i.e. `console=`
+    pub fn console(self, console: Console) -> Self {
+        self.param("console", console.to_string())
+    }
+
+    /// Set how the kernel reboots, i.e. `reboot=`
+    pub fn reboot(self, mode: RebootMode) -> Self {
+        self.param("reboot", mode.to_string())
+    }
+
+    /// Set the number of seconds before the kernel reboots after a panic, i.e. `panic=`
+    ///
+    /// With 0, the kernel waits forever. With a negative value, it reboots immediately.
+    pub fn panic(self, timeout: i32) -> Self {
+        self.param("panic", timeout.to_string())
+    }
+
+    /// Disable the probing of PCI devices, i.e. `pci=off`
+    pub fn pci_off(self) -> Self {
+        self.param("pci", "off")
+    }
+
+    /// Set the root device, i.e. `root=`
+    pub fn root(self, root: RootDevice) -> Self {
+        self.param("root", root.to_string())
+    }
+
+    /// Set the type of the root filesystem, i.e. `rootfstype=`
+    pub fn rootfstype<S: Into<String>>(self, fstype: S) -> Self {
+        self.param("rootfstype", fstype)
+    }
+
+    /// Mount the root filesystem read-only (`ro`) or read-write (`rw`)
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        let (set, unset) = if read_only {
+            ("ro", "rw")
+        } else {
+            ("rw", "ro")
+        };
+        self.remove(unset);
+        self.flag(set)
+    }
+
+    /// Set the program run as init, i.e. `init=`
+    pub fn init<S: Into<String>>(self, init: S) -> Self {
+        self.param("init", init)
+    }
+
+    /// Append an argument passed to init
+    pub fn init_arg<S: Into<String>>(mut self, arg: S) -> Self {
+        self.init_args.push(arg.into());
+        self
+    }
+
+    /// Set the network configuration, i.e. `ip=`
+    pub fn ip(self, ip: &IpConfig) -> Self {
+        self.param("ip", ip.to_string())
+    }
+
+    /// Set a parameter with a value, e.g. `quiet=1`, replacing all its values
+    pub fn param<N: Into<String>, V: Into<String>>(self, name: N, value: V) -> Self {
+        self.set(name.into(), Some(value.into()))
+    }
+
+    /// Set a parameter without value, e.g. `quiet`, replacing all its values
+    pub fn flag<N: Into<String>>(self, name: N) -> Self {
+        self.set(name.into(), None)
+    }
+
+    /// Remove a parameter
+    pub fn remove(&mut self, name: &str) {
+        self.params.retain(|(param, _)| !same_name(param, name));
+    }
+
+    /// The value of a parameter, `Some(None)` if it is set without value
+    ///
+    /// For a parameter set more than once, this is the last value, which most parameters use.
+    pub fn get(&self, name: &str) -> Option<Option<&str>> {
+        self.params
+            .iter()
+            .rfind(|(param, _)| same_name(param, name))
+            .map(|(_, value)| value.as_deref())
+    }
+
+    /// Set `root=`, and `ro` or `rw`, for the root device among `drives`
+    ///
+    /// The command line is unchanged if none of the drives is the root device.
+    pub fn root_from_drives(self, drives: &[Drive]) -> Self {
+        match drives.iter().find(|drive| drive.is_root_device) {
+            None => self,
+            Some(drive) => {
+                let root = match &drive.partuuid {
+                    Some(uuid) => RootDevice::PartUuid(uuid.clone()),
+                    None => RootDevice::Device("/dev/vda".to_owned()),
+                };
+                self.root(root).read_only(drive.is_read_only)
+            }
+        }
+    }
+
+    /// Set `ip=` for the network interface `iface_id` among `interfaces`
+    ///
+    /// The device of `ip` is set to the name of the interface in the guest.
+    pub fn ip_from_interfaces(
+        self,
+        interfaces: &[NetworkInterface],
+        iface_id: &str,
+        mut ip: IpConfig,
+    ) -> Result<Self, CmdlineError> {
+        let index = interfaces
+            .iter()
+            .position(|iface| iface.iface_id == iface_id)
+            .ok_or_else(|| CmdlineError::UnknownInterface(iface_id.to_owned()))?;
+        ip.device = Some(format!("eth{index}"));
+        Ok(self.ip(&ip))
+    }
+
+    /// Build the command line
+    ///
+    /// Fails if a name or a value cannot be represented on the command line, or if the command
+    /// line is too long.
+    pub fn build(&self) -> Result<String, CmdlineError> {
+        let mut words = Vec::with_capacity(self.params.len() + self.init_args.len() + 1);
+        for (name, value) in &self.params {
+            if name.is_empty() || name.contains(|c: char| c == '=' || !is_plain(c)) {
+                return Err(CmdlineError::InvalidName(name.clone()));
+            }
+            match value {
+                None => words.push(name.clone()),
+                Some(value) if !value.chars().all(is_quotable) => {
+                    return Err(CmdlineError::InvalidValue(name.clone(), value.clone()));
+                }
+                Some(value) => words.push(format!("{name}={}", quote(value))),
+            }
+        }
+
+        if !self.init_args.is_empty() {
+            words.push("--".to_owned());
+            for arg in &self.init_args {
+                if arg.is_empty() || !arg.chars().all(is_quotable) {
+                    return Err(CmdlineError::InvalidInitArg(arg.clone()));
+                }
+                words.push(quote(arg));
+            }
+        }
+
+        let cmdline = words.join(" ");
+        // Account for the terminating NUL byte
+        if cmdline.len() + 1 > self.max_len {
+            return Err(CmdlineError::TooLong(cmdline.len() + 1, self.max_len));
+        }
+        Ok(cmdline)
+    }
+
+    // Replace all the values of `name` with a single one, at the position of the first one, or
+    // append it.
+    fn set(mut self, name: String, value: Option<String>) -> Self {
+        let mut first = true;
+        self.params
+            .retain(|(param, _)| !same_name(param, &name) || std::mem::take(&mut first));
+        match self
+            .params
+            .iter_mut()
+            .find(|(param, _)| same_name(param, &name))
+        {
+            Some(param) => *param = (name, value),
+            None => self.params.push((name, value)),
+        }
+        self
+    }
+}
+
+// The kernel does not distinguish dashes and underscores in parameter names.
+fn same_name(a: &str, b: &str) -> bool {
+    a.replace('-', "_") == b.replace('-', "_")
+}
+
+// Characters allowed unquoted on the command line.
+fn is_plain(c: char) -> bool {
+    c.is_ascii_graphic() && c != '"'
+}
+
+// Characters allowed in a quoted value: the kernel has no escape for double quotes.
+fn is_quotable(c: char) -> bool {
+    is_plain(c) || c == ' ' || c == '\t'
+}
+
+fn quote(value: &str) -> String {
+    if value.is_empty() || value.contains(char::is_whitespace) {
+        format!("\"{value}\"")
+    } else {
+        value.to_owned()
+    }
+}
+
+// Split a command line into words, as the kernel does: whitespace separates words, except between
+// double quotes, and the quotes are removed.
+fn split_words(cmdline: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quoted = false;
+    for c in cmdline.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_word = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_and_quote() {
+        assert_eq!(
+            split_words("  a=1\tb=\"x y\"  \"c d\"=e f\"\" -- \"g  h\""),
+            ["a=1", "b=x y", "c d=e", "f", "--", "g  h"]
+        );
+
+        let cmdline = KernelCmdline::parse("quiet init=/init -- \"a b\" c")
+            .param("label", "my disk")
+            .param("empty", "");
+        assert_eq!(
+            cmdline.build().unwrap(),
+            "quiet init=/init label=\"my disk\" empty=\"\" -- \"a b\" c"
+        );
+        assert_eq!(KernelCmdline::parse(&cmdline.build().unwrap()), cmdline);
+
+        let invalid = KernelCmdline::new().param("label", "a\"b");
+        assert_eq!(
+            invalid.build(),
+            Err(CmdlineError::InvalidValue("label".into(), "a\"b".into()))
+        );
+        let invalid = KernelCmdline::new().flag("a b");
+        assert_eq!(
+            invalid.build(),
+            Err(CmdlineError::InvalidName("a b".into()))
+        );
+    }
+
+    #[test]
+    fn repeated_params() {
+        // Parsing keeps every console, and unrelated setters leave them alone
+        let cmdline = KernelCmdline::parse("console=tty0 ro console=ttyS0")
+            .root(RootDevice::Device("/dev/vda".into()))
+            .read_only(false);
+        assert_eq!(
+            cmdline.build().unwrap(),
+            "console=tty0 console=ttyS0 root=/dev/vda rw"
+        );
+        assert_eq!(cmdline.get("console"), Some(Some("ttyS0")));
+
+        // Setting a parameter replaces all its values, at the position of the first one
+        let cmdline = cmdline.console(Console::Device("hvc0".into()));
+        assert_eq!(cmdline.build().unwrap(), "console=hvc0 root=/dev/vda rw");
+
+        let cmdline = KernelCmdline::parse("foo-bar=1 x foo_bar=2").param("foo_bar", "3");
+        assert_eq!(cmdline.build().unwrap(), "foo_bar=3 x");
+    }
+
+    #[test]
+    fn max_len() {
+        let value = "x".repeat(MAX_LEN - "a=".len() - 1);
+        let cmdline = KernelCmdline::new().param("a", value.as_str());
+        assert_eq!(cmdline.build().unwrap().len(), MAX_LEN - 1);
+
+        let cmdline = cmdline.param("a", format!("{value}x"));
+        assert_eq!(
+            cmdline.build(),
+            Err(CmdlineError::TooLong(MAX_LEN + 1, MAX_LEN))
+        );
+
+        let cmdline = KernelCmdline::new().with_max_len(8).flag("quiet");
+        assert_eq!(cmdline.build().unwrap(), "quiet");
+        assert_eq!(
+            cmdline.init_arg("a").build(),
+            Err(CmdlineError::TooLong(11, 8))
+        );
+    }
+}
@@ -8,21 +8,40 @@
 //! but it also has support for asynchronous IO for kernels that support it (>= 5.10.51), although
 //! this feature is in dev-preview.
 //!
+//! Since Firecracker 1.8, a drive can instead be served by a vhost-user backend, listening on a
+//! Unix socket (see [`Drive::vhost_user`]).
+//!
 //! Firecracker allows users to configure multiple drives per VM. It allows defining maximum one
 //! root drive device (i.e. the device that holds the root filesystem). The devices need to be
 //! configured before the microVM is booted, but parts of their configuration can be updated after
 //! booting the microVM.
+//!
+//! [`ApiClient::swap_drive`] replaces the backing file of a drive of a running microVM, checking
+//! the new file beforehand and rolling back if Firecracker does not apply the change.
+//!
+//! Every microVM needs its own writable copy of a root filesystem. [`Drive::clone_to`] clones the
+//! backing file of a drive with a reflink where the host filesystem supports it, so that clones
+//! share the blocks of the original file until they are written. [`Drive::resize`] then sets its
+//! size, the disk quota of the microVM, growing the filesystem inside.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 
 #[cfg(feature = "clap")]
 use clap::{Args, ValueEnum};
 use serde_derive::{Deserialize, Serialize};
 
+use super::partition::{PartitionError, PartitionTable};
 use super::rate_limiter::RateLimiter;
-use super::{ApiClient, Result};
+use super::resize::{resize_image, ResizeError};
+use super::types::MiB;
+use super::{ApiClient, FcClientError, Result};
 
 /// Caching strategy for a block device
-#[cfg_attr(feature = "clap", derive(Clone, ValueEnum))]
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "clap", derive(ValueEnum))]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub enum CacheType {
     #[default]
     Unsafe,
@@ -30,8 +49,8 @@ pub enum CacheType {
 }
 
 /// IO engine to use for the backing file on the host
-#[cfg_attr(feature = "clap", derive(Clone, ValueEnum))]
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "clap", derive(ValueEnum))]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub enum IoEngine {
     #[default]
     Sync,
@@ -42,19 +61,25 @@ pub enum IoEngine {
 ///
 /// By default, the device will use [`Unsafe`] as caching strategy and the [`Sync`] IO engine.
 #[cfg_attr(feature = "clap", derive(Args))]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Drive {
     /// Id of the drive.
     pub drive_id: String,
 
     /// Path to the drive in the host filesystem. If the path is not absolute it will be relative
-    /// to the Firecracker process's current working directory.
+    /// to the Firecracker process's current working directory. Empty for vhost-user drives.
+    #[cfg_attr(
+        feature = "clap",
+        arg(required_unless_present = "socket", default_value = "")
+    )]
+    #[serde(default)]
     pub path_on_host: String,
 
     /// Type of the IO engine used by the device. "Async" is supported on host kernels newer than
     /// 5.10.51.
     /// Host level path for the guest drive
     #[cfg_attr(feature = "clap", arg(long, required = false, default_value = "sync"))]
+    #[serde(default)]
     pub io_engine: IoEngine,
 
     /// Represents the caching strategy for the block device.
@@ -62,10 +87,12 @@ pub struct Drive {
         feature = "clap",
         arg(long, short, required = false, default_value = "unsafe")
     )]
+    #[serde(default)]
     pub cache_type: CacheType,
 
     /// The drive is read-only.
     #[cfg_attr(feature = "clap", arg(long))]
+    #[serde(default)]
     pub is_read_only: bool,
 
     /// The drive contains the root file system of the microVM.
@@ -79,6 +106,24 @@ pub struct Drive {
     /// be taken into account only if the is_root_device field is true.
     #[cfg_attr(feature = "clap", arg(short, long))]
     pub partuuid: Option<String>,
+
+    /// Path to the Unix socket of a vhost-user backend serving the drive, instead of a file.
+    /// Requires Firecracker 1.8 or newer.
+    #[cfg_attr(feature = "clap", arg(long, conflicts_with = "path_on_host"))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub socket: Option<String>,
+}
+
+// Body of a request adding a vhost-user drive, which only takes the fields that do not describe
+// the backing file.
+#[derive(Debug, Serialize)]
+struct VhostUserDrive<'a> {
+    drive_id: &'a str,
+    is_root_device: bool,
+    cache_type: &'a CacheType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    partuuid: Option<&'a str>,
+    socket: &'a str,
 }
 
 impl Drive {
@@ -98,8 +143,218 @@ impl Drive {
             is_root_device,
             rate_limiter: None,
             partuuid: None,
+            socket: None,
+        }
+    }
+
+    /// Create a new [`Drive`] served by the vhost-user backend listening on `socket`
+    pub fn vhost_user(drive_id: String, socket: String, is_root_device: bool) -> Drive {
+        Drive {
+            socket: Some(socket),
+            ..Drive::new(drive_id, String::new(), is_root_device, false)
+        }
+    }
+
+    /// Fill in the `partuuid` of a root drive backed by a partitioned disk image
+    ///
+    /// It is set to the PARTUUID of the root partition of the image, see
+    /// [`PartitionTable::root_partition`], which is returned. Drives that are not the root device,
+    /// that already have a `partuuid`, or whose image is not partitioned, are left unchanged. So are
+    /// drives whose image does not exist on the host, e.g. because its path is relative to the jail
+    /// of Firecracker.
+    pub fn detect_partuuid(&mut self) -> std::result::Result<Option<&str>, PartitionError> {
+        if !self.is_root_device
+            || self.partuuid.is_some()
+            || self.socket.is_some()
+            || !Path::new(&self.path_on_host).exists()
+        {
+            return Ok(None);
+        }
+        let Some(table) = PartitionTable::read(&self.path_on_host)? else {
+            return Ok(None);
+        };
+        let partition = table
+            .root_partition()
+            .ok_or_else(|| PartitionError::Invalid("no Linux partition".to_owned()))?;
+        self.partuuid = Some(partition.partuuid.clone());
+        Ok(self.partuuid.as_deref())
+    }
+
+    /// Clone the backing file of the drive to `path`, and return the writable drive backed by it
+    ///
+    /// The file is cloned with a reflink (`FICLONE`) if the filesystem supports it, e.g. Btrfs or
+    /// XFS, and copied otherwise, without filling its holes. `path` must not exist. The clone is
+    /// deleted when the returned [`DriveClone`] is dropped, unless it is kept.
+    pub fn clone_to<P: AsRef<Path>>(&self, path: P) -> io::Result<DriveClone> {
+        let path = path.as_ref();
+        if self.socket.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a vhost-user drive has no backing file",
+            ));
+        }
+        let path_on_host = path.to_str().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "path is not valid UTF-8")
+        })?;
+
+        let reflink = clone_file(Path::new(&self.path_on_host), path)?;
+        Ok(DriveClone {
+            drive: Drive {
+                path_on_host: path_on_host.to_owned(),
+                is_read_only: false,
+                ..self.clone()
+            },
+            file: ClonedFile(path.to_path_buf()),
+            reflink,
+        })
+    }
+
+    /// Resize the backing file of the drive, and its filesystem, to `size`
+    ///
+    /// See [`resize_image`]. The drive must not be attached to a running microVM.
+    pub fn resize(&self, size: MiB) -> std::result::Result<(), ResizeError> {
+        if self.socket.is_some() {
+            return Err(ResizeError::Unsupported("vhost-user drives".to_owned()));
+        }
+        resize_image(&self.path_on_host, size)
+    }
+}
+
+/// A drive backed by a clone of the backing file of another drive
+///
+/// The clone is deleted when the [`DriveClone`] is dropped, unless it is [kept](DriveClone::keep).
+/// [`MicroVm::add_drive_clone`](crate::microvm::MicroVm::add_drive_clone) ties it to a microVM
+/// instead, which deletes it when it is dropped.
+#[derive(Debug)]
+pub struct DriveClone {
+    drive: Drive,
+    file: ClonedFile,
+    reflink: bool,
+}
+
+impl DriveClone {
+    // Take ownership of the file backing `drive`, which was just created for it
+    pub(crate) fn from_file(drive: Drive) -> DriveClone {
+        DriveClone {
+            file: ClonedFile(PathBuf::from(&drive.path_on_host)),
+            drive,
+            reflink: false,
         }
     }
+
+    /// The drive backed by the clone
+    pub fn drive(&self) -> &Drive {
+        &self.drive
+    }
+
+    /// Set the id of the drive backed by the clone
+    ///
+    /// The path of the drive cannot be changed, as it is the clone deleted on drop.
+    pub fn set_drive_id(&mut self, drive_id: impl Into<String>) {
+        self.drive.drive_id = drive_id.into();
+    }
+
+    /// Whether the clone shares the blocks of the original file, instead of being a copy
+    pub fn is_reflink(&self) -> bool {
+        self.reflink
+    }
+
+    /// Keep the clone on the host, and return the drive backed by it
+    pub fn keep(self) -> Drive {
+        std::mem::forget(self.file);
+        self.drive
+    }
+}
+
+// Backing file of a `DriveClone`, deleted when dropped
+#[derive(Debug)]
+struct ClonedFile(PathBuf);
+
+impl Drop for ClonedFile {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.0) {
+            log::warn!("could not delete drive clone {}: {err}", self.0.display());
+        }
+    }
+}
+
+// Clone `source` to the new file `target`, returning whether it was reflinked.
+fn clone_file(source: &Path, target: &Path) -> io::Result<bool> {
+    let source = File::open(source)?;
+    let target_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(target)?;
+
+    let result = match reflink(&source, &target_file) {
+        Ok(()) => Ok(true),
+        Err(_) => sparse_copy(&source, &target_file).map(|()| false),
+    };
+    if result.is_err() {
+        let _ = fs::remove_file(target);
+    }
+    result
+}
+
+fn reflink(source: &File, target: &File) -> io::Result<()> {
+    // SAFETY: FICLONE only takes the source file descriptor, which is valid, as argument.
+    let ret = unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Copy the data segments of `source` to `target`, leaving holes unallocated.
+fn sparse_copy(source: &File, target: &File) -> io::Result<()> {
+    let len = source.metadata()?.len();
+    copy_data(source, 0, target, 0, len)?;
+    target.set_len(len)
+}
+
+// Copy the data segments of the `len` bytes of `source` at `from` to `target` at `to`, skipping
+// holes.
+pub(super) fn copy_data(
+    mut source: &File,
+    from: u64,
+    mut target: &File,
+    to: u64,
+    len: u64,
+) -> io::Result<()> {
+    let end = from + len;
+    let mut offset = from;
+    while offset < end {
+        let (start, stop) = match seek(source, offset, libc::SEEK_DATA) {
+            Ok(Some(start)) => (start, seek(source, start, libc::SEEK_HOLE)?.unwrap_or(end)),
+            // No data after `offset`
+            Ok(None) => break,
+            // The filesystem cannot report holes: copy everything
+            Err(err) if err.raw_os_error() == Some(libc::EINVAL) => (offset, end),
+            Err(err) => return Err(err),
+        };
+        let (start, stop) = (start.min(end), stop.min(end));
+        source.seek(SeekFrom::Start(start))?;
+        target.seek(SeekFrom::Start(to + start - from))?;
+        io::copy(&mut source.take(stop - start), &mut target)?;
+        offset = stop;
+    }
+    Ok(())
+}
+
+// `lseek(2)` with `SEEK_DATA` or `SEEK_HOLE`, returning `None` past the last data segment.
+fn seek(file: &File, offset: u64, whence: libc::c_int) -> io::Result<Option<u64>> {
+    let offset = libc::off_t::try_from(offset)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "offset out of range"))?;
+    // SAFETY: lseek does not access memory.
+    let ret = unsafe { libc::lseek(file.as_raw_fd(), offset, whence) };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::ENXIO) {
+            return Ok(None);
+        }
+        return Err(err);
+    }
+    Ok(Some(ret as u64))
 }
 
 /// Helper for updating the configuration of a drive
@@ -118,6 +373,27 @@ pub struct PartialDrive {
     pub rate_limiter: Option<RateLimiter>,
 }
 
+/// Errors of [`ApiClient::swap_drive`]
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum DriveSwapError {
+    /// Cannot use {0} as backing file: {1}
+    NewFile(String, #[source] io::Error),
+    /// The new backing file ({new} bytes) is smaller than the current one ({current} bytes)
+    TooSmall { current: u64, new: u64 },
+    /// Drive {0} does not exist
+    UnknownDrive(String),
+    /// Drive {0} is served by a vhost-user backend, not by a file
+    VhostUser(String),
+    /// Firecracker reports {0} as backing file after the swap
+    NotApplied(String),
+    /// {error}, and rolling back to {path} failed: {rollback}
+    RollbackFailed {
+        path: String,
+        error: Box<FcClientError>,
+        rollback: Box<FcClientError>,
+    },
+}
+
 impl ApiClient {
     /// Add a new disk to the VM.
     ///
@@ -128,7 +404,20 @@ impl ApiClient {
     /// * `drive_id` - The id of the new disk
     /// * `drive` - The [`Drive`] object to attach to the VM
     pub async fn add_drive(&mut self, drive_id: &str, drive: &Drive) -> Result<()> {
-        self.put(&format!("/drives/{drive_id}"), &drive).await
+        let path = format!("/drives/{drive_id}");
+        match &drive.socket {
+            Some(socket) => {
+                let drive = VhostUserDrive {
+                    drive_id: &drive.drive_id,
+                    is_root_device: drive.is_root_device,
+                    cache_type: &drive.cache_type,
+                    partuuid: drive.partuuid.as_deref(),
+                    socket,
+                };
+                self.put(&path, drive).await
+            }
+            None => self.put(&path, &drive).await,
+        }
     }
 
     /// Update a disk of the VM.
@@ -140,4 +429,97 @@ impl ApiClient {
     pub async fn update_drive(&mut self, drive_id: &str, drive: &PartialDrive) -> Result<()> {
         self.patch(&format!("/drives/{drive_id}"), drive).await
     }
+
+    /// Replace the backing file of a disk of a booted VM with `path_on_host`.
+    ///
+    /// The new file must exist and must not be smaller than the current one, so that the guest
+    /// does not lose blocks it already uses. If `pause` is set, the VM is paused during the swap
+    /// and resumed afterwards. The swap is confirmed through [`ApiClient::vm_config`], and the
+    /// drive is switched back to its previous file if it fails.
+    pub async fn swap_drive(
+        &mut self,
+        drive_id: &str,
+        path_on_host: &str,
+        pause: bool,
+    ) -> Result<()> {
+        let current = self.drive_path(drive_id).await?;
+        check_swap(&current, path_on_host)?;
+
+        if pause {
+            self.pause_microvm().await?;
+        }
+        let mut result = self.patch_drive_path(drive_id, path_on_host).await;
+        if let Err(error) = result {
+            result = match self.patch_drive_path(drive_id, &current).await {
+                Ok(()) => Err(error),
+                Err(rollback) => Err(DriveSwapError::RollbackFailed {
+                    path: current,
+                    error: Box::new(error),
+                    rollback: Box::new(rollback),
+                }
+                .into()),
+            };
+        }
+        if pause {
+            // Resume the VM even if the swap failed, but report the swap error first
+            let resumed = self.resume_microvm().await;
+            result = result.and(resumed);
+        }
+        result
+    }
+
+    // Current backing file of the drive `drive_id`, from the configuration of the VM
+    async fn drive_path(&self, drive_id: &str) -> Result<String> {
+        let drive = self
+            .vm_config()
+            .await?
+            .drives
+            .unwrap_or_default()
+            .into_iter()
+            .find(|drive| drive.drive_id == drive_id)
+            .ok_or_else(|| DriveSwapError::UnknownDrive(drive_id.to_owned()))?;
+        if drive.socket.is_some() {
+            return Err(DriveSwapError::VhostUser(drive.drive_id).into());
+        }
+        Ok(drive.path_on_host)
+    }
+
+    // Switch the backing file of `drive_id` to `path_on_host`, and check that it was switched.
+    async fn patch_drive_path(&mut self, drive_id: &str, path_on_host: &str) -> Result<()> {
+        let drive = PartialDrive {
+            drive_id: drive_id.to_owned(),
+            path_on_host: Some(path_on_host.to_owned()),
+            rate_limiter: None,
+        };
+        self.update_drive(drive_id, &drive).await?;
+        let path = self.drive_path(drive_id).await?;
+        if path != path_on_host {
+            return Err(DriveSwapError::NotApplied(path).into());
+        }
+        Ok(())
+    }
+}
+
+// Check that the file `new` can replace the backing file `current` of a drive.
+fn check_swap(current: &str, new: &str) -> std::result::Result<(), DriveSwapError> {
+    let metadata = fs::metadata(new).map_err(|err| DriveSwapError::NewFile(new.to_owned(), err))?;
+    if metadata.is_dir() {
+        return Err(DriveSwapError::NewFile(
+            new.to_owned(),
+            io::Error::new(io::ErrorKind::InvalidInput, "is a directory"),
+        ));
+    }
+    // The current file may be relative to the working directory of Firecracker, or gone: only
+    // compare sizes when it can be found.
+    match fs::metadata(current) {
+        Ok(current) if metadata.len() < current.len() => Err(DriveSwapError::TooSmall {
+            current: current.len(),
+            new: metadata.len(),
+        }),
+        Ok(_) => Ok(()),
+        Err(err) => {
+            log::warn!("cannot check the size of {current}: {err}");
+            Ok(())
+        }
+    }
 }
@@ -0,0 +1,103 @@
+//! Dry-run support for [`ApiClient`]
+//!
+//! An [`ApiClient`] using a [`Recorder`] on top of the [`DryRun`] transport does not talk to
+//! Firecracker. It records the method, path and JSON body of every request it would have sent, so
+//! that a sequence of API calls can be reviewed, scripted or compared against a golden file
+//! without a running VMM.
+//!
+//! The pre-boot part of a recorded sequence can be turned into a Firecracker configuration file
+//! (the file passed to Firecracker with `--config-file`) with
+//! [`FullVmConfiguration::from_requests`].
+//!
+//! [`ApiClient`]: super::ApiClient
+//! [`Recorder`]: super::transport::Recorder
+//! [`DryRun`]: super::transport::DryRun
+
+use serde_json::Value;
+
+use super::drive::Drive;
+use super::network::NetworkInterface;
+use super::transport::{ApiRequest, Method};
+use super::vm::FullVmConfiguration;
+use super::Result;
+
+// Merges the non-null fields of `update` into `base`.
+fn merge(base: &mut Value, update: &Value) {
+    if let (Some(base), Some(update)) = (base.as_object_mut(), update.as_object()) {
+        for (key, value) in update.iter().filter(|(_, value)| !value.is_null()) {
+            base.insert(key.clone(), value.clone());
+        }
+    }
+}
+
+impl FullVmConfiguration {
+    /// Build a Firecracker configuration file out of a sequence of recorded requests.
+    ///
+    /// Only the requests issued before the microVM is started are taken into account. Requests
+    /// that cannot be expressed in a configuration file are ignored.
+    pub fn from_requests(requests: &[ApiRequest]) -> Result<FullVmConfiguration> {
+        let mut config = FullVmConfiguration::default();
+        for request in requests.iter().take_while(|r| !r.is_instance_start()) {
+            config.apply_request(request)?;
+        }
+
+        Ok(config)
+    }
+
+    /// Apply a pre-boot configuration request on top of this configuration.
+    ///
+    /// Returns `false` if the request does not correspond to a section of the configuration file,
+    /// e.g. an action, a GET request or a post-boot update.
+    pub fn apply_request(&mut self, request: &ApiRequest) -> Result<bool> {
+        let Some(body) = request.body.clone() else {
+            return Ok(false);
+        };
+
+        let path = request.path.trim_start_matches('/');
+        let (endpoint, id) = match path.split_once('/') {
+            Some((endpoint, id)) => (endpoint, Some(id)),
+            None => (path, None),
+        };
+
+        match (request.method, endpoint, id) {
+            (Method::Put, "boot-source", None) => {
+                self.boot_source = Some(serde_json::from_value(body)?)
+            }
+            (Method::Put, "drives", Some(_)) => {
+                let drive: Drive = serde_json::from_value(body)?;
+                let drives = self.drives.get_or_insert_with(Vec::new);
+                drives.retain(|d| d.drive_id != drive.drive_id);
+                drives.push(drive);
+            }
+            (Method::Put, "network-interfaces", Some(_)) => {
+                let iface: NetworkInterface = serde_json::from_value(body)?;
+                let ifaces = self.network_interfaces.get_or_insert_with(Vec::new);
+                ifaces.retain(|i| i.iface_id != iface.iface_id);
+                ifaces.push(iface);
+            }
+            (Method::Put, "machine-config", None) => {
+                self.machine_config = Some(serde_json::from_value(body)?)
+            }
+            (Method::Patch, "machine-config", None) => {
+                let mut current = serde_json::to_value(&self.machine_config)?;
+                if current.is_null() {
+                    current = body;
+                } else {
+                    merge(&mut current, &body);
+                }
+                self.machine_config = Some(serde_json::from_value(current)?);
+            }
+            (Method::Put, "balloon", None) => self.balloon = Some(serde_json::from_value(body)?),
+            (Method::Put, "vsock", None) => self.vsock = Some(serde_json::from_value(body)?),
+            (Method::Put, "logger", None) => self.logger = Some(serde_json::from_value(body)?),
+            (Method::Put, "metrics", None) => self.metrics = Some(serde_json::from_value(body)?),
+            (Method::Put, "entropy", None) => self.entropy = Some(serde_json::from_value(body)?),
+            (Method::Put, "mmds", Some("config")) => {
+                self.mmds_config = Some(serde_json::from_value(body)?)
+            }
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+}
@@ -0,0 +1,189 @@
+//! Errors of the Firecracker API client
+//!
+//! Firecracker reports errors with an HTTP status code and a free-form `fault_message`. An
+//! [`FcError`] keeps both, along with the request that caused the error, and classifies the fault
+//! message into an [`FcErrorKind`], so that callers can react to common errors without parsing
+//! strings.
+
+use std::time::Duration;
+
+use serde_json::Value;
+
+use super::drive::DriveSwapError;
+use super::kernel_image::KernelImageError;
+use super::transport::{ApiRequest, ApiResponse, Method};
+use super::version::Unsupported;
+
+/// Class of an error returned by Firecracker
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FcErrorKind {
+    /// The operation is not allowed after the microVM has been started.
+    NotAllowedAfterBoot,
+    /// The operation is not allowed before the microVM has been started.
+    NotAllowedBeforeBoot,
+    /// The body of the request has a missing, unknown or invalid field.
+    InvalidField,
+    /// A host resource, e.g. a TAP device or a file, is in use.
+    ResourceBusy,
+    /// The device the request refers to does not exist.
+    DeviceNotFound,
+    /// The operation is not supported on the architecture of the host.
+    UnsupportedArchitecture,
+    /// The method and path of the request do not match any API endpoint.
+    InvalidEndpoint,
+    /// Any other error.
+    Other,
+}
+
+impl FcErrorKind {
+    /// Classify a `fault_message` returned by Firecracker
+    pub fn from_fault_message(message: &str) -> FcErrorKind {
+        let message = message.to_lowercase();
+        let contains_any = |patterns: &[&str]| patterns.iter().any(|p| message.contains(p));
+
+        if contains_any(&["invalid request method", "invalid path"]) {
+            FcErrorKind::InvalidEndpoint
+        } else if contains_any(&["after starting the microvm", "after boot", "post-boot"]) {
+            FcErrorKind::NotAllowedAfterBoot
+        } else if contains_any(&["before starting the microvm", "before boot", "pre-boot"]) {
+            FcErrorKind::NotAllowedBeforeBoot
+        } else if contains_any(&["architecture", "only supported on", "not supported on"]) {
+            FcErrorKind::UnsupportedArchitecture
+        } else if contains_any(&["busy", "os error 16"]) {
+            FcErrorKind::ResourceBusy
+        } else if contains_any(&["not found", "no such device", "invalid block device id"]) {
+            FcErrorKind::DeviceNotFound
+        } else if contains_any(&[
+            "unknown field",
+            "missing field",
+            "invalid value",
+            "invalid type",
+            "deserializing the json body",
+            "body of the request is empty",
+            "invalid",
+        ]) {
+            FcErrorKind::InvalidField
+        } else {
+            FcErrorKind::Other
+        }
+    }
+}
+
+/// An error response of Firecracker
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub struct FcError {
+    /// Class of the error.
+    pub kind: FcErrorKind,
+    /// HTTP status code of the response.
+    pub status: u16,
+    /// HTTP method of the failed request.
+    pub method: Method,
+    /// API endpoint of the failed request.
+    pub path: String,
+    /// Body of the failed request, if any.
+    pub request_body: Option<Value>,
+    /// A description of the error condition, if the response had one.
+    pub fault_message: Option<String>,
+    /// Raw body of the response.
+    pub body: String,
+}
+
+impl FcError {
+    /// Build an [`FcError`] out of an error `response` to `request`
+    pub fn new(request: &ApiRequest, response: ApiResponse) -> FcError {
+        let fault_message = serde_json::from_str::<Value>(&response.body)
+            .ok()
+            .and_then(|body| body.get("fault_message")?.as_str().map(str::to_owned));
+        let kind = fault_message
+            .as_deref()
+            .map_or(FcErrorKind::Other, FcErrorKind::from_fault_message);
+
+        FcError {
+            kind,
+            status: response.status,
+            method: request.method,
+            path: request.path.clone(),
+            request_body: request.body.clone(),
+            fault_message,
+            body: response.body,
+        }
+    }
+}
+
+impl std::fmt::Display for FcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = self.fault_message.as_deref().unwrap_or(&self.body);
+        write!(
+            f,
+            "{message} ({} {} returned {})",
+            self.method, self.path, self.status
+        )
+    }
+}
+
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum FcClientError {
+    /// (De)serialization error: {0}
+    Serde(#[from] serde_json::Error),
+    /// Client error: {0}
+    Client(#[from] reqwest::Error),
+    /// Firecracker error: {0}
+    Firecracker(#[from] FcError),
+    /// Request was not sent to Firecracker (dry-run mode)
+    DryRun,
+    /// Transport error: {0}
+    Transport(Box<dyn std::error::Error + Send + Sync>),
+    /// Timed out connecting to Firecracker after {0:?}
+    ConnectTimeout(Duration),
+    /// Timed out waiting for Firecracker after {0:?}
+    Timeout(Duration),
+    /// {0}
+    Unsupported(#[from] Unsupported),
+    /// Firecracker reported an invalid version: {0}
+    InvalidVersion(String),
+    /// Invalid kernel image: {0}
+    InvalidKernel(#[from] KernelImageError),
+    /// Drive swap failed: {0}
+    DriveSwap(#[from] DriveSwapError),
+}
+
+impl FcClientError {
+    /// The class of the error returned by Firecracker, if Firecracker returned one.
+    pub fn kind(&self) -> Option<FcErrorKind> {
+        match self {
+            FcClientError::Firecracker(err) => Some(err.kind),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if the request timed out, while connecting or waiting for a response.
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            FcClientError::ConnectTimeout(_) | FcClientError::Timeout(_) => true,
+            FcClientError::Client(err) => err.is_timeout(),
+            _ => false,
+        }
+    }
+
+    /// Returns `true` if connecting to Firecracker failed, i.e. the request was not sent.
+    pub fn is_connect(&self) -> bool {
+        match self {
+            FcClientError::ConnectTimeout(_) => true,
+            FcClientError::Client(err) => err.is_connect(),
+            _ => false,
+        }
+    }
+
+    // Returns `true` if a request using `method` that failed with this error can be retried.
+    pub(crate) fn is_retryable(&self, method: Method) -> bool {
+        match self {
+            FcClientError::Client(_)
+            | FcClientError::Transport(_)
+            | FcClientError::ConnectTimeout(_)
+            | FcClientError::Timeout(_) => method == Method::Get || self.is_connect(),
+            _ => false,
+        }
+    }
+}
+
+pub type Result<T> = std::result::Result<T, FcClientError>;
@@ -0,0 +1,80 @@
+//! Memory hotplug
+//!
+//! Since Firecracker 1.14, memory can be plugged into and unplugged from a running microVM through
+//! a virtio-mem device. The device is configured before boot with the total amount of memory that
+//! can be hotplugged. After boot, the guest is asked to plug or unplug memory by updating the
+//! requested size.
+
+#[cfg(feature = "clap")]
+use clap::Args;
+use serde_derive::{Deserialize, Serialize};
+
+use super::types::MiB;
+use super::{ApiClient, Result};
+
+/// Configuration of the memory hotplug device
+#[cfg_attr(feature = "clap", derive(Args))]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MemoryHotplugConfig {
+    /// Total amount of memory, in MiB, that can be hotplugged.
+    pub total_size_mib: MiB,
+    /// Size of the memory slots, in MiB.
+    #[cfg_attr(feature = "clap", arg(long))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slot_size_mib: Option<MiB>,
+    /// Size of the blocks, in MiB, that the guest plugs and unplugs.
+    #[cfg_attr(feature = "clap", arg(long))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_size_mib: Option<MiB>,
+}
+
+impl MemoryHotplugConfig {
+    /// Allow hotplugging up to `total_size_mib` of memory
+    pub fn new(total_size_mib: MiB) -> MemoryHotplugConfig {
+        MemoryHotplugConfig {
+            total_size_mib,
+            slot_size_mib: None,
+            block_size_mib: None,
+        }
+    }
+}
+
+/// Status of the memory hotplug device
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MemoryHotplugStatus {
+    /// Total amount of memory, in MiB, that can be hotplugged.
+    pub total_size_mib: MiB,
+    /// Size of the memory slots, in MiB.
+    pub slot_size_mib: MiB,
+    /// Size of the blocks, in MiB, that the guest plugs and unplugs.
+    pub block_size_mib: MiB,
+    /// Amount of memory, in MiB, currently plugged by the guest.
+    pub plugged_size_mib: MiB,
+    /// Amount of memory, in MiB, the guest has been asked to plug.
+    pub requested_size_mib: MiB,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MemoryHotplugSizeUpdate {
+    requested_size_mib: MiB,
+}
+
+impl ApiClient {
+    /// Configure the memory hotplug device.
+    ///
+    /// This operation is only allowed before the VM has been booted.
+    pub async fn configure_memory_hotplug(&self, config: &MemoryHotplugConfig) -> Result<()> {
+        self.put("/hotplug/memory", config).await
+    }
+
+    /// Ask the guest to plug (or unplug) memory, until `requested_size_mib` is plugged.
+    pub async fn update_memory_hotplug_size(&self, requested_size_mib: MiB) -> Result<()> {
+        let update = MemoryHotplugSizeUpdate { requested_size_mib };
+        self.patch("/hotplug/memory", update).await
+    }
+
+    /// Get the status of the memory hotplug device.
+    pub async fn memory_hotplug_status(&self) -> Result<MemoryHotplugStatus> {
+        self.get("/hotplug/memory").await
+    }
+}
@@ -2,12 +2,14 @@
 use clap::Args;
 use serde_derive::{Deserialize, Serialize};
 
+use super::cmdline::{CmdlineError, KernelCmdline, RootDevice};
+use super::kernel_image::KernelImage;
 use super::{ApiClient, Result};
 
 /// [`BootSource`] includes information about the kernel file and, potentially, initrd used to boot
 /// the microVM
-#[cfg_attr(feature = "clap", derive(Args, Clone))]
-#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "clap", derive(Args))]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BootSource {
     /// Host level path to the kernel image used to boot the guest
     pub kernel_image_path: String,
@@ -28,11 +30,78 @@ impl BootSource {
             kernel_image_path,
         }
     }
+
+    /// Set the kernel boot arguments from a [`KernelCmdline`]
+    pub fn with_cmdline(
+        mut self,
+        cmdline: &KernelCmdline,
+    ) -> std::result::Result<Self, CmdlineError> {
+        self.boot_args = Some(cmdline.build()?);
+        Ok(self)
+    }
+
+    /// The kernel boot arguments, parsed as a [`KernelCmdline`]
+    pub fn cmdline(&self) -> KernelCmdline {
+        KernelCmdline::parse(self.boot_args.as_deref().unwrap_or_default())
+    }
+
+    /// Set `root=PARTUUID=` to boot from the partition `partuuid` of the root drive
+    ///
+    /// The boot arguments are left unchanged if `root=` already names a partition, and `false` is
+    /// returned. `root=` is only replaced if it is missing or names the whole disk, `/dev/vda`.
+    /// Without boot arguments, it is added to the [command line recommended for
+    /// Firecracker](KernelCmdline::firecracker).
+    pub fn set_root(&mut self, partuuid: &str) -> std::result::Result<bool, CmdlineError> {
+        let cmdline = match self.boot_args {
+            Some(_) => self.cmdline(),
+            None => KernelCmdline::firecracker(),
+        };
+        if cmdline
+            .get("root")
+            .is_some_and(|root| root != Some("/dev/vda"))
+        {
+            return Ok(false);
+        }
+        let cmdline = cmdline.root(RootDevice::PartUuid(partuuid.to_owned()));
+        self.boot_args = Some(cmdline.build()?);
+        Ok(true)
+    }
 }
 
 impl ApiClient {
     /// Setup the boot source of the VM.
+    ///
+    /// With validation enabled, the kernel image is inspected first, see [`KernelImage::check`].
     pub async fn set_boot_source(&mut self, boot_source: &BootSource) -> Result<()> {
+        if self.validate {
+            KernelImage::inspect(&boot_source.kernel_image_path)?.check()?;
+        }
         self.put("/boot-source", boot_source).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_root() {
+        let mut boot_source = BootSource::new("vmlinux".to_owned());
+        assert!(boot_source.set_root("1234abcd-01").unwrap());
+        assert_eq!(
+            boot_source.boot_args.as_deref(),
+            Some("console=ttyS0 reboot=k panic=1 pci=off root=PARTUUID=1234abcd-01")
+        );
+
+        boot_source.boot_args = Some("console=tty0 console=ttyS0 root=/dev/vda rw".to_owned());
+        assert!(boot_source.set_root("1234abcd-01").unwrap());
+        assert_eq!(
+            boot_source.boot_args.as_deref(),
+            Some("console=tty0 console=ttyS0 root=PARTUUID=1234abcd-01 rw")
+        );
+
+        boot_source.boot_args = Some("root=/dev/vda2".to_owned());
+        assert!(!boot_source.set_root("1234abcd-01").unwrap());
+        assert_eq!(boot_source.boot_args.as_deref(), Some("root=/dev/vda2"));
+    }
+}
@@ -0,0 +1,514 @@
+//! Inspection of guest kernel images
+//!
+//! Firecracker boots uncompressed kernels only: ELF images (`vmlinux`) on x86_64, and arm64
+//! `Image` files on aarch64. A kernel in any other format is only reported once the microVM fails
+//! to start. [`KernelImage::inspect`] reads the headers of a kernel image, and
+//! [`KernelImage::check`] reports why Firecracker could not boot it on this host.
+//!
+//! When the kernel was built with `CONFIG_IKCONFIG`, its configuration is embedded in the image,
+//! and [`KernelImage::check`] also verifies that the options Firecracker needs to boot it are built
+//! in. [`KernelImage::check_options`] checks the options of the devices of a microVM, e.g.
+//! [`VSOCK_OPTIONS`] when it has a vsock device.
+//!
+//! [`ApiClient::set_boot_source`] inspects the kernel image when validation is enabled, see
+//! [`ApiClient::with_validation`].
+//!
+//! [`ApiClient::set_boot_source`]: super::ApiClient::set_boot_source
+//! [`ApiClient::with_validation`]: super::ApiClient::with_validation
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Read};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+use serde_derive::Serialize;
+
+/// Kernel options that Firecracker needs to boot a kernel from a drive, which must be built in
+pub const REQUIRED_OPTIONS: &[&str] = &["CONFIG_VIRTIO_MMIO", "CONFIG_VIRTIO_BLK"];
+
+/// Kernel options that network interfaces need
+pub const NET_OPTIONS: &[&str] = &["CONFIG_VIRTIO_NET"];
+
+/// Kernel options that a vsock device needs
+pub const VSOCK_OPTIONS: &[&str] = &["CONFIG_VIRTIO_VSOCKETS"];
+
+/// Kernel options that the serial console needs
+pub const CONSOLE_OPTIONS: &[&str] = &["CONFIG_SERIAL_8250_CONSOLE"];
+
+// Lowest guest physical address of the kernel on x86_64, `HIMEM_START` in Firecracker
+const HIMEM_START: u64 = 0x10_0000;
+
+// Markers around the gzipped configuration embedded by `CONFIG_IKCONFIG`
+const IKCONFIG_START: &[u8] = b"IKCFG_ST";
+const IKCONFIG_END: &[u8] = b"IKCFG_ED";
+
+// Prefix of the version banner of the kernel, e.g. `Linux version 6.1.102 (user@host) ...`
+const VERSION_PREFIX: &[u8] = b"Linux version ";
+
+/// A kernel image that Firecracker cannot boot
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum KernelImageError {
+    /// could not read the kernel image: {0}
+    Io(#[from] io::Error),
+    /// the kernel image is compressed with {0}, Firecracker boots uncompressed kernels only
+    Compressed(&'static str),
+    /// the kernel image is a bzImage, Firecracker boots uncompressed ELF kernels (vmlinux) only
+    BzImage,
+    /// the kernel image is neither an ELF file nor an arm64 Image
+    UnknownFormat,
+    /// the kernel image is malformed: {0}
+    Malformed(&'static str),
+    /// the kernel image is built for {0}, but the host is {1}
+    WrongArchitecture(Arch, String),
+    /// Firecracker cannot boot a {0} on {1}
+    WrongFormat(KernelFormat, Arch),
+    /// the entry point {0:#x} of the kernel image is invalid
+    InvalidEntryPoint(u64),
+    /// the kernel image lacks required options: {0:?}
+    MissingOptions(Vec<String>),
+    /// the kernel image builds required options as modules, which must be built in: {0:?}
+    ModuleOptions(Vec<String>),
+}
+
+/// Whether a kernel option is built in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, displaydoc::Display)]
+#[serde(rename_all = "snake_case")]
+pub enum OptionStatus {
+    /// built in
+    BuiltIn,
+    /// built as module
+    Module,
+    /// missing
+    Missing,
+}
+
+/// Format of a kernel image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, displaydoc::Display)]
+pub enum KernelFormat {
+    /// ELF kernel
+    Elf,
+    /// arm64 Image
+    Arm64Image,
+}
+
+/// Architecture of a kernel image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, displaydoc::Display)]
+#[serde(rename_all = "lowercase")]
+pub enum Arch {
+    /// x86_64
+    X86_64,
+    /// aarch64
+    Aarch64,
+    /// ELF machine {0:#x}
+    Other(u16),
+}
+
+impl Arch {
+    /// Architecture of the host, `None` if Firecracker does not support it
+    pub fn host() -> Option<Arch> {
+        match std::env::consts::ARCH {
+            "x86_64" => Some(Arch::X86_64),
+            "aarch64" => Some(Arch::Aarch64),
+            _ => None,
+        }
+    }
+}
+
+/// Properties of a guest kernel image
+#[derive(Debug, Clone, Serialize)]
+pub struct KernelImage {
+    /// Format of the image
+    pub format: KernelFormat,
+    /// Architecture the kernel is built for
+    pub arch: Arch,
+    /// Entry point: a physical address for ELF kernels, an offset from the load address for arm64
+    /// Images
+    pub entry_point: u64,
+    /// Version banner of the kernel, e.g. `Linux version 6.1.102 (...)`
+    pub version: Option<String>,
+    /// Embedded configuration, if the kernel was built with `CONFIG_IKCONFIG`
+    #[serde(skip)]
+    pub config: Option<BTreeMap<String, String>>,
+    // Loadable segments of an ELF kernel, as (physical address, size in memory)
+    #[serde(skip)]
+    segments: Vec<(u64, u64)>,
+}
+
+impl KernelImage {
+    /// Read and parse the kernel image at `path`
+    pub fn inspect<P: AsRef<Path>>(path: P) -> Result<KernelImage, KernelImageError> {
+        let mut image = Vec::new();
+        std::fs::File::open(path)?.read_to_end(&mut image)?;
+        KernelImage::parse(&image)
+    }
+
+    /// Parse a kernel image
+    pub fn parse(image: &[u8]) -> Result<KernelImage, KernelImageError> {
+        if let Some(compression) = compression(image) {
+            return Err(KernelImageError::Compressed(compression));
+        }
+        if image.get(0x202..0x206) == Some(b"HdrS") {
+            return Err(KernelImageError::BzImage);
+        }
+
+        let mut kernel = if image.starts_with(b"\x7fELF") {
+            parse_elf(image)?
+        } else if image.get(0x38..0x3c) == Some(b"ARM\x64") {
+            parse_arm64_image(image)?
+        } else {
+            return Err(KernelImageError::UnknownFormat);
+        };
+
+        kernel.version = find(image, VERSION_PREFIX).map(|start| {
+            let banner = &image[start..];
+            let end = banner
+                .iter()
+                .position(|&b| b == 0 || b == b'\n')
+                .unwrap_or(banner.len());
+            String::from_utf8_lossy(&banner[..end]).trim().to_owned()
+        });
+        kernel.config = ikconfig(image);
+        Ok(kernel)
+    }
+
+    /// Release of the kernel, e.g. `6.1.102`
+    pub fn release(&self) -> Option<&str> {
+        let banner = self.version.as_deref()?;
+        banner
+            .strip_prefix("Linux version ")?
+            .split_whitespace()
+            .next()
+    }
+
+    /// The value of `option` in the embedded configuration, e.g. `y` for `CONFIG_VIRTIO_BLK`
+    ///
+    /// `None` if the option is not set, or the configuration is not embedded.
+    pub fn option(&self, option: &str) -> Option<&str> {
+        self.config.as_ref()?.get(option).map(String::as_str)
+    }
+
+    /// Whether `option` is built in, `None` if the configuration is not embedded
+    pub fn option_status(&self, option: &str) -> Option<OptionStatus> {
+        self.config.as_ref()?;
+        Some(match self.option(option) {
+            Some("y") => OptionStatus::BuiltIn,
+            Some("m") => OptionStatus::Module,
+            _ => OptionStatus::Missing,
+        })
+    }
+
+    /// Of `options`, the ones that are not built in
+    ///
+    /// Empty if the configuration is not embedded.
+    pub fn missing_options(&self, options: &[&str]) -> Vec<String> {
+        options
+            .iter()
+            .filter(|option| {
+                self.option_status(option)
+                    .is_some_and(|status| status != OptionStatus::BuiltIn)
+            })
+            .map(|option| option.to_string())
+            .collect()
+    }
+
+    /// Check that `options` are built in, if the configuration is embedded
+    ///
+    /// Options that are missing are reported first, then options that are built as modules.
+    pub fn check_options(&self, options: &[&str]) -> Result<(), KernelImageError> {
+        let with_status = |status| {
+            options
+                .iter()
+                .filter(|option| self.option_status(option) == Some(status))
+                .map(|option| option.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let missing = with_status(OptionStatus::Missing);
+        if !missing.is_empty() {
+            return Err(KernelImageError::MissingOptions(missing));
+        }
+        let modules = with_status(OptionStatus::Module);
+        if !modules.is_empty() {
+            return Err(KernelImageError::ModuleOptions(modules));
+        }
+        Ok(())
+    }
+
+    /// Check that Firecracker can boot the kernel on this host
+    ///
+    /// It verifies the format, the architecture and the entry point of the kernel, and, if its
+    /// configuration is embedded, that it has [`REQUIRED_OPTIONS`]. The options of devices, e.g.
+    /// [`VSOCK_OPTIONS`], are left to [`KernelImage::check_options`].
+    pub fn check(&self) -> Result<(), KernelImageError> {
+        let host = Arch::host();
+        if host != Some(self.arch) {
+            return Err(KernelImageError::WrongArchitecture(
+                self.arch,
+                std::env::consts::ARCH.to_owned(),
+            ));
+        }
+
+        match (self.format, self.arch) {
+            (KernelFormat::Elf, Arch::X86_64) => {
+                let in_segment = self.segments.iter().any(|&(start, size)| {
+                    (start..start.saturating_add(size)).contains(&self.entry_point)
+                });
+                if self.entry_point < HIMEM_START || !in_segment {
+                    return Err(KernelImageError::InvalidEntryPoint(self.entry_point));
+                }
+            }
+            (KernelFormat::Arm64Image, Arch::Aarch64) => (),
+            (format, arch) => return Err(KernelImageError::WrongFormat(format, arch)),
+        }
+
+        self.check_options(REQUIRED_OPTIONS)
+    }
+}
+
+impl fmt::Display for KernelImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} for {}", self.format, self.arch)?;
+        if let Some(release) = self.release() {
+            write!(f, ", Linux {release}")?;
+        }
+        Ok(())
+    }
+}
+
+// The compression format of `image`, if it is compressed.
+fn compression(image: &[u8]) -> Option<&'static str> {
+    const MAGICS: &[(&[u8], &str)] = &[
+        (b"\x1f\x8b", "gzip"),
+        (b"\xfd7zXZ\x00", "xz"),
+        (b"\x28\xb5\x2f\xfd", "zstd"),
+        (b"BZh", "bzip2"),
+        (b"\x02\x21\x4c\x18", "lz4"),
+        (b"\x89LZO", "lzo"),
+        (b"\x5d\x00\x00", "lzma"),
+    ];
+    // Compressed arm64 kernels with an EFI decompressor, i.e. `vmlinuz.efi`
+    if image.starts_with(b"MZ") && image.get(4..8) == Some(b"zimg") {
+        return Some("EFI zboot");
+    }
+    MAGICS
+        .iter()
+        .find(|(magic, _)| image.starts_with(magic))
+        .map(|(_, name)| *name)
+}
+
+fn parse_elf(image: &[u8]) -> Result<KernelImage, KernelImageError> {
+    const ELFCLASS64: u8 = 2;
+    const ELFDATA2LSB: u8 = 1;
+    const EM_X86_64: u16 = 0x3e;
+    const EM_AARCH64: u16 = 0xb7;
+    const PT_LOAD: u32 = 1;
+
+    if image.get(4) != Some(&ELFCLASS64) {
+        return Err(KernelImageError::Malformed("not a 64-bit ELF file"));
+    }
+    if image.get(5) != Some(&ELFDATA2LSB) {
+        return Err(KernelImageError::Malformed("not a little-endian ELF file"));
+    }
+
+    let truncated = || KernelImageError::Malformed("truncated ELF header");
+    let machine = read_u16(image, 0x12).ok_or_else(truncated)?;
+    let arch = match machine {
+        EM_X86_64 => Arch::X86_64,
+        EM_AARCH64 => Arch::Aarch64,
+        machine => Arch::Other(machine),
+    };
+    let entry_point = read_u64(image, 0x18).ok_or_else(truncated)?;
+
+    // Program headers
+    let phoff = read_u64(image, 0x20).ok_or_else(truncated)?;
+    let phentsize = read_u16(image, 0x36).ok_or_else(truncated)?;
+    let phnum = read_u16(image, 0x38).ok_or_else(truncated)?;
+    let mut segments = Vec::new();
+    for i in 0..u64::from(phnum) {
+        let header = i
+            .checked_mul(u64::from(phentsize))
+            .and_then(|offset| offset.checked_add(phoff))
+            .and_then(|offset| usize::try_from(offset).ok())
+            .ok_or(KernelImageError::Malformed("invalid program header offset"))?;
+        let header = image.get(header..).unwrap_or_default();
+        let p_type = read_u32(header, 0);
+        let p_paddr = read_u64(header, 0x18);
+        let p_memsz = read_u64(header, 0x28);
+        let (Some(p_type), Some(p_paddr), Some(p_memsz)) = (p_type, p_paddr, p_memsz) else {
+            return Err(KernelImageError::Malformed("truncated program header"));
+        };
+        if p_type == PT_LOAD {
+            segments.push((p_paddr, p_memsz));
+        }
+    }
+    if segments.is_empty() {
+        return Err(KernelImageError::Malformed("no loadable segment"));
+    }
+
+    Ok(KernelImage {
+        format: KernelFormat::Elf,
+        arch,
+        entry_point,
+        version: None,
+        config: None,
+        segments,
+    })
+}
+
+// See `Documentation/arch/arm64/booting.rst` in the kernel
+fn parse_arm64_image(image: &[u8]) -> Result<KernelImage, KernelImageError> {
+    let truncated = || KernelImageError::Malformed("truncated arm64 Image header");
+    let text_offset = read_u64(image, 0x08).ok_or_else(truncated)?;
+    let image_size = read_u64(image, 0x10).ok_or_else(truncated)?;
+    let flags = read_u64(image, 0x18).ok_or_else(truncated)?;
+
+    if flags & 1 != 0 {
+        return Err(KernelImageError::Malformed("big-endian arm64 kernel"));
+    }
+    if image_size == 0 {
+        return Err(KernelImageError::Malformed(
+            "arm64 Image without image size, i.e. older than Linux 3.17",
+        ));
+    }
+
+    Ok(KernelImage {
+        format: KernelFormat::Arm64Image,
+        arch: Arch::Aarch64,
+        entry_point: text_offset,
+        version: None,
+        config: None,
+        segments: Vec::new(),
+    })
+}
+
+// The configuration embedded by `CONFIG_IKCONFIG`, as a map of option names to values.
+fn ikconfig(image: &[u8]) -> Option<BTreeMap<String, String>> {
+    let start = find(image, IKCONFIG_START)? + IKCONFIG_START.len();
+    let end = start + find(&image[start..], IKCONFIG_END)?;
+
+    let mut config = String::new();
+    GzDecoder::new(&image[start..end])
+        .read_to_string(&mut config)
+        .ok()?;
+
+    Some(
+        config
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| line.split_once('='))
+            .map(|(name, value)| (name.to_owned(), value.trim_matches('"').to_owned()))
+            .collect(),
+    )
+}
+
+// Offset of the first occurrence of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn read_u16(image: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        image.get(offset..offset.checked_add(2)?)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(image: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        image.get(offset..offset.checked_add(4)?)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(image: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        image.get(offset..offset.checked_add(8)?)?.try_into().ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 64-bit little-endian x86_64 ELF header, with `phnum` program headers at `phoff`.
+    fn elf_header(phoff: u64, phnum: u16) -> Vec<u8> {
+        let mut image = vec![0; 0x40];
+        image[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        image[0x12..0x14].copy_from_slice(&0x3eu16.to_le_bytes());
+        image[0x18..0x20].copy_from_slice(&0x100_0000u64.to_le_bytes());
+        image[0x20..0x28].copy_from_slice(&phoff.to_le_bytes());
+        image[0x36..0x38].copy_from_slice(&0x38u16.to_le_bytes());
+        image[0x38..0x3a].copy_from_slice(&phnum.to_le_bytes());
+        image
+    }
+
+    #[test]
+    fn elf_with_loadable_segment() {
+        let mut image = elf_header(0x40, 1);
+        let mut header = vec![0; 0x38];
+        header[..4].copy_from_slice(&1u32.to_le_bytes());
+        header[0x18..0x20].copy_from_slice(&0x100_0000u64.to_le_bytes());
+        header[0x28..0x30].copy_from_slice(&0x1000u64.to_le_bytes());
+        image.extend(header);
+
+        let kernel = KernelImage::parse(&image).unwrap();
+        assert_eq!(kernel.arch, Arch::X86_64);
+        assert_eq!(kernel.segments, [(0x100_0000, 0x1000)]);
+    }
+
+    #[test]
+    fn elf_with_overflowing_program_headers() {
+        for phoff in [u64::MAX, u64::MAX - 0x20, 0x40] {
+            let image = elf_header(phoff, 2);
+            assert!(matches!(
+                KernelImage::parse(&image),
+                Err(KernelImageError::Malformed(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn option_statuses() {
+        let mut kernel = KernelImage {
+            format: KernelFormat::Elf,
+            arch: Arch::X86_64,
+            entry_point: 0x100_0000,
+            version: None,
+            config: None,
+            segments: vec![(0x100_0000, 0x1000)],
+        };
+        assert_eq!(kernel.option_status("CONFIG_VIRTIO_BLK"), None);
+        assert!(kernel.check_options(REQUIRED_OPTIONS).is_ok());
+
+        kernel.config = Some(BTreeMap::from([
+            ("CONFIG_VIRTIO_MMIO".to_owned(), "y".to_owned()),
+            ("CONFIG_VIRTIO_BLK".to_owned(), "m".to_owned()),
+        ]));
+        assert_eq!(
+            kernel.option_status("CONFIG_VIRTIO_BLK"),
+            Some(OptionStatus::Module)
+        );
+        assert!(matches!(
+            kernel.check_options(REQUIRED_OPTIONS),
+            Err(KernelImageError::ModuleOptions(options)) if options == ["CONFIG_VIRTIO_BLK"]
+        ));
+        assert!(matches!(
+            kernel.check_options(VSOCK_OPTIONS),
+            Err(KernelImageError::MissingOptions(options)) if options == ["CONFIG_VIRTIO_VSOCKETS"]
+        ));
+        assert_eq!(
+            kernel.missing_options(&["CONFIG_VIRTIO_MMIO", "CONFIG_VIRTIO_BLK"]),
+            ["CONFIG_VIRTIO_BLK"]
+        );
+    }
+
+    #[test]
+    fn truncated_elf() {
+        let image = elf_header(0x40, 1);
+        assert!(matches!(
+            KernelImage::parse(&image[..0x30]),
+            Err(KernelImageError::Malformed(_))
+        ));
+    }
+}
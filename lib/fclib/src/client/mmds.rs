@@ -2,6 +2,7 @@
 
 use serde_derive::{Deserialize, Serialize};
 
+use super::types::Ipv4LinkLocal;
 use super::{ApiClient, Result};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -15,7 +16,7 @@ pub struct MmdsConfig {
     /// the device model, and do not reach the associated TAP device.
     pub network_interfaces: Vec<String>,
     /// A valid IPv4 link-local address.
-    pub ipv4_address: Option<String>,
+    pub ipv4_address: Option<Ipv4LinkLocal>,
 }
 
 impl MmdsConfig {
@@ -30,12 +31,21 @@ impl MmdsConfig {
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
-pub struct MmdsContentsObject {}
+pub struct MmdsContentsObject {
+    #[serde(flatten)]
+    contents: serde_json::Map<String, serde_json::Value>,
+}
 
 impl MmdsContentsObject {
     /// Describes the contents of MMDS in JSON format.
     pub fn new() -> MmdsContentsObject {
-        MmdsContentsObject {}
+        MmdsContentsObject::default()
+    }
+
+    /// Set the top-level entry `key` of the contents, e.g. the network metadata from
+    /// [`mmds_network`](crate::net::ipam::mmds_network)
+    pub fn insert<S: Into<String>>(&mut self, key: S, value: serde_json::Value) {
+        self.contents.insert(key.into(), value);
     }
 }
 
@@ -1,59 +1,64 @@
 pub mod balloon;
+pub mod cmdline;
 pub mod cpu_config;
 pub mod drive;
+pub mod dry_run;
 pub mod entropy;
+pub mod error;
+pub mod hotplug;
 pub mod kernel;
+pub mod kernel_image;
 pub mod logger;
 pub mod metrics;
 pub mod mmds;
 pub mod network;
+pub mod partition;
+pub mod policy;
 pub mod rate_limiter;
+pub mod resize;
+pub mod serial;
+pub mod session;
 pub mod snapshot;
+pub mod transport;
+pub mod types;
+pub mod validate;
+pub mod version;
 pub mod vm;
 pub mod vsock;
 
 use std::path::Path;
+use std::sync::Arc;
 
-use log::debug;
-use reqwest::header::{CONTENT_LENGTH, CONTENT_TYPE};
-use reqwest::{Client, ClientBuilder};
+use log::{debug, warn};
+use semver::Version;
 use serde::de::DeserializeOwned;
-use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::OnceCell;
 
-/// Errors returned by Firecracker
-#[derive(Debug, thiserror::Error, Deserialize, Default)]
-pub struct FcError {
-    /// A description of the error condition
-    fault_message: Option<String>,
-}
-
-impl std::fmt::Display for FcError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            self.fault_message.as_ref().unwrap_or(&"".to_owned())
-        )
-    }
-}
-
-#[derive(Debug, thiserror::Error, displaydoc::Display)]
-pub enum FcClientError {
-    /// (De)serialization error: {0}
-    Serde(#[from] serde_json::Error),
-    /// Client error: {0}
-    Client(#[from] reqwest::Error),
-    /// Firecracker error: {0}
-    Firecracker(#[from] FcError),
-}
-
-pub type Result<T> = std::result::Result<T, FcClientError>;
+pub use error::{FcClientError, FcError, FcErrorKind, Result};
+use policy::RequestPolicy;
+use transport::{ApiRequest, ApiResponse, Method, Recorder, Transport, UnixSocket};
+use version::Feature;
+use vm::FirecrackerVersion;
 
-/// An HTTP client that can speak the Firecracker API on top of a Unix socket.
-#[derive(Debug)]
+/// An HTTP client that can speak the Firecracker API.
+///
+/// By default, requests are sent over Firecracker's Unix socket. Use [`ApiClient::with_transport`]
+/// to send them through any other [`Transport`]. Clones of an [ApiClient] share its transport.
+///
+/// Requests follow the client's [`RequestPolicy`], which controls their timeouts and retries.
+///
+/// The body of requests is shaped for the version of the running Firecracker, see [`version`].
+#[derive(Debug, Clone)]
 pub struct ApiClient {
-    /// A reqwest [Client] that can speak on top of a UDS
-    client: Client,
+    /// The [Transport] that delivers requests to Firecracker
+    transport: Arc<dyn Transport>,
+    /// Timeouts and retries applied to requests
+    policy: RequestPolicy,
+    /// Version of Firecracker, queried on first use
+    fc_version: Arc<OnceCell<Version>>,
+    /// Check the configuration on the host before sending it
+    validate: bool,
 }
 
 impl ApiClient {
@@ -63,15 +68,163 @@ impl ApiClient {
     ///
     /// * `path` - Filesystem path to the Unix socket.
     pub fn new<P: AsRef<Path>>(path: P) -> Self {
-        let client = ClientBuilder::new()
-            .unix_socket(path.as_ref())
-            .build()
-            .unwrap();
-        Self { client }
+        Self::with_transport(UnixSocket::new(path))
+    }
+
+    /// Create a new [ApiClient] that sends requests through `transport`
+    pub fn with_transport<T: Transport + 'static>(transport: T) -> Self {
+        Self {
+            transport: Arc::new(transport),
+            policy: RequestPolicy::default(),
+            fc_version: Arc::default(),
+            validate: false,
+        }
+    }
+
+    /// Shape requests for Firecracker `version`, instead of querying the version of Firecracker
+    pub fn with_fc_version(self, version: Version) -> Self {
+        Self {
+            fc_version: Arc::new(OnceCell::new_with(Some(version))),
+            ..self
+        }
+    }
+
+    /// Check the configuration on the host before sending it to Firecracker
+    ///
+    /// With validation enabled, e.g. [`ApiClient::set_boot_source`] inspects the kernel image, and
+    /// fails if Firecracker could not boot it.
+    pub fn with_validation(self, validate: bool) -> Self {
+        Self { validate, ..self }
+    }
+
+    /// Returns `true` if the version of Firecracker has `feature`
+    pub async fn supports(&self, feature: Feature) -> Result<bool> {
+        Ok(feature.is_supported_by(&self.fc_version().await?))
+    }
+
+    /// The version of Firecracker that requests are shaped for
+    ///
+    /// It is queried from `/version` the first time it is needed, and cached afterwards.
+    pub async fn fc_version(&self) -> Result<Version> {
+        let version = self
+            .fc_version
+            .get_or_try_init(|| async {
+                let response = self.send_unshaped(Method::Get, "/version", None).await?;
+                let reported =
+                    serde_json::from_str::<FirecrackerVersion>(&response.body)?.firecracker_version;
+                let version = version::parse_version(&reported)
+                    .ok_or(FcClientError::InvalidVersion(reported))?;
+
+                let supported = crate::supported_fc_version();
+                if (version.major, version.minor) > (supported.major, supported.minor) {
+                    warn!("Firecracker {version} is newer than the supported version {supported}");
+                }
+                Ok::<_, FcClientError>(version)
+            })
+            .await?;
+
+        Ok(version.clone())
     }
 
-    fn url(&self, path: &str) -> String {
-        format!("http://localhost{path}")
+    /// The [`RequestPolicy`] applied to requests
+    pub fn policy(&self) -> &RequestPolicy {
+        &self.policy
+    }
+
+    /// Set the [`RequestPolicy`] applied to requests
+    pub fn set_policy(&mut self, policy: RequestPolicy) {
+        self.policy = policy;
+    }
+
+    /// Get a client that shares the transport of this one, but applies `policy` to requests
+    ///
+    /// Use it to override the policy for a single call, e.g.
+    /// `client.with_policy(policy).instance_info().await`.
+    pub fn with_policy(&self, policy: RequestPolicy) -> Self {
+        Self {
+            transport: self.transport.clone(),
+            policy,
+            fc_version: self.fc_version.clone(),
+            validate: self.validate,
+        }
+    }
+
+    /// Record every request and its response to the session file at `path`
+    ///
+    /// Exchanges are appended to the file, one JSON object per line. See [`session`] for replaying
+    /// a recorded session.
+    pub fn record_to<P: AsRef<Path>>(self, path: P) -> std::io::Result<Self> {
+        let recorder = Recorder::new(self.transport).record_to(path)?;
+        Ok(Self {
+            transport: Arc::new(recorder),
+            ..self
+        })
+    }
+
+    // Sends a single attempt of a request, enforcing the request timeout
+    async fn send_once(&self, request: &ApiRequest) -> Result<ApiResponse> {
+        match self.policy.request_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.transport.send(request))
+                .await
+                .map_err(|_| FcClientError::Timeout(timeout))?,
+            None => self.transport.send(request).await,
+        }
+    }
+
+    // Sends a request, retrying it as allowed by the policy
+    async fn send_with_retries(&self, request: &ApiRequest) -> Result<ApiResponse> {
+        let mut retry = 0;
+        loop {
+            match self.send_once(request).await {
+                Err(err) if retry < self.policy.max_retries && err.is_retryable(request.method) => {
+                    let backoff = self.policy.backoff(retry);
+                    debug!(
+                        "{} @ {} failed, retrying in {backoff:?}: {err}",
+                        request.method, request.path
+                    );
+                    tokio::time::sleep(backoff).await;
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Sends a request, shaped for the version of Firecracker, and fails if Firecracker responds
+    // with an error
+    async fn send(&self, method: Method, path: &str, body: Option<Value>) -> Result<ApiResponse> {
+        let body = match method {
+            Method::Get => body,
+            Method::Put | Method::Patch => {
+                version::shape_request(&self.fc_version().await?, method, path, body)?
+            }
+        };
+        self.send_unshaped(method, path, body).await
+    }
+
+    // Sends a request as is, and fails if Firecracker responds with an error
+    async fn send_unshaped(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<ApiResponse> {
+        debug!("{method} @ {path}");
+        let mut request = ApiRequest::new(method, path, body);
+        request.connect_timeout = self.policy.connect_timeout;
+
+        let response = match self.policy.deadline {
+            Some(deadline) => tokio::time::timeout(deadline, self.send_with_retries(&request))
+                .await
+                .map_err(|_| FcClientError::Timeout(deadline))??,
+            None => self.send_with_retries(&request).await?,
+        };
+
+        if response.is_success() {
+            Ok(response)
+        } else {
+            Err(FcClientError::Firecracker(FcError::new(&request, response)))
+        }
     }
 
     // Performs a PUT request on the specified path
@@ -79,24 +232,9 @@ impl ApiClient {
     where
         T: serde::Serialize,
     {
-        let serialized = serde_json::to_string(&body)?;
-
-        let response = self
-            .client
-            .put(self.url(path))
-            .header(CONTENT_TYPE, "json")
-            .header(CONTENT_LENGTH, serialized.len())
-            .body(serialized)
-            .send()
-            .await?;
-
-        let code = response.status();
-        if code.is_success() {
-            Ok(())
-        } else {
-            let err: FcError = response.json().await?;
-            Err(FcClientError::Firecracker(err))
-        }
+        let body = serde_json::to_value(&body)?;
+        self.send(Method::Put, path, Some(body)).await?;
+        Ok(())
     }
 
     // Performs a PATCH request on the specified path
@@ -104,33 +242,17 @@ impl ApiClient {
     where
         T: serde::Serialize,
     {
-        let serialized = serde_json::to_string(&body)?;
-        debug!("PATCH @ {path}");
-
-        let response = self
-            .client
-            .patch(self.url(path))
-            .header(CONTENT_TYPE, "json")
-            .header(CONTENT_LENGTH, serialized.len())
-            .body(serialized)
-            .send()
-            .await?;
-
-        let code = response.status();
-        if code.is_success() {
-            Ok(())
-        } else {
-            let err: FcError = response.json().await?;
-            Err(FcClientError::Firecracker(err))
-        }
+        let body = serde_json::to_value(&body)?;
+        self.send(Method::Patch, path, Some(body)).await?;
+        Ok(())
     }
 
     // Performs a GET request on the specified path
-    pub(crate) async fn get<'a, T>(&self, path: &str) -> Result<T>
+    pub(crate) async fn get<T>(&self, path: &str) -> Result<T>
     where
         T: DeserializeOwned,
     {
-        let resp = self.client.get(path).send().await?.json().await?;
-        Ok(resp)
+        let response = self.send(Method::Get, path, None).await?;
+        Ok(serde_json::from_str(&response.body)?)
     }
 }
@@ -3,6 +3,7 @@ use clap::Args;
 use serde_derive::{Deserialize, Serialize};
 
 use super::rate_limiter::RateLimiter;
+use super::types::MacAddr;
 use super::{ApiClient, Result};
 
 /// Configuration of a microVM network interface
@@ -15,7 +16,7 @@ pub struct NetworkInterface {
     pub host_dev_name: String,
     /// MAC address to use for the interface inside the guest
     #[cfg_attr(feature = "clap", arg(long, short))]
-    pub guest_mac: Option<String>,
+    pub guest_mac: Option<MacAddr>,
 
     #[cfg_attr(feature = "clap", clap(skip))]
     pub rx_rate_limiter: Option<RateLimiter>,
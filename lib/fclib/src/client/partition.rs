@@ -0,0 +1,747 @@
+//! Partition tables of drive images
+//!
+//! A root drive backed by a partitioned disk image boots from one of its partitions, which the
+//! guest kernel finds with `root=PARTUUID=<partuuid>`. [`PartitionTable::read`] lists the
+//! partitions of a GPT or MBR disk image, along with their PARTUUIDs, as `blkid` reports them, and
+//! [`PartitionTable::root_partition`] picks the partition holding the root filesystem.
+//!
+//! [`Drive::detect_partuuid`](super::drive::Drive::detect_partuuid) fills in the `partuuid` of a
+//! root drive, and [`BootSource::set_root`](super::kernel::BootSource::set_root) the matching
+//! `root=` boot argument.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use serde_derive::Serialize;
+
+// Sector size of MBR disks, and of most GPT disks
+const SECTOR_SIZE: u64 = 512;
+
+// Partition types of MBR disks
+const MBR_PROTECTIVE: u8 = 0xee;
+const MBR_LINUX: u8 = 0x83;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+
+// Longest chain of logical partitions followed, to stop on loops
+const MAX_LOGICAL: u32 = 128;
+
+// Blocks at the end of GPT disks, for the backup header and its partition entry array
+const GPT_BACKUP_BLOCKS: u64 = 34;
+
+// Largest partition entry array of GPT disks accepted, in bytes. The specification requires
+// 16 KiB, i.e. 128 entries of 128 bytes, which is what most tools write.
+const MAX_GPT_ENTRIES_LEN: u64 = 1 << 20;
+
+// Partition types of GPT disks
+const GPT_LINUX: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+#[cfg(target_arch = "x86_64")]
+const GPT_ROOT: &str = "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709";
+#[cfg(target_arch = "aarch64")]
+const GPT_ROOT: &str = "B921B045-1DF0-41C3-AF44-4C6F280D3FAE";
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const GPT_ROOT: &str = "";
+
+const GPT_TYPES: &[(&str, &str)] = &[
+    (GPT_LINUX, "Linux filesystem"),
+    (
+        "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709",
+        "Linux root (x86-64)",
+    ),
+    (
+        "B921B045-1DF0-41C3-AF44-4C6F280D3FAE",
+        "Linux root (ARM-64)",
+    ),
+    ("0657FD6D-A4AB-43C4-84E5-0933C84B4F4F", "Linux swap"),
+    ("E6D6D379-F507-44C2-A23C-238F2A3DF928", "Linux LVM"),
+    (
+        "BC13C2FF-59E6-4262-A352-B275FD6F7172",
+        "Linux extended boot",
+    ),
+    ("C12A7328-F81F-11D2-BA4B-00A0C93EC93B", "EFI System"),
+    ("21686148-6449-6E6F-744E-656564454649", "BIOS boot"),
+    (
+        "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7",
+        "Microsoft basic data",
+    ),
+];
+
+const MBR_TYPES: &[(u8, &str)] = &[
+    (MBR_LINUX, "Linux"),
+    (0x82, "Linux swap"),
+    (0x8e, "Linux LVM"),
+    (0x05, "Extended"),
+    (0x0f, "W95 Extended (LBA)"),
+    (0x85, "Linux extended"),
+    (0x07, "HPFS/NTFS/exFAT"),
+    (0x0b, "W95 FAT32"),
+    (0x0c, "W95 FAT32 (LBA)"),
+    (0xef, "EFI (FAT-12/16/32)"),
+];
+
+/// An error while reading a partition table
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum PartitionError {
+    /// {0}
+    Io(#[from] io::Error),
+    /// invalid partition table: {0}
+    Invalid(String),
+    /// unsupported partition table: {0}
+    Unsupported(String),
+}
+
+/// Kind of a partition table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TableKind {
+    /// GUID partition table
+    Gpt,
+    /// Master boot record, or DOS partition table
+    Mbr,
+}
+
+/// A partition of a disk image
+#[derive(Debug, Clone, Serialize)]
+pub struct Partition {
+    /// Number of the partition, as in `/dev/vdaN`.
+    pub number: u32,
+    /// Offset of the partition in the image, in bytes.
+    pub start: u64,
+    /// Size of the partition, in bytes.
+    pub size: u64,
+    /// Partition type: a GUID on GPT disks, a byte (e.g. `0x83`) on MBR disks.
+    pub type_id: String,
+    /// Name of the partition type, if known.
+    pub type_name: Option<&'static str>,
+    /// PARTUUID of the partition, as used in `root=PARTUUID=`.
+    pub partuuid: String,
+    /// Name of the partition, on GPT disks.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The partition is marked as bootable.
+    pub bootable: bool,
+}
+
+/// The partition table of a disk image
+#[derive(Debug, Clone, Serialize)]
+pub struct PartitionTable {
+    /// Kind of the partition table.
+    pub kind: TableKind,
+    /// Identifier of the disk: its GUID on GPT disks, its signature on MBR disks.
+    pub disk_id: String,
+    /// Size of the logical blocks of the disk, in bytes.
+    pub sector_size: u64,
+    /// Partitions, ordered by number.
+    pub partitions: Vec<Partition>,
+}
+
+impl PartitionTable {
+    /// Read the partition table of the disk image at `path`
+    ///
+    /// Returns `None` if the image is not partitioned, e.g. if it holds a filesystem.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Option<PartitionTable>, PartitionError> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let mut mbr = [0; SECTOR_SIZE as usize];
+        if len < SECTOR_SIZE {
+            return Ok(None);
+        }
+        file.read_exact_at(&mut mbr, 0)?;
+        if mbr[510..512] != [0x55, 0xaa] {
+            return Ok(None);
+        }
+
+        let entries: Vec<MbrEntry> = (0..4).map(|index| MbrEntry::parse(&mbr, index)).collect();
+        // The boot sector of a filesystem has the same signature, but no valid partition entries
+        let valid = entries.iter().all(|entry| match entry.kind {
+            0 | MBR_PROTECTIVE => true,
+            _ => {
+                entry.status & 0x7f == 0
+                    && entry.start > 0
+                    && (entry.start + entry.sectors) * SECTOR_SIZE <= len
+            }
+        });
+        if !valid || entries.iter().all(|entry| entry.kind == 0) {
+            return Ok(None);
+        }
+
+        if entries.iter().any(|entry| entry.kind == MBR_PROTECTIVE) {
+            read_gpt(&file, len).map(Some)
+        } else {
+            read_mbr(&file, &mbr, &entries).map(Some)
+        }
+    }
+
+    /// The partition holding the root filesystem
+    ///
+    /// It is the GPT partition typed as root partition for the architecture of the host or,
+    /// failing that, the largest Linux partition.
+    pub fn root_partition(&self) -> Option<&Partition> {
+        let root = self
+            .partitions
+            .iter()
+            .find(|partition| !GPT_ROOT.is_empty() && partition.type_id == GPT_ROOT);
+        root.or_else(|| {
+            self.partitions
+                .iter()
+                .filter(|partition| {
+                    partition.type_id == GPT_LINUX || partition.type_id == mbr_type(MBR_LINUX)
+                })
+                .max_by_key(|partition| partition.size)
+        })
+    }
+
+    /// The partition that ends last in the image, which is the only one that can be resized
+    pub fn last_partition(&self) -> Option<&Partition> {
+        self.partitions
+            .iter()
+            .filter(|partition| {
+                !MBR_EXTENDED
+                    .iter()
+                    .any(|&kind| partition.type_id == mbr_type(kind))
+            })
+            .max_by_key(|partition| partition.start + partition.size)
+    }
+
+    /// Offset at which partitions must end in an image of `len` bytes
+    ///
+    /// On GPT disks, the backup partition table is at the end of the image.
+    pub fn usable_end(&self, len: u64) -> u64 {
+        let blocks = len / self.sector_size;
+        match self.kind {
+            TableKind::Gpt => blocks.saturating_sub(GPT_BACKUP_BLOCKS) * self.sector_size,
+            TableKind::Mbr => blocks * self.sector_size,
+        }
+    }
+
+    /// Resize the last partition of the image at `path` so that it ends at the usable end of an
+    /// image of `len` bytes, see [`PartitionTable::usable_end`]
+    ///
+    /// On GPT disks, the backup partition table is moved to the end of the resized image, which is
+    /// extended if needed: a shrunk image must be truncated afterwards.
+    pub fn resize_last_partition<P: AsRef<Path>>(
+        &self,
+        path: P,
+        len: u64,
+    ) -> Result<(), PartitionError> {
+        let partition = self
+            .last_partition()
+            .ok_or_else(|| invalid("no partition to resize"))?;
+        let end = self.usable_end(len);
+        if end <= partition.start {
+            return Err(invalid("the image is too small for its partitions"));
+        }
+        let sectors = (end - partition.start) / self.sector_size;
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        match self.kind {
+            TableKind::Mbr => {
+                if partition.number > 4 {
+                    return Err(PartitionError::Unsupported(
+                        "resizing logical partitions".to_owned(),
+                    ));
+                }
+                let sectors = u32::try_from(sectors)
+                    .map_err(|_| PartitionError::Unsupported("MBR disks over 2 TiB".to_owned()))?;
+                let offset = 446 + 16 * u64::from(partition.number - 1) + 12;
+                file.write_all_at(&sectors.to_le_bytes(), offset)?;
+                Ok(())
+            }
+            TableKind::Gpt => {
+                let last_lba = partition.start / self.sector_size + sectors - 1;
+                resize_gpt(&file, self.sector_size, partition.number, last_lba, len)
+            }
+        }
+    }
+}
+
+// A partition entry of a master boot record
+struct MbrEntry {
+    status: u8,
+    kind: u8,
+    start: u64,
+    sectors: u64,
+}
+
+impl MbrEntry {
+    fn parse(sector: &[u8], index: usize) -> MbrEntry {
+        let entry = &sector[446 + 16 * index..446 + 16 * (index + 1)];
+        MbrEntry {
+            status: entry[0],
+            kind: entry[4],
+            start: u64::from(le32(entry, 8)),
+            sectors: u64::from(le32(entry, 12)),
+        }
+    }
+
+    fn partition(&self, number: u32, start: u64, signature: u32) -> Partition {
+        Partition {
+            number,
+            start: start * SECTOR_SIZE,
+            size: self.sectors * SECTOR_SIZE,
+            type_id: mbr_type(self.kind),
+            type_name: MBR_TYPES
+                .iter()
+                .find(|(kind, _)| *kind == self.kind)
+                .map(|(_, name)| *name),
+            partuuid: format!("{signature:08x}-{number:02x}"),
+            name: None,
+            bootable: self.status == 0x80,
+        }
+    }
+}
+
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn mbr_type(kind: u8) -> String {
+    format!("{kind:#04x}")
+}
+
+fn invalid(message: &str) -> PartitionError {
+    PartitionError::Invalid(message.to_owned())
+}
+
+// Format a GUID stored in mixed endianness, as in GPT headers and entries.
+fn guid(bytes: &[u8]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{}",
+        le32(bytes, 0),
+        u16::from_le_bytes([bytes[4], bytes[5]]),
+        u16::from_le_bytes([bytes[6], bytes[7]]),
+        bytes[8],
+        bytes[9],
+        bytes[10..16]
+            .iter()
+            .map(|byte| format!("{byte:02X}"))
+            .collect::<String>()
+    )
+}
+
+fn read_mbr(
+    file: &File,
+    mbr: &[u8],
+    entries: &[MbrEntry],
+) -> Result<PartitionTable, PartitionError> {
+    let signature = le32(mbr, 440);
+    let mut partitions = Vec::new();
+    let mut extended = None;
+    for (number, entry) in (1..).zip(entries) {
+        if entry.kind == 0 {
+            continue;
+        }
+        if MBR_EXTENDED.contains(&entry.kind) {
+            extended = Some(entry.start);
+        }
+        partitions.push(entry.partition(number, entry.start, signature));
+    }
+
+    // Logical partitions are chained in the extended partition, each after its own boot record
+    if let Some(extended_start) = extended {
+        let mut ebr_start = extended_start;
+        let mut sector = [0; SECTOR_SIZE as usize];
+        for number in 5..5 + MAX_LOGICAL {
+            file.read_exact_at(&mut sector, ebr_start * SECTOR_SIZE)?;
+            if sector[510..512] != [0x55, 0xaa] {
+                return Err(invalid("bad signature of extended boot record"));
+            }
+            let logical = MbrEntry::parse(&sector, 0);
+            if logical.kind != 0 {
+                partitions.push(logical.partition(number, ebr_start + logical.start, signature));
+            }
+            let next = MbrEntry::parse(&sector, 1);
+            if next.kind == 0 || next.start == 0 {
+                break;
+            }
+            ebr_start = extended_start + next.start;
+        }
+    }
+
+    Ok(PartitionTable {
+        kind: TableKind::Mbr,
+        disk_id: format!("{signature:08x}"),
+        sector_size: SECTOR_SIZE,
+        partitions,
+    })
+}
+
+fn read_gpt(file: &File, len: u64) -> Result<PartitionTable, PartitionError> {
+    // The header is in the second logical block, whose size is not recorded anywhere
+    let mut last_error = invalid("no GPT header");
+    for block_size in [SECTOR_SIZE, 4096] {
+        if len < 2 * block_size {
+            break;
+        }
+        let primary = read_gpt_header(file, block_size, 1);
+        let header = match primary {
+            Ok(header) => Ok(header),
+            // Fall back to the backup header, in the last block
+            Err(_) => read_gpt_header(file, block_size, len / block_size - 1),
+        };
+        match header.and_then(|header| read_gpt_entries(file, block_size, &header, len)) {
+            Ok(table) => return Ok(table),
+            Err(err) => last_error = err,
+        }
+    }
+    Err(last_error)
+}
+
+struct GptHeader {
+    disk_guid: String,
+    entries_lba: u64,
+    entries: u64,
+    entry_size: u64,
+    entries_crc: u32,
+}
+
+fn read_gpt_header(file: &File, block_size: u64, lba: u64) -> Result<GptHeader, PartitionError> {
+    let mut block = vec![0; block_size as usize];
+    file.read_exact_at(&mut block, lba * block_size)?;
+    if &block[..8] != b"EFI PART" {
+        return Err(invalid("bad GPT header signature"));
+    }
+    let header_size = le32(&block, 12) as usize;
+    if !(92..=block.len()).contains(&header_size) {
+        return Err(invalid("bad GPT header size"));
+    }
+    let crc = le32(&block, 16);
+    block[16..20].fill(0);
+    if crc32fast::hash(&block[..header_size]) != crc {
+        return Err(invalid("bad GPT header checksum"));
+    }
+
+    let entries = u64::from(le32(&block, 80));
+    let entry_size = u64::from(le32(&block, 84));
+    if entries * entry_size > MAX_GPT_ENTRIES_LEN || !(128..=4096).contains(&entry_size) {
+        return Err(invalid("bad GPT partition entry array"));
+    }
+    Ok(GptHeader {
+        disk_guid: guid(&block[56..72]),
+        entries_lba: le64(&block, 72),
+        entries,
+        entry_size,
+        entries_crc: le32(&block, 88),
+    })
+}
+
+// Offset of the logical block `lba`.
+fn block_offset(lba: u64, block_size: u64) -> Result<u64, PartitionError> {
+    lba.checked_mul(block_size)
+        .ok_or_else(|| invalid(&format!("block {lba} past the end of the disk")))
+}
+
+fn read_gpt_entries(
+    file: &File,
+    block_size: u64,
+    header: &GptHeader,
+    len: u64,
+) -> Result<PartitionTable, PartitionError> {
+    let array_len = header.entries * header.entry_size;
+    let array_offset = block_offset(header.entries_lba, block_size)?;
+    if array_offset
+        .checked_add(array_len)
+        .is_none_or(|end| end > len)
+    {
+        return Err(invalid(
+            "GPT partition entry array past the end of the image",
+        ));
+    }
+    let mut array = vec![0; array_len as usize];
+    file.read_exact_at(&mut array, array_offset)?;
+    if crc32fast::hash(&array) != header.entries_crc {
+        return Err(invalid("bad GPT partition entries checksum"));
+    }
+
+    let mut partitions = Vec::new();
+    for (number, entry) in (1..).zip(array.chunks_exact(header.entry_size as usize)) {
+        if entry[..16].iter().all(|&byte| byte == 0) {
+            continue;
+        }
+        let type_id = guid(&entry[..16]);
+        let (first, last) = (le64(entry, 32), le64(entry, 40));
+        let end = last
+            .checked_add(1)
+            .ok_or_else(|| invalid("GPT partition past the end of the disk"))?;
+        let name: Vec<u16> = entry[56..128]
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .take_while(|&unit| unit != 0)
+            .collect();
+        partitions.push(Partition {
+            number,
+            start: block_offset(first, block_size)?,
+            size: block_offset(end.saturating_sub(first), block_size)?,
+            type_name: GPT_TYPES
+                .iter()
+                .find(|(id, _)| *id == type_id)
+                .map(|(_, name)| *name),
+            type_id,
+            partuuid: guid(&entry[16..32]).to_lowercase(),
+            name: Some(String::from_utf16_lossy(&name)).filter(|name| !name.is_empty()),
+            // Legacy BIOS bootable attribute
+            bootable: le64(entry, 48) & (1 << 2) != 0,
+        });
+    }
+
+    Ok(PartitionTable {
+        kind: TableKind::Gpt,
+        disk_id: header.disk_guid.to_lowercase(),
+        sector_size: block_size,
+        partitions,
+    })
+}
+
+// Set the last block of the partition `number` to `last_lba`, and move the backup GPT to the end
+// of an image of `len` bytes.
+fn resize_gpt(
+    file: &File,
+    block_size: u64,
+    number: u32,
+    last_lba: u64,
+    len: u64,
+) -> Result<(), PartitionError> {
+    let old_blocks = file.metadata()?.len() / block_size;
+    let mut primary = vec![0; block_size as usize];
+    file.read_exact_at(&mut primary, block_size)?;
+    let header = read_gpt_header(file, block_size, 1)?;
+    let old_backup_lba = le64(&primary, 32);
+
+    let entries_len = header.entries * header.entry_size;
+    if entries_len > (GPT_BACKUP_BLOCKS - 1) * block_size {
+        return Err(PartitionError::Unsupported(format!(
+            "GPT partition entries of {entries_len} bytes"
+        )));
+    }
+    let entries_offset = block_offset(header.entries_lba, block_size)?;
+    let mut entries = vec![0; entries_len as usize];
+    file.read_exact_at(&mut entries, entries_offset)?;
+    let entry = (u64::from(number) - 1) * header.entry_size;
+    let entry = &mut entries[entry as usize..(entry + header.entry_size) as usize];
+    entry[40..48].copy_from_slice(&last_lba.to_le_bytes());
+    let entries_crc = crc32fast::hash(&entries);
+
+    let blocks = len / block_size;
+    let backup_lba = blocks - 1;
+    let backup_entries_lba = blocks - GPT_BACKUP_BLOCKS + 1;
+    let header_size = le32(&primary, 12) as usize;
+    let write_header = |header: &mut [u8], current: u64, alternate: u64, entries_lba: u64| {
+        header[24..32].copy_from_slice(&current.to_le_bytes());
+        header[32..40].copy_from_slice(&alternate.to_le_bytes());
+        header[48..56].copy_from_slice(&(blocks - GPT_BACKUP_BLOCKS - 1).to_le_bytes());
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        header[16..20].fill(0);
+        let crc = crc32fast::hash(&header[..header_size]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        file.write_all_at(header, current * block_size)
+    };
+
+    // Wipe the old backup header, unless it is cut off
+    if old_backup_lba < blocks.min(old_blocks) {
+        file.write_all_at(&vec![0; block_size as usize], old_backup_lba * block_size)?;
+    }
+    file.write_all_at(&entries, entries_offset)?;
+    file.write_all_at(&entries, backup_entries_lba * block_size)?;
+    let mut backup = primary.clone();
+    write_header(&mut primary, 1, backup_lba, header.entries_lba)?;
+    write_header(&mut backup, backup_lba, 1, backup_entries_lba)?;
+
+    // The protective MBR covers the whole disk, up to 2 TiB
+    let protective = u32::try_from(blocks - 1).unwrap_or(u32::MAX);
+    let index = (0..4)
+        .find(|&index| {
+            let mut kind = [0];
+            file.read_exact_at(&mut kind, 446 + 16 * index + 4).is_ok() && kind[0] == MBR_PROTECTIVE
+        })
+        .ok_or_else(|| invalid("no protective MBR"))?;
+    file.write_all_at(&protective.to_le_bytes(), 446 + 16 * index + 12)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Images are 1 MiB, i.e. 2048 sectors
+    const IMAGE_SECTORS: u64 = 2048;
+    const DISK_GUID: &str = "6A1D3C33-4F0E-4B8E-9C55-6E4D3B2A1F00";
+    const PART_GUID: &str = "0D2E4C1B-7A39-4F6B-8E12-53C7A9B0D4E1";
+
+    fn put(image: &mut [u8], offset: u64, bytes: &[u8]) {
+        let offset = offset as usize;
+        image[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn put_mbr_entry(sector: &mut [u8], index: u64, status: u8, kind: u8, start: u32, len: u32) {
+        let entry = 446 + 16 * index;
+        put(sector, entry, &[status]);
+        put(sector, entry + 4, &[kind]);
+        put(sector, entry + 8, &start.to_le_bytes());
+        put(sector, entry + 12, &len.to_le_bytes());
+        put(sector, 510, &[0x55, 0xaa]);
+    }
+
+    // Inverse of `guid`.
+    fn guid_bytes(guid: &str) -> [u8; 16] {
+        let hex: String = guid.chars().filter(|c| *c != '-').collect();
+        let mut bytes: Vec<u8> = (0..16)
+            .map(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap())
+            .collect();
+        bytes[..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+        bytes.try_into().unwrap()
+    }
+
+    // A GPT disk with a partition entry array at `entries_lba`, and a Linux partition spanning
+    // the blocks `first` to `last`.
+    fn gpt(entries_lba: u64, first: u64, last: u64) -> Vec<u8> {
+        let mut image = vec![0; (IMAGE_SECTORS * SECTOR_SIZE) as usize];
+        put_mbr_entry(
+            &mut image,
+            0,
+            0,
+            MBR_PROTECTIVE,
+            1,
+            IMAGE_SECTORS as u32 - 1,
+        );
+
+        let mut entries = vec![0; 128 * 128];
+        put(&mut entries, 0, &guid_bytes(GPT_LINUX));
+        put(&mut entries, 16, &guid_bytes(PART_GUID));
+        put(&mut entries, 32, &first.to_le_bytes());
+        put(&mut entries, 40, &last.to_le_bytes());
+        put(
+            &mut entries,
+            56,
+            &"root"
+                .encode_utf16()
+                .flat_map(u16::to_le_bytes)
+                .collect::<Vec<_>>(),
+        );
+        put(&mut image, 2 * SECTOR_SIZE, &entries);
+
+        let mut header = vec![0; 92];
+        put(&mut header, 0, b"EFI PART");
+        put(&mut header, 8, &0x0001_0000u32.to_le_bytes());
+        put(&mut header, 12, &92u32.to_le_bytes());
+        put(&mut header, 24, &1u64.to_le_bytes());
+        put(&mut header, 32, &(IMAGE_SECTORS - 1).to_le_bytes());
+        put(&mut header, 40, &34u64.to_le_bytes());
+        put(&mut header, 48, &(IMAGE_SECTORS - 34).to_le_bytes());
+        put(&mut header, 56, &guid_bytes(DISK_GUID));
+        put(&mut header, 72, &entries_lba.to_le_bytes());
+        put(&mut header, 80, &128u32.to_le_bytes());
+        put(&mut header, 84, &128u32.to_le_bytes());
+        put(&mut header, 88, &crc32fast::hash(&entries).to_le_bytes());
+        let crc = crc32fast::hash(&header);
+        put(&mut header, 16, &crc.to_le_bytes());
+        put(&mut image, SECTOR_SIZE, &header);
+        image
+    }
+
+    fn read(image: &[u8]) -> Result<Option<PartitionTable>, PartitionError> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.img");
+        std::fs::write(&path, image).unwrap();
+        PartitionTable::read(path)
+    }
+
+    #[test]
+    fn mbr_with_logical_partitions() {
+        let mut image = vec![0; (IMAGE_SECTORS * SECTOR_SIZE) as usize];
+        put(&mut image, 440, &0x1234_5678u32.to_le_bytes());
+        put_mbr_entry(&mut image, 0, 0x80, MBR_LINUX, 64, 1024);
+        put_mbr_entry(&mut image, 1, 0, 0x05, 1088, 960);
+        // Logical partitions, each after its extended boot record
+        let ebr = &mut image[1088 * 512..];
+        put_mbr_entry(ebr, 0, 0, MBR_LINUX, 32, 100);
+        put_mbr_entry(ebr, 1, 0, 0x05, 200, 300);
+        let ebr = &mut image[(1088 + 200) * 512..];
+        put_mbr_entry(ebr, 0, 0, 0x82, 32, 200);
+
+        let table = read(&image).unwrap().unwrap();
+        assert_eq!(table.kind, TableKind::Mbr);
+        assert_eq!(table.disk_id, "12345678");
+        let partitions: Vec<_> = table
+            .partitions
+            .iter()
+            .map(|p| (p.number, p.start / 512, p.size / 512, p.type_id.as_str()))
+            .collect();
+        assert_eq!(
+            partitions,
+            [
+                (1, 64, 1024, "0x83"),
+                (2, 1088, 960, "0x05"),
+                (5, 1120, 100, "0x83"),
+                (6, 1320, 200, "0x82"),
+            ]
+        );
+        let root = table.root_partition().unwrap();
+        assert_eq!((root.number, root.bootable), (1, true));
+        assert_eq!(root.partuuid, "12345678-01");
+        assert_eq!(table.last_partition().unwrap().number, 6);
+    }
+
+    #[test]
+    fn mbr_of_filesystem() {
+        let mut image = vec![0; (IMAGE_SECTORS * SECTOR_SIZE) as usize];
+        assert!(read(&image).unwrap().is_none());
+
+        // Boot code where partition entries would be
+        put_mbr_entry(&mut image, 0, 0x12, 0x34, 0x5678, 0x9abc);
+        assert!(read(&image).unwrap().is_none());
+
+        // A partition past the end of the image
+        put_mbr_entry(&mut image, 0, 0, MBR_LINUX, u32::MAX, u32::MAX);
+        assert!(read(&image).unwrap().is_none());
+    }
+
+    #[test]
+    fn gpt_partitions() {
+        let table = read(&gpt(2, 34, IMAGE_SECTORS - 35)).unwrap().unwrap();
+        assert_eq!(table.kind, TableKind::Gpt);
+        assert_eq!(table.disk_id, DISK_GUID.to_lowercase());
+        assert_eq!(table.sector_size, 512);
+
+        let root = table.root_partition().unwrap();
+        assert_eq!(root.number, 1);
+        assert_eq!(root.partuuid, PART_GUID.to_lowercase());
+        assert_eq!(root.type_name, Some("Linux filesystem"));
+        assert_eq!(root.name.as_deref(), Some("root"));
+        assert_eq!((root.start, root.size), (34 * 512, 1980 * 512));
+    }
+
+    #[test]
+    fn gpt_out_of_range() {
+        for image in [
+            // Partition entries past the end of the image, or of any disk
+            gpt(IMAGE_SECTORS - 1, 34, 100),
+            gpt(1 << 62, 34, 100),
+            gpt(u64::MAX, 34, 100),
+            // Partitions past the end of any disk
+            gpt(2, 1 << 62, 1 << 62),
+            gpt(2, 34, u64::MAX),
+        ] {
+            assert!(matches!(read(&image), Err(PartitionError::Invalid(_))));
+        }
+    }
+
+    #[test]
+    fn gpt_with_huge_entry_array() {
+        let mut image = gpt(2, 34, 100);
+        // 2^32 - 1 entries of 128 bytes, behind a valid header checksum
+        put(&mut image, SECTOR_SIZE + 80, &u32::MAX.to_le_bytes());
+        let mut header = image[SECTOR_SIZE as usize..SECTOR_SIZE as usize + 92].to_vec();
+        header[16..20].fill(0);
+        let crc = crc32fast::hash(&header);
+        put(&mut image, SECTOR_SIZE + 16, &crc.to_le_bytes());
+        assert!(matches!(read(&image), Err(PartitionError::Invalid(_))));
+    }
+}
@@ -0,0 +1,84 @@
+//! Timeouts and retries for Firecracker API requests
+//!
+//! Every request of an [`ApiClient`] follows a [`RequestPolicy`]. The default policy waits
+//! forever and never retries. A client-wide policy can be set with [`ApiClient::set_policy`].
+//! [`ApiClient::with_policy`] returns a handle, sharing the same transport, that uses a different
+//! policy, so that a single call can override it, e.g.
+//! `client.with_policy(policy).instance_info().await`.
+//!
+//! Only requests that are safe to send twice are retried: GET requests, after any failure to get
+//! a response, and other requests when they failed to connect, i.e. before anything was sent.
+//! Error responses of Firecracker are never retried.
+//!
+//! Requests can also be cancelled by dropping their future, e.g. with `tokio::select!`.
+//!
+//! [`ApiClient`]: super::ApiClient
+//! [`ApiClient::set_policy`]: super::ApiClient::set_policy
+//! [`ApiClient::with_policy`]: super::ApiClient::with_policy
+
+use std::time::Duration;
+
+/// Timeouts and retries applied to Firecracker API requests
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestPolicy {
+    /// Maximum time for connecting to the API server. `None` waits forever.
+    pub connect_timeout: Option<Duration>,
+    /// Maximum time for a single attempt of a request, connection included. `None` waits forever.
+    pub request_timeout: Option<Duration>,
+    /// Maximum time for a request, retries and backoff included. `None` waits forever.
+    pub deadline: Option<Duration>,
+    /// Number of times a failed request may be retried.
+    pub max_retries: u32,
+    /// Delay before the first retry. It doubles on every retry, up to `max_backoff`.
+    pub initial_backoff: Duration,
+    /// Maximum delay between two retries.
+    pub max_backoff: Duration,
+}
+
+impl Default for RequestPolicy {
+    fn default() -> Self {
+        RequestPolicy {
+            connect_timeout: None,
+            request_timeout: None,
+            deadline: None,
+            max_retries: 0,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RequestPolicy {
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn with_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    // Delay before retry number `retry` (starting from 0).
+    pub(crate) fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .checked_mul(2u32.saturating_pow(retry))
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
@@ -10,16 +10,16 @@ use serde_derive::{Deserialize, Serialize};
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TokenBucket {
     /// The initial size of a token bucket.
-    pub one_time_burst: Option<i64>,
+    pub one_time_burst: Option<u64>,
     /// The amount of milliseconds it takes for the bucket to refill.
-    pub refill_time: i64,
+    pub refill_time: u64,
     /// The total number of tokens this bucket can hold.
-    pub size: i64,
+    pub size: u64,
 }
 
 impl TokenBucket {
     /// Create a new [`TokenBucket`] without an one-time burst allowance
-    pub fn new(size: i64, refill_time: i64) -> TokenBucket {
+    pub fn new(size: u64, refill_time: u64) -> TokenBucket {
         TokenBucket {
             refill_time,
             size,
@@ -0,0 +1,306 @@
+//! Resizing of drive images
+//!
+//! The size of the backing file of a drive is the disk quota of a microVM. [`resize_image`] sets
+//! it, extending the file sparsely, and resizes the ext2/3/4 filesystem of the image with
+//! `resize2fs` to match. On partitioned images, the last partition, which must hold the
+//! filesystem, is resized first, and its filesystem is resized in a sparse copy of the partition.
+//! A copy of the image holding the resized partition then replaces the image. Images are never
+//! shrunk below the space used by their filesystem.
+//!
+//! The drive must not be in use: the filesystem is checked with `e2fsck` beforehand.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::process::Command;
+
+use super::drive::copy_data;
+use super::partition::{PartitionError, PartitionTable};
+use super::types::MiB;
+use crate::util::{self, command_error, find_tool, CommandError};
+
+// Offsets, in the superblock of ext filesystems, of the block size and the magic number
+const EXT_SUPERBLOCK: u64 = 1024;
+const EXT_LOG_BLOCK_SIZE: u64 = EXT_SUPERBLOCK + 24;
+const EXT_MAGIC_OFFSET: u64 = EXT_SUPERBLOCK + 56;
+const EXT_MAGIC: u16 = 0xef53;
+
+/// Errors of [`resize_image`]
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum ResizeError {
+    /// Cannot resize the image: {0}
+    Io(#[from] io::Error),
+    /// Cannot resize the partition of the image: {0}
+    Partition(#[from] PartitionError),
+    /// `{0}` failed: {1}
+    Command(String, String),
+    /// The image must be at least {minimum} bytes to hold its filesystem, {requested} requested
+    TooSmall { requested: u64, minimum: u64 },
+    /// Cannot resize {0}
+    Unsupported(String),
+}
+
+impl From<CommandError> for ResizeError {
+    fn from(err: CommandError) -> Self {
+        ResizeError::Command(err.program, err.message)
+    }
+}
+
+/// Resize the image at `path` to `size`, along with its filesystem
+///
+/// Growing an image extends the file with a hole, so that the new space is only allocated on the
+/// host when the guest writes to it. Shrinking it fails with [`ResizeError::TooSmall`] if the
+/// filesystem does not fit.
+pub fn resize_image<P: AsRef<Path>>(path: P, size: MiB) -> Result<(), ResizeError> {
+    let path = path.as_ref();
+    let len = File::open(path)?.metadata()?.len();
+    let new_len = size.bytes();
+
+    let table = PartitionTable::read(path)?;
+    let (offset, fs_len, fs_end) = match &table {
+        Some(table) => {
+            let partition = table
+                .last_partition()
+                .ok_or_else(|| PartitionError::Invalid("no partition".to_owned()))?;
+            (partition.start, partition.size, table.usable_end(new_len))
+        }
+        None => (0, len, new_len),
+    };
+    let block_size = ext_block_size(path, offset)?;
+    let device = match offset {
+        0 => path.display().to_string(),
+        offset => format!("{}?offset={offset}", path.display()),
+    };
+    // resize2fs takes sizes in KiB, which are whole blocks of the smallest ext block size
+    let fs_size = fs_end.saturating_sub(offset) / block_size * block_size;
+
+    // Fix the filesystem if needed, as resize2fs requires a clean one
+    let output = util::output(Command::new(find_tool("e2fsck")).args(["-f", "-p", &device]))?;
+    if output.status.code().is_none_or(|code| code > 1) {
+        return Err(command_error("e2fsck", &output).into());
+    }
+
+    if new_len < len {
+        let minimum = min_fs_size(&device)? * block_size;
+        if fs_size < minimum {
+            return Err(ResizeError::TooSmall {
+                requested: new_len,
+                minimum: new_len - fs_size + minimum,
+            });
+        }
+        resize_fs(path, offset, fs_len, fs_size)?;
+        if let Some(table) = &table {
+            table.resize_last_partition(path, new_len)?;
+        }
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(new_len)?;
+    } else {
+        // Writing the backup GPT extends the file
+        if let Some(table) = &table {
+            table.resize_last_partition(path, new_len)?;
+        }
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(new_len)?;
+        resize_fs(path, offset, fs_len, fs_size)?;
+    }
+    Ok(())
+}
+
+// Block size of the ext filesystem at `offset` of the image at `path`
+fn ext_block_size(path: &Path, offset: u64) -> Result<u64, ResizeError> {
+    let file = File::open(path)?;
+    let mut magic = [0; 2];
+    let mut log_block_size = [0; 4];
+    let read = file
+        .read_exact_at(&mut magic, offset + EXT_MAGIC_OFFSET)
+        .and_then(|()| file.read_exact_at(&mut log_block_size, offset + EXT_LOG_BLOCK_SIZE));
+    match read {
+        Ok(()) if u16::from_le_bytes(magic) == EXT_MAGIC => {}
+        Err(err) if err.kind() != io::ErrorKind::UnexpectedEof => return Err(err.into()),
+        _ => {
+            return Err(ResizeError::Unsupported(
+                "filesystems other than ext2, ext3 and ext4".to_owned(),
+            ))
+        }
+    }
+    match u32::from_le_bytes(log_block_size) {
+        log @ 0..=6 => Ok(1024 << log),
+        log => Err(ResizeError::Unsupported(format!(
+            "ext filesystems with blocks of 2^{} bytes",
+            log + 10
+        ))),
+    }
+}
+
+// Minimum size of the filesystem on `device`, in blocks
+fn min_fs_size(device: &str) -> Result<u64, ResizeError> {
+    let output = util::run(Command::new(find_tool("resize2fs")).args(["-P", device]))?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| {
+            line.strip_prefix("Estimated minimum size of the filesystem:")?
+                .trim()
+                .parse()
+                .ok()
+        })
+        .ok_or_else(|| {
+            ResizeError::Command(
+                "resize2fs".to_owned(),
+                "no estimated minimum size".to_owned(),
+            )
+        })
+}
+
+// Resize the filesystem of `len` bytes at `offset` of the image at `path` to `size` bytes.
+fn resize_fs(path: &Path, offset: u64, len: u64, size: u64) -> Result<(), ResizeError> {
+    if offset == 0 {
+        return resize2fs(&path.display().to_string(), size);
+    }
+    // resize2fs truncates regular files to the size of the filesystem, regardless of its offset:
+    // resize a sparse copy of the partition instead, next to the image. The image is then
+    // replaced with a sparse copy holding the resized partition, so that it is left untouched if
+    // anything fails.
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let image = File::open(path)?;
+    let metadata = image.metadata()?;
+    let partition = tempfile::NamedTempFile::new_in(dir)?;
+    copy_data(&image, offset, partition.as_file(), 0, len)?;
+    partition.as_file().set_len(len)?;
+    resize2fs(&partition.path().display().to_string(), size)?;
+
+    let resized = tempfile::NamedTempFile::new_in(dir)?;
+    let file = resized.as_file();
+    copy_data(&image, 0, file, 0, offset)?;
+    copy_data(partition.as_file(), 0, file, offset, size)?;
+    // The partition is the last one, only the backup GPT may follow it
+    let tail = offset + len.max(size);
+    copy_data(
+        &image,
+        tail,
+        file,
+        tail,
+        metadata.len().saturating_sub(tail),
+    )?;
+    file.set_len(metadata.len())?;
+    file.set_permissions(metadata.permissions())?;
+    file.sync_all()?;
+    resized.persist(path).map_err(|err| err.error)?;
+    Ok(())
+}
+
+// Resize the filesystem on `device` to `size` bytes.
+fn resize2fs(device: &str, size: u64) -> Result<(), ResizeError> {
+    let size = format!("{}K", size / 1024);
+    util::run(Command::new(find_tool("resize2fs")).args([device, &size]))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    const MIB: u64 = 1 << 20;
+
+    // Make an ext4 filesystem of `len` bytes at `path`, holding a file `/hello`.
+    fn mkfs(path: &Path, len: u64) {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("hello"), "world").unwrap();
+        File::create(path).unwrap().set_len(len).unwrap();
+        let output = Command::new(find_tool("mkfs.ext4"))
+            .args(["-q", "-F", "-d"])
+            .arg(root.path())
+            .arg(path)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+    }
+
+    // Check the filesystem on `device`, and return the content of its file `/hello`.
+    fn hello(device: &str) -> String {
+        let output = Command::new(find_tool("e2fsck"))
+            .args(["-f", "-n", device])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        let output = Command::new(find_tool("debugfs"))
+            .args(["-R", "cat /hello", device])
+            .output()
+            .unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    // Size, in bytes, of the ext filesystem at `offset` of the image at `path`.
+    fn fs_len(path: &Path, offset: u64) -> u64 {
+        let mut blocks = [0; 4];
+        File::open(path)
+            .unwrap()
+            .read_exact_at(&mut blocks, offset + EXT_SUPERBLOCK + 4)
+            .unwrap();
+        u64::from(u32::from_le_bytes(blocks)) * ext_block_size(path, offset).unwrap()
+    }
+
+    #[test]
+    fn grow() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rootfs.ext4");
+        mkfs(&path, 8 * MIB);
+
+        resize_image(&path, MiB::new(16)).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 16 * MIB);
+        assert_eq!(fs_len(&path, 0), 16 * MIB);
+        assert_eq!(hello(&path.display().to_string()), "world");
+    }
+
+    #[test]
+    fn grow_partition() {
+        // An MBR disk with a Linux partition from 1 MiB to the end of the image
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.img");
+        let fs_path = dir.path().join("rootfs.ext4");
+        mkfs(&fs_path, 7 * MIB);
+        let mut mbr = [0; 512];
+        mbr[446 + 4] = 0x83;
+        mbr[446 + 8..446 + 12].copy_from_slice(&2048u32.to_le_bytes());
+        mbr[446 + 12..446 + 16].copy_from_slice(&(7 * 2048u32).to_le_bytes());
+        mbr[510..].copy_from_slice(&[0x55, 0xaa]);
+        let image = File::create(&path).unwrap();
+        image.write_all_at(&mbr, 0).unwrap();
+        copy_data(&File::open(&fs_path).unwrap(), 0, &image, MIB, 7 * MIB).unwrap();
+        image.set_len(8 * MIB).unwrap();
+
+        resize_image(&path, MiB::new(16)).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 16 * MIB);
+        let table = PartitionTable::read(&path).unwrap().unwrap();
+        assert_eq!(table.last_partition().unwrap().size, 15 * MIB);
+        assert_eq!(fs_len(&path, MIB), 15 * MIB);
+        assert_eq!(hello(&format!("{}?offset={MIB}", path.display())), "world");
+        // Only the image is left in the directory
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn shrink_below_used_space() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rootfs.ext4");
+        mkfs(&path, 8 * MIB);
+
+        let err = resize_image(&path, MiB::new(1)).unwrap_err();
+        assert!(
+            matches!(err, ResizeError::TooSmall { requested, minimum } if requested == MIB && minimum > MIB),
+            "{err}"
+        );
+        assert_eq!(fs::metadata(&path).unwrap().len(), 8 * MIB);
+        assert_eq!(fs_len(&path, 0), 8 * MIB);
+        assert_eq!(hello(&path.display().to_string()), "world");
+    }
+}
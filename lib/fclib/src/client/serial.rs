@@ -0,0 +1,25 @@
+//! SerialConfig : Describes the configuration of the serial console.
+
+#[cfg(feature = "clap")]
+use clap::Args;
+use serde_derive::{Deserialize, Serialize};
+
+use super::{ApiClient, Result};
+
+#[cfg_attr(feature = "clap", derive(Args))]
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SerialConfig {
+    /// Path to the named pipe or file the output of the serial console is written to. By default,
+    /// it is written to the standard output of Firecracker.
+    pub serial_out_path: Option<String>,
+}
+
+impl ApiClient {
+    /// Configure the serial console of the microVM.
+    ///
+    /// This operation is only allowed before the VM has been booted. Requires Firecracker 1.14 or
+    /// newer.
+    pub async fn configure_serial(&self, serial: &SerialConfig) -> Result<()> {
+        self.put("/serial", serial).await
+    }
+}
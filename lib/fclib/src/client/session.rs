@@ -0,0 +1,260 @@
+//! Recording and replaying Firecracker API sessions
+//!
+//! An [`ApiClient`] can record every request it sends, along with the response it got, its
+//! timestamp and its latency, to a session file (see [`ApiClient::record_to`]). The session file
+//! holds one JSON-encoded [`Exchange`] per line.
+//!
+//! A [`ReplayServer`] serves the responses of a recorded session back on a Unix socket, as if it
+//! was the API server of the Firecracker process that was recorded. This allows reproducing a
+//! failed boot sequence locally, with `fc-ctl` or library code, and without KVM.
+//!
+//! [`ApiClient`]: super::ApiClient
+//! [`ApiClient::record_to`]: super::ApiClient::record_to
+
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use log::{debug, warn};
+use tokio::net::UnixListener;
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinSet;
+
+use super::transport::{ApiRequest, Exchange, Method, Replay};
+
+/// Read the exchanges recorded in the session file at `path`
+pub fn read_session<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<Exchange>> {
+    let file = std::fs::File::open(path)?;
+    let mut exchanges = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        exchanges.push(serde_json::from_str(&line)?);
+    }
+
+    Ok(exchanges)
+}
+
+/// Server replaying a recorded session on a Unix socket
+///
+/// Requests must arrive in the order they were recorded. A request that does not match the next
+/// recorded one gets a `400 Bad Request` response with a Firecracker-like `fault_message`. If no
+/// response was recorded for a request, e.g. because Firecracker was not reachable, the
+/// connection is closed without a response.
+#[derive(Debug)]
+pub struct ReplayServer {
+    path: PathBuf,
+    listener: UnixListener,
+    replay: Arc<Replay>,
+    preserve_latency: bool,
+}
+
+impl ReplayServer {
+    /// Create a new [`ReplayServer`] listening on the Unix socket at `path`
+    ///
+    /// Must be called from within a Tokio runtime.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Filesystem path of the Unix socket. It must not exist.
+    /// * `exchanges` - The recorded session to replay.
+    pub fn bind<P, I>(path: P, exchanges: I) -> std::io::Result<ReplayServer>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = Exchange>,
+    {
+        Ok(ReplayServer {
+            listener: UnixListener::bind(&path)?,
+            path: path.as_ref().to_path_buf(),
+            replay: Arc::new(Replay::new(exchanges)),
+            preserve_latency: false,
+        })
+    }
+
+    /// Delay every response by the latency recorded for it
+    pub fn preserve_latency(mut self) -> Self {
+        self.preserve_latency = true;
+        self
+    }
+
+    /// Serve requests until all the recorded exchanges have been replayed.
+    ///
+    /// Open connections are closed once their last response is written, and the Unix socket is
+    /// removed.
+    pub async fn serve(self) -> std::io::Result<()> {
+        let done = Arc::new(Notify::new());
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let mut connections = JoinSet::new();
+
+        while self.replay.remaining() > 0 {
+            let stream = tokio::select! {
+                accepted = self.listener.accept() => accepted?.0,
+                _ = done.notified() => continue,
+            };
+
+            let replay = self.replay.clone();
+            let done = done.clone();
+            let preserve_latency = self.preserve_latency;
+            let service = service_fn(move |request| {
+                let replay = replay.clone();
+                let done = done.clone();
+                async move {
+                    let response = replay_one(&replay, request, preserve_latency).await;
+                    if replay.remaining() == 0 {
+                        done.notify_one();
+                    }
+                    response
+                }
+            });
+
+            let mut shutdown_rx = shutdown_rx.clone();
+            connections.spawn(async move {
+                let connection =
+                    http1::Builder::new().serve_connection(TokioIo::new(stream), service);
+                tokio::pin!(connection);
+                let result = tokio::select! {
+                    result = connection.as_mut() => result,
+                    _ = async { shutdown_rx.wait_for(|shutdown| *shutdown).await.is_ok() } => {
+                        // Finish writing the response in flight, if any, then close
+                        connection.as_mut().graceful_shutdown();
+                        connection.await
+                    }
+                };
+                if let Err(err) = result {
+                    debug!("Replay connection closed: {err}");
+                }
+            });
+        }
+
+        let _ = shutdown.send(true);
+        while connections.join_next().await.is_some() {}
+        std::fs::remove_file(&self.path)
+    }
+}
+
+fn fault(status: StatusCode, message: String) -> Response<Full<Bytes>> {
+    let body = serde_json::json!({ "fault_message": message }).to_string();
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Full::new(Bytes::from(body)))
+        .unwrap()
+}
+
+// Answers `request` with the next recorded response.
+async fn replay_one(
+    replay: &Replay,
+    request: Request<hyper::body::Incoming>,
+    preserve_latency: bool,
+) -> std::io::Result<Response<Full<Bytes>>> {
+    let method = match request.method().as_str() {
+        "GET" => Method::Get,
+        "PUT" => Method::Put,
+        "PATCH" => Method::Patch,
+        other => {
+            let message = format!("Invalid request method: {other}");
+            return Ok(fault(StatusCode::METHOD_NOT_ALLOWED, message));
+        }
+    };
+    let path = request.uri().path().to_owned();
+    let body = request
+        .into_body()
+        .collect()
+        .await
+        .map_err(std::io::Error::other)?
+        .to_bytes();
+    let body = if body.is_empty() {
+        None
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(body) => Some(body),
+            Err(err) => return Ok(fault(StatusCode::BAD_REQUEST, err.to_string())),
+        }
+    };
+
+    let request = ApiRequest::new(method, path, body);
+    let exchange = match replay.next(&request) {
+        Ok(exchange) => exchange,
+        Err(err) => {
+            warn!("Replay mismatch: {err}");
+            return Ok(fault(StatusCode::BAD_REQUEST, err.to_string()));
+        }
+    };
+
+    if preserve_latency {
+        tokio::time::sleep(Duration::from_micros(exchange.latency_us)).await;
+    }
+
+    let Some(response) = exchange.response else {
+        let error = exchange.error.unwrap_or_default();
+        let message = format!("recorded error for {request}: {error}");
+        return Err(std::io::Error::other(message));
+    };
+
+    let status = StatusCode::from_u16(response.status).map_err(std::io::Error::other)?;
+    let mut builder = Response::builder().status(status);
+    if !response.body.is_empty() {
+        builder = builder.header("Content-Type", "application/json");
+    }
+    builder
+        .body(Full::new(Bytes::from(response.body)))
+        .map_err(std::io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::transport::{ApiResponse, Recorder, Transport, UnixSocket};
+
+    fn exchange(path: &str, body: &str) -> Exchange {
+        Exchange {
+            timestamp: String::new(),
+            request: ApiRequest::new(Method::Get, path, None),
+            response: Some(ApiResponse::new(200, body)),
+            error: None,
+            latency_us: 1000,
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_and_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("api.sock");
+        let session = dir.path().join("session.jsonl");
+        let exchanges = vec![
+            exchange("/", r#"{"state":"Running"}"#),
+            exchange("/machine-config", r#"{"vcpu_count":2}"#),
+        ];
+        let server = ReplayServer::bind(&socket, exchanges.clone())
+            .unwrap()
+            .preserve_latency();
+        let server = tokio::spawn(server.serve());
+
+        // The connection is kept alive by the client, and closed by the server once done
+        let recorder = Recorder::new(UnixSocket::new(&socket))
+            .record_to(&session)
+            .unwrap();
+        for exchange in &exchanges {
+            let response = recorder.send(&exchange.request).await.unwrap();
+            assert_eq!(Some(response), exchange.response);
+        }
+        server.await.unwrap().unwrap();
+        assert!(!socket.exists());
+
+        // Exchanges recorded to a session file are not kept in memory
+        assert!(recorder.exchanges().is_empty());
+        let recorded = read_session(&session).unwrap();
+        let requests: Vec<_> = recorded.iter().map(|e| &e.request).collect();
+        assert_eq!(requests, [&exchanges[0].request, &exchanges[1].request]);
+    }
+}
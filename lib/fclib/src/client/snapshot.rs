@@ -24,6 +24,12 @@ pub struct SnapshotCreateParams {
     /// Type of snapshot to create. It is optional and by default, a full snapshot is created.
     #[cfg_attr(feature = "clap", arg(long, short, default_value = "full"))]
     pub snapshot_type: SnapshotType,
+
+    /// Version of Firecracker that will load the snapshot, e.g. `1.2.0`. Only supported before
+    /// Firecracker 1.5. By default, the snapshot targets the running version.
+    #[cfg_attr(feature = "clap", arg(long = "target-version"))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
 }
 
 impl SnapshotCreateParams {
@@ -32,12 +38,13 @@ impl SnapshotCreateParams {
             mem_file_path,
             snapshot_path,
             snapshot_type: SnapshotType::default(),
+            version: None,
         }
     }
 }
 
-#[cfg_attr(feature = "clap", derive(ValueEnum, Clone))]
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "clap", derive(ValueEnum))]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub enum MemoryBackendType {
     #[default]
     File,
@@ -45,7 +52,7 @@ pub enum MemoryBackendType {
 }
 
 #[cfg_attr(feature = "clap", derive(Args))]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MemoryBackend {
     pub backend_type: MemoryBackendType,
     /// Based on 'backend_type' it is either 1) Path to the file that contains the guest memory to
@@ -55,10 +62,38 @@ pub struct MemoryBackend {
     pub backend_path: String,
 }
 
+/// Host device to use for a network interface of a restored microVM
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkOverride {
+    /// Id of the network interface in the snapshot.
+    pub iface_id: String,
+    /// Name of the host TAP device to use instead of the one in the snapshot.
+    pub host_dev_name: String,
+}
+
+impl std::str::FromStr for NetworkOverride {
+    type Err = String;
+
+    /// Parse an override formatted as `IFACE_ID=HOST_DEV_NAME`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((iface_id, host_dev_name))
+                if !iface_id.is_empty() && !host_dev_name.is_empty() =>
+            {
+                Ok(NetworkOverride {
+                    iface_id: iface_id.to_owned(),
+                    host_dev_name: host_dev_name.to_owned(),
+                })
+            }
+            _ => Err(format!("expected IFACE_ID=HOST_DEV_NAME, got `{s}`")),
+        }
+    }
+}
+
 /// SnapshotLoadParams : Defines the configuration used for handling snapshot resume. Exactly
 /// one of the two `mem_*` fields must be present in the body of the request.
 #[cfg_attr(feature = "clap", derive(Args))]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SnapshotLoadParams {
     /// Path to the file that contains the microVM state to be loaded.
     pub snapshot_path: String,
@@ -76,6 +111,21 @@ pub struct SnapshotLoadParams {
     /// Enable support for incremental (diff) snapshots by tracking dirty guest pages.
     #[cfg_attr(feature = "clap", arg(long, short, required = false))]
     pub enable_diff_snapshots: bool,
+
+    /// Track dirty guest pages, so that diff snapshots can be taken. Replaces
+    /// `enable_diff_snapshots` since Firecracker 1.13, either can be used with any version.
+    #[cfg_attr(feature = "clap", arg(long))]
+    #[serde(default)]
+    pub track_dirty_pages: bool,
+
+    /// Host TAP devices to use for the network interfaces of the restored microVM. Requires
+    /// Firecracker 1.12 or newer.
+    #[cfg_attr(
+        feature = "clap",
+        arg(long = "network-override", value_name = "IFACE_ID=HOST_DEV_NAME")
+    )]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub network_overrides: Vec<NetworkOverride>,
 }
 
 impl SnapshotLoadParams {
@@ -87,6 +137,8 @@ impl SnapshotLoadParams {
             mem_backend,
             snapshot_path,
             resume_vm: false,
+            track_dirty_pages: false,
+            network_overrides: Vec::new(),
         }
     }
 }
@@ -98,6 +150,12 @@ impl ApiClient {
     }
 
     /// Resume a VM from a snapshot.
+    ///
+    /// Since Firecracker 1.8, the VM generation ID of the guest changes on every snapshot load,
+    /// so that guests with a VMGenID driver can reseed their random number generators. Use
+    /// [`ApiClient::supports`] with [`Feature::VmGenId`] to find out whether Firecracker does it.
+    ///
+    /// [`Feature::VmGenId`]: super::version::Feature::VmGenId
     pub async fn load_microvm_snapshot(&self, body: &SnapshotLoadParams) -> Result<()> {
         self.put("/snapshot/load", body).await
     }
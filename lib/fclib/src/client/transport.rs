@@ -0,0 +1,495 @@
+//! Transports carrying Firecracker API requests
+//!
+//! [`ApiClient`](super::ApiClient) does not talk HTTP directly. It turns every API call into an
+//! [`ApiRequest`] and hands it to a [`Transport`], which is responsible for delivering it and
+//! returning the [`ApiResponse`]. fclib provides transports for:
+//!
+//! * Firecracker's Unix socket ([`UnixSocket`]), the default one.
+//! * An HTTP proxy exposing the Firecracker API over TCP, e.g. `socat` ([`Tcp`]).
+//! * An in-process handler, useful for tests ([`InMemory`]).
+//! * Recording the requests and responses going through another transport ([`Recorder`]) and
+//!   serving recorded responses back ([`Replay`]).
+//! * Not sending anything at all ([`DryRun`]).
+//!
+//! Transports can be stacked. Middleware, such as adding authentication headers, tracing,
+//! retrying or collecting metrics, is a [`Transport`] that wraps another one, adjusts the request
+//! and forwards it to the inner transport.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::warn;
+use reqwest::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use reqwest::{Client, ClientBuilder};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{FcClientError, Result};
+
+/// HTTP method of a Firecracker API request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Method {
+    Get,
+    Put,
+    Patch,
+}
+
+impl std::fmt::Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Method::Get => write!(f, "GET"),
+            Method::Put => write!(f, "PUT"),
+            Method::Patch => write!(f, "PATCH"),
+        }
+    }
+}
+
+/// A request to the Firecracker API, as issued by [`ApiClient`](super::ApiClient)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApiRequest {
+    /// HTTP method of the request.
+    pub method: Method,
+    /// Path of the API endpoint, e.g. `/drives/rootfs`.
+    pub path: String,
+    /// Additional HTTP headers to send with the request.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<(String, String)>,
+    /// JSON body of the request, if any.
+    pub body: Option<Value>,
+    /// Maximum time for connecting to Firecracker, for transports that need to connect.
+    #[serde(skip)]
+    pub connect_timeout: Option<Duration>,
+}
+
+impl ApiRequest {
+    /// Create a new [`ApiRequest`] without any additional headers
+    pub fn new<P: Into<String>>(method: Method, path: P, body: Option<Value>) -> ApiRequest {
+        ApiRequest {
+            method,
+            path: path.into(),
+            headers: Vec::new(),
+            body,
+            connect_timeout: None,
+        }
+    }
+
+    /// Returns `true` if this is the request that boots the microVM.
+    pub fn is_instance_start(&self) -> bool {
+        self.method == Method::Put
+            && self.path == "/actions"
+            && self
+                .body
+                .as_ref()
+                .and_then(|body| body.get("action_type"))
+                .and_then(Value::as_str)
+                == Some("InstanceStart")
+    }
+}
+
+impl std::fmt::Display for ApiRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.method, self.path)?;
+        if let Some(body) = &self.body {
+            write!(f, " {body}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A response of the Firecracker API
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiResponse {
+    /// HTTP status code.
+    pub status: u16,
+    /// Raw body of the response. Empty if the response did not have one.
+    pub body: String,
+}
+
+impl ApiResponse {
+    /// Create a new [`ApiResponse`]
+    pub fn new<B: Into<String>>(status: u16, body: B) -> ApiResponse {
+        ApiResponse {
+            status,
+            body: body.into(),
+        }
+    }
+
+    /// Returns `true` if the status code is in the 2xx range.
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// Delivers requests to the Firecracker API
+#[async_trait::async_trait]
+pub trait Transport: std::fmt::Debug + Send + Sync {
+    /// Send `request` and wait for its response.
+    ///
+    /// An error must only be returned if no response was received. Error responses of
+    /// Firecracker are returned as an [`ApiResponse`] with the corresponding status code.
+    ///
+    /// Transports that need to connect to Firecracker should honour
+    /// [`ApiRequest::connect_timeout`] and report connection failures with an error for which
+    /// [`FcClientError::is_connect`] is `true`, so that they can be retried safely.
+    async fn send(&self, request: &ApiRequest) -> Result<ApiResponse>;
+}
+
+#[async_trait::async_trait]
+impl<T: Transport + ?Sized> Transport for Arc<T> {
+    async fn send(&self, request: &ApiRequest) -> Result<ApiResponse> {
+        (**self).send(request).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    async fn send(&self, request: &ApiRequest) -> Result<ApiResponse> {
+        (**self).send(request).await
+    }
+}
+
+// reqwest only supports setting the connect timeout on a [Client], so HTTP transports keep a
+// [Client] per connect timeout requested.
+#[derive(Debug, Clone)]
+struct ClientCache {
+    /// Unix socket to connect to, if any
+    unix_socket: Option<PathBuf>,
+    clients: Arc<Mutex<HashMap<Option<Duration>, Client>>>,
+}
+
+impl ClientCache {
+    fn new(unix_socket: Option<PathBuf>) -> ClientCache {
+        ClientCache {
+            unix_socket,
+            clients: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn get(&self, connect_timeout: Option<Duration>) -> Result<Client> {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.get(&connect_timeout) {
+            return Ok(client.clone());
+        }
+
+        let mut builder = ClientBuilder::new();
+        if let Some(path) = &self.unix_socket {
+            builder = builder.unix_socket(path.as_path());
+        }
+        if let Some(timeout) = connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+
+        let client = builder.build()?;
+        clients.insert(connect_timeout, client.clone());
+        Ok(client)
+    }
+}
+
+// Sends `request` using a reqwest [Client], prefixing its path with `base_url`.
+async fn send_http(
+    clients: &ClientCache,
+    base_url: &str,
+    request: &ApiRequest,
+) -> Result<ApiResponse> {
+    let client = clients.get(request.connect_timeout)?;
+    let url = format!("{base_url}{}", request.path);
+    let mut builder = match request.method {
+        Method::Get => client.get(url),
+        Method::Put => client.put(url),
+        Method::Patch => client.patch(url),
+    };
+
+    for (name, value) in &request.headers {
+        builder = builder.header(name, value);
+    }
+
+    if let Some(body) = &request.body {
+        let serialized = serde_json::to_string(body)?;
+        builder = builder
+            .header(CONTENT_TYPE, "application/json")
+            .header(CONTENT_LENGTH, serialized.len())
+            .body(serialized);
+    }
+
+    let response = builder
+        .send()
+        .await
+        .map_err(|err| match request.connect_timeout {
+            Some(timeout) if err.is_connect() && err.is_timeout() => {
+                FcClientError::ConnectTimeout(timeout)
+            }
+            _ => FcClientError::Client(err),
+        })?;
+    let status = response.status().as_u16();
+    let body = response.text().await?;
+    Ok(ApiResponse { status, body })
+}
+
+/// Transport speaking HTTP over the Unix socket of Firecracker's API server
+#[derive(Debug, Clone)]
+pub struct UnixSocket {
+    /// reqwest [Client]s that can speak on top of a UDS
+    clients: ClientCache,
+}
+
+impl UnixSocket {
+    /// Create a new [`UnixSocket`] transport
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Filesystem path to the Unix socket.
+    pub fn new<P: AsRef<Path>>(path: P) -> UnixSocket {
+        UnixSocket {
+            clients: ClientCache::new(Some(path.as_ref().to_path_buf())),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Transport for UnixSocket {
+    async fn send(&self, request: &ApiRequest) -> Result<ApiResponse> {
+        send_http(&self.clients, "http://localhost", request).await
+    }
+}
+
+/// Transport speaking HTTP over TCP, to a proxy that forwards requests to Firecracker's socket
+#[derive(Debug, Clone)]
+pub struct Tcp {
+    clients: ClientCache,
+    /// URL of the proxy, e.g. `http://10.0.0.1:8080`
+    base_url: String,
+}
+
+impl Tcp {
+    /// Create a new [`Tcp`] transport
+    ///
+    /// # Arguments
+    ///
+    /// * `base_url` - URL of the proxy. API paths are appended to it.
+    pub fn new<U: Into<String>>(base_url: U) -> Tcp {
+        Tcp {
+            clients: ClientCache::new(None),
+            base_url: base_url.into().trim_end_matches('/').to_owned(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Transport for Tcp {
+    async fn send(&self, request: &ApiRequest) -> Result<ApiResponse> {
+        send_http(&self.clients, &self.base_url, request).await
+    }
+}
+
+type Handler = dyn Fn(&ApiRequest) -> ApiResponse + Send + Sync;
+
+/// Transport handing requests to a function in the same process
+///
+/// Useful for testing code built on top of [`ApiClient`](super::ApiClient) without Firecracker.
+#[derive(Clone)]
+pub struct InMemory {
+    handler: Arc<Handler>,
+}
+
+impl InMemory {
+    /// Create a new [`InMemory`] transport that answers requests with `handler`
+    pub fn new<F>(handler: F) -> InMemory
+    where
+        F: Fn(&ApiRequest) -> ApiResponse + Send + Sync + 'static,
+    {
+        InMemory {
+            handler: Arc::new(handler),
+        }
+    }
+}
+
+impl std::fmt::Debug for InMemory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InMemory").finish_non_exhaustive()
+    }
+}
+
+#[async_trait::async_trait]
+impl Transport for InMemory {
+    async fn send(&self, request: &ApiRequest) -> Result<ApiResponse> {
+        Ok((self.handler)(request))
+    }
+}
+
+/// Transport that does not send anything
+///
+/// PUT and PATCH requests succeed with an empty response, while GET requests fail with
+/// [`FcClientError::DryRun`], since there is no response to return. Wrap it in a [`Recorder`] to
+/// find out which requests would have been sent.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DryRun;
+
+#[async_trait::async_trait]
+impl Transport for DryRun {
+    async fn send(&self, request: &ApiRequest) -> Result<ApiResponse> {
+        match request.method {
+            Method::Get => Err(FcClientError::DryRun),
+            Method::Put | Method::Patch => Ok(ApiResponse::new(204, "")),
+        }
+    }
+}
+
+/// A request and the response it got
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Exchange {
+    /// Time the request was sent, in RFC 3339 format.
+    pub timestamp: String,
+    /// The request sent to Firecracker.
+    pub request: ApiRequest,
+    /// The response of Firecracker, if one was received.
+    pub response: Option<ApiResponse>,
+    /// Why no response was received, if that is the case.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Time between sending the request and receiving the response, in microseconds.
+    pub latency_us: u64,
+}
+
+/// Transport recording the requests and responses going through an inner transport
+///
+/// Clones of a [`Recorder`] share the same recording, so a clone can be kept around to inspect
+/// the recording after the transport has been handed to an [`ApiClient`](super::ApiClient).
+///
+/// The recording can instead be written to a session file, with one JSON-encoded [`Exchange`] per
+/// line, using [`Recorder::record_to`]. Session files can be read back with
+/// [`read_session`](super::session::read_session) and served with a
+/// [`ReplayServer`](super::session::ReplayServer).
+#[derive(Debug, Clone)]
+pub struct Recorder<T> {
+    inner: T,
+    exchanges: Arc<Mutex<Vec<Exchange>>>,
+    session: Option<Arc<Mutex<File>>>,
+}
+
+impl<T: Transport> Recorder<T> {
+    /// Create a new [`Recorder`] on top of `inner`
+    pub fn new(inner: T) -> Recorder<T> {
+        Recorder {
+            inner,
+            exchanges: Arc::new(Mutex::new(Vec::new())),
+            session: None,
+        }
+    }
+
+    /// Append every exchange to the session file at `path`, creating it if needed
+    ///
+    /// Exchanges are then no longer kept in memory, so that long sessions can be recorded.
+    pub fn record_to<P: AsRef<Path>>(mut self, path: P) -> std::io::Result<Recorder<T>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.session = Some(Arc::new(Mutex::new(file)));
+        Ok(self)
+    }
+
+    /// Requests and responses recorded so far, in the order they were issued
+    ///
+    /// Empty for exchanges recorded to a session file.
+    pub fn exchanges(&self) -> Vec<Exchange> {
+        self.exchanges.lock().unwrap().clone()
+    }
+
+    /// Requests recorded so far, in the order they were issued
+    ///
+    /// Empty for exchanges recorded to a session file.
+    pub fn requests(&self) -> Vec<ApiRequest> {
+        self.exchanges
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|exchange| exchange.request.clone())
+            .collect()
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: Transport> Transport for Recorder<T> {
+    async fn send(&self, request: &ApiRequest) -> Result<ApiResponse> {
+        let timestamp = humantime::format_rfc3339_micros(SystemTime::now()).to_string();
+        let start = Instant::now();
+        let result = self.inner.send(request).await;
+        let latency_us = u64::try_from(start.elapsed().as_micros()).unwrap_or(u64::MAX);
+
+        let exchange = Exchange {
+            timestamp,
+            request: request.clone(),
+            response: result.as_ref().ok().cloned(),
+            error: result.as_ref().err().map(ToString::to_string),
+            latency_us,
+        };
+
+        match &self.session {
+            Some(session) => {
+                let mut line = serde_json::to_string(&exchange)?;
+                line.push('\n');
+                if let Err(err) = session.lock().unwrap().write_all(line.as_bytes()) {
+                    warn!("Could not record API exchange: {err}");
+                }
+            }
+            None => self.exchanges.lock().unwrap().push(exchange),
+        }
+        result
+    }
+}
+
+/// Transport serving previously recorded responses
+///
+/// Requests must arrive in the order they were recorded. A request that does not match the method
+/// and path of the next recorded one fails with [`FcClientError::Transport`].
+#[derive(Debug)]
+pub struct Replay {
+    exchanges: Mutex<VecDeque<Exchange>>,
+}
+
+impl Replay {
+    /// Create a new [`Replay`] transport serving `exchanges` in order
+    pub fn new<I: IntoIterator<Item = Exchange>>(exchanges: I) -> Replay {
+        Replay {
+            exchanges: Mutex::new(exchanges.into_iter().collect()),
+        }
+    }
+
+    /// Number of recorded exchanges that have not been replayed yet
+    pub fn remaining(&self) -> usize {
+        self.exchanges.lock().unwrap().len()
+    }
+
+    // Pops the next recorded exchange, checking that it was recorded for `request`.
+    pub(crate) fn next(&self, request: &ApiRequest) -> Result<Exchange> {
+        let exchange = self.exchanges.lock().unwrap().pop_front().ok_or_else(|| {
+            FcClientError::Transport(format!("unexpected request: {request}").into())
+        })?;
+
+        if exchange.request.method != request.method || exchange.request.path != request.path {
+            return Err(FcClientError::Transport(
+                format!(
+                    "expected {} {}, got {} {}",
+                    exchange.request.method, exchange.request.path, request.method, request.path
+                )
+                .into(),
+            ));
+        }
+
+        Ok(exchange)
+    }
+}
+
+#[async_trait::async_trait]
+impl Transport for Replay {
+    async fn send(&self, request: &ApiRequest) -> Result<ApiResponse> {
+        let exchange = self.next(request)?;
+        exchange.response.ok_or_else(|| {
+            let error = exchange.error.unwrap_or_default();
+            FcClientError::Transport(format!("recorded error for {request}: {error}").into())
+        })
+    }
+}
@@ -0,0 +1,317 @@
+//! Validated configuration values
+//!
+//! Firecracker rejects many configuration values only once they reach the API socket, e.g. an odd
+//! number of vCPUs or a malformed MAC address. The types in this module can only hold valid
+//! values. They parse from strings, so that command line parsers and deserializers reject invalid
+//! values early, with a description of what is wrong.
+
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use serde_derive::{Deserialize, Serialize};
+
+/// An invalid configuration value
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error, displaydoc::Display)]
+pub enum InvalidValue {
+    /// invalid MAC address `{0}`, expected six hexadecimal bytes separated by colons
+    MacAddr(String),
+    /// invalid address `{0}`, expected an IPv4 link-local address (169.254.0.0/16)
+    Ipv4LinkLocal(String),
+    /// invalid size `{0}`, expected a number of MiB, optionally followed by `M`, `MiB`, `G` or `GiB`
+    MiB(String),
+    /// invalid vCPU count `{0}`, expected 1 or an even number up to 32
+    VcpuCount(String),
+    /// invalid guest CID `{0}`, expected a number greater than or equal to 3
+    GuestCid(String),
+}
+
+/// A MAC address, e.g. `06:00:ac:10:00:02`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct MacAddr([u8; 6]);
+
+impl MacAddr {
+    /// Create a MAC address from its bytes
+    pub const fn new(octets: [u8; 6]) -> MacAddr {
+        MacAddr(octets)
+    }
+
+    /// The bytes of the MAC address
+    pub const fn octets(&self) -> [u8; 6] {
+        self.0
+    }
+
+    /// Returns `true` if the address is locally administered, i.e. not assigned by a vendor.
+    pub const fn is_locally_administered(&self) -> bool {
+        self.0[0] & 0x02 != 0
+    }
+
+    /// Returns `true` if the address is a multicast address.
+    pub const fn is_multicast(&self) -> bool {
+        self.0[0] & 0x01 != 0
+    }
+}
+
+impl FromStr for MacAddr {
+    type Err = InvalidValue;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidValue::MacAddr(s.to_owned());
+        let mut octets = [0; 6];
+        let mut parts = s.split(':');
+        for octet in &mut octets {
+            let part = parts
+                .next()
+                .filter(|part| part.len() == 2)
+                .ok_or_else(invalid)?;
+            *octet = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+        }
+
+        match parts.next() {
+            Some(_) => Err(invalid()),
+            None => Ok(MacAddr(octets)),
+        }
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+impl TryFrom<String> for MacAddr {
+    type Error = InvalidValue;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<MacAddr> for String {
+    fn from(value: MacAddr) -> Self {
+        value.to_string()
+    }
+}
+
+/// An IPv4 link-local address, i.e. in 169.254.0.0/16
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Ipv4LinkLocal(Ipv4Addr);
+
+impl Ipv4LinkLocal {
+    /// Check that `address` is a link-local address
+    pub fn new(address: Ipv4Addr) -> Result<Ipv4LinkLocal, InvalidValue> {
+        if address.is_link_local() {
+            Ok(Ipv4LinkLocal(address))
+        } else {
+            Err(InvalidValue::Ipv4LinkLocal(address.to_string()))
+        }
+    }
+
+    /// The IPv4 address
+    pub const fn addr(&self) -> Ipv4Addr {
+        self.0
+    }
+}
+
+impl FromStr for Ipv4LinkLocal {
+    type Err = InvalidValue;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let address = s
+            .parse()
+            .map_err(|_| InvalidValue::Ipv4LinkLocal(s.to_owned()))?;
+        Ipv4LinkLocal::new(address)
+    }
+}
+
+impl fmt::Display for Ipv4LinkLocal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl TryFrom<String> for Ipv4LinkLocal {
+    type Error = InvalidValue;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Ipv4LinkLocal> for String {
+    fn from(value: Ipv4LinkLocal) -> Self {
+        value.to_string()
+    }
+}
+
+/// An amount of memory, in MiB
+///
+/// It parses from a number of MiB, optionally followed by a unit, e.g. `512`, `512MiB` or `2G`.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct MiB(u32);
+
+impl MiB {
+    /// An amount of `mib` MiB
+    pub const fn new(mib: u32) -> MiB {
+        MiB(mib)
+    }
+
+    /// The amount of memory, in MiB
+    pub const fn get(self) -> u32 {
+        self.0
+    }
+
+    /// The amount of memory, in bytes
+    pub const fn bytes(self) -> u64 {
+        (self.0 as u64) << 20
+    }
+}
+
+impl From<u32> for MiB {
+    fn from(value: u32) -> Self {
+        MiB(value)
+    }
+}
+
+impl FromStr for MiB {
+    type Err = InvalidValue;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidValue::MiB(s.to_owned());
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let number: u32 = number.parse().map_err(|_| invalid())?;
+        let multiplier = match unit.trim() {
+            "" | "M" | "MiB" => 1,
+            "G" | "GiB" => 1024,
+            _ => return Err(invalid()),
+        };
+
+        number.checked_mul(multiplier).map(MiB).ok_or_else(invalid)
+    }
+}
+
+impl fmt::Display for MiB {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A number of vCPUs: 1, or an even number up to 32
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub struct VcpuCount(u8);
+
+impl VcpuCount {
+    /// Maximum number of vCPUs of a microVM
+    pub const MAX: u8 = 32;
+
+    /// Check that `count` is a valid number of vCPUs
+    pub fn new(count: u8) -> Result<VcpuCount, InvalidValue> {
+        if count == 1 || (count > 0 && count.is_multiple_of(2) && count <= Self::MAX) {
+            Ok(VcpuCount(count))
+        } else {
+            Err(InvalidValue::VcpuCount(count.to_string()))
+        }
+    }
+
+    /// The number of vCPUs
+    pub const fn get(self) -> u8 {
+        self.0
+    }
+}
+
+impl FromStr for VcpuCount {
+    type Err = InvalidValue;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let count = s
+            .parse()
+            .map_err(|_| InvalidValue::VcpuCount(s.to_owned()))?;
+        VcpuCount::new(count)
+    }
+}
+
+impl fmt::Display for VcpuCount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl TryFrom<u8> for VcpuCount {
+    type Error = InvalidValue;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        VcpuCount::new(value)
+    }
+}
+
+impl From<VcpuCount> for u8 {
+    fn from(value: VcpuCount) -> Self {
+        value.0
+    }
+}
+
+/// A vsock context identifier of a guest, which is 3 or more
+///
+/// CIDs 0 to 2 are reserved, 2 being the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "u32", into = "u32")]
+pub struct GuestCid(u32);
+
+impl GuestCid {
+    /// Lowest CID available to guests
+    pub const MIN: u32 = 3;
+
+    /// Check that `cid` can be used by a guest
+    pub fn new(cid: u32) -> Result<GuestCid, InvalidValue> {
+        if cid >= Self::MIN {
+            Ok(GuestCid(cid))
+        } else {
+            Err(InvalidValue::GuestCid(cid.to_string()))
+        }
+    }
+
+    /// The CID
+    pub const fn get(self) -> u32 {
+        self.0
+    }
+}
+
+impl FromStr for GuestCid {
+    type Err = InvalidValue;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let cid = s
+            .parse()
+            .map_err(|_| InvalidValue::GuestCid(s.to_owned()))?;
+        GuestCid::new(cid)
+    }
+}
+
+impl fmt::Display for GuestCid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl TryFrom<u32> for GuestCid {
+    type Error = InvalidValue;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        GuestCid::new(value)
+    }
+}
+
+impl From<GuestCid> for u32 {
+    fn from(value: GuestCid) -> Self {
+        value.0
+    }
+}
@@ -0,0 +1,220 @@
+//! Preflight validation of a microVM configuration
+//!
+//! Firecracker checks most of the configuration one request at a time, and only reports problems
+//! that span several requests, e.g. two root devices, once the microVM is started.
+//! [`FullVmConfiguration::validate`] checks the whole configuration on the host, before anything
+//! is sent to Firecracker, and reports every problem it finds at once.
+//!
+//! Relative paths are resolved from the current working directory, which must then be the working
+//! directory of Firecracker for the file checks to be meaningful.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+
+use super::drive::IoEngine;
+use super::kernel_image::{KernelImage, NET_OPTIONS, VSOCK_OPTIONS};
+use super::vm::FullVmConfiguration;
+
+/// Oldest host kernel that supports the `Async` IO engine
+const ASYNC_IO_MIN_KERNEL: (u32, u32, u32) = (5, 10, 51);
+
+/// A problem in a microVM configuration
+#[derive(Debug, Clone, PartialEq, Eq, displaydoc::Display)]
+pub enum ValidationError {
+    /// no boot source is configured
+    MissingBootSource,
+    /// more than one root device: {0:?}
+    MultipleRootDevices(Vec<String>),
+    /// drive `{0}` is configured more than once
+    DuplicateDrive(String),
+    /// network interface `{0}` is configured more than once
+    DuplicateInterface(String),
+    /// MMDS refers to network interface `{0}`, which is not configured
+    UnknownMmdsInterface(String),
+    /// kernel image `{0}` does not exist or is not a file
+    MissingKernel(String),
+    /// initrd `{0}` does not exist or is not a file
+    MissingInitrd(String),
+    /// backing file `{1}` of drive `{0}` does not exist or is not a file
+    MissingDriveFile(String, String),
+    /// drive `{0}` has a partuuid but is not the root device
+    PartuuidWithoutRoot(String),
+    /// root device `{0}` is read-only, but the boot arguments mount it read-write
+    ReadOnlyRootMountedRw(String),
+    /// root device `{0}` is read-only, which vhost-user drives do not support
+    ReadOnlyVhostUserRoot(String),
+    /// drive `{0}` uses the Async IO engine, which requires a host kernel 5.10.51 or newer (found {1})
+    AsyncIoUnsupported(String, String),
+    /// the kernel image cannot drive the {0} of the microVM: {1}
+    KernelOptions(&'static str, String),
+}
+
+/// All the problems found in a microVM configuration
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub struct ValidationErrors(pub Vec<ValidationError>);
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} problem(s) in the microVM configuration:",
+            self.0.len()
+        )?;
+        for error in &self.0 {
+            write!(f, "\n  - {error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FullVmConfiguration {
+    /// Check the configuration for problems that Firecracker would report, or that would prevent
+    /// the microVM from booting
+    ///
+    /// Returns all the problems found. It does not send anything to Firecracker, but inspects the
+    /// host, e.g. to check that the files backing the microVM exist.
+    pub fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = Vec::new();
+        let drives = self.drives.as_deref().unwrap_or_default();
+        let interfaces = self.network_interfaces.as_deref().unwrap_or_default();
+
+        match &self.boot_source {
+            None => errors.push(ValidationError::MissingBootSource),
+            Some(boot_source) => {
+                if !is_file(&boot_source.kernel_image_path) {
+                    errors.push(ValidationError::MissingKernel(
+                        boot_source.kernel_image_path.clone(),
+                    ));
+                }
+                if let Some(initrd) = boot_source.initrd_path.as_ref().filter(|p| !is_file(p)) {
+                    errors.push(ValidationError::MissingInitrd(initrd.clone()));
+                }
+            }
+        }
+
+        // The options of the devices are only needed when the microVM has them. Kernels that
+        // cannot be inspected are left to Firecracker.
+        let devices: Vec<_> = [
+            ("network interfaces", NET_OPTIONS, !interfaces.is_empty()),
+            ("vsock device", VSOCK_OPTIONS, self.vsock.is_some()),
+        ]
+        .into_iter()
+        .filter(|(_, _, configured)| *configured)
+        .collect();
+        let kernel = match &self.boot_source {
+            Some(boot_source) if !devices.is_empty() && is_file(&boot_source.kernel_image_path) => {
+                KernelImage::inspect(&boot_source.kernel_image_path).ok()
+            }
+            _ => None,
+        };
+        if let Some(kernel) = kernel {
+            for (device, options, _) in devices {
+                if let Err(err) = kernel.check_options(options) {
+                    errors.push(ValidationError::KernelOptions(device, err.to_string()));
+                }
+            }
+        }
+
+        let roots: Vec<String> = drives
+            .iter()
+            .filter(|drive| drive.is_root_device)
+            .map(|drive| drive.drive_id.clone())
+            .collect();
+        if roots.len() > 1 {
+            errors.push(ValidationError::MultipleRootDevices(roots));
+        }
+
+        let mut drive_ids = HashSet::new();
+        let mut host_kernel = None;
+        for drive in drives {
+            if !drive_ids.insert(&drive.drive_id) {
+                errors.push(ValidationError::DuplicateDrive(drive.drive_id.clone()));
+            }
+
+            if drive.socket.is_none() && !is_file(&drive.path_on_host) {
+                errors.push(ValidationError::MissingDriveFile(
+                    drive.drive_id.clone(),
+                    drive.path_on_host.clone(),
+                ));
+            }
+
+            if drive.partuuid.is_some() && !drive.is_root_device {
+                errors.push(ValidationError::PartuuidWithoutRoot(drive.drive_id.clone()));
+            }
+
+            if drive.is_root_device && drive.is_read_only {
+                if drive.socket.is_some() {
+                    errors.push(ValidationError::ReadOnlyVhostUserRoot(
+                        drive.drive_id.clone(),
+                    ));
+                } else if self.boot_args().any(|arg| arg == "rw") {
+                    errors.push(ValidationError::ReadOnlyRootMountedRw(
+                        drive.drive_id.clone(),
+                    ));
+                }
+            }
+
+            if matches!(drive.io_engine, IoEngine::Async) {
+                let release = host_kernel.get_or_insert_with(host_kernel_release);
+                if let Some(release) = release {
+                    if parse_kernel_version(release).is_some_and(|v| v < ASYNC_IO_MIN_KERNEL) {
+                        errors.push(ValidationError::AsyncIoUnsupported(
+                            drive.drive_id.clone(),
+                            release.clone(),
+                        ));
+                    }
+                }
+            }
+        }
+
+        let mut iface_ids = HashSet::new();
+        for iface in interfaces {
+            if !iface_ids.insert(&iface.iface_id) {
+                errors.push(ValidationError::DuplicateInterface(iface.iface_id.clone()));
+            }
+        }
+
+        if let Some(mmds) = &self.mmds_config {
+            for iface_id in &mmds.network_interfaces {
+                if !iface_ids.contains(iface_id) {
+                    errors.push(ValidationError::UnknownMmdsInterface(iface_id.clone()));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationErrors(errors))
+        }
+    }
+
+    // The kernel boot arguments, split on whitespace
+    fn boot_args(&self) -> impl Iterator<Item = &str> {
+        self.boot_source
+            .iter()
+            .filter_map(|boot_source| boot_source.boot_args.as_deref())
+            .flat_map(str::split_whitespace)
+    }
+}
+
+fn is_file(path: &str) -> bool {
+    Path::new(path).is_file()
+}
+
+// Release of the host kernel, e.g. `6.1.55-75.123.amzn2023.x86_64`
+fn host_kernel_release() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/osrelease")
+        .ok()
+        .map(|release| release.trim().to_owned())
+}
+
+// Parse the version out of a kernel release, e.g. (6, 1, 55) out of `6.1.55-75.123.amzn2023`
+fn parse_kernel_version(release: &str) -> Option<(u32, u32, u32)> {
+    let mut numbers = release.split(|c: char| !c.is_ascii_digit()).map(str::parse);
+    let major = numbers.next()?.ok()?;
+    let minor = numbers.next()?.ok()?;
+    let patch = numbers.next().and_then(|patch| patch.ok()).unwrap_or(0);
+    Some((major, minor, patch))
+}
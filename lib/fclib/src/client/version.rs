@@ -0,0 +1,250 @@
+//! Firecracker version negotiation
+//!
+//! The API of Firecracker changes between releases: endpoints are added, and fields are added,
+//! renamed or removed. Before its first PUT or PATCH request, an [`ApiClient`] queries `/version`
+//! and caches the result. It then shapes the body of every request for that version, e.g. it sends
+//! `mem_file_path` instead of `mem_backend` to Firecracker 1.0.
+//!
+//! Requests that use a feature the running Firecracker does not have fail with [`Unsupported`],
+//! instead of being rejected by Firecracker with a 400.
+//!
+//! When there is no Firecracker to query, e.g. with the [`DryRun`] transport, the version must be
+//! set with [`ApiClient::with_fc_version`].
+//!
+//! [`ApiClient`]: super::ApiClient
+//! [`ApiClient::with_fc_version`]: super::ApiClient::with_fc_version
+//! [`DryRun`]: super::transport::DryRun
+
+use log::warn;
+use semver::Version;
+use serde_json::{Map, Value};
+
+use super::transport::Method;
+
+/// A feature that the running Firecracker does not have
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{feature} is unsupported in Firecracker {}.{}", .version.major, .version.minor)]
+pub struct Unsupported {
+    /// The unsupported feature.
+    pub feature: String,
+    /// Version of the running Firecracker.
+    pub version: Version,
+}
+
+/// Parse a version reported by Firecracker, e.g. `1.4.1` or `v1.5.0-dev`
+pub fn parse_version(version: &str) -> Option<Version> {
+    Version::parse(version.trim().trim_start_matches('v')).ok()
+}
+
+/// A feature of Firecracker that is missing from older versions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, displaydoc::Display)]
+pub enum Feature {
+    /// custom CPU templates
+    CustomCpuTemplates,
+    /// entropy device
+    Entropy,
+    /// huge pages
+    HugePages,
+    /// vhost-user block devices
+    VhostUserBlock,
+    /// VM generation ID device
+    VmGenId,
+    /// network overrides on snapshot load
+    NetworkOverrides,
+    /// dirty page tracking on snapshot load
+    SnapshotDirtyPageTracking,
+    /// balloon free page hinting
+    BalloonFreePageHinting,
+    /// balloon free page reporting
+    BalloonFreePageReporting,
+    /// memory hotplug
+    MemoryHotplug,
+    /// serial console configuration
+    SerialConfig,
+}
+
+impl Feature {
+    /// All the features
+    pub const ALL: &'static [Feature] = &[
+        Feature::CustomCpuTemplates,
+        Feature::Entropy,
+        Feature::HugePages,
+        Feature::VhostUserBlock,
+        Feature::VmGenId,
+        Feature::NetworkOverrides,
+        Feature::SnapshotDirtyPageTracking,
+        Feature::BalloonFreePageHinting,
+        Feature::BalloonFreePageReporting,
+        Feature::MemoryHotplug,
+        Feature::SerialConfig,
+    ];
+
+    /// The first version of Firecracker that has the feature
+    pub fn since(self) -> Version {
+        let (major, minor) = match self {
+            Feature::CustomCpuTemplates | Feature::Entropy => (1, 4),
+            Feature::HugePages => (1, 7),
+            Feature::VhostUserBlock | Feature::VmGenId => (1, 8),
+            Feature::NetworkOverrides => (1, 12),
+            Feature::SnapshotDirtyPageTracking => (1, 13),
+            Feature::BalloonFreePageHinting
+            | Feature::BalloonFreePageReporting
+            | Feature::MemoryHotplug
+            | Feature::SerialConfig => (1, 14),
+        };
+        Version::new(major, minor, 0)
+    }
+
+    /// Returns `true` if Firecracker `version` has the feature
+    pub fn is_supported_by(self, version: &Version) -> bool {
+        let since = self.since();
+        at_least(version, (since.major, since.minor))
+    }
+}
+
+// Endpoints that are missing from older versions.
+const ENDPOINTS: &[(&str, Feature)] = &[
+    ("/cpu-config", Feature::CustomCpuTemplates),
+    ("/entropy", Feature::Entropy),
+    ("/hotplug/memory", Feature::MemoryHotplug),
+    ("/serial", Feature::SerialConfig),
+];
+
+// Fields of request bodies that are missing from older versions, by endpoint.
+const FIELDS: &[(&str, &str, Feature)] = &[
+    (
+        "/balloon",
+        "free_page_hinting",
+        Feature::BalloonFreePageHinting,
+    ),
+    (
+        "/balloon",
+        "free_page_reporting",
+        Feature::BalloonFreePageReporting,
+    ),
+    ("/drives/", "socket", Feature::VhostUserBlock),
+    ("/machine-config", "huge_pages", Feature::HugePages),
+    (
+        "/snapshot/load",
+        "network_overrides",
+        Feature::NetworkOverrides,
+    ),
+];
+
+// CPU templates that are missing from older versions, along with the first version that has them.
+const CPU_TEMPLATES: &[(&str, (u64, u64))] = &[("T2S", (1, 1)), ("T2CL", (1, 3)), ("T2A", (1, 3))];
+
+// Returns `true` if `version` is `major.minor` or newer, regardless of its patch and pre-release.
+fn at_least(version: &Version, (major, minor): (u64, u64)) -> bool {
+    (version.major, version.minor) >= (major, minor)
+}
+
+/// Shape the body of a request for Firecracker `version`
+///
+/// Fails if the request uses a feature `version` does not have.
+pub fn shape_request(
+    version: &Version,
+    method: Method,
+    path: &str,
+    body: Option<Value>,
+) -> Result<Option<Value>, Unsupported> {
+    let unsupported = |feature: &str| Unsupported {
+        feature: feature.to_owned(),
+        version: version.clone(),
+    };
+
+    for (prefix, feature) in ENDPOINTS {
+        if path.starts_with(prefix) && !feature.is_supported_by(version) {
+            return Err(unsupported(&feature.to_string()));
+        }
+    }
+
+    let mut body = match body {
+        Some(Value::Object(body)) => body,
+        body => return Ok(body),
+    };
+
+    for (prefix, field, feature) in FIELDS {
+        if path.starts_with(prefix) && is_used(&body, field) && !feature.is_supported_by(version) {
+            return Err(unsupported(&feature.to_string()));
+        }
+    }
+
+    match (method, path) {
+        // `version` was removed in 1.5
+        (Method::Put, "/snapshot/create")
+            if is_set(&body, "version") && at_least(version, (1, 5)) =>
+        {
+            return Err(unsupported("the snapshot `version` field"));
+        }
+        (Method::Put, "/snapshot/load") => {
+            // `mem_backend` replaced `mem_file_path` in 1.1
+            if !at_least(version, (1, 1)) {
+                if let Some(backend) = body.remove("mem_backend") {
+                    if backend["backend_type"] == "Uffd" {
+                        return Err(unsupported("the UFFD memory backend"));
+                    }
+                    body.insert("mem_file_path".into(), backend["backend_path"].clone());
+                }
+            }
+
+            // `track_dirty_pages` replaced `enable_diff_snapshots` in 1.13
+            let track_dirty_pages = [
+                body.remove("enable_diff_snapshots"),
+                body.remove("track_dirty_pages"),
+            ]
+            .into_iter()
+            .flatten()
+            .any(|value| value == true);
+            let field = if Feature::SnapshotDirtyPageTracking.is_supported_by(version) {
+                "track_dirty_pages"
+            } else {
+                "enable_diff_snapshots"
+            };
+            body.insert(field.into(), Value::from(track_dirty_pages));
+        }
+        (Method::Put | Method::Patch, "/machine-config") => {
+            // `ht_enabled` was renamed to `smt` in 1.0
+            if !at_least(version, (1, 0)) {
+                if let Some(smt) = body.remove("smt") {
+                    body.insert("ht_enabled".into(), smt);
+                }
+            }
+
+            if let Some(template) = body.get("cpu_template").and_then(Value::as_str) {
+                for (name, since) in CPU_TEMPLATES {
+                    if template == *name && !at_least(version, *since) {
+                        return Err(unsupported(&format!("the {name} CPU template")));
+                    }
+                }
+            }
+        }
+        (Method::Put, "/vsock") => {
+            // `vsock_id` is required before 1.0, and deprecated since then
+            if at_least(version, (1, 0)) {
+                if body.remove("vsock_id").is_some_and(|id| !id.is_null()) {
+                    warn!("`vsock_id` is deprecated since Firecracker 1.0, ignoring it");
+                }
+            } else if !is_set(&body, "vsock_id") {
+                body.insert("vsock_id".into(), Value::from("vsock"));
+            }
+        }
+        _ => (),
+    }
+
+    Ok(Some(Value::Object(body)))
+}
+
+// Returns `true` if `field` is present in `body` and not null.
+fn is_set(body: &Map<String, Value>, field: &str) -> bool {
+    body.get(field).is_some_and(|value| !value.is_null())
+}
+
+// Returns `true` if `field` is present in `body`, and neither null, false nor empty.
+fn is_used(body: &Map<String, Value>, field: &str) -> bool {
+    match body.get(field) {
+        None | Some(Value::Null) | Some(Value::Bool(false)) => false,
+        Some(Value::Array(values)) => !values.is_empty(),
+        Some(_) => true,
+    }
+}
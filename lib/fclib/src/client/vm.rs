@@ -4,11 +4,13 @@ use serde_derive::{Deserialize, Serialize};
 
 use super::balloon::Balloon;
 use super::drive::Drive;
+use super::entropy::EntropyDevice;
 use super::kernel::BootSource;
 use super::logger::Logger;
 use super::metrics::Metrics;
 use super::mmds::MmdsConfig;
 use super::network::NetworkInterface;
+use super::types::{MiB, VcpuCount};
 use super::vsock::Vsock;
 use super::{ApiClient, Result};
 
@@ -114,14 +116,19 @@ pub struct FullVmConfiguration {
     pub balloon: Option<Balloon>,
     /// Configurations for all block devices.
     pub drives: Option<Vec<Drive>>,
+    #[serde(rename = "boot-source")]
     pub boot_source: Option<BootSource>,
     pub logger: Option<Logger>,
+    #[serde(rename = "machine-config")]
     pub machine_config: Option<MachineConfiguration>,
     pub metrics: Option<Metrics>,
+    #[serde(rename = "mmds-config")]
     pub mmds_config: Option<MmdsConfig>,
     /// Configurations for all net devices.
+    #[serde(rename = "network-interfaces")]
     pub network_interfaces: Option<Vec<NetworkInterface>>,
     pub vsock: Option<Vsock>,
+    pub entropy: Option<EntropyDevice>,
 }
 
 impl ApiClient {
@@ -153,14 +160,27 @@ pub enum CpuTemplate {
     None,
 }
 
+/// Huge pages backing the guest memory
+#[cfg_attr(feature = "clap", derive(Clone, ValueEnum))]
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub enum HugePages {
+    /// Regular 4K pages.
+    #[default]
+    None,
+    /// 2M hugetlbfs pages.
+    #[serde(rename = "2M")]
+    #[cfg_attr(feature = "clap", value(name = "2M"))]
+    Hugetlbfs2M,
+}
+
 #[cfg_attr(feature = "clap", derive(Args))]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MachineConfiguration {
-    /// Memory size of VM
-    pub mem_size_mib: i32,
+    /// Memory size of VM, in MiB (e.g. `512` or `2G`)
+    pub mem_size_mib: MiB,
 
     /// Number of vCPUs (either 1 or an even number)
-    pub vcpu_count: i32,
+    pub vcpu_count: VcpuCount,
 
     /// Flag for enabling/disabling simultaneous multithreading. Can be enabled only on x86.
     #[cfg_attr(feature = "clap", arg(long, short, default_value = "false"))]
@@ -176,17 +196,23 @@ pub struct MachineConfiguration {
     /// CPU template to use.
     #[cfg_attr(feature = "clap", arg(long, short, default_value = "none"))]
     pub cpu_template: Option<CpuTemplate>,
+
+    /// Huge pages backing the guest memory. Requires Firecracker 1.7 or newer.
+    #[cfg_attr(feature = "clap", arg(long))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub huge_pages: Option<HugePages>,
 }
 
 impl MachineConfiguration {
     /// Describes the number of vCPUs, memory size, SMT capabilities and the CPU template.
-    pub fn new(mem_size_mib: i32, vcpu_count: i32) -> MachineConfiguration {
+    pub fn new(mem_size_mib: MiB, vcpu_count: VcpuCount) -> MachineConfiguration {
         MachineConfiguration {
             cpu_template: None,
             smt: None,
             mem_size_mib,
             track_dirty_pages: None,
             vcpu_count,
+            huge_pages: None,
         }
     }
 }
@@ -10,16 +10,18 @@
 use clap::Args;
 use serde_derive::{Deserialize, Serialize};
 
+use super::types::GuestCid;
 use super::{ApiClient, Result};
 
 #[cfg_attr(feature = "clap", derive(Args, Clone))]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Vsock {
     /// Guest Vsock CID
-    pub guest_cid: i32,
+    pub guest_cid: GuestCid,
     /// Path to UNIX domain socket, used to proxy vsock connections.
     pub uds_path: String,
-    /// This parameter has been deprecated since v1.0.0.
+    /// This parameter has been deprecated since v1.0.0. It is only sent to older versions, which
+    /// require it.
     pub vsock_id: Option<String>,
 }
 
@@ -0,0 +1,286 @@
+//! Conversion of qcow2 and VMDK disk images to raw images
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use super::qcow2::Qcow2;
+use super::vmdk::Vmdk;
+use super::ImageError;
+use crate::client::drive::{Drive, DriveClone};
+
+// Longest chain of backing files followed, to stop on loops
+const MAX_CHAIN: usize = 64;
+
+// Size of the chunks converted at once
+const CHUNK_SIZE: usize = 1 << 20;
+
+// Size of the blocks of zeros left as holes in raw images
+const BLOCK_SIZE: usize = 4096;
+
+/// Format of a disk image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskFormat {
+    /// A raw image, as Firecracker expects it
+    Raw,
+    /// A QEMU copy-on-write image, version 2 or 3
+    Qcow2,
+    /// A monolithic sparse, or stream-optimized, VMDK image
+    Vmdk,
+}
+
+impl DiskFormat {
+    /// Detect the format of the image at `path` from its magic bytes
+    pub fn detect<P: AsRef<Path>>(path: P) -> Result<DiskFormat, ImageError> {
+        let mut magic = Vec::with_capacity(21);
+        File::open(path)?.take(21).read_to_end(&mut magic)?;
+        Ok(if magic.starts_with(b"QFI\xfb") {
+            DiskFormat::Qcow2
+        } else if magic.starts_with(b"KDMV") || magic.starts_with(b"# Disk DescriptorFile") {
+            DiskFormat::Vmdk
+        } else {
+            DiskFormat::Raw
+        })
+    }
+
+    // Parse the backing format recorded in a qcow2 image
+    pub(super) fn from_name(name: &str) -> Result<DiskFormat, ImageError> {
+        match name {
+            "raw" => Ok(DiskFormat::Raw),
+            "qcow2" => Ok(DiskFormat::Qcow2),
+            "vmdk" => Ok(DiskFormat::Vmdk),
+            other => Err(ImageError::UnsupportedDisk(format!(
+                "backing files in {other} format"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for DiskFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiskFormat::Raw => write!(f, "raw"),
+            DiskFormat::Qcow2 => write!(f, "qcow2"),
+            DiskFormat::Vmdk => write!(f, "vmdk"),
+        }
+    }
+}
+
+// A layer of a chain of disk images, i.e. an image on top of its backing file.
+pub(super) trait Layer: fmt::Debug {
+    // Size of the disk, in bytes
+    fn size(&self) -> u64;
+
+    // Fill `buf` with the content of the disk at `offset`, zeros past its end. Returns `false` if
+    // the range is known to be zeros.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<bool, ImageError>;
+}
+
+// Files of a chain of disk images, to open backing files and recognize the files of the chain
+#[derive(Debug, Default)]
+pub(super) struct Chain {
+    // Device and inode numbers of the files
+    files: Vec<(u64, u64)>,
+}
+
+impl Chain {
+    // Open the image at `path`, in `format` or the detected one, and its backing files.
+    pub(super) fn open(
+        &mut self,
+        path: &Path,
+        format: Option<DiskFormat>,
+    ) -> Result<Box<dyn Layer>, ImageError> {
+        if self.files.len() == MAX_CHAIN {
+            return Err(ImageError::InvalidDisk(format!(
+                "more than {MAX_CHAIN} backing files"
+            )));
+        }
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        self.files.push((metadata.dev(), metadata.ino()));
+
+        let format = match format {
+            Some(format) => format,
+            None => DiskFormat::detect(path)?,
+        };
+        Ok(match format {
+            DiskFormat::Raw => Box::new(Raw {
+                file,
+                size: metadata.len(),
+            }),
+            DiskFormat::Qcow2 => Box::new(Qcow2::open(file, path, self)?),
+            DiskFormat::Vmdk => Box::new(Vmdk::open(file, path, self)?),
+        })
+    }
+
+    fn contains(&self, metadata: &fs::Metadata) -> bool {
+        self.files.contains(&(metadata.dev(), metadata.ino()))
+    }
+}
+
+// Path of the backing file `name` of the image at `path`
+pub(super) fn backing_path(path: &Path, name: &str) -> PathBuf {
+    match path.parent() {
+        Some(dir) => dir.join(name),
+        None => PathBuf::from(name),
+    }
+}
+
+// Read `buf.len()` bytes of the image `file` at `offset`.
+pub(super) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> Result<(), ImageError> {
+    file.read_exact_at(buf, offset)
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => ImageError::InvalidDisk(format!(
+                "truncated image, {} bytes missing at offset {offset}",
+                buf.len()
+            )),
+            _ => err.into(),
+        })
+}
+
+// Decompress a cluster of `size` bytes; the last one may be shorter.
+pub(super) fn inflate<R: Read>(decoder: R, size: usize) -> Result<Vec<u8>, ImageError> {
+    let mut cluster = Vec::with_capacity(size);
+    decoder
+        .take(size as u64)
+        .read_to_end(&mut cluster)
+        .map_err(|err| ImageError::InvalidDisk(format!("corrupted compressed cluster: {err}")))?;
+    cluster.resize(size, 0);
+    Ok(cluster)
+}
+
+// A raw image
+#[derive(Debug)]
+struct Raw {
+    file: File,
+    size: u64,
+}
+
+impl Layer for Raw {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<bool, ImageError> {
+        let len = self.size.saturating_sub(offset).min(buf.len() as u64) as usize;
+        let (data, past_end) = buf.split_at_mut(len);
+        read_exact_at(&self.file, data, offset)?;
+        past_end.fill(0);
+        Ok(len > 0)
+    }
+}
+
+/// A disk image, along with its chain of backing files, to convert to a raw image
+///
+/// Firecracker only accepts raw images as backing files of drives. qcow2 images (versions 2 and
+/// 3), with their backing files and compressed clusters, and monolithic sparse or stream-optimized
+/// VMDK images are converted to sparse raw images: the clusters that are not allocated, or only
+/// hold zeros, are left as holes.
+#[derive(Debug)]
+pub struct DiskImage {
+    format: DiskFormat,
+    layer: Box<dyn Layer>,
+    chain: Chain,
+}
+
+impl DiskImage {
+    /// Open the disk image at `path`, detecting its format
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<DiskImage, ImageError> {
+        let path = path.as_ref();
+        let format = DiskFormat::detect(path)?;
+        let mut chain = Chain::default();
+        let layer = chain.open(path, Some(format))?;
+        Ok(DiskImage {
+            format,
+            layer,
+            chain,
+        })
+    }
+
+    /// Format of the image
+    pub fn format(&self) -> DiskFormat {
+        self.format
+    }
+
+    /// Size of the disk, in bytes
+    pub fn virtual_size(&self) -> u64 {
+        self.layer.size()
+    }
+
+    /// Write the disk as a sparse raw image at `path`, replacing any existing file
+    pub fn convert<P: AsRef<Path>>(&mut self, path: P) -> Result<(), ImageError> {
+        let path = path.as_ref();
+        if let Ok(metadata) = fs::metadata(path) {
+            if self.chain.contains(&metadata) {
+                return Err(ImageError::InvalidDisk(format!(
+                    "{} is a file of the image",
+                    path.display()
+                )));
+            }
+        }
+        self.write_raw(&File::create(path)?)
+    }
+
+    /// Convert the disk straight into a clone of `drive` at `path`, e.g. the location of the
+    /// writable copy of a golden image for a microVM
+    ///
+    /// `path` must not exist. As with [`Drive::clone_to`], the returned [`DriveClone`] is
+    /// writable, and the file is deleted when it is dropped.
+    pub fn clone_to<P: AsRef<Path>>(
+        &mut self,
+        drive: &Drive,
+        path: P,
+    ) -> Result<DriveClone, ImageError> {
+        let path = path.as_ref();
+        let path_on_host = path.to_str().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "path is not valid UTF-8")
+        })?;
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        let clone = DriveClone::from_file(Drive {
+            path_on_host: path_on_host.to_owned(),
+            is_read_only: false,
+            ..drive.clone()
+        });
+        // `clone` deletes the file if the conversion fails
+        self.write_raw(&file)?;
+        Ok(clone)
+    }
+
+    fn write_raw(&mut self, out: &File) -> Result<(), ImageError> {
+        let size = self.layer.size();
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let mut offset = 0;
+        while offset < size {
+            let len = (size - offset).min(CHUNK_SIZE as u64) as usize;
+            let chunk = &mut chunk[..len];
+            if self.layer.read_at(offset, chunk)? {
+                write_sparse(out, chunk, offset)?;
+            }
+            offset += len as u64;
+        }
+        out.set_len(size)?;
+        Ok(())
+    }
+}
+
+// Write `data` at `offset` of `out`, leaving blocks of zeros as holes.
+fn write_sparse(out: &File, data: &[u8], offset: u64) -> io::Result<()> {
+    let mut run_start = None;
+    for (index, block) in data.chunks(BLOCK_SIZE).enumerate() {
+        let start = index * BLOCK_SIZE;
+        match (block.iter().all(|&byte| byte == 0), run_start) {
+            (false, None) => run_start = Some(start),
+            (true, Some(run)) => {
+                out.write_all_at(&data[run..start], offset + run as u64)?;
+                run_start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(run) = run_start {
+        out.write_all_at(&data[run..], offset + run as u64)?;
+    }
+    Ok(())
+}
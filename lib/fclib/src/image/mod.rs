@@ -0,0 +1,380 @@
+//! Root filesystem image builder
+//!
+//! [`RootfsBuilder`] produces the ext4 image backing a root [`Drive`], from a directory of the host
+//! or from an OCI or `docker save` image tarball. The image is built with `mkfs.ext4 -d`, from
+//! e2fsprogs, and does not require root: owners, permissions and device nodes of image tarballs are
+//! applied to the filesystem with `debugfs` afterwards. Files of a host directory keep the owners
+//! they have on the host.
+//!
+//! An init can be injected as `/sbin/init`, e.g. a script running the entrypoint of the image, see
+//! [`Init`].
+//!
+//! Firecracker only accepts raw images: [`DiskImage`] converts qcow2 and VMDK images to sparse raw
+//! images, e.g. straight into the writable clone of a drive for a microVM.
+
+mod convert;
+mod oci;
+mod qcow2;
+mod vmdk;
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+pub use convert::{DiskFormat, DiskImage};
+pub use oci::ImageConfig;
+
+use crate::client::drive::Drive;
+use crate::client::types::MiB;
+use crate::util::{self, find_tool, CommandError};
+use oci::Fixup;
+
+/// Path of the injected init in the image
+pub const INIT_PATH: &str = "/sbin/init";
+
+// Free space left in images sized automatically, on top of a quarter of their content
+const MIN_FREE_SPACE: MiB = MiB::new(64);
+
+/// An error while building or converting an image
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum ImageError {
+    /// {0}
+    Io(#[from] std::io::Error),
+    /// invalid image tarball: {0}
+    InvalidImage(String),
+    /// invalid image configuration: {0}
+    Json(#[from] serde_json::Error),
+    /// `{0}` failed: {1}
+    Command(String, String),
+    /// the image has no entrypoint nor command to run as init
+    NoEntrypoint,
+    /// invalid disk image: {0}
+    InvalidDisk(String),
+    /// unsupported disk image: {0}
+    UnsupportedDisk(String),
+}
+
+impl From<CommandError> for ImageError {
+    fn from(err: CommandError) -> Self {
+        ImageError::Command(err.program, err.message)
+    }
+}
+
+/// Content of a root filesystem image
+#[derive(Debug, Clone)]
+pub enum Source {
+    /// A directory of the host
+    Dir(PathBuf),
+    /// An OCI image layout, or the output of `docker save`, as a tarball
+    OciTarball(PathBuf),
+}
+
+/// An init injected into a root filesystem image
+#[derive(Debug, Clone)]
+pub enum Init {
+    /// An executable of the host
+    Binary(PathBuf),
+    /// A script, e.g. starting with `#!/bin/sh`
+    Script(String),
+    /// A `/bin/sh` script that mounts `/proc`, `/sys` and `/dev`, and runs the entrypoint and
+    /// command of the image tarball, with its environment and working directory
+    ///
+    /// The microVM stops when the command exits, provided the guest kernel reboots on panic.
+    Entrypoint,
+}
+
+/// Builder of a root filesystem image
+#[derive(Debug, Clone)]
+pub struct RootfsBuilder {
+    source: Source,
+    size: Option<MiB>,
+    free_space: MiB,
+    label: String,
+    init: Option<Init>,
+    drive_id: String,
+}
+
+impl RootfsBuilder {
+    /// Build an image with the content of `source`
+    pub fn new(source: Source) -> RootfsBuilder {
+        RootfsBuilder {
+            source,
+            size: None,
+            free_space: MIN_FREE_SPACE,
+            label: "rootfs".to_owned(),
+            init: None,
+            drive_id: "rootfs".to_owned(),
+        }
+    }
+
+    /// Set the size of the image, instead of sizing it for its content
+    pub fn with_size(mut self, size: MiB) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    /// Set the free space left in an image sized for its content, 64 MiB by default
+    pub fn with_free_space(mut self, free_space: MiB) -> Self {
+        self.free_space = free_space;
+        self
+    }
+
+    /// Set the label of the filesystem
+    pub fn with_label<S: Into<String>>(mut self, label: S) -> Self {
+        self.label = label.into();
+        self
+    }
+
+    /// Inject `init` as [`INIT_PATH`], replacing any existing one
+    pub fn with_init(mut self, init: Init) -> Self {
+        self.init = Some(init);
+        self
+    }
+
+    /// Set the ID of the returned drive, `rootfs` by default
+    pub fn with_drive_id<S: Into<String>>(mut self, drive_id: S) -> Self {
+        self.drive_id = drive_id.into();
+        self
+    }
+
+    /// Build the image at `path`, and return a read-write root [`Drive`] backed by it
+    pub fn build<P: AsRef<Path>>(&self, path: P) -> Result<Drive, ImageError> {
+        let path = path.as_ref();
+        let scratch_dir = path.parent().filter(|dir| !dir.as_os_str().is_empty());
+        let scratch = tempfile::Builder::new()
+            .prefix(".rootfs-")
+            .tempdir_in(scratch_dir.unwrap_or(Path::new(".")))?;
+
+        let (root, config, fixups) = match &self.source {
+            Source::Dir(dir) => (dir.clone(), None, BTreeMap::new()),
+            Source::OciTarball(tarball) => {
+                let root = scratch.path().join("root");
+                let blobs = scratch.path().join("blobs");
+                fs::create_dir(&root)?;
+                fs::create_dir(&blobs)?;
+                let (config, fixups) = oci::unpack(tarball, &root, &blobs)?;
+                fs::remove_dir_all(&blobs)?;
+                (root, Some(config), fixups)
+            }
+        };
+
+        let mut script = String::new();
+        for (entry, fixup) in &fixups {
+            fixup_commands(&mut script, &root, entry, fixup);
+        }
+        if let Some(init) = &self.init {
+            let init_file = scratch.path().join("init");
+            match init {
+                Init::Binary(binary) => {
+                    fs::copy(binary, &init_file)?;
+                }
+                Init::Script(content) => fs::write(&init_file, content)?,
+                Init::Entrypoint => {
+                    let config = config.as_ref().ok_or(ImageError::NoEntrypoint)?;
+                    fs::write(&init_file, entrypoint_script(config)?)?;
+                }
+            }
+            init_commands(&mut script, &root, &init_file);
+        }
+
+        let size = match self.size {
+            Some(size) => size,
+            None => {
+                let used = disk_usage(&root)? + fs::metadata(scratch.path())?.len();
+                let mib = (used + used / 4).div_ceil(1 << 20) + u64::from(self.free_space.get());
+                MiB::new(u32::try_from(mib).unwrap_or(u32::MAX))
+            }
+        };
+
+        File::create(path)?.set_len(size.bytes())?;
+        let mut mkfs = Command::new(find_tool("mkfs.ext4"));
+        mkfs.args(["-q", "-F", "-E", "root_owner=0:0", "-L", &self.label, "-d"])
+            .arg(&root)
+            .arg(path);
+        if let Err(err) = util::run(&mut mkfs) {
+            let _ = fs::remove_file(path);
+            return Err(err.into());
+        }
+
+        if !script.is_empty() {
+            let script_file = scratch.path().join("debugfs");
+            fs::write(&script_file, script)?;
+            let mut debugfs = Command::new(find_tool("debugfs"));
+            debugfs.arg("-w").arg("-f").arg(&script_file).arg(path);
+            if let Err(err) = run_debugfs(&mut debugfs) {
+                let _ = fs::remove_file(path);
+                return Err(err);
+            }
+        }
+
+        Ok(Drive::new(
+            self.drive_id.clone(),
+            path.display().to_string(),
+            true,
+            false,
+        ))
+    }
+}
+
+// Append the `debugfs` commands that give `entry` the owner, mode and device of `fixup`.
+fn fixup_commands(script: &mut String, root: &Path, entry: &str, fixup: &Fixup) {
+    if let Some((major, minor)) = fixup.device {
+        let kind = if fixup.mode & 0o170000 == 0o060000 {
+            'b'
+        } else {
+            'c'
+        };
+        // `mknod` creates a node in the current directory
+        let (parent, name) = split(root, entry);
+        let _ = writeln!(script, "cd {}", quote(&parent));
+        let _ = writeln!(script, "mknod {} {kind} {major} {minor}", quote(name));
+        let _ = writeln!(script, "cd /");
+    }
+    let entry = quote(entry);
+    let _ = writeln!(script, "sif {entry} mode 0{:o}", fixup.mode);
+    let _ = writeln!(script, "sif {entry} uid {}", fixup.uid);
+    let _ = writeln!(script, "sif {entry} gid {}", fixup.gid);
+}
+
+// Append the `debugfs` commands that install the host file `init` as `INIT_PATH`.
+fn init_commands(script: &mut String, root: &Path, init: &Path) {
+    // `write` creates a file in the current directory
+    let (parent, name) = split(root, INIT_PATH);
+    let host_parent = root.join(parent.trim_start_matches('/'));
+    if fs::symlink_metadata(&host_parent).is_err() {
+        let _ = writeln!(script, "mkdir {}", quote(&parent));
+    }
+    let _ = writeln!(script, "cd {}", quote(&parent));
+    if fs::symlink_metadata(host_parent.join(name)).is_ok() {
+        let _ = writeln!(script, "rm {}", quote(name));
+    }
+    let _ = writeln!(
+        script,
+        "write {} {}",
+        quote(&init.display().to_string()),
+        quote(name)
+    );
+    let _ = writeln!(script, "sif {} mode 0100755", quote(name));
+    let _ = writeln!(script, "sif {} uid 0", quote(name));
+    let _ = writeln!(script, "sif {} gid 0", quote(name));
+    let _ = writeln!(script, "cd /");
+}
+
+// Split `path` into its parent directory, with symbolic links resolved within `root`, and its
+// name.
+fn split<'a>(root: &Path, path: &'a str) -> (String, &'a str) {
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let parent = resolve(root, Path::new(parent));
+    (format!("/{}", parent.display()), name)
+}
+
+// Resolve the symbolic links of `path` within `root`, as the guest would.
+fn resolve(root: &Path, path: &Path) -> PathBuf {
+    const MAX_LINKS: usize = 40;
+    let mut resolved = PathBuf::new();
+    let mut pending: Vec<PathBuf> = path.iter().rev().map(PathBuf::from).collect();
+    let mut links = 0;
+    while let Some(part) = pending.pop() {
+        match part.to_str() {
+            Some("/" | "." | "") => (),
+            Some("..") => {
+                resolved.pop();
+            }
+            _ => {
+                let candidate = resolved.join(&part);
+                match fs::read_link(root.join(&candidate)) {
+                    Ok(target) if links < MAX_LINKS => {
+                        links += 1;
+                        if target.is_absolute() {
+                            resolved = PathBuf::new();
+                        }
+                        pending.extend(target.iter().rev().map(PathBuf::from));
+                    }
+                    _ => resolved = candidate,
+                }
+            }
+        }
+    }
+    resolved
+}
+
+// A `/bin/sh` script that runs the entrypoint and command of an image.
+fn entrypoint_script(config: &ImageConfig) -> Result<String, ImageError> {
+    let command: Vec<&String> = config
+        .entrypoint
+        .iter()
+        .flatten()
+        .chain(config.cmd.iter().flatten())
+        .collect();
+    if command.is_empty() {
+        return Err(ImageError::NoEntrypoint);
+    }
+
+    let mut script = String::from(
+        "#!/bin/sh\n\
+         mkdir -p /proc /sys /dev\n\
+         mount -t proc proc /proc\n\
+         mount -t sysfs sysfs /sys\n\
+         mount -t devtmpfs devtmpfs /dev\n",
+    );
+    for variable in config.env.iter().flatten() {
+        let _ = writeln!(script, "export {}", shell_quote(variable));
+    }
+    if let Some(dir) = config.working_dir.as_ref().filter(|dir| !dir.is_empty()) {
+        let _ = writeln!(script, "cd {}", shell_quote(dir));
+    }
+    let command: Vec<String> = command.iter().map(|arg| shell_quote(arg)).collect();
+    let _ = writeln!(script, "exec {}", command.join(" "));
+    Ok(script)
+}
+
+// Quote `arg` for `/bin/sh`.
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+// Quote a path for `debugfs`. Paths of image tarballs with quotes or control characters, e.g.
+// newlines that would end the command, are rejected when unpacking them.
+fn quote(path: &str) -> String {
+    format!("\"{path}\"")
+}
+
+// Space used by the files under `dir`, in bytes, counting hard links once.
+fn disk_usage(dir: &Path) -> std::io::Result<u64> {
+    const BLOCK_SIZE: u64 = 4096;
+    let mut seen = std::collections::HashSet::new();
+    let mut total = 0;
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            }
+            if seen.insert((metadata.dev(), metadata.ino())) {
+                total += metadata.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE + BLOCK_SIZE;
+            }
+        }
+    }
+    Ok(total)
+}
+
+// Run `debugfs`, which succeeds even when its commands fail, and fail if they reported errors.
+fn run_debugfs(command: &mut Command) -> Result<(), ImageError> {
+    let output = util::output(command)?;
+    // The first line is the version banner
+    let errors: Vec<_> = String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .skip(1)
+        .map(str::to_owned)
+        .collect();
+    if output.status.success() && errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ImageError::Command("debugfs".to_owned(), errors.join("\n")))
+    }
+}
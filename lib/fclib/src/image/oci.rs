@@ -0,0 +1,547 @@
+//! Unpacking of OCI and `docker save` image tarballs
+//!
+//! Both formats store the layers of the image as tarballs, listed by a manifest: `manifest.json`
+//! for `docker save`, `index.json` and the blobs it refers to for OCI image layouts. Layers are
+//! applied in order, along with their whiteouts, i.e. `.wh.<name>` files that delete `<name>` from
+//! the layers below, and `.wh..wh..opq` files that empty their directory.
+//!
+//! Unpacking does not require root. Owners, permissions and device nodes that cannot be applied
+//! on the host are returned as [`Fixup`]s, to apply to the filesystem image afterwards.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use serde_derive::Deserialize;
+use tar::{Archive, EntryType};
+
+use super::ImageError;
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// How an entry of the image should look in the filesystem, as opposed to how it was unpacked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Fixup {
+    /// Mode, file type included
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    /// Major and minor numbers of device nodes, which are not created on the host
+    pub device: Option<(u32, u32)>,
+}
+
+/// Runtime configuration of an image
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ImageConfig {
+    /// Command run by the container, before `cmd`
+    #[serde(default)]
+    pub entrypoint: Option<Vec<String>>,
+    /// Arguments of the entrypoint, or command if there is no entrypoint
+    #[serde(default)]
+    pub cmd: Option<Vec<String>>,
+    /// Environment, as `NAME=value`
+    #[serde(default)]
+    pub env: Option<Vec<String>>,
+    /// Working directory of the command
+    #[serde(default)]
+    pub working_dir: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    config: ImageConfig,
+}
+
+// An entry of the `manifest.json` of `docker save`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerManifest {
+    config: String,
+    layers: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Descriptor {
+    #[serde(rename = "mediaType", default)]
+    media_type: String,
+    digest: String,
+    #[serde(default)]
+    platform: Option<Platform>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Platform {
+    architecture: String,
+}
+
+// An OCI image index, or image manifest
+#[derive(Debug, Deserialize)]
+struct OciManifest {
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+    config: Option<Descriptor>,
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+}
+
+/// Unpack the image tarball `tarball` into `root`, using `scratch` for its blobs
+///
+/// Returns the runtime configuration of the image, and the fixups to apply, by path.
+pub(super) fn unpack(
+    tarball: &Path,
+    root: &Path,
+    scratch: &Path,
+) -> Result<(ImageConfig, BTreeMap<String, Fixup>), ImageError> {
+    Archive::new(BufReader::new(File::open(tarball)?)).unpack(scratch)?;
+
+    let (config, layers): (PathBuf, Vec<PathBuf>) = if scratch.join("manifest.json").is_file() {
+        let manifests: Vec<DockerManifest> = read_json(&scratch.join("manifest.json"))?;
+        let manifest = manifests
+            .into_iter()
+            .next()
+            .ok_or_else(|| invalid("`manifest.json` lists no image"))?;
+        let layers = manifest
+            .layers
+            .iter()
+            .map(|layer| scratch.join(layer))
+            .collect();
+        (scratch.join(manifest.config), layers)
+    } else if scratch.join("index.json").is_file() {
+        let mut manifest: OciManifest = read_json(&scratch.join("index.json"))?;
+        // Follow image indexes down to the image manifest for the architecture of the host
+        while manifest.config.is_none() {
+            let descriptor = pick_manifest(&manifest.manifests)
+                .ok_or_else(|| invalid("the image index lists no image"))?;
+            manifest = read_json(&blob(scratch, &descriptor.digest)?)?;
+        }
+        let config = manifest
+            .config
+            .as_ref()
+            .map(|config| config.digest.as_str());
+        let layers = manifest
+            .layers
+            .iter()
+            .map(|layer| blob(scratch, &layer.digest))
+            .collect::<Result<_, _>>()?;
+        (blob(scratch, config.unwrap_or_default())?, layers)
+    } else {
+        return Err(invalid("neither `manifest.json` nor `index.json` found"));
+    };
+
+    let config = read_json::<ConfigFile>(&config)?.config;
+    let mut fixups = BTreeMap::new();
+    for layer in &layers {
+        apply_layer(layer, root, &mut fixups)?;
+    }
+    Ok((config, fixups))
+}
+
+// Apply the layer tarball `layer` on top of `root`.
+fn apply_layer(
+    layer: &Path,
+    root: &Path,
+    fixups: &mut BTreeMap<String, Fixup>,
+) -> Result<(), ImageError> {
+    let mut reader = BufReader::new(File::open(layer)?);
+    let mut magic = [0; 4];
+    let read = reader.get_mut().read(&mut magic)?;
+    reader = BufReader::new(File::open(layer)?);
+    let reader: Box<dyn Read> = match &magic[..read] {
+        [0x1f, 0x8b, ..] => Box::new(GzDecoder::new(reader)),
+        [0x28, 0xb5, 0x2f, 0xfd] => return Err(invalid("zstd-compressed layers are unsupported")),
+        _ => Box::new(reader),
+    };
+
+    // Entries of this layer, which opaque whiteouts must not delete
+    let mut added = HashSet::new();
+    let mut archive = Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = normalize(&entry.path()?)?;
+        // Symbolic links are resolved when fixing up the entries below them
+        check_name(&path)?;
+        if let Some(target) = entry.link_name()? {
+            check_name(&target)?;
+        }
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let parent = path.parent().unwrap_or(Path::new(""));
+
+        if name == OPAQUE_WHITEOUT {
+            let dir = host_path(root, &path)?;
+            let children = match fs::read_dir(dir.parent().unwrap_or(root)) {
+                Ok(children) => children,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            for child in children {
+                let child = parent.join(child?.file_name());
+                if !added.contains(&child) {
+                    remove(root, &child, fixups)?;
+                }
+            }
+            continue;
+        }
+        if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+            remove(root, &parent.join(hidden), fixups)?;
+            continue;
+        }
+
+        let header = entry.header();
+        let entry_type = header.entry_type();
+        let mut mode = header.mode()? & 0o7777;
+        let (uid, gid) = (header.uid()?, header.gid()?);
+        let mut device = None;
+        let file_type = match entry_type {
+            EntryType::Directory => S_IFDIR,
+            EntryType::Symlink => S_IFLNK,
+            EntryType::Char | EntryType::Block => {
+                let major = header.device_major()?.unwrap_or_default();
+                let minor = header.device_minor()?.unwrap_or_default();
+                device = Some((major, minor));
+                if entry_type == EntryType::Char {
+                    S_IFCHR
+                } else {
+                    S_IFBLK
+                }
+            }
+            EntryType::Link => {
+                // Hard links share the inode, and the fixup, of their target
+                let target = normalize(&entry.link_name()?.unwrap_or_default())?;
+                let fixup = fixups.get(&key(&target)).cloned();
+                replace(root, &path, false, fixups)?;
+                entry.unpack_in(root)?;
+                if let Some(fixup) = fixup {
+                    fixups.insert(key(&path), fixup);
+                }
+                added.insert(path);
+                continue;
+            }
+            _ => S_IFREG,
+        };
+        if file_type == S_IFLNK {
+            mode = 0o777;
+        }
+
+        let is_dir = file_type == S_IFDIR;
+        replace(root, &path, is_dir, fixups)?;
+        if device.is_none() {
+            entry.unpack_in(root)?;
+        }
+        // Keep the entry readable and writable by the user unpacking the image; its mode is fixed
+        // in the filesystem image.
+        if file_type == S_IFDIR || file_type == S_IFREG {
+            let host_mode = if is_dir { mode | 0o700 } else { mode | 0o600 };
+            fs::set_permissions(
+                host_path(root, &path)?,
+                fs::Permissions::from_mode(host_mode),
+            )?;
+        }
+
+        // Directories created implicitly, for entries listed before their parent, belong to root
+        for ancestor in path
+            .ancestors()
+            .skip(1)
+            .filter(|a| !a.as_os_str().is_empty())
+        {
+            fixups.entry(key(ancestor)).or_insert(Fixup {
+                mode: S_IFDIR | 0o755,
+                uid: 0,
+                gid: 0,
+                device: None,
+            });
+        }
+        fixups.insert(
+            key(&path),
+            Fixup {
+                mode: file_type | mode,
+                uid,
+                gid,
+                device,
+            },
+        );
+        added.insert(path);
+    }
+    Ok(())
+}
+
+// Remove whatever is at `path`, unless it is a directory and `is_dir` is set, before unpacking an
+// entry there.
+fn replace(
+    root: &Path,
+    path: &Path,
+    is_dir: bool,
+    fixups: &mut BTreeMap<String, Fixup>,
+) -> Result<(), ImageError> {
+    match fs::symlink_metadata(host_path(root, path)?) {
+        Ok(metadata) if metadata.is_dir() && is_dir => Ok(()),
+        Ok(_) => remove(root, path, fixups),
+        Err(_) => Ok(()),
+    }
+}
+
+// Remove `path` from `root`, recursively, along with its fixups.
+fn remove(
+    root: &Path,
+    path: &Path,
+    fixups: &mut BTreeMap<String, Fixup>,
+) -> Result<(), ImageError> {
+    let host_path = host_path(root, path)?;
+    match fs::symlink_metadata(&host_path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&host_path)?,
+        Ok(_) => fs::remove_file(&host_path)?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err.into()),
+    }
+
+    let removed = key(path);
+    let prefix = format!("{removed}/");
+    fixups.retain(|path, _| *path != removed && !path.starts_with(&prefix));
+    Ok(())
+}
+
+// Path of `path` on the host, refusing paths below a symbolic link, which earlier layers could
+// point out of `root`. The last component is not resolved, so that a symbolic link there is itself
+// replaced or removed.
+fn host_path(root: &Path, path: &Path) -> Result<PathBuf, ImageError> {
+    let mut host_path = root.to_path_buf();
+    for component in path.parent().into_iter().flat_map(Path::components) {
+        host_path.push(component);
+        match fs::symlink_metadata(&host_path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                return Err(invalid(&format!(
+                    "`{}` is below the symbolic link `{}`",
+                    key(path),
+                    key(host_path.strip_prefix(root).unwrap_or(&host_path)),
+                )))
+            }
+            Ok(_) => (),
+            // Nothing exists below a missing directory
+            Err(err) if err.kind() == io::ErrorKind::NotFound => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(root.join(path))
+}
+
+// Pick, among the manifests of an image index, the one for the architecture of the host.
+fn pick_manifest(manifests: &[Descriptor]) -> Option<&Descriptor> {
+    let arch = match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        arch => arch,
+    };
+    let is_image = |descriptor: &&Descriptor| !descriptor.media_type.contains("attestation");
+    manifests
+        .iter()
+        .filter(is_image)
+        .find(|descriptor| {
+            descriptor
+                .platform
+                .as_ref()
+                .is_some_and(|platform| platform.architecture == arch)
+        })
+        .or_else(|| manifests.iter().find(is_image))
+}
+
+// Path of the blob with `digest`, e.g. `sha256:abcd...`, in an OCI image layout.
+fn blob(layout: &Path, digest: &str) -> Result<PathBuf, ImageError> {
+    let (algorithm, hash) = digest
+        .split_once(':')
+        .filter(|(algorithm, hash)| !algorithm.contains('/') && !hash.contains('/'))
+        .ok_or_else(|| invalid(&format!("invalid digest `{digest}`")))?;
+    Ok(layout.join("blobs").join(algorithm).join(hash))
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, ImageError> {
+    let file = File::open(path)
+        .map_err(|err| invalid(&format!("cannot read {}: {err}", path.display())))?;
+    Ok(serde_json::from_reader(BufReader::new(file))?)
+}
+
+// Normalize the path of a tarball entry, e.g. `./usr/bin/` to `usr/bin`.
+fn normalize(path: &Path) -> Result<PathBuf, ImageError> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::RootDir | Component::CurDir => (),
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(invalid(&format!("invalid path `{}`", path.display())))
+            }
+        }
+    }
+    Ok(normalized)
+}
+
+// Reject paths that cannot be quoted in the `debugfs` script applying the fixups, which reads a
+// command per line and does not unescape quotes.
+fn check_name(path: &Path) -> Result<(), ImageError> {
+    let name = path.to_string_lossy();
+    if name.chars().any(|c| c.is_control() || c == '"') {
+        return Err(invalid(&format!("unsupported character in path {name:?}")));
+    }
+    Ok(())
+}
+
+// Key of a path in the fixups, i.e. its absolute path in the image.
+fn key(path: &Path) -> String {
+    format!("/{}", path.display())
+}
+
+fn invalid(message: &str) -> ImageError {
+    ImageError::InvalidImage(message.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tar::{Builder, Header};
+
+    enum Entry<'a> {
+        File(&'a str, &'a [u8]),
+        Dir(&'a str),
+        Symlink(&'a str, &'a Path),
+    }
+
+    fn layer(entries: &[Entry]) -> Vec<u8> {
+        let mut builder = Builder::new(Vec::new());
+        for entry in entries {
+            let mut header = Header::new_gnu();
+            header.set_mode(0o644);
+            header.set_size(0);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            match entry {
+                Entry::File(path, data) => {
+                    header.set_size(data.len() as u64);
+                    builder.append_data(&mut header, path, *data).unwrap();
+                }
+                Entry::Dir(path) => {
+                    header.set_entry_type(EntryType::Directory);
+                    header.set_mode(0o755);
+                    builder.append_data(&mut header, path, io::empty()).unwrap();
+                }
+                Entry::Symlink(path, target) => {
+                    header.set_entry_type(EntryType::Symlink);
+                    builder.append_link(&mut header, path, target).unwrap();
+                }
+            }
+        }
+        builder.into_inner().unwrap()
+    }
+
+    // A `docker save` tarball of `layers`, unpacked into a new root.
+    fn unpack_layers(
+        dir: &Path,
+        layers: &[Vec<u8>],
+    ) -> Result<(PathBuf, BTreeMap<String, Fixup>), ImageError> {
+        let names: Vec<_> = (0..layers.len())
+            .map(|i| format!("{i}/layer.tar"))
+            .collect();
+        let manifest = serde_json::json!([{ "Config": "config.json", "Layers": names }]);
+
+        let mut builder = Builder::new(Vec::new());
+        let mut files = vec![
+            (
+                "manifest.json".to_owned(),
+                manifest.to_string().into_bytes(),
+            ),
+            ("config.json".to_owned(), b"{}".to_vec()),
+        ];
+        files.extend(names.into_iter().zip(layers.iter().cloned()));
+        for (path, data) in files {
+            let mut header = Header::new_gnu();
+            header.set_mode(0o644);
+            header.set_size(data.len() as u64);
+            builder.append_data(&mut header, path, &data[..]).unwrap();
+        }
+        let tarball = dir.join("image.tar");
+        fs::write(&tarball, builder.into_inner().unwrap()).unwrap();
+
+        let (root, scratch) = (dir.join("root"), dir.join("scratch"));
+        fs::create_dir_all(&root).unwrap();
+        fs::create_dir_all(&scratch).unwrap();
+        let (_, fixups) = unpack(&tarball, &root, &scratch)?;
+        Ok((root, fixups))
+    }
+
+    #[test]
+    fn whiteouts() {
+        let dir = tempfile::tempdir().unwrap();
+        let layers = [
+            layer(&[
+                Entry::Dir("etc"),
+                Entry::File("etc/passwd", b"root"),
+                Entry::File("etc/shadow", b"root"),
+                Entry::Dir("var"),
+                Entry::File("var/log", b"log"),
+            ]),
+            layer(&[
+                Entry::File("etc/.wh.shadow", b""),
+                Entry::File("var/.wh..wh..opq", b""),
+                Entry::File("var/run", b"run"),
+            ]),
+        ];
+
+        let (root, fixups) = unpack_layers(dir.path(), &layers).unwrap();
+        assert!(root.join("etc/passwd").is_file());
+        assert!(!root.join("etc/shadow").exists());
+        assert!(!root.join("var/log").exists());
+        assert!(root.join("var/run").is_file());
+        assert!(!fixups.contains_key("/etc/shadow"));
+        assert!(fixups.contains_key("/var/run"));
+    }
+
+    #[test]
+    fn names_breaking_debugfs_scripts() {
+        let dir = tempfile::tempdir().unwrap();
+        for entry in [
+            Entry::File("x\nwrite /etc/hostname leaked\n", b""),
+            Entry::File("etc/\"hostname", b""),
+            Entry::Symlink("sbin", Path::new("x\nwrite /etc/hostname leaked")),
+        ] {
+            let scratch = tempfile::tempdir_in(dir.path()).unwrap();
+            assert!(matches!(
+                unpack_layers(scratch.path(), &[layer(&[entry])]),
+                Err(ImageError::InvalidImage(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn whiteout_below_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let victim = dir.path().join("victim");
+        fs::create_dir(&victim).unwrap();
+        fs::write(victim.join("secret"), b"secret").unwrap();
+
+        for second in [
+            layer(&[Entry::File("evil/.wh.secret", b"")]),
+            layer(&[Entry::File("evil/.wh..wh..opq", b"")]),
+            layer(&[Entry::File("evil/secret", b"overwritten")]),
+        ] {
+            let layers = [layer(&[Entry::Symlink("evil", &victim)]), second];
+            let scratch = tempfile::tempdir_in(dir.path()).unwrap();
+            assert!(matches!(
+                unpack_layers(scratch.path(), &layers),
+                Err(ImageError::InvalidImage(_))
+            ));
+            assert_eq!(fs::read(victim.join("secret")).unwrap(), b"secret");
+        }
+    }
+}
@@ -0,0 +1,466 @@
+//! Reader of qcow2 images
+//!
+//! See the [specification](https://gitlab.com/qemu-project/qemu/-/blob/master/docs/interop/qcow2.txt).
+
+use std::fs::File;
+use std::path::Path;
+
+use flate2::read::DeflateDecoder;
+
+use super::convert::{backing_path, inflate, read_exact_at, Chain, DiskFormat, Layer};
+use super::ImageError;
+
+// Bits of the incompatible features field
+const INCOMPAT_CORRUPT: u64 = 1 << 1;
+const INCOMPAT_DATA_FILE: u64 = 1 << 2;
+const INCOMPAT_EXTL2: u64 = 1 << 4;
+const INCOMPAT_KNOWN: u64 = 0x1f;
+
+// Header extension holding the format of the backing file
+const EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+
+// Bits of L1 and L2 table entries
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const COPIED: u64 = 1 << 63;
+const COMPRESSED: u64 = 1 << 62;
+const ZERO: u64 = 1;
+
+// Largest L1 table accepted, as in QEMU
+const MAX_L1_SIZE: u64 = 32 << 20;
+
+#[derive(Debug)]
+pub(super) struct Qcow2 {
+    file: File,
+    file_len: u64,
+    size: u64,
+    cluster_bits: u32,
+    l1: Vec<u64>,
+    // Last L2 table read, with its offset
+    l2: Option<(u64, Vec<u64>)>,
+    // Last compressed cluster read, with its L2 entry
+    compressed: Option<(u64, Vec<u8>)>,
+    backing: Option<Box<dyn Layer>>,
+}
+
+fn be32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn be64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn invalid(message: &str) -> ImageError {
+    ImageError::InvalidDisk(format!("qcow2: {message}"))
+}
+
+impl Qcow2 {
+    // Open the qcow2 image `file`, at `path`, and its backing files.
+    pub(super) fn open(file: File, path: &Path, chain: &mut Chain) -> Result<Qcow2, ImageError> {
+        let mut header = [0; 104];
+        read_exact_at(&file, &mut header[..72], 0)?;
+        let version = be32(&header, 4);
+        let backing_file_offset = be64(&header, 8);
+        let backing_file_size = be32(&header, 16);
+        let cluster_bits = be32(&header, 20);
+        let size = be64(&header, 24);
+        let crypt_method = be32(&header, 32);
+        let l1_size = u64::from(be32(&header, 36));
+        let l1_table_offset = be64(&header, 40);
+
+        let header_length = match version {
+            2 => 72,
+            3 => {
+                read_exact_at(&file, &mut header[72..], 72)?;
+                be32(&header, 100)
+            }
+            _ => {
+                return Err(ImageError::UnsupportedDisk(format!(
+                    "qcow2 version {version}"
+                )))
+            }
+        };
+        if version == 3 {
+            let incompatible = be64(&header, 72);
+            if incompatible & INCOMPAT_CORRUPT != 0 {
+                return Err(invalid("the image is marked as corrupt"));
+            }
+            if incompatible & INCOMPAT_DATA_FILE != 0 {
+                return Err(ImageError::UnsupportedDisk(
+                    "qcow2 images with an external data file".to_owned(),
+                ));
+            }
+            if incompatible & INCOMPAT_EXTL2 != 0 {
+                return Err(ImageError::UnsupportedDisk(
+                    "qcow2 images with extended L2 entries".to_owned(),
+                ));
+            }
+            if incompatible & !INCOMPAT_KNOWN != 0 {
+                return Err(ImageError::UnsupportedDisk(format!(
+                    "qcow2 incompatible features {incompatible:#x}"
+                )));
+            }
+            // The compression type is only present in longer headers, deflate by default
+            if header_length > 104 {
+                let mut compression_type = [0];
+                read_exact_at(&file, &mut compression_type, 104)?;
+                if compression_type[0] != 0 {
+                    return Err(ImageError::UnsupportedDisk(
+                        "qcow2 images compressed with zstd".to_owned(),
+                    ));
+                }
+            }
+        }
+        if crypt_method != 0 {
+            return Err(ImageError::UnsupportedDisk(
+                "encrypted qcow2 images".to_owned(),
+            ));
+        }
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(invalid(&format!("invalid cluster size 2^{cluster_bits}")));
+        }
+
+        // Each L2 table maps a cluster of clusters
+        let l2_bits = cluster_bits - 3;
+        let needed = size.div_ceil(1 << (cluster_bits + l2_bits));
+        if l1_size < needed || l1_size * 8 > MAX_L1_SIZE {
+            return Err(invalid(&format!("invalid L1 table size {l1_size}")));
+        }
+        let mut l1 = vec![0; l1_size as usize * 8];
+        read_exact_at(&file, &mut l1, l1_table_offset)?;
+        let l1 = l1.chunks_exact(8).map(|entry| be64(entry, 0)).collect();
+
+        let backing = if backing_file_offset == 0 {
+            None
+        } else {
+            if backing_file_size > 1023 {
+                return Err(invalid("backing file name too long"));
+            }
+            let mut name = vec![0; backing_file_size as usize];
+            read_exact_at(&file, &mut name, backing_file_offset)?;
+            let name =
+                String::from_utf8(name).map_err(|_| invalid("backing file name is not UTF-8"))?;
+            let format = backing_format(&file, header_length, cluster_bits)?;
+            Some(chain.open(&backing_path(path, &name), format)?)
+        };
+
+        Ok(Qcow2 {
+            file_len: file.metadata()?.len(),
+            file,
+            size,
+            cluster_bits,
+            l1,
+            l2: None,
+            compressed: None,
+            backing,
+        })
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    // L2 entry of the cluster `index`, or 0 if its L2 table is not allocated.
+    fn l2_entry(&mut self, index: u64) -> Result<u64, ImageError> {
+        let l2_bits = self.cluster_bits - 3;
+        let l1_entry = self.l1[(index >> l2_bits) as usize];
+        let l2_offset = l1_entry & OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(0);
+        }
+
+        if self
+            .l2
+            .as_ref()
+            .is_none_or(|(offset, _)| *offset != l2_offset)
+        {
+            let mut table = vec![0; self.cluster_size() as usize];
+            read_exact_at(&self.file, &mut table, l2_offset)?;
+            let table = table.chunks_exact(8).map(|entry| be64(entry, 0)).collect();
+            self.l2 = Some((l2_offset, table));
+        }
+        let (_, table) = self.l2.as_ref().unwrap();
+        Ok(table[(index & ((1 << l2_bits) - 1)) as usize])
+    }
+
+    // Decompressed content of the cluster with the L2 entry `entry`
+    fn compressed_cluster(&mut self, entry: u64) -> Result<&[u8], ImageError> {
+        if self
+            .compressed
+            .as_ref()
+            .is_none_or(|(cached, _)| *cached != entry)
+        {
+            // The offset is followed by the number of additional 512-byte sectors
+            let offset_bits = 62 - (self.cluster_bits - 8);
+            let offset = entry & ((1 << offset_bits) - 1);
+            let sectors = (entry >> offset_bits) & ((1 << (self.cluster_bits - 8)) - 1);
+            let len = (sectors + 1) * 512 - (offset & 511);
+            // The last compressed cluster may end before the last sector
+            let len = len.min(self.file_len.saturating_sub(offset));
+
+            let mut data = vec![0; len as usize];
+            read_exact_at(&self.file, &mut data, offset)?;
+            let cluster = inflate(DeflateDecoder::new(&data[..]), self.cluster_size() as usize)?;
+            self.compressed = Some((entry, cluster));
+        }
+        Ok(&self.compressed.as_ref().unwrap().1)
+    }
+
+    // Read `buf`, which does not cross a cluster boundary, at `offset`.
+    fn read_cluster(&mut self, offset: u64, buf: &mut [u8]) -> Result<bool, ImageError> {
+        let in_cluster = (offset & (self.cluster_size() - 1)) as usize;
+        let entry = self.l2_entry(offset >> self.cluster_bits)? & !COPIED;
+
+        if entry & COMPRESSED != 0 {
+            let cluster = self.compressed_cluster(entry & !COMPRESSED)?;
+            buf.copy_from_slice(&cluster[in_cluster..in_cluster + buf.len()]);
+            return Ok(true);
+        }
+        if entry & ZERO != 0 {
+            buf.fill(0);
+            return Ok(false);
+        }
+        let host_offset = entry & OFFSET_MASK;
+        if host_offset == 0 {
+            // Not allocated in this image
+            return match &mut self.backing {
+                Some(backing) => backing.read_at(offset, buf),
+                None => {
+                    buf.fill(0);
+                    Ok(false)
+                }
+            };
+        }
+        read_exact_at(&self.file, buf, host_offset + in_cluster as u64)?;
+        Ok(true)
+    }
+}
+
+impl Layer for Qcow2 {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<bool, ImageError> {
+        let mut data = false;
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let in_cluster = position & (self.cluster_size() - 1);
+            let len = ((self.cluster_size() - in_cluster) as usize).min(buf.len() - done);
+            let chunk = &mut buf[done..done + len];
+            if position >= self.size {
+                chunk.fill(0);
+            } else {
+                data |= self.read_cluster(position, chunk)?;
+            }
+            done += len;
+        }
+        Ok(data)
+    }
+}
+
+// Format of the backing file, from the header extensions following the header.
+fn backing_format(
+    file: &File,
+    header_length: u32,
+    cluster_bits: u32,
+) -> Result<Option<DiskFormat>, ImageError> {
+    // Header extensions end with the first cluster
+    let end = 1u64 << cluster_bits;
+    let mut offset = u64::from(header_length);
+    while offset + 8 <= end {
+        let mut extension = [0; 8];
+        read_exact_at(file, &mut extension, offset)?;
+        let (kind, len) = (be32(&extension, 0), be32(&extension, 4));
+        offset += 8;
+        match kind {
+            0 => break,
+            EXT_BACKING_FORMAT => {
+                if offset + u64::from(len) > end {
+                    return Err(invalid("header extension past the first cluster"));
+                }
+                let mut name = vec![0; len as usize];
+                read_exact_at(file, &mut name, offset)?;
+                let name = String::from_utf8_lossy(&name);
+                return DiskFormat::from_name(&name).map(Some);
+            }
+            _ => {}
+        }
+        offset += u64::from(len).next_multiple_of(8);
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+
+    use flate2::write::DeflateEncoder;
+    use flate2::Compression;
+
+    use super::*;
+    use crate::image::DiskImage;
+
+    // Images have 512-byte clusters: the header and its extensions, the L1 table, a single L2
+    // table, then data clusters.
+    const CLUSTER: usize = 512;
+    const L1_OFFSET: usize = CLUSTER;
+    const L2_OFFSET: usize = 2 * CLUSTER;
+    const BACKING_NAME_OFFSET: usize = 256;
+
+    fn put(image: &mut [u8], offset: usize, bytes: &[u8]) {
+        image[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    // A version 3 image of `size` bytes, on top of `backing`, as (name, format).
+    fn qcow2(size: u64, backing: Option<(&str, &str)>) -> Vec<u8> {
+        let mut image = vec![0; 3 * CLUSTER];
+        put(&mut image, 0, b"QFI\xfb");
+        put(&mut image, 4, &3u32.to_be_bytes());
+        put(&mut image, 20, &9u32.to_be_bytes());
+        put(&mut image, 24, &size.to_be_bytes());
+        put(&mut image, 36, &1u32.to_be_bytes());
+        put(&mut image, 40, &(L1_OFFSET as u64).to_be_bytes());
+        put(&mut image, 100, &104u32.to_be_bytes());
+        put(
+            &mut image,
+            L1_OFFSET,
+            &(L2_OFFSET as u64 | COPIED).to_be_bytes(),
+        );
+        if let Some((name, format)) = backing {
+            put(&mut image, 8, &(BACKING_NAME_OFFSET as u64).to_be_bytes());
+            put(&mut image, 16, &(name.len() as u32).to_be_bytes());
+            put(&mut image, BACKING_NAME_OFFSET, name.as_bytes());
+            put(&mut image, 104, &EXT_BACKING_FORMAT.to_be_bytes());
+            put(&mut image, 108, &(format.len() as u32).to_be_bytes());
+            put(&mut image, 112, format.as_bytes());
+        }
+        image
+    }
+
+    fn set_l2_entry(image: &mut [u8], index: usize, entry: u64) {
+        put(image, L2_OFFSET + index * 8, &entry.to_be_bytes());
+    }
+
+    // Append `data` as the cluster `index`.
+    fn add_cluster(image: &mut Vec<u8>, index: usize, data: &[u8]) {
+        let offset = image.len() as u64;
+        image.extend_from_slice(data);
+        image.resize(image.len().next_multiple_of(CLUSTER), 0);
+        set_l2_entry(image, index, offset | COPIED);
+    }
+
+    fn convert(dir: &Path, image: &[u8]) -> Result<Vec<u8>, ImageError> {
+        let (path, raw) = (dir.join("disk.qcow2"), dir.join("disk.raw"));
+        fs::write(&path, image).unwrap();
+        DiskImage::open(&path)?.convert(&raw)?;
+        Ok(fs::read(raw).unwrap())
+    }
+
+    #[test]
+    fn allocated_and_unallocated_clusters() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut image = qcow2(4 * CLUSTER as u64, None);
+        add_cluster(&mut image, 1, &[0xaa; CLUSTER]);
+
+        let raw = convert(dir.path(), &image).unwrap();
+        assert_eq!(raw.len(), 4 * CLUSTER);
+        assert!(raw[..CLUSTER].iter().all(|&b| b == 0));
+        assert!(raw[CLUSTER..2 * CLUSTER].iter().all(|&b| b == 0xaa));
+        assert!(raw[2 * CLUSTER..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn compressed_clusters() {
+        let dir = tempfile::tempdir().unwrap();
+        let cluster: Vec<u8> = (0..CLUSTER).map(|i| (i % 251) as u8).collect();
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&cluster).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut image = qcow2(2 * CLUSTER as u64, None);
+        let offset = image.len() as u64;
+        image.extend_from_slice(&compressed);
+        // With 512-byte clusters, the number of additional sectors starts at bit 61
+        let sectors = (compressed.len() as u64).div_ceil(512) - 1;
+        set_l2_entry(&mut image, 0, COMPRESSED | offset | (sectors << 61));
+
+        let raw = convert(dir.path(), &image).unwrap();
+        assert_eq!(raw[..CLUSTER], cluster[..]);
+        assert!(raw[CLUSTER..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn backing_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("base.raw"), [0x55; 3 * CLUSTER]).unwrap();
+
+        let mut image = qcow2(4 * CLUSTER as u64, Some(("base.raw", "raw")));
+        add_cluster(&mut image, 0, &[0xaa; CLUSTER]);
+        set_l2_entry(&mut image, 2, ZERO);
+
+        let raw = convert(dir.path(), &image).unwrap();
+        let clusters: Vec<_> = raw.chunks(CLUSTER).collect();
+        assert!(clusters[0].iter().all(|&b| b == 0xaa));
+        // Unallocated clusters come from the backing file, zeros past its end
+        assert!(clusters[1].iter().all(|&b| b == 0x55));
+        assert!(clusters[2].iter().all(|&b| b == 0));
+        assert!(clusters[3].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn backing_loop() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = qcow2(CLUSTER as u64, Some(("disk.qcow2", "qcow2")));
+        assert!(matches!(
+            convert(dir.path(), &image),
+            Err(ImageError::InvalidDisk(_))
+        ));
+    }
+
+    #[test]
+    fn truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut image = qcow2(4 * CLUSTER as u64, None);
+        add_cluster(&mut image, 0, &[0xaa; CLUSTER]);
+        for len in [50, 100, L1_OFFSET + 4, L2_OFFSET + 100, image.len() - 1] {
+            assert!(
+                matches!(
+                    convert(dir.path(), &image[..len]),
+                    Err(ImageError::InvalidDisk(_))
+                ),
+                "truncated at {len}"
+            );
+        }
+    }
+
+    #[test]
+    fn overflowing_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let invalid =
+            |image: &[u8]| matches!(convert(dir.path(), image), Err(ImageError::InvalidDisk(_)));
+
+        // A disk larger than its L1 table maps
+        let image = qcow2(u64::MAX, None);
+        assert!(invalid(&image));
+
+        // An L1 table larger than QEMU accepts
+        let mut image = qcow2(CLUSTER as u64, None);
+        put(&mut image, 36, &u32::MAX.to_be_bytes());
+        assert!(invalid(&image));
+
+        // A backing format extension past the first cluster, or 4 GiB long
+        for len in [CLUSTER as u32, u32::MAX] {
+            let mut image = qcow2(CLUSTER as u64, Some(("base.raw", "raw")));
+            put(&mut image, 108, &len.to_be_bytes());
+            assert!(invalid(&image));
+        }
+
+        // A backing file name longer than QEMU accepts
+        let mut image = qcow2(CLUSTER as u64, Some(("base.raw", "raw")));
+        put(&mut image, 16, &u32::MAX.to_be_bytes());
+        assert!(invalid(&image));
+    }
+}
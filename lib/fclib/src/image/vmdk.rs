@@ -0,0 +1,495 @@
+//! Reader of VMDK sparse extents
+//!
+//! Monolithic sparse images, optionally with a parent image, and stream-optimized images, whose
+//! grains are compressed, are supported. Images made of a descriptor file and separate extents
+//! are not.
+
+use std::fs::File;
+use std::path::Path;
+
+use flate2::read::ZlibDecoder;
+
+use super::convert::{backing_path, inflate, read_exact_at, Chain, Layer};
+use super::ImageError;
+
+const SECTOR_SIZE: u64 = 512;
+
+// Bits of the flags field of the header
+const FLAG_COMPRESSED: u32 = 1 << 16;
+
+// Grain directory offset of stream-optimized images, whose footer holds the actual offset
+const GD_AT_END: u64 = u64::MAX;
+
+// Grain table entry of a grain that only holds zeros
+const ZERO_GRAIN: u32 = 1;
+
+// Largest grain and grain table accepted
+const MAX_GRAIN_SECTORS: u64 = 1 << 17;
+const MAX_GTES_PER_GT: u32 = 1 << 16;
+
+#[derive(Debug)]
+pub(super) struct Vmdk {
+    file: File,
+    file_len: u64,
+    size: u64,
+    grain_size: u64,
+    gtes_per_gt: u32,
+    compressed: bool,
+    gd: Vec<u32>,
+    // Last grain table read, with its index
+    gt: Option<(usize, Vec<u32>)>,
+    // Last compressed grain read, with its offset
+    grain: Option<(u64, Vec<u8>)>,
+    parent: Option<Box<dyn Layer>>,
+}
+
+// Fields of the header of a sparse extent
+struct Header {
+    flags: u32,
+    capacity: u64,
+    grain_size: u64,
+    descriptor_offset: u64,
+    descriptor_size: u64,
+    gtes_per_gt: u32,
+    gd_offset: u64,
+}
+
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn invalid(message: &str) -> ImageError {
+    ImageError::InvalidDisk(format!("vmdk: {message}"))
+}
+
+impl Header {
+    fn read(file: &File, offset: u64) -> Result<Header, ImageError> {
+        let mut bytes = [0; 79];
+        read_exact_at(file, &mut bytes, offset)?;
+        if &bytes[..4] != b"KDMV" {
+            return Err(invalid("bad magic"));
+        }
+        let version = le32(&bytes, 4);
+        if !(1..=3).contains(&version) {
+            return Err(ImageError::UnsupportedDisk(format!(
+                "VMDK version {version}"
+            )));
+        }
+        let header = Header {
+            flags: le32(&bytes, 8),
+            capacity: le64(&bytes, 12),
+            grain_size: le64(&bytes, 20),
+            descriptor_offset: le64(&bytes, 28),
+            descriptor_size: le64(&bytes, 36),
+            gtes_per_gt: le32(&bytes, 44),
+            gd_offset: le64(&bytes, 56),
+        };
+        let algorithm = u16::from_le_bytes([bytes[77], bytes[78]]);
+        if header.flags & FLAG_COMPRESSED != 0 && algorithm != 1 {
+            return Err(ImageError::UnsupportedDisk(format!(
+                "VMDK grains compressed with algorithm {algorithm}"
+            )));
+        }
+        Ok(header)
+    }
+}
+
+impl Vmdk {
+    // Open the VMDK image `file`, at `path`, and its parent images.
+    pub(super) fn open(file: File, path: &Path, chain: &mut Chain) -> Result<Vmdk, ImageError> {
+        let mut magic = [0; 4];
+        read_exact_at(&file, &mut magic, 0)?;
+        if &magic != b"KDMV" {
+            return Err(ImageError::UnsupportedDisk(
+                "VMDK descriptor files, with separate extents".to_owned(),
+            ));
+        }
+        let file_len = file.metadata()?.len();
+        let mut header = Header::read(&file, 0)?;
+        if header.gd_offset == GD_AT_END {
+            // The footer is followed by an end-of-stream marker
+            if file_len < 3 * SECTOR_SIZE {
+                return Err(invalid("missing footer"));
+            }
+            header = Header::read(&file, file_len - 2 * SECTOR_SIZE)?;
+        }
+
+        let Header {
+            capacity,
+            grain_size,
+            gtes_per_gt,
+            ..
+        } = header;
+        if grain_size == 0 || grain_size > MAX_GRAIN_SECTORS || !grain_size.is_power_of_two() {
+            return Err(invalid(&format!(
+                "invalid grain size of {grain_size} sectors"
+            )));
+        }
+        if gtes_per_gt == 0 || gtes_per_gt > MAX_GTES_PER_GT {
+            return Err(invalid(&format!("invalid grain table size {gtes_per_gt}")));
+        }
+        let size = capacity
+            .checked_mul(SECTOR_SIZE)
+            .ok_or_else(|| invalid("invalid capacity"))?;
+        let grain_size = grain_size * SECTOR_SIZE;
+        let gd_entries = size.div_ceil(grain_size).div_ceil(u64::from(gtes_per_gt));
+        if gd_entries * 4 > file_len {
+            return Err(invalid("grain directory larger than the image"));
+        }
+        let gd_offset = header
+            .gd_offset
+            .checked_mul(SECTOR_SIZE)
+            .ok_or_else(|| invalid("invalid grain directory offset"))?;
+        let mut gd = vec![0; gd_entries as usize * 4];
+        read_exact_at(&file, &mut gd, gd_offset)?;
+        let gd = gd.chunks_exact(4).map(|entry| le32(entry, 0)).collect();
+
+        let parent = match parent_name(&file, &header)? {
+            Some(name) => Some(chain.open(&backing_path(path, &name), None)?),
+            None => None,
+        };
+
+        Ok(Vmdk {
+            file,
+            file_len,
+            size,
+            grain_size,
+            gtes_per_gt,
+            compressed: header.flags & FLAG_COMPRESSED != 0,
+            gd,
+            gt: None,
+            grain: None,
+            parent,
+        })
+    }
+
+    // Grain table entry of the grain `index`, or 0 if its grain table is not allocated.
+    fn gt_entry(&mut self, index: u64) -> Result<u32, ImageError> {
+        let gt_index = (index / u64::from(self.gtes_per_gt)) as usize;
+        let gt_offset = u64::from(self.gd[gt_index]);
+        if gt_offset == 0 {
+            return Ok(0);
+        }
+
+        if self
+            .gt
+            .as_ref()
+            .is_none_or(|(cached, _)| *cached != gt_index)
+        {
+            let mut table = vec![0; self.gtes_per_gt as usize * 4];
+            read_exact_at(&self.file, &mut table, gt_offset * SECTOR_SIZE)?;
+            let table = table.chunks_exact(4).map(|entry| le32(entry, 0)).collect();
+            self.gt = Some((gt_index, table));
+        }
+        let (_, table) = self.gt.as_ref().unwrap();
+        Ok(table[(index % u64::from(self.gtes_per_gt)) as usize])
+    }
+
+    // Decompressed content of the grain at `offset`, behind its marker
+    fn compressed_grain(&mut self, offset: u64) -> Result<&[u8], ImageError> {
+        if self
+            .grain
+            .as_ref()
+            .is_none_or(|(cached, _)| *cached != offset)
+        {
+            // The marker holds the sector of the grain, and the size of the compressed data
+            let mut marker = [0; 12];
+            read_exact_at(&self.file, &mut marker, offset)?;
+            let len = u64::from(le32(&marker, 8));
+            if offset + 12 + len > self.file_len {
+                return Err(invalid("compressed grain past the end of the image"));
+            }
+            let mut data = vec![0; len as usize];
+            read_exact_at(&self.file, &mut data, offset + 12)?;
+            let grain = inflate(ZlibDecoder::new(&data[..]), self.grain_size as usize)?;
+            self.grain = Some((offset, grain));
+        }
+        Ok(&self.grain.as_ref().unwrap().1)
+    }
+
+    // Read `buf`, which does not cross a grain boundary, at `offset`.
+    fn read_grain(&mut self, offset: u64, buf: &mut [u8]) -> Result<bool, ImageError> {
+        let in_grain = (offset % self.grain_size) as usize;
+        let entry = self.gt_entry(offset / self.grain_size)?;
+        match entry {
+            // Not allocated in this image
+            0 => match &mut self.parent {
+                Some(parent) => parent.read_at(offset, buf),
+                None => {
+                    buf.fill(0);
+                    Ok(false)
+                }
+            },
+            ZERO_GRAIN => {
+                buf.fill(0);
+                Ok(false)
+            }
+            sector if self.compressed => {
+                let grain = self.compressed_grain(u64::from(sector) * SECTOR_SIZE)?;
+                buf.copy_from_slice(&grain[in_grain..in_grain + buf.len()]);
+                Ok(true)
+            }
+            sector => {
+                let grain_offset = u64::from(sector) * SECTOR_SIZE;
+                read_exact_at(&self.file, buf, grain_offset + in_grain as u64)?;
+                Ok(true)
+            }
+        }
+    }
+}
+
+impl Layer for Vmdk {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<bool, ImageError> {
+        let mut data = false;
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let in_grain = position % self.grain_size;
+            let len = ((self.grain_size - in_grain) as usize).min(buf.len() - done);
+            let chunk = &mut buf[done..done + len];
+            if position >= self.size {
+                chunk.fill(0);
+            } else {
+                data |= self.read_grain(position, chunk)?;
+            }
+            done += len;
+        }
+        Ok(data)
+    }
+}
+
+// Name of the parent image, from the descriptor embedded in the image.
+fn parent_name(file: &File, header: &Header) -> Result<Option<String>, ImageError> {
+    if header.descriptor_offset == 0 || header.descriptor_size == 0 {
+        return Ok(None);
+    }
+    let (Some(len), Some(offset)) = (
+        header.descriptor_size.checked_mul(SECTOR_SIZE),
+        header.descriptor_offset.checked_mul(SECTOR_SIZE),
+    ) else {
+        return Err(invalid("invalid descriptor location"));
+    };
+    let mut descriptor = vec![0; len.min(1 << 20) as usize];
+    read_exact_at(file, &mut descriptor, offset)?;
+    let descriptor = String::from_utf8_lossy(&descriptor);
+
+    let value = |key: &str| {
+        descriptor.lines().find_map(|line| {
+            let (name, value) = line.split_once('=')?;
+            (name.trim() == key).then(|| value.trim().trim_matches('"').to_owned())
+        })
+    };
+    match value("parentCID") {
+        // An image without a parent has a parent CID of `ffffffff`
+        Some(cid) if !cid.eq_ignore_ascii_case("ffffffff") => value("parentFileNameHint")
+            .map(Some)
+            .ok_or_else(|| invalid("the descriptor has a parent but no parentFileNameHint")),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+
+    use super::*;
+    use crate::image::{DiskFormat, DiskImage};
+
+    // Images have grains of a single sector and grain tables of 8 entries: the header, the
+    // descriptor, the grain directory, a single grain table, then grains.
+    const GRAIN: usize = SECTOR_SIZE as usize;
+    const GTES_PER_GT: u32 = 8;
+    const DESCRIPTOR_SECTOR: u64 = 1;
+    const GD_SECTOR: u64 = 2;
+    const GT_SECTOR: u32 = 3;
+
+    fn put(image: &mut [u8], offset: usize, bytes: &[u8]) {
+        image[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    // A header of an image of `grains` grains, with the grain directory at `gd_offset`.
+    fn header(grains: u64, flags: u32, gd_offset: u64) -> Vec<u8> {
+        let mut header = vec![0; GRAIN];
+        put(&mut header, 0, b"KDMV");
+        put(&mut header, 4, &3u32.to_le_bytes());
+        put(&mut header, 8, &flags.to_le_bytes());
+        put(&mut header, 12, &grains.to_le_bytes());
+        put(&mut header, 20, &1u64.to_le_bytes());
+        put(&mut header, 28, &DESCRIPTOR_SECTOR.to_le_bytes());
+        put(&mut header, 36, &1u64.to_le_bytes());
+        put(&mut header, 44, &GTES_PER_GT.to_le_bytes());
+        put(&mut header, 56, &gd_offset.to_le_bytes());
+        if flags & FLAG_COMPRESSED != 0 {
+            put(&mut header, 77, &1u16.to_le_bytes());
+        }
+        header
+    }
+
+    // A monolithic sparse image of `grains` grains, on top of the image `parent`, if any.
+    fn vmdk(grains: u64, flags: u32, parent: Option<&str>) -> Vec<u8> {
+        let mut image = header(grains, flags, GD_SECTOR);
+        let descriptor = match parent {
+            Some(name) => format!(
+                "# Disk DescriptorFile\nparentCID=12345678\nparentFileNameHint=\"{name}\"\n"
+            ),
+            None => "# Disk DescriptorFile\nparentCID=ffffffff\n".to_owned(),
+        };
+        image.extend_from_slice(descriptor.as_bytes());
+        image.resize(GD_SECTOR as usize * GRAIN, 0);
+        image.extend_from_slice(&GT_SECTOR.to_le_bytes());
+        image.resize((GT_SECTOR as usize + 1) * GRAIN, 0);
+        image
+    }
+
+    fn set_gt_entry(image: &mut [u8], index: usize, entry: u32) {
+        put(
+            image,
+            GT_SECTOR as usize * GRAIN + index * 4,
+            &entry.to_le_bytes(),
+        );
+    }
+
+    // Append `data` as the grain `index`.
+    fn add_grain(image: &mut Vec<u8>, index: usize, data: &[u8]) {
+        let sector = (image.len() / GRAIN) as u32;
+        image.extend_from_slice(data);
+        image.resize(image.len().next_multiple_of(GRAIN), 0);
+        set_gt_entry(image, index, sector);
+    }
+
+    fn convert(dir: &Path, image: &[u8]) -> Result<Vec<u8>, ImageError> {
+        let (path, raw) = (dir.join("disk.vmdk"), dir.join("disk.raw"));
+        fs::write(&path, image).unwrap();
+        DiskImage::open(&path)?.convert(&raw)?;
+        Ok(fs::read(raw).unwrap())
+    }
+
+    #[test]
+    fn allocated_unallocated_and_zero_grains() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut image = vmdk(4, 0, None);
+        add_grain(&mut image, 1, &[0xaa; GRAIN]);
+        add_grain(&mut image, 2, &[0xbb; GRAIN]);
+        set_gt_entry(&mut image, 2, ZERO_GRAIN);
+
+        let raw = convert(dir.path(), &image).unwrap();
+        let grains: Vec<_> = raw.chunks(GRAIN).collect();
+        assert_eq!(grains.len(), 4);
+        assert!(grains[0].iter().all(|&b| b == 0));
+        assert!(grains[1].iter().all(|&b| b == 0xaa));
+        assert!(grains[2].iter().all(|&b| b == 0));
+        assert!(grains[3].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn stream_optimized() {
+        // The grain directory is found through the footer, before the end-of-stream marker
+        let dir = tempfile::tempdir().unwrap();
+        let grain: Vec<u8> = (0..GRAIN).map(|i| (i % 251) as u8).collect();
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&grain).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut image = vmdk(2, FLAG_COMPRESSED, None);
+        put(&mut image, 56, &GD_AT_END.to_le_bytes());
+        let sector = (image.len() / GRAIN) as u32;
+        image.extend_from_slice(&1u64.to_le_bytes());
+        image.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        image.extend_from_slice(&compressed);
+        image.resize(image.len().next_multiple_of(GRAIN), 0);
+        set_gt_entry(&mut image, 1, sector);
+        image.extend_from_slice(&header(2, FLAG_COMPRESSED, GD_SECTOR));
+        image.resize(image.len() + GRAIN, 0);
+
+        let raw = convert(dir.path(), &image).unwrap();
+        assert!(raw[..GRAIN].iter().all(|&b| b == 0));
+        assert_eq!(raw[GRAIN..], grain[..]);
+    }
+
+    #[test]
+    fn parent_image() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("base.raw"), [0x55; 2 * GRAIN]).unwrap();
+
+        let mut image = vmdk(3, 0, Some("base.raw"));
+        add_grain(&mut image, 0, &[0xaa; GRAIN]);
+
+        let raw = convert(dir.path(), &image).unwrap();
+        let grains: Vec<_> = raw.chunks(GRAIN).collect();
+        assert!(grains[0].iter().all(|&b| b == 0xaa));
+        // Unallocated grains come from the parent, zeros past its end
+        assert!(grains[1].iter().all(|&b| b == 0x55));
+        assert!(grains[2].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn descriptor_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("disk.vmdk");
+        fs::write(
+            &path,
+            "# Disk DescriptorFile\nRW 8 SPARSE \"disk-s001.vmdk\"\n",
+        )
+        .unwrap();
+        assert_eq!(DiskFormat::detect(&path).unwrap(), DiskFormat::Vmdk);
+        assert!(matches!(
+            DiskImage::open(&path),
+            Err(ImageError::UnsupportedDisk(_))
+        ));
+    }
+
+    #[test]
+    fn truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut image = vmdk(4, 0, None);
+        add_grain(&mut image, 0, &[0xaa; GRAIN]);
+        for len in [50, GD_SECTOR as usize * GRAIN + 2, image.len() - 1] {
+            assert!(
+                matches!(
+                    convert(dir.path(), &image[..len]),
+                    Err(ImageError::InvalidDisk(_))
+                ),
+                "truncated at {len}"
+            );
+        }
+    }
+
+    #[test]
+    fn overflowing_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let invalid =
+            |image: &[u8]| matches!(convert(dir.path(), image), Err(ImageError::InvalidDisk(_)));
+
+        // A disk of more than 2^64 bytes
+        let mut image = vmdk(4, 0, None);
+        put(&mut image, 12, &u64::MAX.to_le_bytes());
+        assert!(invalid(&image));
+
+        // A grain size that is not a power of two, and grain tables larger than accepted
+        let mut image = vmdk(4, 0, None);
+        put(&mut image, 20, &3u64.to_le_bytes());
+        assert!(invalid(&image));
+        let mut image = vmdk(4, 0, None);
+        put(&mut image, 44, &u32::MAX.to_le_bytes());
+        assert!(invalid(&image));
+
+        // Offsets in sectors that overflow in bytes
+        for field in [28, 36, 56] {
+            let mut image = vmdk(4, 0, None);
+            put(&mut image, field, &(u64::MAX / 2).to_le_bytes());
+            assert!(invalid(&image), "field at {field}");
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! Parser of `gen_init_cpio` file lists
+
+use super::{DeviceType, Initramfs, InitrdError, Metadata};
+
+// Add the entries of `list`, read from the file `name`, to `initramfs`.
+pub(super) fn parse(initramfs: &mut Initramfs, name: &str, list: &str) -> Result<(), InitrdError> {
+    for (number, line) in (1..).zip(list.lines()) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.first().is_none_or(|field| field.starts_with('#')) {
+            continue;
+        }
+        parse_line(initramfs, &fields).map_err(|err| match err {
+            LineError::Initrd(err) => err,
+            LineError::Syntax(message) => InitrdError::FileList(name.to_owned(), number, message),
+        })?;
+    }
+    Ok(())
+}
+
+enum LineError {
+    Syntax(String),
+    Initrd(InitrdError),
+}
+
+impl From<InitrdError> for LineError {
+    fn from(err: InitrdError) -> Self {
+        LineError::Initrd(err)
+    }
+}
+
+fn parse_line(initramfs: &mut Initramfs, fields: &[&str]) -> Result<(), LineError> {
+    let (kind, args) = fields.split_first().unwrap_or((&"", &[]));
+    let expect = |count: usize, usage: &str| {
+        if args.len() == count {
+            Ok(())
+        } else {
+            Err(LineError::Syntax(format!("expected `{kind} {usage}`")))
+        }
+    };
+
+    match *kind {
+        "file" => {
+            expect(5, "<name> <location> <mode> <uid> <gid>")?;
+            let metadata = metadata(&args[2..5])?;
+            initramfs.add_file(args[0], args[1], metadata)?;
+        }
+        "dir" => {
+            expect(4, "<name> <mode> <uid> <gid>")?;
+            initramfs.add_dir(args[0], metadata(&args[1..4])?)?;
+        }
+        "nod" => {
+            expect(7, "<name> <mode> <uid> <gid> <dev_type> <maj> <min>")?;
+            let device_type = match args[4] {
+                "c" => DeviceType::Char,
+                "b" => DeviceType::Block,
+                other => {
+                    return Err(LineError::Syntax(format!(
+                        "invalid device type `{other}`, expected `c` or `b`"
+                    )))
+                }
+            };
+            let major = number(args[5], 10)?;
+            let minor = number(args[6], 10)?;
+            let metadata = metadata(&args[1..4])?;
+            initramfs.add_device(args[0], device_type, major, minor, metadata)?;
+        }
+        "slink" => {
+            expect(5, "<name> <target> <mode> <uid> <gid>")?;
+            let metadata = metadata(&args[2..5])?;
+            initramfs.add_symlink(args[0], args[1], metadata)?;
+        }
+        "pipe" => {
+            expect(4, "<name> <mode> <uid> <gid>")?;
+            initramfs.add_fifo(args[0], metadata(&args[1..4])?)?;
+        }
+        "sock" => {
+            expect(4, "<name> <mode> <uid> <gid>")?;
+            initramfs.add_socket(args[0], metadata(&args[1..4])?)?;
+        }
+        other => return Err(LineError::Syntax(format!("unknown entry type `{other}`"))),
+    }
+    Ok(())
+}
+
+// Parse `<mode> <uid> <gid>`
+fn metadata(fields: &[&str]) -> Result<Metadata, LineError> {
+    Ok(Metadata {
+        mode: number(fields[0], 8)?,
+        uid: number(fields[1], 10)?,
+        gid: number(fields[2], 10)?,
+    })
+}
+
+fn number(field: &str, radix: u32) -> Result<u32, LineError> {
+    u32::from_str_radix(field, radix)
+        .map_err(|_| LineError::Syntax(format!("invalid number `{field}`")))
+}
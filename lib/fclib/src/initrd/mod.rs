@@ -0,0 +1,498 @@
+//! Initramfs builder
+//!
+//! [`Initramfs`] builds a `newc` cpio archive, optionally gzip-compressed, that the guest kernel
+//! unpacks into its root filesystem before running `/init`. The archive is written to a file,
+//! whose path goes into [`BootSource::initrd_path`].
+//!
+//! Entries are added from a host directory, from a file list in the format of the kernel's
+//! `gen_init_cpio` (see [`Initramfs::add_file_list`]), or one by one. Building an archive does not
+//! require root: owners and device nodes are recorded in the archive, not created on the host.
+//!
+//! Archives are reproducible: entries are sorted by path, and their modification time is 0.
+//!
+//! [`BootSource::initrd_path`]: crate::client::kernel::BootSource::initrd_path
+
+mod file_list;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+
+#[cfg(feature = "clap")]
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
+use log::warn;
+
+const S_IFIFO: u32 = 0o010000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFSOCK: u32 = 0o140000;
+
+const NEWC_MAGIC: &str = "070701";
+const TRAILER: &str = "TRAILER!!!";
+
+/// An error while building an initramfs
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum InitrdError {
+    /// {0}
+    Io(#[from] io::Error),
+    /// invalid path `{0}` in the archive
+    InvalidPath(String),
+    /// {0}, line {1}: {2}
+    FileList(String, usize, String),
+}
+
+/// Compression of an initramfs
+#[cfg_attr(feature = "clap", derive(ValueEnum))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// No compression
+    None,
+    /// gzip, which every kernel can unpack
+    #[default]
+    Gzip,
+}
+
+/// Type of a device node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceType {
+    /// Character device
+    Char,
+    /// Block device
+    Block,
+}
+
+/// Owner and permissions of an entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// Permission bits, e.g. `0o755`
+    pub mode: u32,
+    /// Owner
+    pub uid: u32,
+    /// Group
+    pub gid: u32,
+}
+
+impl Metadata {
+    /// Permissions `mode`, owned by root
+    pub const fn new(mode: u32) -> Metadata {
+        Metadata {
+            mode,
+            uid: 0,
+            gid: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Content {
+    Dir,
+    // Copied from a host file when the archive is written
+    File(PathBuf),
+    Data(Vec<u8>),
+    Symlink(String),
+    Device(DeviceType, u32, u32),
+    Fifo,
+    Socket,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    content: Content,
+    metadata: Metadata,
+}
+
+/// A `newc` cpio archive, to be used as an initramfs
+#[derive(Debug, Clone, Default)]
+pub struct Initramfs {
+    /// Entries, by path in the archive, without leading `/`
+    entries: BTreeMap<String, Entry>,
+    compression: Compression,
+}
+
+impl Initramfs {
+    /// An empty, gzip-compressed archive
+    pub fn new() -> Initramfs {
+        Initramfs::default()
+    }
+
+    /// Set the compression of the archive
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Add a directory
+    pub fn add_dir(&mut self, path: &str, metadata: Metadata) -> Result<(), InitrdError> {
+        self.insert(path, Content::Dir, metadata)
+    }
+
+    /// Add a regular file, copied from `source` on the host when the archive is written
+    pub fn add_file<P: AsRef<Path>>(
+        &mut self,
+        path: &str,
+        source: P,
+        metadata: Metadata,
+    ) -> Result<(), InitrdError> {
+        let source = source.as_ref().to_path_buf();
+        self.insert(path, Content::File(source), metadata)
+    }
+
+    /// Add a regular file with `data` as content
+    pub fn add_data(
+        &mut self,
+        path: &str,
+        data: Vec<u8>,
+        metadata: Metadata,
+    ) -> Result<(), InitrdError> {
+        self.insert(path, Content::Data(data), metadata)
+    }
+
+    /// Add a symbolic link to `target`
+    pub fn add_symlink(
+        &mut self,
+        path: &str,
+        target: &str,
+        metadata: Metadata,
+    ) -> Result<(), InitrdError> {
+        self.insert(path, Content::Symlink(target.to_owned()), metadata)
+    }
+
+    /// Add a device node, e.g. `dev/console`, a character device 5:1
+    pub fn add_device(
+        &mut self,
+        path: &str,
+        device_type: DeviceType,
+        major: u32,
+        minor: u32,
+        metadata: Metadata,
+    ) -> Result<(), InitrdError> {
+        self.insert(path, Content::Device(device_type, major, minor), metadata)
+    }
+
+    /// Add a named pipe
+    pub fn add_fifo(&mut self, path: &str, metadata: Metadata) -> Result<(), InitrdError> {
+        self.insert(path, Content::Fifo, metadata)
+    }
+
+    /// Add a Unix socket
+    pub fn add_socket(&mut self, path: &str, metadata: Metadata) -> Result<(), InitrdError> {
+        self.insert(path, Content::Socket, metadata)
+    }
+
+    /// Add the content of the host directory `dir`, recursively, under `path` in the archive
+    ///
+    /// Permissions are preserved, but entries are owned by root. Symbolic links are added as is,
+    /// not followed.
+    pub fn add_host_dir<P: AsRef<Path>>(&mut self, path: &str, dir: P) -> Result<(), InitrdError> {
+        let mut children: Vec<_> = std::fs::read_dir(dir)?.collect::<io::Result<_>>()?;
+        children.sort_by_key(|child| child.file_name());
+
+        for child in children {
+            let name = child.file_name();
+            let name = name
+                .to_str()
+                .ok_or_else(|| InitrdError::InvalidPath(name.to_string_lossy().into_owned()))?;
+            let child_path = join(path, name);
+            let host_path = child.path();
+            let host = std::fs::symlink_metadata(&host_path)?;
+            let metadata = Metadata::new(host.mode() & 0o7777);
+            let file_type = host.file_type();
+
+            if file_type.is_dir() {
+                self.add_dir(&child_path, metadata)?;
+                self.add_host_dir(&child_path, &host_path)?;
+            } else if file_type.is_symlink() {
+                let target = std::fs::read_link(&host_path)?;
+                let target = target
+                    .to_str()
+                    .ok_or_else(|| InitrdError::InvalidPath(target.display().to_string()))?;
+                self.add_symlink(&child_path, target, metadata)?;
+            } else if file_type.is_char_device() || file_type.is_block_device() {
+                let device_type = if file_type.is_char_device() {
+                    DeviceType::Char
+                } else {
+                    DeviceType::Block
+                };
+                let (major, minor) = split_dev(host.rdev());
+                self.add_device(&child_path, device_type, major, minor, metadata)?;
+            } else if file_type.is_fifo() {
+                self.add_fifo(&child_path, metadata)?;
+            } else if file_type.is_socket() {
+                self.add_socket(&child_path, metadata)?;
+            } else {
+                self.add_file(&child_path, &host_path, metadata)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the entries of a file list in the format of the kernel's `gen_init_cpio`
+    ///
+    /// Each line describes an entry:
+    ///
+    /// ```text
+    /// file <name> <location> <mode> <uid> <gid>
+    /// dir <name> <mode> <uid> <gid>
+    /// nod <name> <mode> <uid> <gid> <dev_type> <maj> <min>
+    /// slink <name> <target> <mode> <uid> <gid>
+    /// pipe <name> <mode> <uid> <gid>
+    /// sock <name> <mode> <uid> <gid>
+    /// ```
+    ///
+    /// Modes are in octal, and `dev_type` is `c` or `b`. Empty lines and lines starting with `#`
+    /// are ignored. Relative locations are resolved from the current working directory.
+    pub fn add_file_list<P: AsRef<Path>>(&mut self, path: P) -> Result<(), InitrdError> {
+        let path = path.as_ref();
+        let list = std::fs::read_to_string(path)?;
+        file_list::parse(self, &path.display().to_string(), &list)
+    }
+
+    /// Returns `true` if the archive has an entry at `path`
+    pub fn contains(&self, path: &str) -> bool {
+        normalize(path).is_ok_and(|path| self.entries.contains_key(&path))
+    }
+
+    /// Write the archive
+    pub fn write_to<W: Write>(&self, writer: W) -> Result<(), InitrdError> {
+        if !self.contains("init") {
+            warn!("The initramfs has no /init, the kernel will look for the root device instead");
+        }
+
+        match self.compression {
+            Compression::None => self.write_cpio(writer)?,
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(writer, flate2::Compression::default());
+                self.write_cpio(&mut encoder)?;
+                encoder.finish()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the archive to the file at `path`, and return the path for
+    /// [`BootSource::initrd_path`]
+    ///
+    /// [`BootSource::initrd_path`]: crate::client::kernel::BootSource::initrd_path
+    pub fn build<P: AsRef<Path>>(&self, path: P) -> Result<String, InitrdError> {
+        let path = path.as_ref();
+        let mut file = BufWriter::new(File::create(path)?);
+        self.write_to(&mut file)?;
+        file.flush()?;
+        Ok(path.display().to_string())
+    }
+
+    // Add an entry, along with its missing parent directories, replacing any entry at `path`.
+    fn insert(
+        &mut self,
+        path: &str,
+        content: Content,
+        metadata: Metadata,
+    ) -> Result<(), InitrdError> {
+        let path = normalize(path)?;
+        for (i, _) in path.match_indices('/') {
+            self.entries
+                .entry(path[..i].to_owned())
+                .or_insert_with(|| Entry {
+                    content: Content::Dir,
+                    metadata: Metadata::new(0o755),
+                });
+        }
+        self.entries.insert(path, Entry { content, metadata });
+        Ok(())
+    }
+
+    fn write_cpio<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut offset = 0;
+        for (ino, (path, entry)) in (1..).zip(&self.entries) {
+            let Metadata { mode, uid, gid } = entry.metadata;
+            let mode = mode & 0o7777;
+            let mut header = Header {
+                ino,
+                mode,
+                uid,
+                gid,
+                nlink: 1,
+                size: 0,
+                rdev: (0, 0),
+            };
+
+            match &entry.content {
+                Content::Dir => {
+                    header.mode |= S_IFDIR;
+                    header.nlink = 2;
+                    offset += header.write(&mut writer, path)?;
+                }
+                Content::File(source) => {
+                    let mut file = File::open(source)?;
+                    let size = file.metadata()?.len();
+                    header.mode |= S_IFREG;
+                    header.size = u32::try_from(size).map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("{} is too large for a cpio archive", source.display()),
+                        )
+                    })?;
+                    offset += header.write(&mut writer, path)?;
+                    let copied = io::copy(&mut (&mut file).take(size), &mut writer)?;
+                    if copied != size {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            format!("{} changed while being archived", source.display()),
+                        ));
+                    }
+                    offset += copied;
+                    offset += pad(&mut writer, offset)?;
+                }
+                Content::Data(data) => {
+                    header.mode |= S_IFREG;
+                    offset += header.write_with_data(&mut writer, path, data, offset)?;
+                }
+                Content::Symlink(target) => {
+                    header.mode |= S_IFLNK;
+                    offset +=
+                        header.write_with_data(&mut writer, path, target.as_bytes(), offset)?;
+                }
+                Content::Device(device_type, major, minor) => {
+                    header.mode |= match device_type {
+                        DeviceType::Char => S_IFCHR,
+                        DeviceType::Block => S_IFBLK,
+                    };
+                    header.rdev = (*major, *minor);
+                    offset += header.write(&mut writer, path)?;
+                }
+                Content::Fifo => {
+                    header.mode |= S_IFIFO;
+                    offset += header.write(&mut writer, path)?;
+                }
+                Content::Socket => {
+                    header.mode |= S_IFSOCK;
+                    offset += header.write(&mut writer, path)?;
+                }
+            }
+        }
+
+        let trailer = Header {
+            ino: 0,
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            nlink: 1,
+            size: 0,
+            rdev: (0, 0),
+        };
+        offset += trailer.write(&mut writer, TRAILER)?;
+        // The kernel reads archives in blocks of 512 bytes
+        let padding = (512 - offset % 512) % 512;
+        writer.write_all(&vec![0; padding as usize])?;
+        Ok(())
+    }
+}
+
+// The header of an entry in a `newc` archive
+struct Header {
+    ino: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    size: u32,
+    rdev: (u32, u32),
+}
+
+impl Header {
+    // Write the header and the name of an entry, padded to 4 bytes, and return the number of
+    // bytes written.
+    fn write<W: Write>(&self, writer: &mut W, name: &str) -> io::Result<u64> {
+        let fields = [
+            self.ino,
+            self.mode,
+            self.uid,
+            self.gid,
+            self.nlink,
+            0, // mtime
+            self.size,
+            0, // devmajor
+            0, // devminor
+            self.rdev.0,
+            self.rdev.1,
+            name.len() as u32 + 1,
+            0, // check
+        ];
+        let mut header = String::from(NEWC_MAGIC);
+        for field in fields {
+            header.push_str(&format!("{field:08x}"));
+        }
+        header.push_str(name);
+        header.push('\0');
+        let len = header.len() as u64;
+        writer.write_all(header.as_bytes())?;
+        Ok(len + pad(writer, len)?)
+    }
+
+    // Write the header, the name and the data of an entry, padded to 4 bytes, starting at
+    // `offset` in the archive, and return the number of bytes written.
+    fn write_with_data<W: Write>(
+        &mut self,
+        writer: &mut W,
+        name: &str,
+        data: &[u8],
+        offset: u64,
+    ) -> io::Result<u64> {
+        self.size = u32::try_from(data.len()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{name} is too large for a cpio archive"),
+            )
+        })?;
+        let mut written = self.write(writer, name)?;
+        writer.write_all(data)?;
+        written += data.len() as u64;
+        Ok(written + pad(writer, offset + written)?)
+    }
+}
+
+// Pad the archive to 4 bytes after `offset` bytes, and return the number of bytes written.
+fn pad<W: Write>(writer: &mut W, offset: u64) -> io::Result<u64> {
+    let padding = (4 - offset % 4) % 4;
+    writer.write_all(&[0; 3][..padding as usize])?;
+    Ok(padding)
+}
+
+// Normalize a path in the archive, e.g. `/bin/sh` to `bin/sh`
+fn normalize(path: &str) -> Result<String, InitrdError> {
+    let mut parts = Vec::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::RootDir | Component::CurDir => (),
+            Component::Normal(part) => parts.push(part.to_str().unwrap_or_default()),
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(InitrdError::InvalidPath(path.to_owned()))
+            }
+        }
+    }
+    if parts.is_empty() {
+        return Err(InitrdError::InvalidPath(path.to_owned()));
+    }
+    Ok(parts.join("/"))
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() || dir == "/" {
+        name.to_owned()
+    } else {
+        format!("{}/{name}", dir.trim_end_matches('/'))
+    }
+}
+
+// Split a device number into its major and minor numbers, as glibc's `major` and `minor` do.
+fn split_dev(dev: u64) -> (u32, u32) {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    (major as u32, minor as u32)
+}
@@ -1,9 +1,14 @@
 pub mod client;
+pub mod image;
+pub mod initrd;
+pub mod microvm;
+pub mod net;
+mod util;
 pub mod vmm;
 
 use semver::Version;
 
-static FC_VERSION: &str = "1.4.0";
+static FC_VERSION: &str = "1.14.0";
 
 pub fn supported_fc_version() -> Version {
     Version::parse(FC_VERSION).unwrap()
@@ -0,0 +1,506 @@
+//! A microVM handle that tracks the lifecycle of the microVM in its type
+//!
+//! [`ApiClient`] allows issuing any request at any time, e.g. adding a drive after the microVM
+//! has booted, and such mistakes are only reported by Firecracker at runtime. A [`MicroVm`] is
+//! parameterized by the state of the microVM instead, and only exposes the operations that are
+//! valid in that state:
+//!
+//! * [`Configuring`]: Firecracker is running but the microVM has not been started yet. Pre-boot
+//!   configuration happens here.
+//! * [`Running`]: the microVM has been started and its vCPUs are running.
+//! * [`Paused`]: the microVM has been started, but its vCPUs are paused.
+//! * [`Stopped`]: the Firecracker process has exited.
+//!
+//! State transitions consume the handle and return it in its new state. If a transition fails, the
+//! handle is returned, in its original state, inside a [`TransitionError`].
+
+use std::marker::PhantomData;
+use std::process::ExitStatus;
+
+use log::warn;
+use serde_json::Value;
+
+use crate::client::balloon::{Balloon, BalloonStats};
+use crate::client::cpu_config::CpuConfig;
+use crate::client::drive::{Drive, DriveClone, PartialDrive};
+use crate::client::entropy::EntropyDevice;
+use crate::client::hotplug::{MemoryHotplugConfig, MemoryHotplugStatus};
+use crate::client::kernel::BootSource;
+use crate::client::logger::Logger;
+use crate::client::metrics::Metrics;
+use crate::client::mmds::{MmdsConfig, MmdsContentsObject};
+use crate::client::network::{NetworkInterface, PartialNetworkInterface};
+use crate::client::serial::SerialConfig;
+use crate::client::snapshot::{SnapshotCreateParams, SnapshotLoadParams};
+use crate::client::types::MiB;
+use crate::client::vm::{
+    FirecrackerVersion, FullVmConfiguration, InstanceInfo, MachineConfiguration,
+};
+use crate::client::vsock::Vsock;
+use crate::client::{ApiClient, FcClientError, Result};
+use crate::net::host::HostNetwork;
+use crate::net::tap::Tap;
+use crate::vmm::Vmm;
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// State of a [`MicroVm`]
+pub trait State: private::Sealed + std::fmt::Debug {}
+
+/// States in which the Firecracker process is running
+pub trait Alive: State {}
+
+/// States in which the microVM has been started
+pub trait Booted: Alive {}
+
+/// The microVM has not been started yet
+#[derive(Debug)]
+pub struct Configuring;
+
+/// The microVM has been started and its vCPUs are running
+#[derive(Debug)]
+pub struct Running;
+
+/// The microVM has been started and its vCPUs are paused
+#[derive(Debug)]
+pub struct Paused;
+
+/// The Firecracker process has exited
+#[derive(Debug)]
+pub struct Stopped;
+
+impl private::Sealed for Configuring {}
+impl private::Sealed for Running {}
+impl private::Sealed for Paused {}
+impl private::Sealed for Stopped {}
+
+impl State for Configuring {}
+impl State for Running {}
+impl State for Paused {}
+impl State for Stopped {}
+
+impl Alive for Configuring {}
+impl Alive for Running {}
+impl Alive for Paused {}
+
+impl Booted for Running {}
+impl Booted for Paused {}
+
+/// A failed state transition
+///
+/// Holds the microVM, still in the state it was before the transition was attempted.
+#[derive(Debug, thiserror::Error)]
+#[error("{error}")]
+pub struct TransitionError<S: State> {
+    /// The microVM, in its original state.
+    pub vm: MicroVm<S>,
+    /// Why the transition failed.
+    #[source]
+    pub error: FcClientError,
+}
+
+/// A Firecracker microVM in state `S`
+#[derive(Debug)]
+pub struct MicroVm<S: State> {
+    vmm: Vmm,
+    // Dropped after the Firecracker process is killed
+    clones: Vec<DriveClone>,
+    taps: Vec<Tap>,
+    host_networks: Vec<HostNetwork>,
+    // PARTUUID of the root partition of the root drive, when detected
+    root_partuuid: Option<String>,
+    client: ApiClient,
+    exit_status: Option<ExitStatus>,
+    state: PhantomData<S>,
+}
+
+impl<S: State> MicroVm<S> {
+    fn into_state<T: State>(self) -> MicroVm<T> {
+        MicroVm {
+            vmm: self.vmm,
+            clones: self.clones,
+            taps: self.taps,
+            host_networks: self.host_networks,
+            root_partuuid: self.root_partuuid,
+            client: self.client,
+            exit_status: self.exit_status,
+            state: PhantomData,
+        }
+    }
+
+    fn fail(self, error: FcClientError) -> TransitionError<S> {
+        TransitionError { vm: self, error }
+    }
+
+    /// The Firecracker process of the microVM
+    pub fn vmm(&self) -> &Vmm {
+        &self.vmm
+    }
+
+    /// The Firecracker process of the microVM
+    pub fn vmm_mut(&mut self) -> &mut Vmm {
+        &mut self.vmm
+    }
+}
+
+impl MicroVm<Configuring> {
+    /// Manage the microVM of a freshly started Firecracker process
+    pub fn new(vmm: Vmm) -> Self {
+        let client = vmm.api_client();
+        Self::with_client(vmm, client)
+    }
+
+    /// Manage the microVM of a freshly started Firecracker process, through `client`
+    ///
+    /// Use it to configure the [`ApiClient`], e.g. its timeouts, or to record the session.
+    pub fn with_client(vmm: Vmm, client: ApiClient) -> Self {
+        MicroVm {
+            vmm,
+            clones: Vec::new(),
+            taps: Vec::new(),
+            host_networks: Vec::new(),
+            root_partuuid: None,
+            client,
+            exit_status: None,
+            state: PhantomData,
+        }
+    }
+
+    /// Setup the boot source of the microVM.
+    ///
+    /// If the root drive is a partitioned image, `root=PARTUUID=` is set in the boot arguments,
+    /// see [`BootSource::set_root`].
+    pub async fn set_boot_source(&mut self, boot_source: &BootSource) -> Result<()> {
+        match &self.root_partuuid {
+            Some(partuuid) => {
+                let mut boot_source = boot_source.clone();
+                set_root(&mut boot_source, partuuid);
+                self.client.set_boot_source(&boot_source).await
+            }
+            None => self.client.set_boot_source(boot_source).await,
+        }
+    }
+
+    /// Configure the vCPUs and memory of the microVM.
+    pub async fn configure_machine(&mut self, config: &MachineConfiguration) -> Result<()> {
+        self.client.configure_machine(config).await
+    }
+
+    /// Update the vCPUs and memory configuration of the microVM.
+    pub async fn update_machine_configuration(
+        &mut self,
+        config: &MachineConfiguration,
+    ) -> Result<()> {
+        self.client.update_machine_configuration(config).await
+    }
+
+    /// Apply a custom CPU template to the microVM.
+    pub async fn apply_cpu_config(&mut self, config: &CpuConfig) -> Result<()> {
+        self.client.apply_cpu_config(config).await
+    }
+
+    /// Add a disk to the microVM.
+    ///
+    /// The `partuuid` of a root drive backed by a partitioned image on the host is filled in, see
+    /// [`Drive::detect_partuuid`], and `root=PARTUUID=` is set in the boot arguments. Set the
+    /// `partuuid` of the drive to skip the detection.
+    pub async fn add_drive(&mut self, drive: &Drive) -> Result<()> {
+        let mut root = drive.clone();
+        let partuuid = match root.detect_partuuid() {
+            Ok(partuuid) => partuuid.map(str::to_owned),
+            Err(err) => {
+                warn!(
+                    "cannot read the partition table of {}: {err}",
+                    drive.path_on_host
+                );
+                None
+            }
+        };
+        let Some(partuuid) = partuuid else {
+            return self.client.add_drive(&drive.drive_id, drive).await;
+        };
+
+        self.client.add_drive(&root.drive_id, &root).await?;
+        // The boot source may have been set already
+        if let Some(mut boot_source) = self.client.vm_config().await?.boot_source {
+            if set_root(&mut boot_source, &partuuid) {
+                self.client.set_boot_source(&boot_source).await?;
+            }
+        }
+        self.root_partuuid = Some(partuuid);
+        Ok(())
+    }
+
+    /// Add a disk backed by a clone, e.g. from [`Drive::clone_to`], to the microVM.
+    ///
+    /// The clone is deleted when the microVM is dropped.
+    pub async fn add_drive_clone(&mut self, clone: DriveClone) -> Result<()> {
+        self.add_drive(clone.drive()).await?;
+        self.clones.push(clone);
+        Ok(())
+    }
+
+    /// Add a network interface to the microVM.
+    pub async fn add_network_interface(&mut self, iface: &NetworkInterface) -> Result<()> {
+        self.client
+            .add_network_interface(&iface.iface_id, iface)
+            .await
+    }
+
+    /// Add a network interface backed by `tap`, e.g. from [`TapBuilder::create`], to the
+    /// microVM.
+    ///
+    /// The `host_dev_name` of `iface` is set to the name of the device, which is deleted when the
+    /// microVM is dropped.
+    ///
+    /// [`TapBuilder::create`]: crate::net::tap::TapBuilder::create
+    pub async fn add_tap(&mut self, mut iface: NetworkInterface, tap: Tap) -> Result<()> {
+        iface.host_dev_name = tap.name().to_owned();
+        self.add_network_interface(&iface).await?;
+        self.taps.push(tap);
+        Ok(())
+    }
+
+    /// Add the network interface of `network`, e.g. from [`HostNetworkBuilder::setup`], to the
+    /// microVM.
+    ///
+    /// Its firewall rules and TAP device are removed when the microVM is dropped.
+    ///
+    /// [`HostNetworkBuilder::setup`]: crate::net::host::HostNetworkBuilder::setup
+    pub async fn add_host_network(&mut self, network: HostNetwork) -> Result<()> {
+        self.add_network_interface(&network.network_interface())
+            .await?;
+        self.host_networks.push(network);
+        Ok(())
+    }
+
+    /// Configure the balloon device of the microVM.
+    pub async fn configure_balloon(&mut self, balloon: &Balloon) -> Result<()> {
+        self.client.configure_balloon(balloon).await
+    }
+
+    /// Configure the vsock device of the microVM.
+    pub async fn config_vsock(&mut self, vsock: &Vsock) -> Result<()> {
+        self.client.config_vsock(vsock).await
+    }
+
+    /// Enable the entropy device of the microVM.
+    pub async fn configure_entropy_device(&mut self, entropy: &EntropyDevice) -> Result<()> {
+        self.client.configure_entropy_device(entropy).await
+    }
+
+    /// Configure the microVM metadata service.
+    pub async fn configure_mmds(&mut self, config: &MmdsConfig) -> Result<()> {
+        self.client.configure_mmds(config).await
+    }
+
+    /// Configure the serial console of the microVM.
+    pub async fn configure_serial(&mut self, serial: &SerialConfig) -> Result<()> {
+        self.client.configure_serial(serial).await
+    }
+
+    /// Configure the memory hotplug device of the microVM.
+    pub async fn configure_memory_hotplug(&mut self, config: &MemoryHotplugConfig) -> Result<()> {
+        self.client.configure_memory_hotplug(config).await
+    }
+
+    /// Configure the logger of Firecracker.
+    pub async fn config_logger(&mut self, logger: &Logger) -> Result<()> {
+        self.client.config_logger(logger).await
+    }
+
+    /// Configure the metrics of Firecracker.
+    pub async fn config_metrics(&mut self, metrics: &Metrics) -> Result<()> {
+        self.client.config_metrics(metrics).await
+    }
+
+    /// Start the microVM.
+    pub async fn start(
+        self,
+    ) -> std::result::Result<MicroVm<Running>, TransitionError<Configuring>> {
+        match self.client.start_microvm().await {
+            Ok(()) => Ok(self.into_state()),
+            Err(err) => Err(self.fail(err)),
+        }
+    }
+
+    /// Restore the microVM from a snapshot, leaving it paused.
+    ///
+    /// The `resume_vm` field of `params` is ignored. Use [`MicroVm::resume`] on the returned
+    /// handle to resume the microVM.
+    pub async fn load_snapshot(
+        self,
+        params: &SnapshotLoadParams,
+    ) -> std::result::Result<MicroVm<Paused>, TransitionError<Configuring>> {
+        let params = SnapshotLoadParams {
+            resume_vm: false,
+            ..params.clone()
+        };
+
+        match self.client.load_microvm_snapshot(&params).await {
+            Ok(()) => Ok(self.into_state()),
+            Err(err) => Err(self.fail(err)),
+        }
+    }
+}
+
+impl<S: Alive> MicroVm<S> {
+    /// Get information about the microVM instance.
+    pub async fn instance_info(&self) -> Result<InstanceInfo> {
+        self.client.instance_info().await
+    }
+
+    /// Get the full configuration of the microVM.
+    pub async fn vm_config(&self) -> Result<FullVmConfiguration> {
+        self.client.vm_config().await
+    }
+
+    /// Get the version of Firecracker.
+    pub async fn firecracker_version(&self) -> Result<FirecrackerVersion> {
+        self.client.firecracker_version().await
+    }
+
+    /// Get the vCPUs and memory configuration of the microVM.
+    pub async fn machine_configuration(&self) -> Result<MachineConfiguration> {
+        self.client.get_machine_configuration().await
+    }
+
+    /// Get the contents of the microVM metadata service.
+    pub async fn mmds_contents(&self) -> Result<Value> {
+        self.client.mmds_contents().await
+    }
+
+    /// Replace the contents of the microVM metadata service.
+    pub async fn store_mmds(&self, contents: &MmdsContentsObject) -> Result<()> {
+        self.client.store_mmds(contents).await
+    }
+
+    /// Update the contents of the microVM metadata service.
+    pub async fn update_mmds(&self, contents: &MmdsContentsObject) -> Result<()> {
+        self.client.update_mmds(contents).await
+    }
+
+    /// Kill the Firecracker process.
+    pub fn stop(mut self) -> std::io::Result<MicroVm<Stopped>> {
+        self.exit_status = Some(self.vmm.kill()?);
+        Ok(self.into_state())
+    }
+}
+
+impl<S: Booted> MicroVm<S> {
+    /// Update the backing file or rate limiter of a disk.
+    pub async fn update_drive(&mut self, drive: &PartialDrive) -> Result<()> {
+        self.client.update_drive(&drive.drive_id, drive).await
+    }
+
+    /// Update the rate limiters of a network interface.
+    pub async fn update_network_interface(&self, iface: &PartialNetworkInterface) -> Result<()> {
+        self.client
+            .update_network_interface(&iface.iface_id, iface)
+            .await
+    }
+
+    /// Get the configuration of the balloon device.
+    pub async fn balloon_config(&self) -> Result<Balloon> {
+        self.client.balloon_config().await
+    }
+
+    /// Get the statistics of the balloon device.
+    pub async fn balloon_stats(&self) -> Result<BalloonStats> {
+        self.client.balloon_stats().await
+    }
+
+    /// Update the target size of the balloon device.
+    pub async fn update_balloon_size(&self, amount_mib: MiB) -> Result<()> {
+        self.client.update_balloon_size(amount_mib).await
+    }
+
+    /// Update the statistics polling interval of the balloon device.
+    pub async fn update_balloon_stats_interval(&self, interval_s: i32) -> Result<()> {
+        self.client.update_balloon_stats_interval(interval_s).await
+    }
+
+    /// Get the status of the memory hotplug device.
+    pub async fn memory_hotplug_status(&self) -> Result<MemoryHotplugStatus> {
+        self.client.memory_hotplug_status().await
+    }
+
+    /// Ask the guest to plug (or unplug) memory, until `requested_size_mib` is plugged.
+    pub async fn update_memory_hotplug_size(&self, requested_size_mib: MiB) -> Result<()> {
+        self.client
+            .update_memory_hotplug_size(requested_size_mib)
+            .await
+    }
+
+    /// Flush the metrics of Firecracker.
+    pub async fn flush_metrics(&self) -> Result<()> {
+        self.client.flush_metrics().await
+    }
+}
+
+impl MicroVm<Running> {
+    /// Replace the backing file of a disk, see [`ApiClient::swap_drive`].
+    ///
+    /// If `pause` is set, the microVM is paused during the swap.
+    pub async fn swap_drive(
+        &mut self,
+        drive_id: &str,
+        path_on_host: &str,
+        pause: bool,
+    ) -> Result<()> {
+        self.client.swap_drive(drive_id, path_on_host, pause).await
+    }
+
+    /// Pause the vCPUs of the microVM.
+    pub async fn pause(self) -> std::result::Result<MicroVm<Paused>, TransitionError<Running>> {
+        match self.client.pause_microvm().await {
+            Ok(()) => Ok(self.into_state()),
+            Err(err) => Err(self.fail(err)),
+        }
+    }
+
+    /// Send Ctrl+Alt+Del to the microVM, asking the guest to shut down.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub async fn send_ctrl_alt_del(&self) -> Result<()> {
+        self.client.stop_microvm().await
+    }
+}
+
+impl MicroVm<Paused> {
+    /// Replace the backing file of a disk, see [`ApiClient::swap_drive`].
+    pub async fn swap_drive(&mut self, drive_id: &str, path_on_host: &str) -> Result<()> {
+        self.client.swap_drive(drive_id, path_on_host, false).await
+    }
+
+    /// Resume the vCPUs of the microVM.
+    pub async fn resume(self) -> std::result::Result<MicroVm<Running>, TransitionError<Paused>> {
+        match self.client.resume_microvm().await {
+            Ok(()) => Ok(self.into_state()),
+            Err(err) => Err(self.fail(err)),
+        }
+    }
+
+    /// Take a snapshot of the paused microVM.
+    ///
+    /// The microVM stays paused; [resume](MicroVm::resume) it to continue running.
+    pub async fn create_snapshot(&self, params: &SnapshotCreateParams) -> Result<()> {
+        self.client.snapshot_microvm(params).await
+    }
+}
+
+impl MicroVm<Stopped> {
+    /// Exit status of the Firecracker process
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        self.exit_status
+    }
+}
+
+// Set `root=PARTUUID=` in the boot arguments of `boot_source`, returning whether they changed.
+fn set_root(boot_source: &mut BootSource, partuuid: &str) -> bool {
+    boot_source.set_root(partuuid).unwrap_or_else(|err| {
+        warn!("cannot set root=PARTUUID={partuuid} in the boot arguments: {err}");
+        false
+    })
+}
@@ -0,0 +1,615 @@
+//! NAT networking of microVMs through the host
+//!
+//! [`HostNetworkBuilder`] connects a network interface of a microVM to the outside world: its TAP
+//! device is attached to a bridge, whose address is the gateway of the guest, IP forwarding is
+//! enabled, and the traffic of the guest is masqueraded behind the addresses of the host. Ports of
+//! the host can be forwarded to the guest, see [`PortForward`]. The addresses of the guest come
+//! from a [`Lease`].
+//!
+//! IP forwarding is a setting of the whole host, which is left enabled when the networking of the
+//! microVM is removed, as other microVMs may rely on it. Enabling IPv6 forwarding also makes the
+//! host ignore router advertisements, and thus lose the IPv6 addresses and routes it learns from
+//! them: it is only enabled on request, see [`HostNetworkBuilder::with_ipv6_forwarding`].
+//!
+//! Firewall rules are managed with `iptables`, or `ip6tables` for IPv6 guests, which also program
+//! nftables on hosts using `iptables-nft`. Each rule is tagged with the ID of its microVM in a
+//! comment, e.g. `fclib:vm1`. Adding rules is idempotent, and [`remove_vm_rules`] removes exactly
+//! the rules of a microVM, e.g. after a crash.
+
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::str::FromStr;
+
+use log::{info, warn};
+
+use super::ipam::Lease;
+use super::netlink::Netlink;
+use super::tap::{Tap, TapBuilder};
+use super::{link_index, NetError, NetErrors};
+use crate::client::network::NetworkInterface;
+use crate::util::{self, find_tool};
+
+/// Name of the bridge used by default
+pub const DEFAULT_BRIDGE: &str = "fcbr0";
+
+// Prefix of the comments tagging the firewall rules of microVMs
+const TAG_PREFIX: &str = "fclib:";
+
+// Longest microVM ID accepted in tags
+const MAX_VM_ID_LEN: usize = 64;
+
+// Chains holding the rules of microVMs, with their tables
+const CHAINS: [(&str, &str); 4] = [
+    ("nat", "POSTROUTING"),
+    ("nat", "PREROUTING"),
+    ("nat", "OUTPUT"),
+    ("filter", "FORWARD"),
+];
+
+/// Transport protocol of a forwarded port
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        })
+    }
+}
+
+/// A port of the host forwarded to a port of the guest
+///
+/// It parses from `HOST_PORT:GUEST_PORT`, or a single port, optionally followed by `/tcp` or
+/// `/udp`, e.g. `8080:80/tcp`. Connections from other hosts, and from the host to its own
+/// non-loopback addresses, are forwarded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortForward {
+    /// Protocol of the port, TCP by default
+    pub protocol: Protocol,
+    /// Port of the host
+    pub host_port: u16,
+    /// Port of the guest
+    pub guest_port: u16,
+}
+
+impl FromStr for PortForward {
+    type Err = NetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || NetError::InvalidPortForward(s.to_owned());
+        let (ports, protocol) = match s.split_once('/') {
+            Some((ports, "tcp")) => (ports, Protocol::Tcp),
+            Some((ports, "udp")) => (ports, Protocol::Udp),
+            Some(_) => return Err(invalid()),
+            None => (s, Protocol::Tcp),
+        };
+        let port = |port: &str| port.parse::<u16>().ok().filter(|&port| port != 0);
+        let (host_port, guest_port) = match ports.split_once(':') {
+            Some((host, guest)) => (port(host), port(guest)),
+            None => (port(ports), port(ports)),
+        };
+        Ok(PortForward {
+            protocol,
+            host_port: host_port.ok_or_else(invalid)?,
+            guest_port: guest_port.ok_or_else(invalid)?,
+        })
+    }
+}
+
+impl fmt::Display for PortForward {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}/{}",
+            self.host_port, self.guest_port, self.protocol
+        )
+    }
+}
+
+// A firewall rule of a microVM
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Rule {
+    ipv6: bool,
+    table: &'static str,
+    chain: &'static str,
+    // Insert the rule first in its chain, to come before rules rejecting traffic
+    insert: bool,
+    spec: Vec<String>,
+}
+
+impl Rule {
+    // A rule matching `matches`, tagged with `tag`, with the target `target`
+    fn new(
+        lease: &Lease,
+        (table, chain): (&'static str, &'static str),
+        matches: &[&str],
+        tag: &str,
+        target: &[&str],
+    ) -> Rule {
+        let comment = ["-m", "comment", "--comment", tag];
+        let spec = matches
+            .iter()
+            .chain(&comment)
+            .chain(target)
+            .map(|&arg| arg.to_owned())
+            .collect();
+        Rule {
+            ipv6: lease.address.is_ipv6(),
+            table,
+            chain,
+            insert: table == "filter",
+            spec,
+        }
+    }
+
+    // Run `iptables` with `action` on the rule, e.g. `-C` to check that it exists.
+    fn run(&self, action: &str) -> Result<Output, NetError> {
+        let mut args = vec!["-t", self.table, action, self.chain];
+        args.extend(self.spec.iter().map(String::as_str));
+        iptables(self.ipv6, &args)
+    }
+
+    // Returns `true` if the rule exists.
+    fn exists(&self) -> Result<bool, NetError> {
+        let output = self.run("-C")?;
+        match output.status.code() {
+            Some(0) => Ok(true),
+            // The rule, or its chain, does not exist
+            Some(1) => Ok(false),
+            _ => Err(command_error(self.ipv6, &output)),
+        }
+    }
+
+    // Add the rule, unless it exists.
+    fn add(&self) -> Result<(), NetError> {
+        if self.exists()? {
+            return Ok(());
+        }
+        let output = self.run(if self.insert { "-I" } else { "-A" })?;
+        check_status(self.ipv6, &output)
+    }
+
+    // Delete the rule, if it exists.
+    fn delete(&self) -> Result<(), NetError> {
+        if !self.exists()? {
+            return Ok(());
+        }
+        let output = self.run("-D")?;
+        check_status(self.ipv6, &output)
+    }
+}
+
+fn iptables_path(ipv6: bool) -> PathBuf {
+    find_tool(if ipv6 { "ip6tables" } else { "iptables" })
+}
+
+// Run `iptables`, or `ip6tables`, waiting for the lock of the firewall rules.
+fn iptables(ipv6: bool, args: &[&str]) -> Result<Output, NetError> {
+    Ok(util::output(
+        Command::new(iptables_path(ipv6)).arg("-w").args(args),
+    )?)
+}
+
+fn check_status(ipv6: bool, output: &Output) -> Result<(), NetError> {
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(command_error(ipv6, output))
+    }
+}
+
+fn command_error(ipv6: bool, output: &Output) -> NetError {
+    util::command_error(&iptables_path(ipv6).display().to_string(), output).into()
+}
+
+// Tag of the rules of the microVM `vm_id`
+fn vm_tag(vm_id: &str) -> Result<String, NetError> {
+    let valid = !vm_id.is_empty()
+        && vm_id.len() <= MAX_VM_ID_LEN
+        && vm_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if valid {
+        Ok(format!("{TAG_PREFIX}{vm_id}"))
+    } else {
+        Err(NetError::InvalidVmId(vm_id.to_owned()))
+    }
+}
+
+/// Remove all the firewall rules of the microVM `vm_id`, and return how many were removed
+///
+/// It cleans up after microVMs whose [`HostNetwork`] was not torn down, e.g. after a crash.
+/// `ip6tables` is skipped if it is not installed.
+pub fn remove_vm_rules(vm_id: &str) -> Result<usize, NetError> {
+    let tag = vm_tag(vm_id)?;
+    let mut removed = 0;
+    for ipv6 in [false, true] {
+        for (table, chain) in CHAINS {
+            let output = match iptables(ipv6, &["-t", table, "-S", chain]) {
+                Ok(output) => output,
+                Err(_) if ipv6 && !iptables_path(true).is_absolute() => break,
+                Err(err) => return Err(err),
+            };
+            check_status(ipv6, &output)?;
+            for line in String::from_utf8_lossy(&output.stdout).lines() {
+                // Rules are listed as `-A CHAIN SPEC`, and tags are never quoted
+                let words: Vec<&str> = line.split_whitespace().collect();
+                let tagged = words
+                    .windows(2)
+                    .any(|pair| pair[0] == "--comment" && pair[1] == tag);
+                if words.len() < 2 || words[0] != "-A" || !tagged {
+                    continue;
+                }
+                let mut args = vec!["-t", table, "-D"];
+                args.extend(&words[1..]);
+                check_status(ipv6, &iptables(ipv6, &args)?)?;
+                removed += 1;
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Create the bridge `name` with the address `gateway`, if it does not exist, and bring it up
+///
+/// `gateway` is added to the bridge if it is missing. Returns the index of the bridge.
+pub fn setup_bridge(name: &str, gateway: IpAddr, prefix_len: u8) -> Result<u32, NetError> {
+    let mut netlink =
+        Netlink::open().map_err(|err| NetError::failed(format!("set up bridge {name}"), err))?;
+    if link_index(name)?.is_none() {
+        match netlink.create_link(name, "bridge") {
+            Err(err) if err.raw_os_error() != Some(libc::EEXIST) => {
+                return Err(NetError::failed(format!("create bridge {name}"), err))
+            }
+            _ => {}
+        }
+    }
+    let index = link_index(name)?.ok_or_else(|| NetError::NotFound(name.to_owned()))?;
+    match netlink.add_address(index, gateway, prefix_len) {
+        Err(err) if err.raw_os_error() != Some(libc::EEXIST) => {
+            return Err(NetError::failed(
+                format!("add {gateway}/{prefix_len} to {name}"),
+                err,
+            ))
+        }
+        _ => {}
+    }
+    netlink
+        .set_up(index)
+        .map_err(|err| NetError::failed(format!("bring {name} up"), err))?;
+    Ok(index)
+}
+
+/// Delete the bridge `name`, if it exists
+///
+/// With `only_unused`, the bridge is only deleted if no device is attached to it anymore, and
+/// `false` is returned otherwise.
+pub fn delete_bridge(name: &str, only_unused: bool) -> Result<bool, NetError> {
+    let Some(index) = link_index(name)? else {
+        return Ok(true);
+    };
+    if only_unused {
+        let ports = Path::new("/sys/class/net").join(name).join("brif");
+        let used = fs::read_dir(&ports)
+            .map_err(|err| NetError::failed(format!("list the devices of bridge {name}"), err))?
+            .next()
+            .is_some();
+        if used {
+            return Ok(false);
+        }
+    }
+    Netlink::open()
+        .and_then(|mut netlink| netlink.delete_link(index))
+        .map_err(|err| NetError::failed(format!("delete bridge {name}"), err))?;
+    Ok(true)
+}
+
+// Enable the forwarding of IPv4, or IPv6, packets between the devices of the host, unless it is
+// already enabled. IPv6 forwarding is only enabled if `allow_ipv6` is set. Returns whether it was
+// enabled.
+fn enable_forwarding(ipv6: bool, allow_ipv6: bool) -> Result<bool, NetError> {
+    let path = match ipv6 {
+        false => "/proc/sys/net/ipv4/ip_forward",
+        true => "/proc/sys/net/ipv6/conf/all/forwarding",
+    };
+    let value = fs::read_to_string(path).unwrap_or_default();
+    if value.trim() == "1" {
+        return Ok(false);
+    }
+    if ipv6 && !allow_ipv6 {
+        return Err(NetError::Ipv6ForwardingDisabled);
+    }
+
+    fs::write(path, "1").map_err(|err| NetError::failed("enable IP forwarding", err))?;
+    if ipv6 {
+        warn!("enabled IPv6 forwarding: {path} is now 1, the host ignores router advertisements");
+    } else {
+        info!("enabled IPv4 forwarding: {path} is now 1");
+    }
+    Ok(true)
+}
+
+/// Builder of the host networking of a network interface of a microVM
+#[derive(Debug, Clone)]
+pub struct HostNetworkBuilder {
+    vm_id: String,
+    lease: Lease,
+    tap: TapBuilder,
+    bridge: String,
+    port_forwards: Vec<PortForward>,
+    ipv6_forwarding: bool,
+}
+
+impl HostNetworkBuilder {
+    /// Networking of the interface of `lease`, of the microVM `vm_id`, through the TAP device
+    /// `tap_name`, which must not exist
+    ///
+    /// The ID of the microVM tags its firewall rules: it is made of letters, digits, `-`, `_` and
+    /// `.`.
+    pub fn new<S: Into<String>, T: Into<String>>(vm_id: S, tap_name: T, lease: Lease) -> Self {
+        HostNetworkBuilder {
+            vm_id: vm_id.into(),
+            lease,
+            tap: TapBuilder::new(tap_name),
+            bridge: DEFAULT_BRIDGE.to_owned(),
+            port_forwards: Vec::new(),
+            ipv6_forwarding: false,
+        }
+    }
+
+    /// Attach the TAP device to the bridge `bridge`, [`DEFAULT_BRIDGE`] by default
+    pub fn with_bridge<S: Into<String>>(mut self, bridge: S) -> Self {
+        self.bridge = bridge.into();
+        self
+    }
+
+    /// Let the user `uid`, e.g. the user Firecracker runs as, open the TAP device
+    pub fn with_owner(mut self, uid: u32) -> Self {
+        self.tap = self.tap.with_owner(uid);
+        self
+    }
+
+    /// Set the MTU of the TAP device
+    pub fn with_mtu(mut self, mtu: u32) -> Self {
+        self.tap = self.tap.with_mtu(mtu);
+        self
+    }
+
+    /// Forward a port of the host to the guest
+    pub fn with_port_forward(mut self, port_forward: PortForward) -> Self {
+        self.port_forwards.push(port_forward);
+        self
+    }
+
+    /// Enable IPv6 forwarding on the host if an IPv6 guest needs it, which is not done by default
+    ///
+    /// IPv6 forwarding applies to all the devices of the host, and is left enabled afterwards.
+    /// Hosts with forwarding enabled ignore router advertisements, unless `accept_ra` is set to 2:
+    /// a host that configures its IPv6 addresses or routes from them loses them.
+    pub fn with_ipv6_forwarding(mut self, enable: bool) -> Self {
+        self.ipv6_forwarding = enable;
+        self
+    }
+
+    /// Set up the bridge, the TAP device and the firewall rules
+    ///
+    /// Everything but the bridge, which may be shared with other microVMs, is removed if a step
+    /// fails. An IPv6 guest fails with [`NetError::Ipv6ForwardingDisabled`] if IPv6 forwarding is
+    /// disabled, unless [enabling it](HostNetworkBuilder::with_ipv6_forwarding) was requested.
+    pub fn setup(self) -> Result<HostNetwork, NetError> {
+        let tag = vm_tag(&self.vm_id)?;
+        let enabled_forwarding =
+            enable_forwarding(self.lease.address.is_ipv6(), self.ipv6_forwarding)?;
+        setup_bridge(&self.bridge, self.lease.gateway, self.lease.prefix_len)?;
+        let tap = self.tap.with_bridge(&self.bridge).create()?;
+
+        let rules = rules(&self.lease, &self.bridge, &tag, &self.port_forwards);
+        // From now on, dropping `network` removes the rules and the TAP device
+        let network = HostNetwork {
+            vm_id: self.vm_id,
+            lease: self.lease,
+            tap: Some(tap),
+            bridge: self.bridge,
+            port_forwards: self.port_forwards,
+            rules,
+            enabled_forwarding,
+        };
+        for rule in &network.rules {
+            rule.add()?;
+        }
+        Ok(network)
+    }
+}
+
+// The firewall rules of the guest with the address of `lease`, behind `bridge`
+fn rules(lease: &Lease, bridge: &str, tag: &str, port_forwards: &[PortForward]) -> Vec<Rule> {
+    let guest = match lease.address {
+        IpAddr::V4(address) => format!("{address}/32"),
+        IpAddr::V6(address) => format!("{address}/128"),
+    };
+    let guest = guest.as_str();
+    let (nat, filter) = (("nat", "POSTROUTING"), ("filter", "FORWARD"));
+    let mut rules = vec![
+        Rule::new(
+            lease,
+            nat,
+            &["-s", guest, "!", "-o", bridge],
+            tag,
+            &["-j", "MASQUERADE"],
+        ),
+        Rule::new(
+            lease,
+            filter,
+            &["-i", bridge, "-s", guest, "!", "-o", bridge],
+            tag,
+            &["-j", "ACCEPT"],
+        ),
+        Rule::new(
+            lease,
+            filter,
+            &[
+                "-o",
+                bridge,
+                "-d",
+                guest,
+                "-m",
+                "conntrack",
+                "--ctstate",
+                "RELATED,ESTABLISHED",
+            ],
+            tag,
+            &["-j", "ACCEPT"],
+        ),
+    ];
+
+    for forward in port_forwards {
+        let protocol = forward.protocol.to_string();
+        let host_port = forward.host_port.to_string();
+        let guest_port = forward.guest_port.to_string();
+        let destination = match lease.address {
+            IpAddr::V4(address) => format!("{address}:{guest_port}"),
+            IpAddr::V6(address) => format!("[{address}]:{guest_port}"),
+        };
+        let dnat = [
+            "-p",
+            &protocol,
+            "-m",
+            &protocol,
+            "--dport",
+            &host_port,
+            "-m",
+            "addrtype",
+            "--dst-type",
+            "LOCAL",
+        ];
+        let target = ["-j", "DNAT", "--to-destination", &destination];
+        rules.push(Rule::new(lease, ("nat", "PREROUTING"), &dnat, tag, &target));
+        rules.push(Rule::new(lease, ("nat", "OUTPUT"), &dnat, tag, &target));
+        rules.push(Rule::new(
+            lease,
+            filter,
+            &[
+                "-o",
+                bridge,
+                "-d",
+                guest,
+                "-p",
+                &protocol,
+                "-m",
+                &protocol,
+                "--dport",
+                &guest_port,
+            ],
+            tag,
+            &["-j", "ACCEPT"],
+        ));
+    }
+    rules
+}
+
+/// The host networking of a network interface of a microVM
+///
+/// The firewall rules and the TAP device are removed when it is dropped, unless it is
+/// [kept](HostNetwork::keep). The bridge is left in place.
+#[derive(Debug)]
+pub struct HostNetwork {
+    vm_id: String,
+    lease: Lease,
+    // `None` once kept or torn down
+    tap: Option<Tap>,
+    bridge: String,
+    port_forwards: Vec<PortForward>,
+    rules: Vec<Rule>,
+    enabled_forwarding: bool,
+}
+
+impl HostNetwork {
+    /// ID of the microVM
+    pub fn vm_id(&self) -> &str {
+        &self.vm_id
+    }
+
+    /// Addresses of the interface
+    pub fn lease(&self) -> &Lease {
+        &self.lease
+    }
+
+    /// Name of the TAP device
+    pub fn tap_name(&self) -> &str {
+        self.tap.as_ref().map(Tap::name).unwrap_or_default()
+    }
+
+    /// Name of the bridge
+    pub fn bridge(&self) -> &str {
+        &self.bridge
+    }
+
+    /// Forwarded ports
+    pub fn port_forwards(&self) -> &[PortForward] {
+        &self.port_forwards
+    }
+
+    /// Whether IP forwarding was disabled on the host, and enabled for this interface
+    ///
+    /// Forwarding is left enabled when the networking is removed.
+    pub fn enabled_forwarding(&self) -> bool {
+        self.enabled_forwarding
+    }
+
+    /// The network interface of the microVM, backed by the TAP device, with the MAC address of
+    /// the lease
+    pub fn network_interface(&self) -> NetworkInterface {
+        self.lease.network_interface(self.tap_name())
+    }
+
+    /// Remove the firewall rules and the TAP device now, reporting errors
+    ///
+    /// Every removal is attempted, and several failures are reported as [`NetError::Teardown`].
+    pub fn teardown(mut self) -> Result<(), NetError> {
+        self.remove()
+    }
+
+    /// Keep the firewall rules and the TAP device after the [`HostNetwork`] is dropped
+    ///
+    /// [`remove_vm_rules`] removes the rules later on.
+    pub fn keep(mut self) {
+        if let Some(tap) = self.tap.take() {
+            tap.keep();
+        }
+    }
+
+    // Delete every rule and the TAP device, even if some of them cannot be deleted.
+    fn remove(&mut self) -> Result<(), NetError> {
+        let Some(tap) = self.tap.take() else {
+            return Ok(());
+        };
+        let mut errors: Vec<NetError> = self
+            .rules
+            .iter()
+            .filter_map(|rule| rule.delete().err())
+            .collect();
+        errors.extend(tap.delete().err());
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(NetError::Teardown(NetErrors(errors))),
+        }
+    }
+}
+
+impl Drop for HostNetwork {
+    fn drop(&mut self) {
+        if let Err(err) = self.remove() {
+            warn!("{err}");
+        }
+    }
+}
@@ -0,0 +1,606 @@
+//! MAC and IP address management
+//!
+//! [`Ipam`] hands out the addresses of the network interfaces of microVMs from configured
+//! [`Subnet`]s, and records them as leases in a JSON file, so that microVMs started by different
+//! processes never share an address. Each interface, identified by the ID of its microVM and its
+//! `iface_id`, gets a locally administered MAC address and an IPv4 or IPv6 address, the first free
+//! one of the subnets. The first address of a subnet is its gateway.
+//!
+//! A [`Lease`] fills in the `guest_mac` of a [`NetworkInterface`], the `ip=` parameter of the guest
+//! kernel (see [`Lease::ip_config`]), and the network metadata served by MMDS (see
+//! [`mmds_network`]).
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::client::cmdline::IpConfig;
+use crate::client::network::NetworkInterface;
+use crate::client::types::MacAddr;
+
+// First byte of the MAC addresses handed out: locally administered and unicast
+const MAC_PREFIX: u8 = 0x06;
+
+/// An error while allocating or releasing addresses
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum IpamError {
+    /// cannot access the lease file: {0}
+    Io(#[from] io::Error),
+    /// invalid lease file: {0}
+    Json(#[from] serde_json::Error),
+    /// invalid subnet `{0}`, expected e.g. `172.16.0.0/24` or `fd00::/64`
+    InvalidSubnet(String),
+    /// no free address left in {0}
+    Exhausted(String),
+}
+
+/// An IPv4 or IPv6 subnet, e.g. `172.16.0.0/24`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Subnet {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Subnet {
+    /// The subnet of the addresses sharing the first `prefix_len` bits of `addr`
+    ///
+    /// IPv4 subnets need room for a gateway and a guest, i.e. a prefix of at most 30 bits, and IPv6
+    /// subnets a prefix of at most 126 bits.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Subnet, IpamError> {
+        let max_len = match addr {
+            IpAddr::V4(_) => 30,
+            IpAddr::V6(_) => 126,
+        };
+        if prefix_len > max_len {
+            return Err(IpamError::InvalidSubnet(format!("{addr}/{prefix_len}")));
+        }
+        let subnet = Subnet {
+            network: addr,
+            prefix_len,
+        };
+        Ok(Subnet {
+            network: subnet.addr(0),
+            prefix_len,
+        })
+    }
+
+    /// Address of the network, i.e. its first address
+    pub fn network(&self) -> IpAddr {
+        self.network
+    }
+
+    /// Length of the prefix of the subnet, in bits
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Address of the gateway of the subnet, i.e. the first address after the network address
+    pub fn gateway(&self) -> IpAddr {
+        self.addr(1)
+    }
+
+    /// Netmask of the subnet, e.g. `255.255.255.0` for a /24
+    pub fn netmask(&self) -> IpAddr {
+        netmask(self.network, self.prefix_len)
+    }
+
+    /// Returns `true` if `addr` is in the subnet.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        self.offset(addr).is_some()
+    }
+
+    fn bits(&self) -> u32 {
+        match self.network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+
+    // Range of the offsets of the addresses that can be handed out to guests: after the network
+    // address and the gateway, and before the broadcast address of IPv4 subnets
+    fn host_range(&self) -> (u128, u128) {
+        let size = 1u128
+            .checked_shl(self.bits() - u32::from(self.prefix_len))
+            .unwrap_or(u128::MAX);
+        match self.network {
+            IpAddr::V4(_) => (2, size - 1),
+            IpAddr::V6(_) => (2, size),
+        }
+    }
+
+    // The address at `offset` in the subnet
+    fn addr(&self, offset: u128) -> IpAddr {
+        let host_bits = self.bits() - u32::from(self.prefix_len);
+        let mask = u128::MAX.checked_shl(host_bits).unwrap_or(0);
+        match self.network {
+            IpAddr::V4(network) => {
+                let network = u128::from(u32::from(network)) & mask;
+                IpAddr::V4(Ipv4Addr::from((network + offset) as u32))
+            }
+            IpAddr::V6(network) => {
+                let network = u128::from(network) & mask;
+                IpAddr::V6(Ipv6Addr::from(network + offset))
+            }
+        }
+    }
+
+    // Offset of `addr` in the subnet, if it is in the subnet
+    fn offset(&self, addr: IpAddr) -> Option<u128> {
+        let (network, addr) = match (self.network, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                (u128::from(u32::from(network)), u128::from(u32::from(addr)))
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => (u128::from(network), u128::from(addr)),
+            _ => return None,
+        };
+        let host_bits = self.bits() - u32::from(self.prefix_len);
+        let offset = addr.wrapping_sub(network);
+        (addr >= network && offset.checked_shr(host_bits).unwrap_or(0) == 0).then_some(offset)
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = IpamError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || IpamError::InvalidSubnet(s.to_owned());
+        let (addr, prefix_len) = s.split_once('/').ok_or_else(invalid)?;
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let prefix_len: u8 = prefix_len.parse().map_err(|_| invalid())?;
+        Subnet::new(addr, prefix_len).map_err(|_| invalid())
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+// Netmask of a prefix of `prefix_len` bits, in the family of `addr`
+fn netmask(addr: IpAddr, prefix_len: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(_) => {
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(prefix_len))
+                .unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(mask))
+        }
+        IpAddr::V6(_) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(prefix_len))
+                .unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(mask))
+        }
+    }
+}
+
+/// Addresses of a network interface of a microVM
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lease {
+    /// ID of the microVM
+    pub vm_id: String,
+    /// ID of the network interface in the microVM
+    pub iface_id: String,
+    /// MAC address of the interface in the guest
+    pub mac: MacAddr,
+    /// IP address of the guest
+    pub address: IpAddr,
+    /// Length of the prefix of the subnet of the address
+    pub prefix_len: u8,
+    /// Address of the gateway
+    pub gateway: IpAddr,
+}
+
+impl Lease {
+    /// Netmask of the subnet of the address
+    pub fn netmask(&self) -> IpAddr {
+        netmask(self.address, self.prefix_len)
+    }
+
+    /// A network interface backed by the host device `host_dev_name`, with the MAC address of the
+    /// lease
+    pub fn network_interface<S: Into<String>>(&self, host_dev_name: S) -> NetworkInterface {
+        let mut iface = NetworkInterface::new(host_dev_name.into(), self.iface_id.clone());
+        iface.guest_mac = Some(self.mac);
+        iface
+    }
+
+    /// The static configuration of the guest kernel, i.e. `ip=`, for an IPv4 address
+    ///
+    /// The kernel only configures IPv4 addresses, so it is `None` for IPv6 leases. See
+    /// [`KernelCmdline::ip_from_interfaces`](crate::client::cmdline::KernelCmdline::ip_from_interfaces)
+    /// to set the device.
+    pub fn ip_config(&self) -> Option<IpConfig> {
+        match (self.address, self.gateway, self.netmask()) {
+            (IpAddr::V4(address), IpAddr::V4(gateway), IpAddr::V4(netmask)) => {
+                Some(IpConfig::new(address, gateway, netmask))
+            }
+            _ => None,
+        }
+    }
+
+    // The network metadata of the interface served by MMDS
+    fn mmds_metadata(&self) -> Value {
+        let family = match self.address {
+            IpAddr::V4(_) => "ipv4",
+            IpAddr::V6(_) => "ipv6",
+        };
+        json!({
+            "mac": self.mac.to_string(),
+            family: {
+                "address": self.address.to_string(),
+                "prefix_len": self.prefix_len,
+                "netmask": self.netmask().to_string(),
+                "gateway": self.gateway.to_string(),
+            },
+        })
+    }
+}
+
+/// The network metadata of the interfaces of a microVM, to serve with MMDS
+///
+/// It maps the ID of each interface to its MAC address and its address, e.g.
+/// `{"interfaces": {"eth0": {"mac": "06:00:ac:10:00:02", "ipv4": {"address": "172.16.0.2", ...}}}}`.
+pub fn mmds_network(leases: &[Lease]) -> Value {
+    let interfaces: serde_json::Map<String, Value> = leases
+        .iter()
+        .map(|lease| (lease.iface_id.clone(), lease.mmds_metadata()))
+        .collect();
+    json!({ "interfaces": interfaces })
+}
+
+// Content of the lease file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LeaseFile {
+    leases: Vec<Lease>,
+}
+
+/// An allocator of addresses from subnets, recording its leases in a file
+///
+/// The lease file is locked while it is read and updated, and replaced atomically.
+#[derive(Debug, Clone)]
+pub struct Ipam {
+    path: PathBuf,
+    subnets: Vec<Subnet>,
+}
+
+impl Ipam {
+    /// An allocator of addresses from `subnets`, in order, with the lease file at `path`
+    ///
+    /// The file is created on the first allocation.
+    pub fn new<P: AsRef<Path>>(path: P, subnets: Vec<Subnet>) -> Ipam {
+        Ipam {
+            path: path.as_ref().to_path_buf(),
+            subnets,
+        }
+    }
+
+    /// Lease addresses to the interface `iface_id` of the microVM `vm_id`
+    ///
+    /// The current lease of the interface is returned if it has one.
+    pub fn allocate(&self, vm_id: &str, iface_id: &str) -> Result<Lease, IpamError> {
+        self.update(|leases| {
+            if let Some(lease) = leases
+                .iter()
+                .find(|lease| lease.vm_id == vm_id && lease.iface_id == iface_id)
+            {
+                return Ok(lease.clone());
+            }
+            let (subnet, address) = self.free_address(leases)?;
+            let lease = Lease {
+                vm_id: vm_id.to_owned(),
+                iface_id: iface_id.to_owned(),
+                mac: free_mac(leases, address)
+                    .ok_or_else(|| IpamError::Exhausted(format!("MAC addresses for {address}")))?,
+                address,
+                prefix_len: subnet.prefix_len,
+                gateway: subnet.gateway(),
+            };
+            leases.push(lease.clone());
+            Ok(lease)
+        })
+    }
+
+    /// Release the lease of the interface `iface_id` of the microVM `vm_id`, and return it
+    pub fn release(&self, vm_id: &str, iface_id: &str) -> Result<Option<Lease>, IpamError> {
+        self.update(|leases| {
+            let index = leases
+                .iter()
+                .position(|lease| lease.vm_id == vm_id && lease.iface_id == iface_id);
+            Ok(index.map(|index| leases.remove(index)))
+        })
+    }
+
+    /// Release the leases of all the interfaces of the microVM `vm_id`, e.g. when it is torn down
+    pub fn release_vm(&self, vm_id: &str) -> Result<Vec<Lease>, IpamError> {
+        self.update(|leases| {
+            let (released, kept) = leases.drain(..).partition(|lease| lease.vm_id == vm_id);
+            *leases = kept;
+            Ok(released)
+        })
+    }
+
+    /// The current leases
+    pub fn leases(&self) -> Result<Vec<Lease>, IpamError> {
+        let _lock = self.lock()?;
+        Ok(self.read()?.leases)
+    }
+
+    /// The current leases of the microVM `vm_id`
+    pub fn vm_leases(&self, vm_id: &str) -> Result<Vec<Lease>, IpamError> {
+        let mut leases = self.leases()?;
+        leases.retain(|lease| lease.vm_id == vm_id);
+        Ok(leases)
+    }
+
+    // The first address of the subnets that is not leased
+    fn free_address(&self, leases: &[Lease]) -> Result<(Subnet, IpAddr), IpamError> {
+        for subnet in &self.subnets {
+            let used: BTreeSet<u128> = leases
+                .iter()
+                .filter_map(|lease| subnet.offset(lease.address))
+                .collect();
+            let (start, end) = subnet.host_range();
+            // `used` is sorted: the first gap is the first free address
+            let mut offset = start;
+            for &used in used.range(start..end) {
+                if used != offset {
+                    break;
+                }
+                offset += 1;
+            }
+            if offset < end {
+                return Ok((*subnet, subnet.addr(offset)));
+            }
+        }
+        let subnets: Vec<String> = self.subnets.iter().map(Subnet::to_string).collect();
+        Err(IpamError::Exhausted(subnets.join(", ")))
+    }
+
+    // Run `f` on the leases, and save them, with the lease file locked.
+    fn update<T>(
+        &self,
+        f: impl FnOnce(&mut Vec<Lease>) -> Result<T, IpamError>,
+    ) -> Result<T, IpamError> {
+        let _lock = self.lock()?;
+        let mut file = self.read()?;
+        let result = f(&mut file.leases)?;
+        self.write(&file)?;
+        Ok(result)
+    }
+
+    fn read(&self) -> Result<LeaseFile, IpamError> {
+        match fs::read(&self.path) {
+            Ok(content) => {
+                let file: LeaseFile = serde_json::from_slice(&content)?;
+                for lease in &file.leases {
+                    let bits = if lease.address.is_ipv4() { 32 } else { 128 };
+                    if lease.prefix_len > bits {
+                        return Err(IpamError::InvalidSubnet(format!(
+                            "{}/{}",
+                            lease.address, lease.prefix_len
+                        )));
+                    }
+                }
+                Ok(file)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(LeaseFile::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn write(&self, file: &LeaseFile) -> Result<(), IpamError> {
+        let mut temp = tempfile::NamedTempFile::new_in(self.dir())?;
+        serde_json::to_writer_pretty(&mut temp, file)?;
+        temp.write_all(b"\n")?;
+        temp.as_file().sync_all()?;
+        temp.persist(&self.path).map_err(|err| err.error)?;
+        Ok(())
+    }
+
+    fn dir(&self) -> &Path {
+        self.path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."))
+    }
+
+    // Lock the lease file, through a lock file next to it, as the lease file itself is replaced.
+    fn lock(&self) -> Result<File, IpamError> {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".lock");
+        let lock = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        // SAFETY: flock does not access memory.
+        while unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err.into());
+            }
+        }
+        // The lock is released when the file is closed
+        Ok(lock)
+    }
+}
+
+// A MAC address for `address` that is not leased: `06:00` followed by the last 4 bytes of the
+// address, e.g. `06:00:ac:10:00:02` for `172.16.0.2`, with the second byte changed on conflicts.
+fn free_mac(leases: &[Lease], address: IpAddr) -> Option<MacAddr> {
+    let low = match address {
+        IpAddr::V4(address) => address.octets(),
+        IpAddr::V6(address) => {
+            let octets = address.octets();
+            [octets[12], octets[13], octets[14], octets[15]]
+        }
+    };
+    let used: BTreeSet<MacAddr> = leases.iter().map(|lease| lease.mac).collect();
+    (0..=u8::MAX)
+        .map(|second| MacAddr::new([MAC_PREFIX, second, low[0], low[1], low[2], low[3]]))
+        .find(|mac| !used.contains(mac))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subnet(s: &str) -> Subnet {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn ip_v6(s: &str) -> Ipv6Addr {
+        s.parse().unwrap()
+    }
+
+    fn lease(address: IpAddr, mac: MacAddr) -> Lease {
+        Lease {
+            vm_id: "vm".to_owned(),
+            iface_id: address.to_string(),
+            mac,
+            address,
+            prefix_len: 24,
+            gateway: address,
+        }
+    }
+
+    #[test]
+    fn parse_subnet() {
+        // Host addresses are normalized to the address of their network
+        let parsed = subnet("172.16.0.5/24");
+        assert_eq!(parsed.network(), ip("172.16.0.0"));
+        assert_eq!(parsed.gateway(), ip("172.16.0.1"));
+        assert_eq!(parsed.netmask(), ip("255.255.255.0"));
+        assert_eq!(parsed.to_string(), "172.16.0.0/24");
+        assert_eq!(subnet("fd00::1:2/64").to_string(), "fd00::/64");
+        assert_eq!(subnet("10.1.2.3/0").to_string(), "0.0.0.0/0");
+
+        for invalid in [
+            "172.16.0.0/31",
+            "fd00::/127",
+            "172.16.0.0",
+            "x/24",
+            "10.0.0.0/-1",
+        ] {
+            assert!(
+                matches!(invalid.parse::<Subnet>(), Err(IpamError::InvalidSubnet(s)) if s == invalid),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn host_range_and_offset() {
+        // The broadcast address of IPv4 subnets is excluded
+        assert_eq!(subnet("172.16.0.0/30").host_range(), (2, 3));
+        assert_eq!(subnet("172.16.0.0/24").host_range(), (2, 255));
+        assert_eq!(subnet("0.0.0.0/0").host_range(), (2, (1 << 32) - 1));
+        assert_eq!(subnet("fd00::/126").host_range(), (2, 4));
+        assert_eq!(subnet("::/0").host_range(), (2, u128::MAX));
+
+        let v4 = subnet("172.16.0.0/24");
+        assert_eq!(v4.offset(ip("172.16.0.0")), Some(0));
+        assert_eq!(v4.offset(ip("172.16.0.255")), Some(255));
+        assert_eq!(v4.offset(ip("172.16.1.0")), None);
+        assert_eq!(v4.offset(ip("172.15.255.255")), None);
+        assert_eq!(v4.offset(ip("::ffff:172.16.0.2")), None);
+        assert_eq!(
+            subnet("0.0.0.0/0").offset(ip("255.255.255.255")),
+            Some(u128::from(u32::MAX))
+        );
+        assert_eq!(subnet("fd00::/126").offset(ip("fd00::3")), Some(3));
+        assert_eq!(subnet("fd00::/126").offset(ip("fd00::4")), None);
+        assert_eq!(
+            subnet("::/0").offset(ip("ffff::")),
+            Some(u128::from(ip_v6("ffff::")))
+        );
+    }
+
+    #[test]
+    fn allocate_and_release() {
+        let dir = tempfile::tempdir().unwrap();
+        let ipam = Ipam::new(
+            dir.path().join("leases.json"),
+            vec![subnet("172.16.0.0/29")],
+        );
+        let address = |vm_id: &str| ipam.allocate(vm_id, "eth0").unwrap().address;
+
+        // .2 to .6 are handed out, but neither the gateway nor the broadcast address
+        let addresses: Vec<_> = ["a", "b", "c", "d", "e"].map(address).into();
+        assert_eq!(addresses[0], ip("172.16.0.2"));
+        assert_eq!(addresses[4], ip("172.16.0.6"));
+        assert!(matches!(
+            ipam.allocate("f", "eth0"),
+            Err(IpamError::Exhausted(subnets)) if subnets == "172.16.0.0/29"
+        ));
+
+        // An interface keeps its lease, and released addresses are reused, first gap first
+        assert_eq!(address("a"), ip("172.16.0.2"));
+        ipam.release("d", "eth0").unwrap().unwrap();
+        ipam.release("b", "eth0").unwrap().unwrap();
+        assert_eq!(ipam.release("b", "eth0").unwrap(), None);
+        assert_eq!(address("f"), ip("172.16.0.3"));
+        assert_eq!(address("g"), ip("172.16.0.5"));
+        assert_eq!(ipam.leases().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn allocate_across_subnets() {
+        let dir = tempfile::tempdir().unwrap();
+        let ipam = Ipam::new(
+            dir.path().join("leases.json"),
+            vec![subnet("172.16.0.0/30"), subnet("fd00::/126")],
+        );
+        let lease = ipam.allocate("vm1", "eth0").unwrap();
+        assert_eq!(lease.address, ip("172.16.0.2"));
+        assert_eq!(lease.gateway, ip("172.16.0.1"));
+        assert_eq!(lease.mac.to_string(), "06:00:ac:10:00:02");
+
+        let lease = ipam.allocate("vm1", "eth1").unwrap();
+        assert_eq!(lease.address, ip("fd00::2"));
+        assert_eq!(lease.gateway, ip("fd00::1"));
+        assert_eq!(lease.prefix_len, 126);
+        assert_eq!(ipam.allocate("vm2", "eth0").unwrap().address, ip("fd00::3"));
+        assert!(matches!(
+            ipam.allocate("vm3", "eth0"),
+            Err(IpamError::Exhausted(subnets)) if subnets == "172.16.0.0/30, fd00::/126"
+        ));
+
+        assert_eq!(ipam.release_vm("vm1").unwrap().len(), 2);
+        assert_eq!(ipam.vm_leases("vm2").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn mac_collisions() {
+        // Addresses sharing their last 4 bytes get different MAC addresses
+        let address = ip("fd00::ac10:2");
+        let taken = MacAddr::new([MAC_PREFIX, 0, 0xac, 0x10, 0, 2]);
+        let mut leases = vec![lease(ip("172.16.0.2"), taken)];
+        assert_eq!(
+            free_mac(&leases, address),
+            Some(MacAddr::new([MAC_PREFIX, 1, 0xac, 0x10, 0, 2]))
+        );
+
+        leases.extend((1..=u8::MAX).map(|second| {
+            lease(
+                address,
+                MacAddr::new([MAC_PREFIX, second, 0xac, 0x10, 0, 2]),
+            )
+        }));
+        assert_eq!(free_mac(&leases, address), None);
+    }
+}
@@ -0,0 +1,117 @@
+//! Host networking for microVMs
+//!
+//! Firecracker backs the network interfaces of a microVM with TAP devices of the host, which it
+//! expects to exist. [`tap`] creates and tears them down. Devices are configured with ioctls and
+//! rtnetlink, without running `ip`, and all of it requires CAP_NET_ADMIN.
+//!
+//! [`ipam`] hands out the MAC and IP addresses of the interfaces of microVMs, and [`host`] connects
+//! them to the outside world through a bridge and NAT. [`netns`] isolates Firecracker processes in
+//! network namespaces, and [`stats`] reports the traffic of their interfaces.
+
+pub mod host;
+pub mod ipam;
+mod netlink;
+pub mod netns;
+pub mod stats;
+pub mod tap;
+
+use std::fmt;
+use std::io;
+
+use crate::util::CommandError;
+
+// Longest name of a network device, without its terminating nul byte
+const MAX_NAME_LEN: usize = 15;
+
+/// An error while configuring host networking
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum NetError {
+    /// cannot {0}: {1}
+    Failed(String, #[source] io::Error),
+    /// cannot {0}: permission denied, CAP_NET_ADMIN is required
+    PermissionDenied(String),
+    /// network device {0} already exists
+    Exists(String),
+    /// network device {0} does not exist
+    NotFound(String),
+    /// network device {0} is not a TAP device
+    NotTap(String),
+    /// invalid network device name {0:?}
+    InvalidName(String),
+    /// invalid microVM ID {0:?}, expected letters, digits, `-`, `_` and `.`
+    InvalidVmId(String),
+    /// invalid port forward {0:?}, expected `HOST_PORT:GUEST_PORT[/tcp|/udp]`
+    InvalidPortForward(String),
+    /// `{0}` failed: {1}
+    Command(String, String),
+    /// IPv6 forwarding is disabled on the host, enabling it must be requested explicitly
+    Ipv6ForwardingDisabled,
+    /// cannot remove the network of the microVM: {0}
+    Teardown(NetErrors),
+}
+
+impl From<CommandError> for NetError {
+    fn from(err: CommandError) -> Self {
+        NetError::Command(err.program, err.message)
+    }
+}
+
+/// Errors of the steps of an operation that goes on after failures, e.g. removing the network of
+/// a microVM
+#[derive(Debug, thiserror::Error)]
+pub struct NetErrors(pub Vec<NetError>);
+
+impl fmt::Display for NetErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} error(s):", self.0.len())?;
+        for error in &self.0 {
+            write!(f, "\n  - {error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl NetError {
+    // Error of the operation `what` failing with `err`
+    fn failed(what: impl Into<String>, err: io::Error) -> NetError {
+        match err.raw_os_error() {
+            Some(libc::EPERM | libc::EACCES) => NetError::PermissionDenied(what.into()),
+            _ => NetError::Failed(what.into(), err),
+        }
+    }
+}
+
+// Check that `name` is a valid name for a network device.
+fn check_name(name: &str) -> Result<(), NetError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name != "."
+        && name != ".."
+        && !name
+            .chars()
+            .any(|c| c == '/' || c == ':' || c == '%' || c.is_whitespace());
+    if valid {
+        Ok(())
+    } else {
+        Err(NetError::InvalidName(name.to_owned()))
+    }
+}
+
+// Index of the network device `name`, if it exists
+fn link_index(name: &str) -> Result<Option<u32>, NetError> {
+    check_name(name)?;
+    let c_name = std::ffi::CString::new(name).expect("checked name");
+    // SAFETY: `c_name` is a valid nul-terminated string.
+    let index = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+    if index != 0 {
+        return Ok(Some(index));
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::ENODEV) => Ok(None),
+        _ => Err(NetError::failed(
+            format!("look up network device {name}"),
+            err,
+        )),
+    }
+}
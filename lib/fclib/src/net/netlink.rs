@@ -0,0 +1,348 @@
+//! A minimal rtnetlink client, to configure network devices
+
+use std::io;
+use std::net::IpAddr;
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+
+// Message types and flags, from `linux/netlink.h` and `linux/rtnetlink.h`
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 1;
+const NLM_F_ACK: u16 = 4;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+const RTM_NEWLINK: u16 = 16;
+const RTM_DELLINK: u16 = 17;
+const RTM_GETLINK: u16 = 18;
+const RTM_NEWADDR: u16 = 20;
+const RTM_NEWROUTE: u16 = 24;
+
+// Attributes of links, from `linux/if_link.h`
+const IFLA_IFNAME: u16 = 3;
+const IFLA_MTU: u16 = 4;
+const IFLA_MASTER: u16 = 10;
+const IFLA_LINKINFO: u16 = 18;
+const IFLA_STATS64: u16 = 23;
+const IFLA_INFO_KIND: u16 = 1;
+const IFLA_INFO_DATA: u16 = 2;
+const IFLA_NET_NS_FD: u16 = 28;
+const VETH_INFO_PEER: u16 = 1;
+
+// Attributes of addresses, from `linux/if_addr.h`
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+
+// Routes, from `linux/rtnetlink.h`
+const RTA_GATEWAY: u16 = 5;
+const RT_TABLE_MAIN: u8 = 254;
+const RTPROT_BOOT: u8 = 3;
+const RTN_UNICAST: u8 = 1;
+
+// Sizes of the netlink message header, and of the link, address and route message headers
+const NLMSG_HDRLEN: usize = 16;
+const IFINFOMSG_LEN: usize = 16;
+const IFADDRMSG_LEN: usize = 8;
+const RTMSG_LEN: usize = 12;
+
+// Large enough for the reply to, and the acknowledgement of, any request sent
+const RECV_BUFFER_LEN: usize = 32 << 10;
+
+// A request, made of a header and attributes
+#[derive(Debug)]
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    // A request of type `kind`, with the `flags` of requests creating objects, and a header of
+    // `header_len` bytes
+    fn new(kind: u16, flags: u16, header_len: usize) -> Message {
+        let mut buf = vec![0; NLMSG_HDRLEN + header_len];
+        buf[4..6].copy_from_slice(&kind.to_ne_bytes());
+        buf[6..8].copy_from_slice(&(NLM_F_REQUEST | NLM_F_ACK | flags).to_ne_bytes());
+        Message { buf }
+    }
+
+    // A request of type `kind` on the link `index`
+    fn link(kind: u16, index: u32) -> Message {
+        let mut message = Message::new(kind, 0, IFINFOMSG_LEN);
+        // The family is AF_UNSPEC
+        message.buf[NLMSG_HDRLEN + 4..NLMSG_HDRLEN + 8].copy_from_slice(&index.to_ne_bytes());
+        message
+    }
+
+    // Set the `flags` of the link that are in `change`.
+    fn link_flags(mut self, flags: u32, change: u32) -> Message {
+        let header = &mut self.buf[NLMSG_HDRLEN..];
+        header[8..12].copy_from_slice(&flags.to_ne_bytes());
+        header[12..16].copy_from_slice(&change.to_ne_bytes());
+        self
+    }
+
+    fn attr(mut self, kind: u16, data: &[u8]) -> Message {
+        self.buf.extend_from_slice(&attr(kind, data));
+        self
+    }
+
+    fn attr_u32(self, kind: u16, value: u32) -> Message {
+        self.attr(kind, &value.to_ne_bytes())
+    }
+
+    // A string attribute, which is nul-terminated
+    fn attr_str(self, kind: u16, value: &str) -> Message {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        self.attr(kind, &data)
+    }
+}
+
+// An attribute, padded to 4 bytes
+fn attr(kind: u16, data: &[u8]) -> Vec<u8> {
+    let len = 4 + data.len();
+    let mut attr = Vec::with_capacity(len.next_multiple_of(4));
+    attr.extend_from_slice(&(len as u16).to_ne_bytes());
+    attr.extend_from_slice(&kind.to_ne_bytes());
+    attr.extend_from_slice(data);
+    attr.resize(len.next_multiple_of(4), 0);
+    attr
+}
+
+// Counters of a link, the first fields of `struct rtnl_link_stats64`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(super) struct LinkStats {
+    pub(super) rx_packets: u64,
+    pub(super) tx_packets: u64,
+    pub(super) rx_bytes: u64,
+    pub(super) tx_bytes: u64,
+    pub(super) rx_errors: u64,
+    pub(super) tx_errors: u64,
+    pub(super) rx_dropped: u64,
+    pub(super) tx_dropped: u64,
+}
+
+impl LinkStats {
+    fn parse(data: &[u8]) -> Option<LinkStats> {
+        let field = |index: usize| {
+            let bytes = data.get(8 * index..8 * index + 8)?;
+            Some(u64::from_ne_bytes(bytes.try_into().unwrap()))
+        };
+        Some(LinkStats {
+            rx_packets: field(0)?,
+            tx_packets: field(1)?,
+            rx_bytes: field(2)?,
+            tx_bytes: field(3)?,
+            rx_errors: field(4)?,
+            tx_errors: field(5)?,
+            rx_dropped: field(6)?,
+            tx_dropped: field(7)?,
+        })
+    }
+}
+
+// A rtnetlink socket
+#[derive(Debug)]
+pub(super) struct Netlink {
+    fd: OwnedFd,
+    seq: u32,
+}
+
+impl Netlink {
+    pub(super) fn open() -> io::Result<Netlink> {
+        // SAFETY: socket does not access memory.
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` is a new file descriptor, owned by nobody else.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        Ok(Netlink { fd, seq: 0 })
+    }
+
+    pub(super) fn set_up(&mut self, index: u32) -> io::Result<()> {
+        let up = libc::IFF_UP as u32;
+        self.request(Message::link(RTM_NEWLINK, index).link_flags(up, up))
+    }
+
+    pub(super) fn set_mtu(&mut self, index: u32, mtu: u32) -> io::Result<()> {
+        self.request(Message::link(RTM_NEWLINK, index).attr_u32(IFLA_MTU, mtu))
+    }
+
+    // Attach the link `index` to the bridge `master`, or detach it if `master` is 0.
+    pub(super) fn set_master(&mut self, index: u32, master: u32) -> io::Result<()> {
+        self.request(Message::link(RTM_NEWLINK, index).attr_u32(IFLA_MASTER, master))
+    }
+
+    pub(super) fn delete_link(&mut self, index: u32) -> io::Result<()> {
+        self.request(Message::link(RTM_DELLINK, index))
+    }
+
+    // Counters of the link `index`.
+    pub(super) fn link_stats(&mut self, index: u32) -> io::Result<LinkStats> {
+        let invalid = |what| io::Error::new(io::ErrorKind::InvalidData, what);
+        let reply = self
+            .query(Message::link(RTM_GETLINK, index))?
+            .ok_or_else(|| invalid("no link in the reply"))?;
+        let mut attrs = reply.get(IFINFOMSG_LEN..).unwrap_or_default();
+        while attrs.len() >= 4 {
+            let len = u16::from_ne_bytes(attrs[0..2].try_into().unwrap()) as usize;
+            let kind = u16::from_ne_bytes(attrs[2..4].try_into().unwrap());
+            if len < 4 || len > attrs.len() {
+                break;
+            }
+            if kind == IFLA_STATS64 {
+                return LinkStats::parse(&attrs[4..len]).ok_or_else(|| invalid("truncated stats"));
+            }
+            attrs = &attrs[len.next_multiple_of(4).min(attrs.len())..];
+        }
+        Err(invalid("no stats in the reply"))
+    }
+
+    // Create the link `name` of type `kind`, e.g. `bridge`.
+    pub(super) fn create_link(&mut self, name: &str, kind: &str) -> io::Result<()> {
+        let message = Message::new(RTM_NEWLINK, NLM_F_CREATE | NLM_F_EXCL, IFINFOMSG_LEN)
+            .attr_str(IFLA_IFNAME, name);
+        let mut info_kind = kind.as_bytes().to_vec();
+        info_kind.push(0);
+        self.request(message.attr(IFLA_LINKINFO, &attr(IFLA_INFO_KIND, &info_kind)))
+    }
+
+    // Create the veth pair `name` and `peer`, and move `peer` to the network namespace of
+    // `peer_netns`.
+    pub(super) fn create_veth(
+        &mut self,
+        name: &str,
+        peer: &str,
+        peer_netns: BorrowedFd<'_>,
+    ) -> io::Result<()> {
+        let mut peer_info = vec![0; IFINFOMSG_LEN];
+        let mut peer_name = peer.as_bytes().to_vec();
+        peer_name.push(0);
+        peer_info.extend(attr(IFLA_IFNAME, &peer_name));
+        let fd = peer_netns.as_raw_fd() as u32;
+        peer_info.extend(attr(IFLA_NET_NS_FD, &fd.to_ne_bytes()));
+        let mut info = attr(IFLA_INFO_KIND, b"veth\0");
+        info.extend(attr(IFLA_INFO_DATA, &attr(VETH_INFO_PEER, &peer_info)));
+        let message = Message::new(RTM_NEWLINK, NLM_F_CREATE | NLM_F_EXCL, IFINFOMSG_LEN)
+            .attr_str(IFLA_IFNAME, name)
+            .attr(IFLA_LINKINFO, &info);
+        self.request(message)
+    }
+
+    // Add the default route through `gateway`.
+    pub(super) fn add_default_route(&mut self, gateway: IpAddr) -> io::Result<()> {
+        let mut message = Message::new(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_EXCL, RTMSG_LEN);
+        let (family, octets) = match gateway {
+            IpAddr::V4(addr) => (libc::AF_INET, addr.octets().to_vec()),
+            IpAddr::V6(addr) => (libc::AF_INET6, addr.octets().to_vec()),
+        };
+        // The scope is RT_SCOPE_UNIVERSE, i.e. 0
+        let header = &mut message.buf[NLMSG_HDRLEN..];
+        header[0] = family as u8;
+        header[4] = RT_TABLE_MAIN;
+        header[5] = RTPROT_BOOT;
+        header[7] = RTN_UNICAST;
+        self.request(message.attr(RTA_GATEWAY, &octets))
+    }
+
+    // Add the address `addr`, of a subnet with a prefix of `prefix_len` bits, to the link
+    // `index`. Fails with `EEXIST` if the link already has it.
+    pub(super) fn add_address(
+        &mut self,
+        index: u32,
+        addr: IpAddr,
+        prefix_len: u8,
+    ) -> io::Result<()> {
+        let mut message = Message::new(RTM_NEWADDR, NLM_F_CREATE | NLM_F_EXCL, IFADDRMSG_LEN);
+        let (family, octets) = match addr {
+            IpAddr::V4(addr) => (libc::AF_INET, addr.octets().to_vec()),
+            IpAddr::V6(addr) => (libc::AF_INET6, addr.octets().to_vec()),
+        };
+        let header = &mut message.buf[NLMSG_HDRLEN..];
+        header[0] = family as u8;
+        header[1] = prefix_len;
+        header[4..8].copy_from_slice(&index.to_ne_bytes());
+        let message = match addr {
+            IpAddr::V4(_) => message.attr(IFA_LOCAL, &octets),
+            IpAddr::V6(_) => message,
+        };
+        self.request(message.attr(IFA_ADDRESS, &octets))
+    }
+
+    // Send `message`, and wait for its acknowledgement.
+    fn request(&mut self, message: Message) -> io::Result<()> {
+        self.query(message).map(drop)
+    }
+
+    // Send `message`, wait for its acknowledgement, and return the payload of the reply sent
+    // before it, if any.
+    fn query(&mut self, mut message: Message) -> io::Result<Option<Vec<u8>>> {
+        self.seq = self.seq.wrapping_add(1);
+        let len = message.buf.len() as u32;
+        message.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        message.buf[8..12].copy_from_slice(&self.seq.to_ne_bytes());
+
+        // SAFETY: the buffer is valid for its length.
+        let sent = unsafe {
+            libc::send(
+                self.fd.as_raw_fd(),
+                message.buf.as_ptr().cast(),
+                message.buf.len(),
+                0,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0u8; RECV_BUFFER_LEN];
+        let mut reply = None;
+        loop {
+            // SAFETY: the buffer is valid for its length.
+            let received =
+                unsafe { libc::recv(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+            if received < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            if let Some(result) = self.ack(&buf[..received as usize], &mut reply) {
+                return result.map(|()| reply);
+            }
+        }
+    }
+
+    // Result of the request in the acknowledgement in `messages`, if any. The payload of other
+    // replies to the request is stored in `reply`.
+    fn ack(&self, mut messages: &[u8], reply: &mut Option<Vec<u8>>) -> Option<io::Result<()>> {
+        while messages.len() >= NLMSG_HDRLEN {
+            let len = u32::from_ne_bytes(messages[0..4].try_into().unwrap()) as usize;
+            let kind = u16::from_ne_bytes(messages[4..6].try_into().unwrap());
+            let seq = u32::from_ne_bytes(messages[8..12].try_into().unwrap());
+            if len < NLMSG_HDRLEN || len > messages.len() {
+                return Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "truncated netlink message",
+                )));
+            }
+            if kind == NLMSG_ERROR && seq == self.seq && len >= NLMSG_HDRLEN + 4 {
+                let error = i32::from_ne_bytes(messages[16..20].try_into().unwrap());
+                return Some(match error {
+                    0 => Ok(()),
+                    error => Err(io::Error::from_raw_os_error(-error)),
+                });
+            }
+            if seq == self.seq {
+                *reply = Some(messages[NLMSG_HDRLEN..len].to_vec());
+            }
+            messages = &messages[len.next_multiple_of(4).min(messages.len())..];
+        }
+        None
+    }
+}
@@ -0,0 +1,376 @@
+//! Network namespaces isolating Firecracker processes
+//!
+//! [`NetNs`] creates a network namespace, either named, i.e. bind-mounted in `/run/netns` like
+//! `ip netns add` does, or anonymous, i.e. alive as long as it is open or a process runs in it.
+//! [`NetNs::add_veth`] connects it to the host with a veth pair, and [`NetNs::create_tap`] creates
+//! the TAP devices of a microVM inside it. [`VmmBuilder::with_netns`] then starts Firecracker in
+//! the namespace.
+//!
+//! Dropping the [`NetNs`] deletes the namespace, along with the devices inside and the host end of
+//! its veth pairs, unless it is [kept](NetNs::keep). Creating and entering namespaces requires
+//! CAP_SYS_ADMIN, on top of the CAP_NET_ADMIN needed to configure devices.
+//!
+//! [`VmmBuilder::with_netns`]: crate::vmm::VmmBuilder::with_netns
+
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::net::IpAddr;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use log::warn;
+
+use super::netlink::Netlink;
+use super::tap::{Tap, TapBuilder};
+use super::{check_name, link_index, NetError};
+
+/// Directory of the named network namespaces, as used by `ip netns`
+pub const NETNS_DIR: &str = "/run/netns";
+
+// The network namespace of the calling thread
+const THREAD_NETNS: &str = "/proc/thread-self/ns/net";
+
+// Index of the loopback device, in every network namespace
+const LOOPBACK_INDEX: u32 = 1;
+
+/// A veth pair between the host and a network namespace
+///
+/// If both ends have an address, the host end is the default gateway of the namespace.
+#[derive(Debug, Clone)]
+pub struct Veth {
+    host_name: String,
+    netns_name: String,
+    host_address: Option<(IpAddr, u8)>,
+    netns_address: Option<(IpAddr, u8)>,
+}
+
+impl Veth {
+    /// A pair made of the device `host_name` on the host and `netns_name` in the namespace
+    pub fn new<S: Into<String>, T: Into<String>>(host_name: S, netns_name: T) -> Self {
+        Veth {
+            host_name: host_name.into(),
+            netns_name: netns_name.into(),
+            host_address: None,
+            netns_address: None,
+        }
+    }
+
+    /// Set the address of the end on the host, with the prefix length of its subnet
+    pub fn with_host_address(mut self, address: IpAddr, prefix_len: u8) -> Self {
+        self.host_address = Some((address, prefix_len));
+        self
+    }
+
+    /// Set the address of the end in the namespace, with the prefix length of its subnet
+    pub fn with_netns_address(mut self, address: IpAddr, prefix_len: u8) -> Self {
+        self.netns_address = Some((address, prefix_len));
+        self
+    }
+}
+
+/// A network namespace
+#[derive(Debug)]
+pub struct NetNs {
+    file: File,
+    // Where the namespace is mounted, if it is named
+    path: Option<PathBuf>,
+    // Host ends of the veth pairs
+    veths: Vec<String>,
+    // Whether the namespace is deleted when dropped
+    owned: bool,
+}
+
+impl NetNs {
+    /// Create the network namespace `name`, which must not exist
+    pub fn create_named(name: &str) -> Result<NetNs, NetError> {
+        check_name(name)?;
+        let what = format!("create network namespace {name}");
+        fs::create_dir_all(NETNS_DIR).map_err(|err| NetError::Failed(what.clone(), err))?;
+        let path = Path::new(NETNS_DIR).join(name);
+        // The mount point of the namespace
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::AlreadyExists => NetError::Exists(name.to_owned()),
+                _ => NetError::Failed(what.clone(), err),
+            })?;
+
+        let mount_path = path.clone();
+        let file = unshare(move || bind_mount(Path::new(THREAD_NETNS), &mount_path));
+        let file = match file {
+            Ok(file) => file,
+            Err(err) => {
+                // The namespace is destroyed along with the thread
+                let _ = fs::remove_file(&path);
+                return Err(NetError::Failed(what, err));
+            }
+        };
+        let netns = NetNs {
+            file,
+            path: Some(path),
+            veths: Vec::new(),
+            owned: true,
+        };
+        netns.setup_loopback()?;
+        Ok(netns)
+    }
+
+    /// Create an anonymous network namespace, which lives as long as it is open or a process
+    /// runs in it
+    pub fn create_anonymous() -> Result<NetNs, NetError> {
+        let file = unshare(|| Ok(()))
+            .map_err(|err| NetError::Failed("create a network namespace".to_owned(), err))?;
+        let netns = NetNs {
+            file,
+            path: None,
+            veths: Vec::new(),
+            owned: true,
+        };
+        netns.setup_loopback()?;
+        Ok(netns)
+    }
+
+    /// Open the existing network namespace `name`, e.g. one created by `ip netns add`
+    ///
+    /// It is left in place when the [`NetNs`] is dropped.
+    pub fn open(name: &str) -> Result<NetNs, NetError> {
+        check_name(name)?;
+        let path = Path::new(NETNS_DIR).join(name);
+        let file = File::open(&path).map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => NetError::NotFound(name.to_owned()),
+            _ => NetError::Failed(format!("open network namespace {name}"), err),
+        })?;
+        Ok(NetNs {
+            file,
+            path: Some(path),
+            veths: Vec::new(),
+            owned: false,
+        })
+    }
+
+    /// Name of the namespace, if it is named
+    pub fn name(&self) -> Option<&str> {
+        self.path
+            .as_ref()
+            .and_then(|path| path.file_name())
+            .and_then(|name| name.to_str())
+    }
+
+    /// Run `f` in a thread in the namespace, and return its result
+    ///
+    /// Network devices created by `f`, e.g. with [`TapBuilder::create`], are created in the
+    /// namespace.
+    pub fn enter<T, F>(&self, f: F) -> Result<T, NetError>
+    where
+        T: Send,
+        F: FnOnce() -> Result<T, NetError> + Send,
+    {
+        let fd = self.file.as_fd();
+        thread::scope(|scope| {
+            let thread = scope.spawn(move || {
+                setns(fd)
+                    .map_err(|err| NetError::Failed("enter a network namespace".to_owned(), err))?;
+                f()
+            });
+            thread
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+        })
+    }
+
+    /// Create a veth pair between the host and the namespace, and bring it up
+    ///
+    /// The pair is deleted along with the namespace.
+    pub fn add_veth(&mut self, veth: &Veth) -> Result<(), NetError> {
+        check_name(&veth.netns_name)?;
+        if link_index(&veth.host_name)?.is_some() {
+            return Err(NetError::Exists(veth.host_name.clone()));
+        }
+        let what = format!("create veth pair {}", veth.host_name);
+        Netlink::open()
+            .and_then(|mut netlink| {
+                netlink.create_veth(&veth.host_name, &veth.netns_name, self.file.as_fd())
+            })
+            .map_err(|err| NetError::failed(&what, err))?;
+        // From now on, deleting the namespace deletes the pair
+        self.veths.push(veth.host_name.clone());
+
+        configure_link(&veth.host_name, veth.host_address)?;
+        let gateway = match (veth.host_address, veth.netns_address) {
+            (Some((gateway, _)), Some((address, _))) if gateway.is_ipv4() == address.is_ipv4() => {
+                Some(gateway)
+            }
+            _ => None,
+        };
+        self.enter(|| {
+            configure_link(&veth.netns_name, veth.netns_address)?;
+            if let Some(gateway) = gateway {
+                Netlink::open()
+                    .and_then(|mut netlink| netlink.add_default_route(gateway))
+                    .map_err(|err| {
+                        NetError::failed(format!("add a route through {gateway}"), err)
+                    })?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Create a TAP device in the namespace, e.g. with a bridge of the namespace, and return its
+    /// name
+    ///
+    /// The device is deleted along with the namespace.
+    pub fn create_tap(&self, tap: TapBuilder) -> Result<String, NetError> {
+        self.enter(|| tap.create().map(Tap::keep))
+    }
+
+    /// Delete the namespace now, reporting errors
+    ///
+    /// The devices inside are deleted once no process runs in the namespace anymore.
+    pub fn delete(mut self) -> Result<(), NetError> {
+        self.remove()
+    }
+
+    /// Keep the namespace after the [`NetNs`] is dropped
+    ///
+    /// Anonymous namespaces only live on while a process runs in them.
+    pub fn keep(mut self) {
+        self.owned = false;
+    }
+
+    fn setup_loopback(&self) -> Result<(), NetError> {
+        self.enter(|| {
+            Netlink::open()
+                .and_then(|mut netlink| netlink.set_up(LOOPBACK_INDEX))
+                .map_err(|err| NetError::failed("bring lo up", err))
+        })
+    }
+
+    fn remove(&mut self) -> Result<(), NetError> {
+        if !std::mem::replace(&mut self.owned, false) {
+            return Ok(());
+        }
+        // Deleting either end of a veth pair deletes both
+        let mut result = Ok(());
+        for name in self.veths.drain(..) {
+            let deleted = match link_index(&name) {
+                Ok(Some(index)) => Netlink::open()
+                    .and_then(|mut netlink| netlink.delete_link(index))
+                    .or_else(|err| match err.raw_os_error() {
+                        Some(libc::ENODEV) => Ok(()),
+                        _ => Err(err),
+                    })
+                    .map_err(|err| NetError::failed(format!("delete veth pair {name}"), err)),
+                Ok(None) => Ok(()),
+                Err(err) => Err(err),
+            };
+            result = result.and(deleted);
+        }
+        if let Some(path) = &self.path {
+            let what = format!("delete network namespace {}", path.display());
+            let removed = unmount(path)
+                .and_then(|()| fs::remove_file(path))
+                .map_err(|err| NetError::Failed(what, err));
+            result = result.and(removed);
+        }
+        result
+    }
+}
+
+impl AsFd for NetNs {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+impl Drop for NetNs {
+    fn drop(&mut self) {
+        if let Err(err) = self.remove() {
+            warn!("{err}");
+        }
+    }
+}
+
+// Bring the device `name` up, with `address` if any.
+fn configure_link(name: &str, address: Option<(IpAddr, u8)>) -> Result<(), NetError> {
+    let index = link_index(name)?.ok_or_else(|| NetError::NotFound(name.to_owned()))?;
+    let mut netlink =
+        Netlink::open().map_err(|err| NetError::failed(format!("configure {name}"), err))?;
+    if let Some((address, prefix_len)) = address {
+        netlink
+            .add_address(index, address, prefix_len)
+            .map_err(|err| {
+                NetError::failed(format!("add {address}/{prefix_len} to {name}"), err)
+            })?;
+    }
+    netlink
+        .set_up(index)
+        .map_err(|err| NetError::failed(format!("bring {name} up"), err))
+}
+
+// Run `setup` in a thread moved to a new network namespace, and return the namespace.
+fn unshare<F>(setup: F) -> io::Result<File>
+where
+    F: FnOnce() -> io::Result<()> + Send + 'static,
+{
+    // The namespace of a thread is its own, and the thread exits along with it
+    thread::spawn(move || {
+        // SAFETY: unshare does not access memory.
+        if unsafe { libc::unshare(libc::CLONE_NEWNET) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        setup()?;
+        File::open(THREAD_NETNS)
+    })
+    .join()
+    .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
+// Move the calling thread to the network namespace `netns`.
+fn setns(netns: BorrowedFd<'_>) -> io::Result<()> {
+    // SAFETY: setns does not access memory.
+    if unsafe { libc::setns(netns.as_raw_fd(), libc::CLONE_NEWNET) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+fn bind_mount(source: &Path, target: &Path) -> io::Result<()> {
+    let (source, target) = (c_path(source)?, c_path(target)?);
+    // SAFETY: the paths are valid nul-terminated strings, and bind mounts take no data.
+    let mounted = unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            std::ptr::null(),
+            libc::MS_BIND,
+            std::ptr::null(),
+        )
+    };
+    if mounted < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn unmount(path: &Path) -> io::Result<()> {
+    let path = c_path(path)?;
+    // SAFETY: the path is a valid nul-terminated string.
+    if unsafe { libc::umount2(path.as_ptr(), libc::MNT_DETACH) } < 0 {
+        let err = io::Error::last_os_error();
+        // Not mounted anymore
+        if err.raw_os_error() != Some(libc::EINVAL) {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,417 @@
+//! Traffic statistics of the network interfaces of microVMs
+//!
+//! Firecracker only reports the traffic of the network interfaces of a microVM in its metrics,
+//! once they are flushed, and it does not see the packets dropped by the host. [`InterfaceStats`]
+//! joins the counters of the TAP devices of the host, read with rtnetlink, in the network
+//! namespace of Firecracker if it has its own, with the net metrics of Firecracker, read by
+//! [`MetricsReader`], by interface ID. Two samples of an interface give its
+//! [rates](InterfaceStats::rates).
+//!
+//! The counters of a TAP device are seen from the host: it receives what the guest sends. The
+//! metrics of Firecracker are seen from the guest.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::netlink::Netlink;
+use super::netns::NetNs;
+use super::{link_index, NetError};
+use crate::client::network::NetworkInterface;
+
+// Prefix of the metrics of each network interface, followed by its ID
+const IFACE_METRICS_PREFIX: &str = "net_";
+
+/// An error while reading the metrics of Firecracker
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum StatsError {
+    /// cannot read the metrics of Firecracker: {0}
+    Io(#[from] io::Error),
+    /// invalid metrics of Firecracker: {0}
+    Json(#[from] serde_json::Error),
+}
+
+/// Counters of a TAP device, seen from the host
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct TapCounters {
+    /// Bytes sent by the guest
+    pub rx_bytes: u64,
+    /// Packets sent by the guest
+    pub rx_packets: u64,
+    /// Packets sent by the guest that were invalid
+    pub rx_errors: u64,
+    /// Packets sent by the guest that the host dropped
+    pub rx_dropped: u64,
+    /// Bytes sent to the guest
+    pub tx_bytes: u64,
+    /// Packets sent to the guest
+    pub tx_packets: u64,
+    /// Packets that could not be sent to the guest
+    pub tx_errors: u64,
+    /// Packets dropped before reaching the guest, e.g. because Firecracker did not read them in
+    /// time
+    pub tx_dropped: u64,
+}
+
+impl TapCounters {
+    /// Read the counters of the network device `dev`, in the network namespace of the caller
+    pub fn read(dev: &str) -> Result<TapCounters, NetError> {
+        let index = link_index(dev)?.ok_or_else(|| NetError::NotFound(dev.to_owned()))?;
+        let stats = Netlink::open()
+            .and_then(|mut netlink| netlink.link_stats(index))
+            .map_err(|err| NetError::failed(format!("read the statistics of {dev}"), err))?;
+        Ok(TapCounters {
+            rx_bytes: stats.rx_bytes,
+            rx_packets: stats.rx_packets,
+            rx_errors: stats.rx_errors,
+            rx_dropped: stats.rx_dropped,
+            tx_bytes: stats.tx_bytes,
+            tx_packets: stats.tx_packets,
+            tx_errors: stats.tx_errors,
+            tx_dropped: stats.tx_dropped,
+        })
+    }
+
+    /// Read the counters of the network device `dev` in the network namespace `netns`
+    pub fn read_in(netns: &NetNs, dev: &str) -> Result<TapCounters, NetError> {
+        netns.enter(|| TapCounters::read(dev))
+    }
+}
+
+/// Counters of a network interface in Firecracker, seen from the guest
+///
+/// They are the totals of the `net_<iface_id>` metrics over all the flushes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceMetrics {
+    /// Bytes received by the guest
+    pub rx_bytes_count: u64,
+    /// Packets received by the guest
+    pub rx_packets_count: u64,
+    /// Failures to receive packets
+    pub rx_fails: u64,
+    /// Bytes sent by the guest
+    pub tx_bytes_count: u64,
+    /// Packets sent by the guest
+    pub tx_packets_count: u64,
+    /// Failures to send packets
+    pub tx_fails: u64,
+    /// Failures to read from the TAP device
+    pub tap_read_fails: u64,
+    /// Failures to write to the TAP device
+    pub tap_write_fails: u64,
+}
+
+impl DeviceMetrics {
+    fn add(&mut self, other: &DeviceMetrics) {
+        self.rx_bytes_count += other.rx_bytes_count;
+        self.rx_packets_count += other.rx_packets_count;
+        self.rx_fails += other.rx_fails;
+        self.tx_bytes_count += other.tx_bytes_count;
+        self.tx_packets_count += other.tx_packets_count;
+        self.tx_fails += other.tx_fails;
+        self.tap_read_fails += other.tap_read_fails;
+        self.tap_write_fails += other.tap_write_fails;
+    }
+
+    fn errors(&self) -> u64 {
+        self.rx_fails + self.tx_fails + self.tap_read_fails + self.tap_write_fails
+    }
+}
+
+/// The net metrics of Firecracker, summed over its flushes
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct NetMetrics {
+    /// When the metrics were last flushed, in milliseconds since the Unix epoch
+    pub timestamp_ms: Option<u64>,
+    /// Metrics of each network interface, by interface ID
+    pub interfaces: BTreeMap<String, DeviceMetrics>,
+}
+
+// A line of the metrics file, written by a flush
+#[derive(Debug, Deserialize)]
+struct Flush {
+    utc_timestamp_ms: Option<u64>,
+    #[serde(flatten)]
+    metrics: BTreeMap<String, Value>,
+}
+
+/// A reader of the metrics file of Firecracker, following it as metrics are flushed
+///
+/// Firecracker appends the metrics of each flush to the file, as a line of JSON, and resets its
+/// counters. The reader sums them up. The file must be a regular file, not a named pipe.
+#[derive(Debug, Clone)]
+pub struct MetricsReader {
+    path: PathBuf,
+    // End of the last line read
+    offset: u64,
+    metrics: NetMetrics,
+}
+
+impl MetricsReader {
+    /// A reader of the metrics file at `path`, e.g. the `metrics_path` of the microVM
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        MetricsReader {
+            path: path.as_ref().to_path_buf(),
+            offset: 0,
+            metrics: NetMetrics::default(),
+        }
+    }
+
+    /// Read the metrics flushed since the last read, and return the totals
+    pub fn read(&mut self) -> Result<&NetMetrics, StatsError> {
+        let mut file = File::open(&self.path)?;
+        // The file was replaced or truncated
+        if file.metadata()?.len() < self.offset {
+            self.offset = 0;
+            self.metrics = NetMetrics::default();
+        }
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        // The last line may still be being written
+        let end = data
+            .iter()
+            .rposition(|&byte| byte == b'\n')
+            .map_or(0, |index| index + 1);
+        for line in data[..end].split(|&byte| byte == b'\n') {
+            if line.trim_ascii().is_empty() {
+                continue;
+            }
+            let flush: Flush = serde_json::from_slice(line)?;
+            for (key, value) in flush.metrics {
+                let Some(iface_id) = key.strip_prefix(IFACE_METRICS_PREFIX) else {
+                    continue;
+                };
+                let metrics: DeviceMetrics = serde_json::from_value(value)?;
+                self.metrics
+                    .interfaces
+                    .entry(iface_id.to_owned())
+                    .or_default()
+                    .add(&metrics);
+            }
+            if flush.utc_timestamp_ms.is_some() {
+                self.metrics.timestamp_ms = flush.utc_timestamp_ms;
+            }
+        }
+        self.offset += end as u64;
+        Ok(&self.metrics)
+    }
+}
+
+/// Traffic of a network interface of a microVM, seen from the host and from Firecracker
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InterfaceStats {
+    /// ID of the network interface
+    pub iface_id: String,
+    /// Name of the TAP device
+    pub host_dev_name: String,
+    /// When the counters of the TAP device were read, in milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    /// Counters of the TAP device
+    pub host: TapCounters,
+    /// When the metrics of Firecracker were last flushed, in milliseconds since the Unix epoch
+    pub guest_timestamp_ms: Option<u64>,
+    /// Metrics of the interface in Firecracker, if it reported any
+    pub guest: Option<DeviceMetrics>,
+}
+
+impl InterfaceStats {
+    /// Sample the traffic of `iface`, joined with its metrics in `metrics`, if any
+    ///
+    /// The TAP device is looked up in `netns`, the network namespace of Firecracker, if it has its
+    /// own.
+    pub fn sample(
+        iface: &NetworkInterface,
+        metrics: Option<&NetMetrics>,
+        netns: Option<&NetNs>,
+    ) -> Result<InterfaceStats, NetError> {
+        let host = match netns {
+            Some(netns) => TapCounters::read_in(netns, &iface.host_dev_name)?,
+            None => TapCounters::read(&iface.host_dev_name)?,
+        };
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis() as u64);
+        let guest = metrics.and_then(|metrics| metrics.interfaces.get(&iface.iface_id));
+        Ok(InterfaceStats {
+            iface_id: iface.iface_id.clone(),
+            host_dev_name: iface.host_dev_name.clone(),
+            timestamp_ms,
+            host,
+            guest_timestamp_ms: guest.and(metrics.and_then(|metrics| metrics.timestamp_ms)),
+            guest: guest.copied(),
+        })
+    }
+
+    /// Sample the traffic of all the network interfaces `ifaces` of a microVM
+    pub fn sample_all(
+        ifaces: &[NetworkInterface],
+        metrics: Option<&NetMetrics>,
+        netns: Option<&NetNs>,
+    ) -> Result<Vec<InterfaceStats>, NetError> {
+        ifaces
+            .iter()
+            .map(|iface| InterfaceStats::sample(iface, metrics, netns))
+            .collect()
+    }
+
+    /// Rates of traffic since the `earlier` sample of the interface
+    ///
+    /// The rates of the guest are only known if the metrics were flushed in between.
+    pub fn rates(&self, earlier: &InterfaceStats) -> InterfaceRates {
+        let (host, before) = (&self.host, &earlier.host);
+        let seconds = self.timestamp_ms.saturating_sub(earlier.timestamp_ms) as f64 / 1000.0;
+        let rate = |now: u64, before: u64| {
+            if seconds > 0.0 {
+                now.saturating_sub(before) as f64 / seconds
+            } else {
+                0.0
+            }
+        };
+        let host_rates = Rates {
+            rx_bytes: rate(host.rx_bytes, before.rx_bytes),
+            rx_packets: rate(host.rx_packets, before.rx_packets),
+            tx_bytes: rate(host.tx_bytes, before.tx_bytes),
+            tx_packets: rate(host.tx_packets, before.tx_packets),
+            errors: rate(
+                host.rx_errors + host.tx_errors,
+                before.rx_errors + before.tx_errors,
+            ),
+        };
+        let host_dropped = rate(
+            host.rx_dropped + host.tx_dropped,
+            before.rx_dropped + before.tx_dropped,
+        );
+
+        let guest = match (
+            self.guest.zip(self.guest_timestamp_ms),
+            earlier.guest.zip(earlier.guest_timestamp_ms),
+        ) {
+            (Some((guest, now_ms)), Some((before, before_ms))) if now_ms > before_ms => {
+                let seconds = (now_ms - before_ms) as f64 / 1000.0;
+                let rate = |now: u64, before: u64| now.saturating_sub(before) as f64 / seconds;
+                Some(Rates {
+                    rx_bytes: rate(guest.rx_bytes_count, before.rx_bytes_count),
+                    rx_packets: rate(guest.rx_packets_count, before.rx_packets_count),
+                    tx_bytes: rate(guest.tx_bytes_count, before.tx_bytes_count),
+                    tx_packets: rate(guest.tx_packets_count, before.tx_packets_count),
+                    errors: rate(guest.errors(), before.errors()),
+                })
+            }
+            _ => None,
+        };
+        InterfaceRates {
+            host: host_rates,
+            host_dropped,
+            guest,
+        }
+    }
+}
+
+/// Rates of traffic, per second
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Rates {
+    /// Bytes received
+    pub rx_bytes: f64,
+    /// Packets received
+    pub rx_packets: f64,
+    /// Bytes transmitted
+    pub tx_bytes: f64,
+    /// Packets transmitted
+    pub tx_packets: f64,
+    /// Errors
+    pub errors: f64,
+}
+
+/// Rates of traffic of a network interface, per second
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct InterfaceRates {
+    /// Rates of the TAP device, seen from the host
+    pub host: Rates,
+    /// Packets dropped by the host
+    pub host_dropped: f64,
+    /// Rates of the interface in Firecracker, seen from the guest
+    pub guest: Option<Rates>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use super::*;
+
+    fn append(path: &Path, data: &str) {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        file.write_all(data.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn tap_counters() {
+        TapCounters::read("lo").unwrap();
+        assert!(matches!(
+            TapCounters::read("fc-missing0"),
+            Err(NetError::NotFound(dev)) if dev == "fc-missing0"
+        ));
+    }
+
+    #[test]
+    fn metrics_reader() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metrics.json");
+        let mut reader = MetricsReader::new(&path);
+        let eth0 = |rx_bytes_count, tx_fails| DeviceMetrics {
+            rx_bytes_count,
+            tx_fails,
+            ..DeviceMetrics::default()
+        };
+
+        // Flushes are summed up, and the aggregate `net` metrics are ignored
+        append(
+            &path,
+            concat!(
+                r#"{"utc_timestamp_ms":1,"net":{"rx_bytes_count":9},"net_eth0":{"rx_bytes_count":100}}"#,
+                "\n",
+                r#"{"utc_timestamp_ms":2,"net_eth0":{"rx_bytes_count":50,"tx_fails":1}}"#,
+                "\n",
+            ),
+        );
+        let metrics = reader.read().unwrap();
+        assert_eq!(metrics.timestamp_ms, Some(2));
+        assert_eq!(metrics.interfaces.len(), 1);
+        assert_eq!(metrics.interfaces["eth0"], eth0(150, 1));
+
+        // A line being written is only read once complete
+        append(&path, r#"{"utc_timestamp_ms":3,"net_eth0":{"rx_bytes"#);
+        let metrics = reader.read().unwrap();
+        assert_eq!(metrics.timestamp_ms, Some(2));
+        assert_eq!(metrics.interfaces["eth0"], eth0(150, 1));
+        append(&path, "_count\":25},\"net_eth1\":{\"tx_fails\":2}}\n");
+        let metrics = reader.read().unwrap();
+        assert_eq!(metrics.timestamp_ms, Some(3));
+        assert_eq!(metrics.interfaces["eth0"], eth0(175, 1));
+        assert_eq!(metrics.interfaces["eth1"], eth0(0, 2));
+
+        // A truncated file starts over
+        File::create(&path).unwrap();
+        append(
+            &path,
+            "{\"utc_timestamp_ms\":4,\"net_eth0\":{\"rx_bytes_count\":7}}\n",
+        );
+        let metrics = reader.read().unwrap();
+        assert_eq!(metrics.timestamp_ms, Some(4));
+        assert_eq!(metrics.interfaces.len(), 1);
+        assert_eq!(metrics.interfaces["eth0"], eth0(7, 0));
+    }
+}
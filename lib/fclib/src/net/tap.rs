@@ -0,0 +1,323 @@
+//! Persistent TAP devices
+//!
+//! [`TapBuilder`] creates a persistent TAP device, owned by the user running Firecracker so that
+//! it can open it without privileges, sets its MTU and offloads, brings it up, and optionally
+//! attaches it to a bridge. The returned [`Tap`] deletes the device when it is dropped, unless it
+//! is [kept](Tap::keep). [`MicroVm::add_tap`](crate::microvm::MicroVm::add_tap) ties it to a
+//! microVM instead, which deletes it when it is torn down.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use log::warn;
+
+use super::netlink::Netlink;
+use super::{check_name, link_index, NetError, MAX_NAME_LEN};
+use crate::client::network::NetworkInterface;
+
+const TUN_PATH: &str = "/dev/net/tun";
+
+/// Offloads of a TAP device, i.e. the partial packets it accepts from the guest
+///
+/// Firecracker sets the offloads it needs when it opens the device.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Offloads {
+    /// Packets with a partial checksum
+    pub csum: bool,
+    /// TCP segmentation over IPv4
+    pub tso4: bool,
+    /// TCP segmentation over IPv6
+    pub tso6: bool,
+    /// TCP segmentation with ECN
+    pub tso_ecn: bool,
+    /// UDP fragmentation
+    pub ufo: bool,
+}
+
+impl Offloads {
+    /// The offloads that Firecracker enables
+    pub const FIRECRACKER: Offloads = Offloads {
+        csum: true,
+        tso4: true,
+        tso6: true,
+        tso_ecn: false,
+        ufo: true,
+    };
+
+    fn flags(self) -> libc::c_uint {
+        [
+            (self.csum, libc::TUN_F_CSUM),
+            (self.tso4, libc::TUN_F_TSO4),
+            (self.tso6, libc::TUN_F_TSO6),
+            (self.tso_ecn, libc::TUN_F_TSO_ECN),
+            (self.ufo, libc::TUN_F_UFO),
+        ]
+        .into_iter()
+        .filter(|(enabled, _)| *enabled)
+        .fold(0, |flags, (_, flag)| flags | flag)
+    }
+}
+
+// `struct ifreq`, with the flags member of its union
+#[repr(C)]
+struct IfReq {
+    name: [u8; MAX_NAME_LEN + 1],
+    flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+/// Builder of a persistent TAP device
+#[derive(Debug, Clone)]
+pub struct TapBuilder {
+    name: String,
+    owner: Option<u32>,
+    group: Option<u32>,
+    mtu: Option<u32>,
+    offloads: Option<Offloads>,
+    bridge: Option<String>,
+}
+
+impl TapBuilder {
+    /// A TAP device named `name`, which must not exist
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        TapBuilder {
+            name: name.into(),
+            owner: None,
+            group: None,
+            mtu: None,
+            offloads: None,
+            bridge: None,
+        }
+    }
+
+    /// Let the user `uid` open the device, e.g. the user Firecracker runs as
+    pub fn with_owner(mut self, uid: u32) -> Self {
+        self.owner = Some(uid);
+        self
+    }
+
+    /// Let the members of the group `gid` open the device
+    pub fn with_group(mut self, gid: u32) -> Self {
+        self.group = Some(gid);
+        self
+    }
+
+    /// Set the MTU of the device, 1500 by default
+    pub fn with_mtu(mut self, mtu: u32) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    /// Set the offloads of the device, none by default
+    pub fn with_offloads(mut self, offloads: Offloads) -> Self {
+        self.offloads = Some(offloads);
+        self
+    }
+
+    /// Attach the device to the bridge `bridge`, which must exist
+    pub fn with_bridge<S: Into<String>>(mut self, bridge: S) -> Self {
+        self.bridge = Some(bridge.into());
+        self
+    }
+
+    /// Create the device and bring it up
+    ///
+    /// The device is deleted if any step fails.
+    pub fn create(self) -> Result<Tap, NetError> {
+        check_name(&self.name)?;
+        if link_index(&self.name)?.is_some() {
+            return Err(NetError::Exists(self.name));
+        }
+        let bridge = match &self.bridge {
+            Some(bridge) => {
+                Some(link_index(bridge)?.ok_or_else(|| NetError::NotFound(bridge.clone()))?)
+            }
+            None => None,
+        };
+
+        let what = format!("create TAP device {}", self.name);
+        self.create_persistent()
+            .map_err(|err| NetError::failed(&what, err))?;
+        let index = link_index(&self.name)?.ok_or_else(|| NetError::NotFound(self.name.clone()))?;
+        // From now on, dropping `tap` deletes the device
+        let tap = Tap {
+            name: self.name,
+            index,
+        };
+
+        let mut netlink = Netlink::open().map_err(|err| NetError::failed(&what, err))?;
+        if let Some(mtu) = self.mtu {
+            netlink
+                .set_mtu(index, mtu)
+                .map_err(|err| NetError::failed(format!("set the MTU of {}", tap.name), err))?;
+        }
+        if let Some(bridge) = bridge {
+            tap.set_master(&mut netlink, bridge)?;
+        }
+        netlink
+            .set_up(index)
+            .map_err(|err| NetError::failed(format!("bring {} up", tap.name), err))?;
+        Ok(tap)
+    }
+
+    // Create the device with the ioctls of the TUN driver, and make it outlive its file
+    // descriptor.
+    fn create_persistent(&self) -> io::Result<()> {
+        let tun = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_CLOEXEC)
+            .open(TUN_PATH)?;
+        let mut request = IfReq {
+            name: [0; MAX_NAME_LEN + 1],
+            // As Firecracker opens it
+            flags: (libc::IFF_TAP | libc::IFF_NO_PI | libc::IFF_VNET_HDR) as libc::c_short,
+            _pad: [0; 22],
+        };
+        request.name[..self.name.len()].copy_from_slice(self.name.as_bytes());
+        // SAFETY: `request` is a valid `struct ifreq`, and the other requests take integers.
+        unsafe {
+            tun_ioctl(
+                &tun,
+                libc::TUNSETIFF,
+                &mut request as *mut IfReq as libc::c_ulong,
+            )?;
+            // The device is deleted when `tun` is closed until it is made persistent
+            if let Some(uid) = self.owner {
+                tun_ioctl(&tun, libc::TUNSETOWNER, uid.into())?;
+            }
+            if let Some(gid) = self.group {
+                tun_ioctl(&tun, libc::TUNSETGROUP, gid.into())?;
+            }
+            if let Some(offloads) = self.offloads {
+                tun_ioctl(&tun, libc::TUNSETOFFLOAD, offloads.flags().into())?;
+            }
+            tun_ioctl(&tun, libc::TUNSETPERSIST, 1)
+        }
+    }
+}
+
+// Issue the ioctl `request` on the TUN device.
+//
+// Safety: `arg` must be a pointer to a valid structure for requests taking one.
+unsafe fn tun_ioctl(tun: &File, request: libc::Ioctl, arg: libc::c_ulong) -> io::Result<()> {
+    if libc::ioctl(tun.as_raw_fd(), request, arg) < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A persistent TAP device, deleted when dropped
+#[derive(Debug)]
+pub struct Tap {
+    name: String,
+    index: u32,
+}
+
+impl Tap {
+    /// The existing TAP device `name`, e.g. one that was [kept](Tap::keep)
+    pub fn open(name: &str) -> Result<Tap, NetError> {
+        let index = link_index(name)?.ok_or_else(|| NetError::NotFound(name.to_owned()))?;
+        // Only TUN and TAP devices have flags
+        if !Path::new("/sys/class/net")
+            .join(name)
+            .join("tun_flags")
+            .exists()
+        {
+            return Err(NetError::NotTap(name.to_owned()));
+        }
+        Ok(Tap {
+            name: name.to_owned(),
+            index,
+        })
+    }
+
+    /// Name of the device
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Index of the device
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// A network interface of a microVM backed by the device
+    pub fn network_interface<S: Into<String>>(&self, iface_id: S) -> NetworkInterface {
+        NetworkInterface::new(self.name.clone(), iface_id.into())
+    }
+
+    /// Set the MTU of the device
+    pub fn set_mtu(&self, mtu: u32) -> Result<(), NetError> {
+        let what = format!("set the MTU of {}", self.name);
+        Netlink::open()
+            .and_then(|mut netlink| netlink.set_mtu(self.index, mtu))
+            .map_err(|err| NetError::failed(what, err))
+    }
+
+    /// Attach the device to the bridge `bridge`
+    pub fn attach_to_bridge(&self, bridge: &str) -> Result<(), NetError> {
+        let bridge = link_index(bridge)?.ok_or_else(|| NetError::NotFound(bridge.to_owned()))?;
+        let mut netlink = Netlink::open()
+            .map_err(|err| NetError::failed(format!("attach {} to a bridge", self.name), err))?;
+        self.set_master(&mut netlink, bridge)
+    }
+
+    /// Detach the device from its bridge
+    pub fn detach_from_bridge(&self) -> Result<(), NetError> {
+        let mut netlink = Netlink::open()
+            .map_err(|err| NetError::failed(format!("detach {}", self.name), err))?;
+        self.set_master(&mut netlink, 0)
+    }
+
+    fn set_master(&self, netlink: &mut Netlink, bridge: u32) -> Result<(), NetError> {
+        netlink.set_master(self.index, bridge).map_err(|err| {
+            let what = match bridge {
+                0 => format!("detach {} from its bridge", self.name),
+                _ => format!("attach {} to a bridge", self.name),
+            };
+            NetError::failed(what, err)
+        })
+    }
+
+    /// Delete the device now, reporting errors
+    pub fn delete(mut self) -> Result<(), NetError> {
+        let name = self.keep_name();
+        delete_link(&name, self.index)
+    }
+
+    /// Keep the device after the [`Tap`] is dropped, and return its name
+    pub fn keep(mut self) -> String {
+        self.keep_name()
+    }
+
+    // Take the name of the device, leaving it alone when `self` is dropped.
+    fn keep_name(&mut self) -> String {
+        std::mem::take(&mut self.name)
+    }
+}
+
+impl Drop for Tap {
+    fn drop(&mut self) {
+        // The device is kept
+        if self.name.is_empty() {
+            return;
+        }
+        if let Err(err) = delete_link(&self.name, self.index) {
+            warn!("{err}");
+        }
+    }
+}
+
+// Delete the device `name`, unless it is already gone.
+fn delete_link(name: &str, index: u32) -> Result<(), NetError> {
+    match Netlink::open().and_then(|mut netlink| netlink.delete_link(index)) {
+        Err(err) if err.raw_os_error() != Some(libc::ENODEV) => {
+            Err(NetError::failed(format!("delete TAP device {name}"), err))
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,55 @@
+//! Helpers running the tools of the host, e.g. `mkfs.ext4`, `resize2fs` or `iptables`
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+// A tool that could not be run, or that failed: its name, and the reason or its error output
+#[derive(Debug)]
+pub(crate) struct CommandError {
+    pub(crate) program: String,
+    pub(crate) message: String,
+}
+
+// Path of a system tool, e.g. `mkfs.ext4` or `iptables`. They are often installed in `/sbin` or
+// `/usr/sbin`, which are not in the `PATH` of users.
+pub(crate) fn find_tool(name: &str) -> PathBuf {
+    let in_path = std::env::var_os("PATH")
+        .into_iter()
+        .flat_map(|path| std::env::split_paths(&path).collect::<Vec<_>>())
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file());
+    let in_sbin = ["/usr/sbin", "/sbin"]
+        .into_iter()
+        .map(|dir| Path::new(dir).join(name))
+        .find(|path| path.is_file());
+    in_path.or(in_sbin).unwrap_or_else(|| PathBuf::from(name))
+}
+
+// Run `command`, and return its output, whether it succeeded or not.
+pub(crate) fn output(command: &mut Command) -> Result<Output, CommandError> {
+    command.output().map_err(|err| CommandError {
+        program: command.get_program().to_string_lossy().into_owned(),
+        message: err.to_string(),
+    })
+}
+
+// Run `command`, and fail with its error output if it fails.
+pub(crate) fn run(command: &mut Command) -> Result<Output, CommandError> {
+    let output = output(command)?;
+    if output.status.success() {
+        Ok(output)
+    } else {
+        Err(command_error(
+            &command.get_program().to_string_lossy(),
+            &output,
+        ))
+    }
+}
+
+// Error of `program`, which failed with `output`.
+pub(crate) fn command_error(program: &str, output: &Output) -> CommandError {
+    CommandError {
+        program: program.to_owned(),
+        message: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+    }
+}
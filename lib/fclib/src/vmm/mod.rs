@@ -1,10 +1,13 @@
 use std::io::{Read, Write};
+use std::os::fd::{AsFd, AsRawFd};
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
-use std::process::{Child, Command, Stdio};
+use std::process::{Child, Command, ExitStatus, Stdio};
 use std::thread;
 use std::time::Duration;
 
 use crate::client::ApiClient;
+use crate::net::netns::NetNs;
 
 #[derive(Debug)]
 pub enum LogLevel {
@@ -41,6 +44,8 @@ pub struct VmmBuilder {
     log_path: Option<PathBuf>,
     // Log level
     log_level: LogLevel,
+    // Network namespace to run Firecracker in
+    netns: Option<NetNs>,
 }
 
 impl VmmBuilder {
@@ -53,6 +58,7 @@ impl VmmBuilder {
             disable_seccomp: false,
             log_path: None,
             log_level: LogLevel::Error,
+            netns: None,
         }
     }
 
@@ -81,6 +87,15 @@ impl VmmBuilder {
         self
     }
 
+    /// Run Firecracker in the network namespace `netns`, named or anonymous
+    ///
+    /// The process enters the namespace right before Firecracker is executed. The namespace is
+    /// deleted along with the [`Vmm`], unless it was opened with [`NetNs::open`] or is kept.
+    pub fn with_netns(mut self, netns: NetNs) -> Self {
+        self.netns = Some(netns);
+        self
+    }
+
     pub fn start_vmm(self) -> std::result::Result<Vmm, std::io::Error> {
         let mut cmd = Command::new(self.fc_path);
         cmd.arg("--api-sock").arg(&self.api_sock);
@@ -102,6 +117,19 @@ impl VmmBuilder {
             cmd.arg("--level").arg(self.log_level.to_string());
         }
 
+        if let Some(netns) = &self.netns {
+            let fd = netns.as_fd().as_raw_fd();
+            // SAFETY: setns is async-signal-safe, and `fd` is open until the process is spawned.
+            unsafe {
+                cmd.pre_exec(move || {
+                    if libc::setns(fd, libc::CLONE_NEWNET) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+
         let vmm = cmd
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
@@ -115,6 +143,7 @@ impl VmmBuilder {
         Ok(Vmm {
             vmm,
             api_sock: self.api_sock,
+            netns: self.netns,
         })
     }
 }
@@ -125,6 +154,8 @@ pub struct Vmm {
     vmm: Child,
     // Unix socket of the VMM
     api_sock: PathBuf,
+    // Deleted after the process is killed
+    netns: Option<NetNs>,
 }
 
 impl Vmm {
@@ -148,9 +179,20 @@ impl Vmm {
         self.vmm.stdout.as_mut().unwrap().read_to_string(buf)
     }
 
+    /// The network namespace Firecracker runs in, if it was started in one
+    pub fn netns(&self) -> Option<&NetNs> {
+        self.netns.as_ref()
+    }
+
     pub fn api_client(&self) -> ApiClient {
         ApiClient::new(&self.api_sock)
     }
+
+    /// Kill the Firecracker process and wait for it to exit.
+    pub fn kill(&mut self) -> std::result::Result<ExitStatus, std::io::Error> {
+        self.vmm.kill()?;
+        self.vmm.wait()
+    }
 }
 
 impl Drop for Vmm {